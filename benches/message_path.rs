@@ -0,0 +1,189 @@
+//! Benchmarks of the path every message takes through wl-mitm: framing,
+//! parsing, object tracking, and all of it together in a proxy
+//!
+//! Run with `cargo bench`. Baselines are checked in under benches/baselines;
+//! see "Benchmarking" in the README for comparing against them.
+
+use std::{collections::VecDeque, hint::black_box, sync::Arc};
+
+use bytes::BytesMut;
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use fixed::types::I24F8;
+use wl_mitm::{
+    bench::{self, BENCH_DEFAULT_CONFIG, WlBenchMsg},
+    codec::WlRawMsg,
+    config::Config,
+    objects::WlObjects,
+    proto::{
+        self, WL_COMPOSITOR, WL_KEYBOARD, WL_POINTER, WL_REGISTRY, WL_SURFACE,
+        WaylandProtocolParsingOutcome, WlConstructableMessage, WlKeyboardEnterEvent,
+        WlPointerMotionEvent, WlRegistryBindRequest, WlSurfaceCommitRequest,
+        WlSurfaceDamageRequest, XDG_TOPLEVEL, XdgToplevelSetTitleRequest,
+    },
+};
+
+const REGISTRY_ID: u32 = 2;
+const COMPOSITOR_ID: u32 = 3;
+const SURFACE_ID: u32 = 4;
+const TOPLEVEL_ID: u32 = 5;
+const POINTER_ID: u32 = 6;
+const KEYBOARD_ID: u32 = 7;
+
+/// Frames per buffer when measuring [WlRawMsg::try_decode]
+const DECODE_BATCH: usize = 64;
+/// Objects alive on the connection when measuring lookups
+const LIVE_OBJECTS: u32 = 1000;
+
+/// The objects messages in these benchmarks are sent to
+fn objects() -> WlObjects {
+    let mut objects = WlObjects::new();
+    objects.record_object(WL_REGISTRY, REGISTRY_ID, 1);
+    objects.record_object(WL_COMPOSITOR, COMPOSITOR_ID, 6);
+    objects.record_child_object(WL_SURFACE, SURFACE_ID, COMPOSITOR_ID, None);
+    objects.record_object(XDG_TOPLEVEL, TOPLEVEL_ID, 6);
+    objects.record_object(WL_POINTER, POINTER_ID, 9);
+    objects.record_object(WL_KEYBOARD, KEYBOARD_ID, 9);
+    objects
+}
+
+fn decode(c: &mut Criterion) {
+    let mut buf = BytesMut::new();
+    for i in 0..DECODE_BATCH {
+        let msg = match i % 3 {
+            0 => WlSurfaceDamageRequest::new(SURFACE_ID, 0, 0, 64, 64).build(),
+            1 => WlSurfaceCommitRequest::new(SURFACE_ID).build(),
+            _ => WlPointerMotionEvent::new(POINTER_ID, i as u32, I24F8::ONE, I24F8::ONE).build(),
+        };
+        buf.extend_from_slice(msg.as_bytes());
+    }
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(DECODE_BATCH as u64));
+    group.bench_function("try_decode", |b| {
+        b.iter_batched(
+            || buf.clone(),
+            |mut buf| {
+                let mut fds = VecDeque::new();
+                while let Some(msg) = WlRawMsg::try_decode(&mut buf, &mut fds) {
+                    black_box(msg);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn parse(c: &mut Criterion) {
+    let objects = objects();
+    let requests = [
+        (
+            "wl_surface.damage",
+            WlSurfaceDamageRequest::new(SURFACE_ID, 0, 0, 64, 64).build(),
+        ),
+        (
+            "wl_registry.bind",
+            WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 8).build(),
+        ),
+        (
+            "xdg_toplevel.set_title",
+            XdgToplevelSetTitleRequest::new(TOPLEVEL_ID, "A window with a fairly long title")
+                .build(),
+        ),
+    ];
+    let events = [
+        (
+            "wl_pointer.motion",
+            WlPointerMotionEvent::new(POINTER_ID, 1, I24F8::ONE, I24F8::ONE).build(),
+        ),
+        (
+            "wl_keyboard.enter",
+            WlKeyboardEnterEvent::new(KEYBOARD_ID, 1, SURFACE_ID, &[0; 16]).build(),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("parse");
+    for (name, msg) in &requests {
+        assert!(matches!(
+            proto::decode_request(&objects, msg),
+            WaylandProtocolParsingOutcome::Ok(_)
+        ));
+        group.bench_function(*name, |b| {
+            b.iter(|| black_box(proto::decode_request(&objects, black_box(msg))))
+        });
+    }
+    for (name, msg) in &events {
+        assert!(matches!(
+            proto::decode_event(&objects, msg),
+            WaylandProtocolParsingOutcome::Ok(_)
+        ));
+        group.bench_function(*name, |b| {
+            b.iter(|| black_box(proto::decode_event(&objects, black_box(msg))))
+        });
+    }
+    group.finish();
+}
+
+fn objects_ops(c: &mut Criterion) {
+    let mut objects = objects();
+    let first = 100;
+    for id in first..first + LIVE_OBJECTS {
+        objects.record_child_object(WL_SURFACE, id, COMPOSITOR_ID, None);
+    }
+
+    let mut group = c.benchmark_group("objects");
+    group.bench_function("lookup", |b| {
+        let mut id = first;
+        b.iter(|| {
+            id = if id + 1 < first + LIVE_OBJECTS {
+                id + 1
+            } else {
+                first
+            };
+            black_box(objects.lookup_object(black_box(id)))
+        })
+    });
+    // A surface's whole life: created, destroyed by the client, then
+    // acknowledged by the server
+    group.bench_function("create_destroy", |b| {
+        let id = first + LIVE_OBJECTS;
+        b.iter(|| {
+            objects.record_child_object(WL_SURFACE, id, COMPOSITOR_ID, None);
+            objects.remove_object(id, true);
+            objects.remove_object(id, false);
+        })
+    });
+    group.bench_function("descendants", |b| {
+        b.iter(|| black_box(objects.descendants(black_box(COMPOSITOR_ID))))
+    });
+    group.finish();
+}
+
+fn proxy(c: &mut Criterion) {
+    let config = Arc::new(Config::parse(BENCH_DEFAULT_CONFIG).unwrap());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mix = [
+        WlBenchMsg::Damage,
+        WlBenchMsg::Commit,
+        WlBenchMsg::Motion,
+        WlBenchMsg::Frame,
+    ];
+
+    let mut group = c.benchmark_group("proxy");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("mixed", |b| {
+        b.iter_custom(|iters| {
+            let msgs: Vec<_> = mix.iter().copied().cycle().take(iters as usize).collect();
+            runtime
+                .block_on(bench::time_proxied(config.clone(), &msgs))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode, parse, objects_ops, proxy);
+criterion_main!(benches);
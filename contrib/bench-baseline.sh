@@ -0,0 +1,41 @@
+#!/usr/bin/env bash
+# Keep the criterion baselines under benches/baselines, and check against them
+#
+#   contrib/bench-baseline.sh save      benchmark this tree and make it the baseline
+#   contrib/bench-baseline.sh compare   benchmark this tree, failing if anything regressed
+#
+# Further arguments are passed on to criterion, e.g. a filter like `parse/`.
+# Changes within NOISE_THRESHOLD (default 0.05, i.e. 5%) aren't regressions.
+
+set -e
+
+cd "$(dirname "$0")/.."
+baselines=benches/baselines
+criterion="${CARGO_TARGET_DIR:-target}/criterion"
+cmd="$1"
+shift || true
+
+case "$cmd" in
+  save)
+    cargo bench --bench message_path -- --save-baseline main "$@"
+    rm -rf "$baselines"
+    (cd "$criterion" && find . -path '*/main/*.json') | while read -r file; do
+      mkdir -p "$baselines/$(dirname "$file")"
+      cp "$criterion/$file" "$baselines/$file"
+    done
+    ;;
+  compare)
+    mkdir -p "$criterion"
+    cp -r "$baselines/." "$criterion/"
+    out=$(cargo bench --bench message_path -- --baseline main \
+      --noise-threshold "${NOISE_THRESHOLD:-0.05}" --color never "$@" | tee /dev/stderr)
+    if grep -q "Performance has regressed" <<< "$out"; then
+      echo "Regressed against benches/baselines" >&2
+      exit 1
+    fi
+    ;;
+  *)
+    echo "usage: $0 save|compare [criterion args...]" >&2
+    exit 2
+    ;;
+esac
@@ -91,6 +91,8 @@ impl WlMsg {
         let interface_name_snake_upper =
             format_ident!("{}", self.interface_name_snake.to_uppercase());
         let msg_name_snake = &self.name_snake;
+        // e.g. "wl_surface#{}.damage("
+        let display_prefix = format!("{}#{{}}.{}(", self.interface_name_snake, self.name_snake);
 
         let struct_name = format_ident!("{}", self.struct_name());
 
@@ -159,8 +161,28 @@ impl WlMsg {
             }
         };
 
+        let creates_objects = !new_id_name.is_empty();
+        let signature = self.args.iter().map(|(_, tt)| tt.wire_arg());
         let is_destructor = self.is_destructor;
 
+        // Arguments formatted like WAYLAND_DEBUG does
+        let display_code = self
+            .args
+            .iter()
+            .enumerate()
+            .map(|(i, (arg_name, arg_type))| {
+                let arg_name_ident = format_ident!("{arg_name}");
+                let fmt_code = arg_type.generate_display_code(&arg_name_ident);
+                if i == 0 {
+                    fmt_code
+                } else {
+                    quote! {
+                        f.write_str(", ")?;
+                        #fmt_code
+                    }
+                }
+            });
+
         quote! {
             #[allow(unused, non_snake_case)]
             #[derive(Serialize)]
@@ -236,6 +258,15 @@ impl WlMsg {
                 }
             }
 
+            impl<'a> std::fmt::Display for #struct_name<'a> {
+                #[allow(unused)]
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    write!(f, #display_prefix, self.obj_id)?;
+                    #( #display_code )*
+                    f.write_str(")")
+                }
+            }
+
             unsafe impl<'a> crate::proto::DowncastableWlParsedMessage<'a> for #struct_name<'a> {
                 type Static = #struct_name<'static>;
             }
@@ -250,6 +281,30 @@ impl WlMsg {
                 ) -> crate::proto::WaylandProtocolParsingOutcome<Box<dyn crate::proto::AnyWlParsedMessage + 'msg>> {
                     #struct_name::try_from_msg(objects, msg).map(|r| Box::new(r) as Box<_>)
                 }
+
+                fn msg_name(&self) -> &'static str {
+                    #msg_name_snake
+                }
+
+                fn static_type_id(&self) -> std::any::TypeId {
+                    std::any::TypeId::of::<#struct_name<'static>>()
+                }
+
+                fn num_consumed_fds(&self) -> usize {
+                    #num_consumed_fds
+                }
+
+                fn is_destructor(&self) -> bool {
+                    #is_destructor
+                }
+
+                fn creates_objects(&self) -> bool {
+                    #creates_objects
+                }
+
+                fn signature(&self) -> &'static [crate::proto::WlWireArg] {
+                    &[ #( #signature ),* ]
+                }
             }
 
             impl<'a> crate::proto::WlConstructableMessage<'a> for #struct_name<'a> {
@@ -310,6 +365,16 @@ impl WlArgType {
         }
     }
 
+    /// How this is laid out on the wire, as a `WlWireArg`
+    pub fn wire_arg(&self) -> proc_macro2::TokenStream {
+        match self {
+            WlArgType::String => quote! { crate::proto::WlWireArg::String },
+            WlArgType::Array => quote! { crate::proto::WlWireArg::Array },
+            WlArgType::Fd => quote! { crate::proto::WlWireArg::Fd },
+            _ => quote! { crate::proto::WlWireArg::Word },
+        }
+    }
+
     /// What's the Rust type corresponding to this WL protocol type?
     /// Returned as a token that can be used directly in quote! {}
     pub fn to_rust_type(&self) -> proc_macro2::TokenStream {
@@ -436,6 +501,41 @@ impl WlArgType {
         }
     }
 
+    /// Generate code formatting the argument in a [std::fmt::Display] impl,
+    /// with `f` as the formatter
+    pub fn generate_display_code(&self, var_name: &Ident) -> proc_macro2::TokenStream {
+        match self {
+            WlArgType::Int | WlArgType::Uint | WlArgType::Fixed | WlArgType::Enum => quote! {
+                write!(f, "{}", self.#var_name)?;
+            },
+            WlArgType::Object => quote! {
+                if self.#var_name == 0 {
+                    f.write_str("nil")?;
+                } else {
+                    write!(f, "#{}", self.#var_name)?;
+                }
+            },
+            WlArgType::NewId(Some(interface)) => {
+                let fmt = format!("new id {}#{{}}", interface);
+                quote! {
+                    write!(f, #fmt, self.#var_name)?;
+                }
+            }
+            WlArgType::NewId(None) => quote! {
+                write!(f, "new id #{}", self.#var_name)?;
+            },
+            WlArgType::String => quote! {
+                write!(f, "{:?}", self.#var_name)?;
+            },
+            WlArgType::Array => quote! {
+                write!(f, "array[{}]", self.#var_name.len())?;
+            },
+            WlArgType::Fd => quote! {
+                write!(f, "fd {}", std::os::fd::AsRawFd::as_raw_fd(&self.#var_name))?;
+            },
+        }
+    }
+
     pub fn generate_builder_code(&self, var_name: &Ident) -> proc_macro2::TokenStream {
         match self {
             WlArgType::Int => quote! {
@@ -0,0 +1,218 @@
+//! Alerting on bursts of requests, turning wl-mitm into a behavioral monitor
+//!
+//! Rules under `[alerts]` count a connection's requests of some kind, and
+//! fire once a count is reached within a sliding window: more than N
+//! screencopy frames a minute, M clipboard reads an hour, a burst of blocked
+//! requests. Alerts are logged, and can be passed to `notify_cmd` and POSTed
+//! to a webhook as JSON.
+//!
+//! Counting starts over once a rule fires, so a client keeping it up is
+//! alerted on once per `count` requests rather than for every single one.
+
+use std::{
+    collections::VecDeque,
+    io,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_derive::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{error, warn};
+
+use crate::{
+    config::{WlAlertRule, WlAlertVerdict, WlAlertsConfig},
+    glob::glob_match,
+};
+
+/// How long a webhook may take to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A rule that fired
+#[derive(Serialize, Clone, Debug)]
+pub struct WlAlert {
+    pub rule: String,
+    pub conn_id: u64,
+    /// The request that made the count, which all of them matched
+    pub interface: String,
+    pub request: String,
+    pub count: usize,
+    pub window_secs: u64,
+    pub pid: Option<i32>,
+    pub exe: Option<String>,
+    /// The Flatpak app ID, or the app_id of the client's first toplevel having one
+    pub app_id: Option<String>,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl WlAlert {
+    /// An alert for `rule`, with nothing known about the client yet
+    pub fn new(rule: &WlAlertRule, conn_id: u64, interface: &str, request: &str) -> WlAlert {
+        WlAlert {
+            rule: rule.name.clone(),
+            conn_id,
+            interface: interface.to_string(),
+            request: request.to_string(),
+            count: rule.count,
+            window_secs: rule.window_secs,
+            pid: None,
+            exe: None,
+            app_id: None,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// One line for humans, e.g. as the description passed to `notify_cmd`
+    pub fn describe(&self) -> String {
+        format!(
+            "{}: {} {}::{} requests within {}s",
+            self.rule, self.count, self.interface, self.request, self.window_secs
+        )
+    }
+}
+
+/// Per-connection alert state
+pub struct WlAlerts {
+    rules: Vec<WlAlertRule>,
+    /// When each rule's matching requests were seen, oldest first
+    windows: Vec<VecDeque<Instant>>,
+}
+
+impl WlAlerts {
+    /// Returns [None] if there are no alert rules
+    pub fn new(config: &WlAlertsConfig) -> Option<WlAlerts> {
+        if config.rules.is_empty() {
+            return None;
+        }
+
+        Some(WlAlerts {
+            rules: config.rules.clone(),
+            windows: vec![VecDeque::new(); config.rules.len()],
+        })
+    }
+
+    /// Count a request seen at `now`, which was blocked if `blocked` is set.
+    /// Returns the rules this made fire.
+    pub fn record(
+        &mut self,
+        interface: &str,
+        request: &str,
+        blocked: bool,
+        now: Instant,
+    ) -> Vec<&WlAlertRule> {
+        let mut fired = Vec::new();
+        for (rule, window) in self.rules.iter().zip(self.windows.iter_mut()) {
+            if !rule.matches(interface, request, blocked) {
+                continue;
+            }
+
+            let span = Duration::from_secs(rule.window_secs);
+            while window
+                .front()
+                .is_some_and(|seen| now.duration_since(*seen) >= span)
+            {
+                window.pop_front();
+            }
+            window.push_back(now);
+
+            if window.len() >= rule.count.max(1) {
+                window.clear();
+                fired.push(rule);
+            }
+        }
+        fired
+    }
+}
+
+impl WlAlertRule {
+    fn matches(&self, interface: &str, request: &str, blocked: bool) -> bool {
+        let verdict = match self.verdict {
+            None => true,
+            Some(WlAlertVerdict::Allowed) => !blocked,
+            Some(WlAlertVerdict::Blocked) => blocked,
+        };
+
+        verdict
+            && glob_match(&self.interface, interface)
+            && self.requests.iter().any(|r| r == "*" || r == request)
+    }
+}
+
+/// Split an `http://host[:port][/path]` URL into (host, port, path)
+fn parse_http_url(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 80),
+    };
+    (!host.is_empty()).then_some((host, port, path))
+}
+
+/// POST `alert` as JSON to `url`, which has to be a plain `http://` URL.
+/// Returns the HTTP status code.
+pub async fn post_webhook(url: &str, alert: &WlAlert) -> io::Result<u16> {
+    let (host, port, path) = parse_http_url(url).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "webhook must be an http:// URL",
+        )
+    })?;
+    let body = serde_json::to_vec(alert)?;
+
+    let post = async {
+        let mut stream = tokio::net::TcpStream::connect((host, port)).await?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            host,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+
+        // All we care about is the status line
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let status_line = response.split(|b| *b == b'\n').next().unwrap_or_default();
+        std::str::from_utf8(status_line)
+            .ok()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))
+    };
+
+    tokio::time::timeout(WEBHOOK_TIMEOUT, post)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Log `alert` and POST it to the webhook, if any, in the background
+pub fn dispatch(config: &WlAlertsConfig, alert: WlAlert) {
+    warn!(
+        rule = alert.rule,
+        interface = alert.interface,
+        request = alert.request,
+        count = alert.count,
+        window_secs = alert.window_secs,
+        "Alert: {}",
+        alert.describe()
+    );
+
+    let Some(url) = config.webhook.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        match post_webhook(&url, &alert).await {
+            Ok(status) if (200..300).contains(&status) => {}
+            Ok(status) => error!(status, "Alert webhook refused the alert"),
+            Err(e) => error!(error = ?e, "Cannot POST alert to webhook"),
+        }
+    });
+}
@@ -0,0 +1,259 @@
+//! Tamper-evident audit log of policy decisions
+//!
+//! With `audit.file`, every request a `[[filter.requests]]` rule matches, and
+//! every one denied while the session is locked or by an enforced app profile,
+//! is appended to that file as a JSON record per line: who sent it, what it
+//! was, what came of it and where its object came from.
+//!
+//! Records are chained. Each carries the MAC of the record before it in
+//! `prev`, and its own in `mac`, which is always the last field and covers the
+//! line up to it. Altering, removing or reordering records breaks the chain,
+//! and `wl-mitm verify-audit` points out where. The MAC is HMAC-SHA256 keyed
+//! with the contents of `audit.key_file`, or a plain SHA-256 without one. A
+//! plain hash chain only catches accidents, as whoever can write the log can
+//! recompute it as well.
+//!
+//! No chain can tell a log whose last records were cut off from one that ended
+//! there. The sequence number and MAC of every record are logged as well, and
+//! `verify-audit --head` checks that the log still contains a given record.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use hmac::{Hmac, Mac};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::WlAuditConfig,
+    objects::{WlProvenanceRecord, WlProvenanceVerdict},
+};
+
+/// `prev` of the first record
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One decision, as written to the log along with `seq`, `prev` and `mac`
+#[derive(Serialize, Clone, Debug)]
+pub struct WlAuditEntry {
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    pub exe: Option<String>,
+    pub app_id: Option<String>,
+    pub flatpak_app_id: Option<String>,
+    pub security_context: Option<String>,
+    pub interface: String,
+    pub request: String,
+    /// The rule which decided, by its `desc` or as `interface::request`, or
+    /// `lock.deny` and `learning` for requests denied by those
+    pub rule: String,
+    pub verdict: WlProvenanceVerdict,
+    /// Where the object the request was sent on came from
+    pub provenance: Vec<WlProvenanceRecord>,
+}
+
+#[derive(Serialize)]
+struct WlAuditRecord<'a> {
+    seq: u64,
+    /// Milliseconds since the Unix epoch
+    time: u64,
+    #[serde(flatten)]
+    entry: &'a WlAuditEntry,
+    prev: &'a str,
+}
+
+struct WlAuditWriter {
+    file: File,
+    /// Sequence number of the next record
+    seq: u64,
+    /// MAC of the last record
+    prev: String,
+}
+
+/// The audit log, shared by all connections
+pub struct WlAuditLog {
+    path: PathBuf,
+    key: Option<Vec<u8>>,
+    writer: Mutex<WlAuditWriter>,
+}
+
+impl WlAuditLog {
+    /// Open the log for appending, continuing the chain of any records it
+    /// already has. Returns [None] if auditing is not enabled, and an error
+    /// if the last record is malformed: a new chain in the same file would
+    /// hide whatever came before it from `verify-audit`.
+    pub fn open(config: &WlAuditConfig) -> io::Result<Option<Arc<WlAuditLog>>> {
+        let Some(ref path) = config.file else {
+            return Ok(None);
+        };
+        let key = config.key_file.as_ref().map(std::fs::read).transpose()?;
+        if key.is_none() {
+            warn!("No audit.key_file given, the audit log is only hash-chained");
+        }
+
+        let (seq, prev) = match std::fs::read_to_string(path) {
+            Ok(contents) => match contents.lines().last().map(parse_head) {
+                None => (0, GENESIS.to_string()),
+                Some(Some((seq, mac))) => (seq + 1, mac),
+                Some(None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "last record of the audit log {} is malformed; move the log aside to start a new one",
+                            path
+                        ),
+                    ));
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, GENESIS.to_string()),
+            Err(e) => return Err(e),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        info!(path = path, seq = seq, "Writing the audit log");
+        Ok(Some(Arc::new(WlAuditLog {
+            path: path.into(),
+            key,
+            writer: Mutex::new(WlAuditWriter { file, seq, prev }),
+        })))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `entry` to the log
+    pub fn record(&self, entry: &WlAuditEntry) {
+        let mut writer = self.writer.lock().unwrap();
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let body = serde_json::to_string(&WlAuditRecord {
+            seq: writer.seq,
+            time,
+            entry,
+            prev: &writer.prev,
+        })
+        .unwrap();
+        let mac = compute_mac(self.key.as_deref(), &body);
+        let line = format!("{}\n", with_mac(&body, &mac));
+
+        if let Err(e) = writer.file.write_all(line.as_bytes()) {
+            error!(error = ?e, path = ?self.path, "Failed to write audit record");
+            return;
+        }
+        info!(seq = writer.seq, mac = mac, "Audit record written");
+        writer.seq += 1;
+        writer.prev = mac;
+    }
+}
+
+/// What [verify] found in an intact log
+#[derive(Debug, PartialEq, Eq)]
+pub struct WlAuditSummary {
+    pub records: u64,
+    /// `(seq, mac)` of the last record
+    pub head: Option<(u64, String)>,
+}
+
+/// Check the chain of the log in `contents`, with the key it was written with.
+/// With `expected_head`, the log also has to contain the record with that MAC.
+pub fn verify(
+    contents: &str,
+    key: Option<&[u8]>,
+    expected_head: Option<&str>,
+) -> Result<WlAuditSummary, String> {
+    let mut prev = GENESIS.to_string();
+    let mut head = None;
+    let mut found_head = false;
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_no = i + 1;
+        let value: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| format!("line {}: not a record ({})", line_no, e))?;
+        let field = |name: &str| value.get(name).and_then(|v| v.as_str());
+        let (Some(seq), Some(record_prev), Some(mac)) = (
+            value.get("seq").and_then(|v| v.as_u64()),
+            field("prev"),
+            field("mac"),
+        ) else {
+            return Err(format!("line {}: seq, prev or mac missing", line_no));
+        };
+
+        if seq != i as u64 {
+            return Err(format!(
+                "line {}: record {} where {} was expected",
+                line_no, seq, i
+            ));
+        }
+        if record_prev != prev {
+            return Err(format!(
+                "line {}: record {} doesn't follow the record before it",
+                line_no, seq
+            ));
+        }
+        let body = without_mac(line, mac)
+            .ok_or_else(|| format!("line {}: mac is not the last field", line_no))?;
+        if compute_mac(key, &body) != mac {
+            return Err(format!(
+                "line {}: record {} has been altered, or the key is wrong",
+                line_no, seq
+            ));
+        }
+
+        found_head |= expected_head == Some(mac);
+        prev = mac.to_string();
+        head = Some((seq, prev.clone()));
+    }
+
+    if let Some(expected) = expected_head
+        && !found_head
+    {
+        return Err(format!(
+            "no record has mac {}, the log has been cut short",
+            expected
+        ));
+    }
+
+    Ok(WlAuditSummary {
+        records: head.as_ref().map_or(0, |(seq, _)| seq + 1),
+        head,
+    })
+}
+
+/// `(seq, mac)` of a record, without checking anything else
+fn parse_head(line: &str) -> Option<(u64, String)> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    Some((
+        value.get("seq")?.as_u64()?,
+        value.get("mac")?.as_str()?.to_string(),
+    ))
+}
+
+fn compute_mac(key: Option<&[u8]>, body: &str) -> String {
+    let digest = match key {
+        Some(key) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+            mac.update(body.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        None => Sha256::digest(body.as_bytes()).to_vec(),
+    };
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `body`, a JSON object, with `mac` added as its last field
+fn with_mac(body: &str, mac: &str) -> String {
+    format!("{},\"mac\":\"{}\"}}", &body[..body.len() - 1], mac)
+}
+
+/// The inverse of [with_mac]
+fn without_mac(line: &str, mac: &str) -> Option<String> {
+    let body = line.strip_suffix(&format!(",\"mac\":\"{}\"}}", mac))?;
+    Some(format!("{}}}", body))
+}
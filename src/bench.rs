@@ -0,0 +1,384 @@
+//! `wl-mitm bench`: measures the overhead of the proxy path in-process
+//!
+//! A synthetic client and server exchange a configurable mix of messages,
+//! once over a direct socket pair and once through a [ConnDuplex] running
+//! the full decode -> state -> encode pipeline. The difference between the two
+//! is the latency and throughput cost of wl-mitm itself.
+
+use std::{
+    collections::VecDeque,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use fixed::types::I24F8;
+use tokio::{net::UnixStream, task::JoinHandle};
+
+use crate::{
+    ConnDuplex,
+    codec::{DecoderOutcome, WlRawMsg},
+    config::Config,
+    io_util::{WlMsgReader, WlMsgWriter},
+    proto::{
+        WL_DISPLAY_OBJECT_ID, WlCompositorCreateSurfaceRequest, WlConstructableMessage,
+        WlDisplayGetRegistryRequest, WlPointerFrameEvent, WlPointerMotionEvent,
+        WlRegistryBindRequest, WlRegistryGlobalEvent, WlSeatGetPointerRequest,
+        WlSurfaceCommitRequest, WlSurfaceDamageRequest,
+    },
+    socket::WlStream,
+    state::WlMitmState,
+};
+
+const BENCH_REGISTRY_ID: u32 = 2;
+const BENCH_COMPOSITOR_ID: u32 = 3;
+const BENCH_SEAT_ID: u32 = 4;
+const BENCH_SURFACE_ID: u32 = 5;
+const BENCH_POINTER_ID: u32 = 6;
+
+/// Messages allowed in flight at once when measuring throughput
+const BENCH_THROUGHPUT_WINDOW: usize = 64;
+/// Give up if nothing arrives for this long, e.g. because the config filters messages
+const BENCH_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Used unless a config file is given; allows everything the benchmark needs
+pub const BENCH_DEFAULT_CONFIG: &str = r#"
+[socket]
+listen = "@wl-mitm-bench"
+upstream = "@wl-mitm-bench-upstream"
+
+[filter]
+allowed_globals = ["wl_compositor", "wl_seat"]
+requests = []
+"#;
+
+#[derive(Clone, Copy, Debug)]
+pub enum WlBenchMsg {
+    /// wl_surface.damage (request)
+    Damage,
+    /// wl_surface.commit (request)
+    Commit,
+    /// wl_pointer.motion (event)
+    Motion,
+    /// wl_pointer.frame (event)
+    Frame,
+}
+
+impl WlBenchMsg {
+    pub fn parse(s: &str) -> Option<WlBenchMsg> {
+        match s {
+            "damage" => Some(WlBenchMsg::Damage),
+            "commit" => Some(WlBenchMsg::Commit),
+            "motion" => Some(WlBenchMsg::Motion),
+            "frame" => Some(WlBenchMsg::Frame),
+            _ => None,
+        }
+    }
+
+    fn is_request(&self) -> bool {
+        matches!(self, WlBenchMsg::Damage | WlBenchMsg::Commit)
+    }
+
+    fn build(&self, seq: usize) -> WlRawMsg {
+        match self {
+            WlBenchMsg::Damage => {
+                WlSurfaceDamageRequest::new(BENCH_SURFACE_ID, 0, 0, 64, 64).build()
+            }
+            WlBenchMsg::Commit => WlSurfaceCommitRequest::new(BENCH_SURFACE_ID).build(),
+            WlBenchMsg::Motion => WlPointerMotionEvent::new(
+                BENCH_POINTER_ID,
+                seq as u32,
+                I24F8::from_num(seq % 1000),
+                I24F8::from_num(seq % 700),
+            )
+            .build(),
+            WlBenchMsg::Frame => WlPointerFrameEvent::new(BENCH_POINTER_ID).build(),
+        }
+    }
+}
+
+pub struct WlBenchOptions {
+    pub messages: usize,
+    /// Kinds of messages along with their relative weights
+    pub mix: Vec<(WlBenchMsg, usize)>,
+    pub config: Arc<Config>,
+}
+
+struct Endpoint<'a> {
+    reader: WlMsgReader<'a>,
+    writer: WlMsgWriter<'a>,
+}
+
+impl<'a> Endpoint<'a> {
+    fn new(stream: &'a mut WlStream) -> Self {
+        let (r, w) = stream.split();
+        Endpoint {
+            reader: WlMsgReader::new(r),
+            writer: WlMsgWriter::new(w),
+        }
+    }
+
+    async fn send(&mut self, msg: WlRawMsg) -> io::Result<()> {
+        self.writer.queue_write(msg);
+        while self.writer.has_pending_writes() {
+            self.writer.dequeue_write().await?;
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> io::Result<WlRawMsg> {
+        loop {
+            match self.reader.read().await? {
+                DecoderOutcome::Decoded(msg) => return Ok(msg),
+                DecoderOutcome::Eof => return Err(eof()),
+                DecoderOutcome::Incomplete => continue,
+            }
+        }
+    }
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "benchmark peer went away")
+}
+
+fn stream_pair() -> io::Result<(WlStream, WlStream)> {
+    let (a, b) = UnixStream::pair()?;
+    Ok((WlStream::Unix(a), WlStream::Unix(b)))
+}
+
+/// Create the objects messages in the mix are sent to
+async fn handshake(client: &mut Endpoint<'_>, server: &mut Endpoint<'_>) -> io::Result<()> {
+    client
+        .send(WlDisplayGetRegistryRequest::new(WL_DISPLAY_OBJECT_ID, BENCH_REGISTRY_ID).build())
+        .await?;
+    server.recv().await?;
+
+    server
+        .send(WlRegistryGlobalEvent::new(BENCH_REGISTRY_ID, 1, "wl_compositor", 6).build())
+        .await?;
+    server
+        .send(WlRegistryGlobalEvent::new(BENCH_REGISTRY_ID, 2, "wl_seat", 9).build())
+        .await?;
+    for _ in 0..2 {
+        client.recv().await?;
+    }
+
+    for msg in [
+        WlRegistryBindRequest::new(
+            BENCH_REGISTRY_ID,
+            1,
+            "wl_compositor",
+            6,
+            BENCH_COMPOSITOR_ID,
+        )
+        .build(),
+        WlRegistryBindRequest::new(BENCH_REGISTRY_ID, 2, "wl_seat", 9, BENCH_SEAT_ID).build(),
+        WlCompositorCreateSurfaceRequest::new(BENCH_COMPOSITOR_ID, BENCH_SURFACE_ID).build(),
+        WlSeatGetPointerRequest::new(BENCH_SEAT_ID, BENCH_POINTER_ID).build(),
+    ] {
+        client.send(msg).await?;
+        server.recv().await?;
+    }
+
+    Ok(())
+}
+
+/// Push `msgs` through with at most `window` messages in flight. Returns the
+/// total time taken and the latency of every message.
+async fn pump(
+    client: &mut Endpoint<'_>,
+    server: &mut Endpoint<'_>,
+    msgs: &[WlBenchMsg],
+    window: usize,
+) -> io::Result<(Duration, Vec<Duration>)> {
+    let mut sent_c2s: VecDeque<Instant> = VecDeque::new();
+    let mut sent_s2c: VecDeque<Instant> = VecDeque::new();
+    let mut latencies = Vec::with_capacity(msgs.len());
+    let mut next = 0;
+
+    let start = Instant::now();
+    while latencies.len() < msgs.len() {
+        while next < msgs.len() && next - latencies.len() < window {
+            let msg = msgs[next];
+            if msg.is_request() {
+                client.writer.queue_write(msg.build(next));
+                sent_c2s.push_back(Instant::now());
+            } else {
+                server.writer.queue_write(msg.build(next));
+                sent_s2c.push_back(Instant::now());
+            }
+            next += 1;
+        }
+
+        tokio::select! {
+            biased;
+
+            res = client.writer.dequeue_write() => res?,
+            res = server.writer.dequeue_write() => res?,
+
+            msg = server.reader.read() => match msg? {
+                DecoderOutcome::Decoded(_) => {
+                    latencies.push(sent_c2s.pop_front().ok_or_else(eof)?.elapsed());
+                }
+                DecoderOutcome::Eof => return Err(eof()),
+                DecoderOutcome::Incomplete => {}
+            },
+            msg = client.reader.read() => match msg? {
+                DecoderOutcome::Decoded(_) => {
+                    latencies.push(sent_s2c.pop_front().ok_or_else(eof)?.elapsed());
+                }
+                DecoderOutcome::Eof => return Err(eof()),
+                DecoderOutcome::Incomplete => {}
+            },
+
+            _ = tokio::time::sleep(BENCH_STALL_TIMEOUT) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "messages stopped arriving; is the config filtering them?",
+                ));
+            }
+        }
+    }
+
+    Ok((start.elapsed(), latencies))
+}
+
+struct BenchResult {
+    throughput: f64,
+    latencies: Vec<Duration>,
+}
+
+impl BenchResult {
+    fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let idx = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+        self.latencies[idx]
+    }
+}
+
+async fn run_one(
+    client: &mut WlStream,
+    server: &mut WlStream,
+    msgs: &[WlBenchMsg],
+) -> io::Result<BenchResult> {
+    let mut client = Endpoint::new(client);
+    let mut server = Endpoint::new(server);
+
+    handshake(&mut client, &mut server).await?;
+
+    let (elapsed, _) = pump(&mut client, &mut server, msgs, BENCH_THROUGHPUT_WINDOW).await?;
+    let (_, mut latencies) = pump(&mut client, &mut server, msgs, 1).await?;
+    latencies.sort();
+
+    Ok(BenchResult {
+        throughput: msgs.len() as f64 / elapsed.as_secs_f64(),
+        latencies,
+    })
+}
+
+async fn run_direct(msgs: &[WlBenchMsg]) -> io::Result<BenchResult> {
+    let (mut client, mut server) = stream_pair()?;
+    run_one(&mut client, &mut server, msgs).await
+}
+
+/// Run a proxy between a client and a server, returning their ends
+fn spawn_proxy(
+    config: Arc<Config>,
+) -> io::Result<(WlStream, WlStream, JoinHandle<io::Result<()>>)> {
+    let (client, mut downstream) = stream_pair()?;
+    let (mut upstream, server) = stream_pair()?;
+
+    let proxy = tokio::spawn(async move {
+        let state = WlMitmState::new(config.clone(), None);
+        ConnDuplex::new(config, 0, state, None, &mut upstream, &mut downstream)
+            .run_to_completion()
+            .await
+    });
+    Ok((client, server, proxy))
+}
+
+async fn run_proxied(config: Arc<Config>, msgs: &[WlBenchMsg]) -> io::Result<BenchResult> {
+    let (mut client, mut server, proxy) = spawn_proxy(config)?;
+    let res = run_one(&mut client, &mut server, msgs).await;
+    drop(client);
+    drop(server);
+    proxy.await.map_err(io::Error::other)??;
+    res
+}
+
+/// How long it takes to push `msgs` through a fresh proxy, not counting its
+/// setup. For harnesses doing statistics of their own, e.g. `cargo bench`.
+pub async fn time_proxied(config: Arc<Config>, msgs: &[WlBenchMsg]) -> io::Result<Duration> {
+    let (mut client, mut server, proxy) = spawn_proxy(config)?;
+    let res = async {
+        let mut client = Endpoint::new(&mut client);
+        let mut server = Endpoint::new(&mut server);
+        handshake(&mut client, &mut server).await?;
+        let (elapsed, _) = pump(&mut client, &mut server, msgs, BENCH_THROUGHPUT_WINDOW).await?;
+        Ok(elapsed)
+    }
+    .await;
+    drop(client);
+    drop(server);
+    proxy.await.map_err(io::Error::other)??;
+    res
+}
+
+fn mix_sequence(opts: &WlBenchOptions) -> Vec<WlBenchMsg> {
+    // Interleave kinds according to their weights, e.g. damage=2,commit=1
+    // yields damage, damage, commit, damage, damage, commit...
+    let pattern: Vec<_> = opts
+        .mix
+        .iter()
+        .flat_map(|(kind, weight)| std::iter::repeat_n(*kind, *weight))
+        .collect();
+    pattern
+        .iter()
+        .copied()
+        .cycle()
+        .take(opts.messages)
+        .collect()
+}
+
+pub async fn bench(opts: WlBenchOptions) -> io::Result<()> {
+    let msgs = mix_sequence(&opts);
+    if msgs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "nothing to benchmark",
+        ));
+    }
+
+    let direct = run_direct(&msgs).await?;
+    let proxied = run_proxied(opts.config.clone(), &msgs).await?;
+
+    println!("messages: {}, mix: {:?}", msgs.len(), opts.mix);
+    println!(
+        "{:<10} {:>14} {:>10} {:>10} {:>10}",
+        "", "throughput/s", "p50", "p99", "max"
+    );
+    for (name, res) in [("direct", &direct), ("proxied", &proxied)] {
+        println!(
+            "{:<10} {:>14.0} {:>10.1?} {:>10.1?} {:>10.1?}",
+            name,
+            res.throughput,
+            res.percentile(0.5),
+            res.percentile(0.99),
+            res.percentile(1.0)
+        );
+    }
+    println!(
+        "added latency: p50 {:.1?}, p99 {:.1?}; throughput {:.1}% of direct",
+        proxied
+            .percentile(0.5)
+            .saturating_sub(direct.percentile(0.5)),
+        proxied
+            .percentile(0.99)
+            .saturating_sub(direct.percentile(0.99)),
+        proxied.throughput / direct.throughput * 100.0
+    );
+
+    Ok(())
+}
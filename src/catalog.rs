@@ -0,0 +1,128 @@
+//! Message catalogs, for prompts in the user's language
+//!
+//! A rule's `desc` starting with `@` names a message in the catalogs under
+//! `i18n.catalog_dir` instead, e.g. `desc = "@clipboard.paste"`. Catalogs are
+//! TOML files named after a locale, mapping keys to text; tables nest, such
+//! that `paste` in `[clipboard]` is `clipboard.paste`.
+//!
+//! The locale is `i18n.locale`, or the first of `LC_ALL`, `LC_MESSAGES` and
+//! `LANG` that is set. For `de_DE.UTF-8`, messages are looked up in
+//! `de_DE.toml`, then `de.toml`, then `en.toml`; missing files are skipped.
+//! Keys found in none of them stand for themselves. Catalogs are loaded once,
+//! along with the config.
+
+use std::{collections::HashMap, io, path::Path};
+
+use tracing::warn;
+
+use crate::config::WlI18nConfig;
+
+/// Where messages come from if no catalog of the locale has them
+const FALLBACK_LOCALE: &str = "en";
+
+#[derive(Default, Debug)]
+pub struct WlCatalog {
+    /// The locale messages are looked up for, if catalogs are used
+    locale: Option<String>,
+    messages: HashMap<String, String>,
+}
+
+/// A rule's `desc`, as shown to the user
+#[derive(Debug, PartialEq, Eq)]
+pub struct WlResolvedDesc<'a> {
+    pub text: &'a str,
+    /// The catalog key `text` was looked up by, if any
+    pub key: Option<&'a str>,
+}
+
+impl WlCatalog {
+    /// Load the catalogs of the configured locale. Empty if there is no
+    /// catalog directory.
+    pub fn load(config: &WlI18nConfig) -> io::Result<WlCatalog> {
+        let Some(ref dir) = config.catalog_dir else {
+            return Ok(Default::default());
+        };
+        let locale = config
+            .locale
+            .clone()
+            .or_else(env_locale)
+            .unwrap_or_else(|| FALLBACK_LOCALE.to_string());
+
+        // Least specific first, such that more specific catalogs take precedence
+        let mut candidates = vec![FALLBACK_LOCALE.to_string()];
+        let base = locale.split(['.', '@']).next().unwrap_or_default();
+        if let Some((language, _)) = base.split_once('_') {
+            candidates.push(language.to_string());
+        }
+        candidates.push(base.to_string());
+        candidates.dedup();
+
+        let mut messages = HashMap::new();
+        for candidate in candidates {
+            let path = Path::new(dir).join(format!("{}.toml", candidate));
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let table: toml::Table = toml::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            flatten("", table, &mut messages);
+        }
+
+        Ok(WlCatalog {
+            locale: Some(locale),
+            messages,
+        })
+    }
+
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    /// Look `desc` up if it names a message
+    pub fn resolve<'a>(&'a self, desc: &'a str) -> WlResolvedDesc<'a> {
+        let Some(key) = desc.strip_prefix('@') else {
+            return WlResolvedDesc {
+                text: desc,
+                key: None,
+            };
+        };
+
+        let text = self.messages.get(key).map_or_else(
+            || {
+                warn!(key = key, locale = ?self.locale, "No message in any catalog");
+                key
+            },
+            String::as_str,
+        );
+        WlResolvedDesc {
+            text,
+            key: Some(key),
+        }
+    }
+}
+
+fn env_locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+}
+
+/// Add the messages in `table` to `messages`, with keys prefixed by `prefix`
+fn flatten(prefix: &str, table: toml::Table, messages: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let key = match prefix {
+            "" => key,
+            _ => format!("{}.{}", prefix, key),
+        };
+        match value {
+            toml::Value::String(text) => {
+                messages.insert(key, text);
+            }
+            toml::Value::Table(table) => flatten(&key, table, messages),
+            _ => warn!(key = key, "Ignoring catalog entry which isn't text"),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Chaos mode: mutates, drops, duplicates and reorders a random selection of
+//! forwarded messages, for compositor and client developers who want to see
+//! how their code copes with a slightly hostile peer.
+//!
+//! Mutations are applied after wl-mitm has reached its verdict, so filtering
+//! and recordings always see the original messages. For that reason, the
+//! arguments of requests are never touched: a flipped bit could turn an
+//! allowed request into one filter rules would have blocked. Every injected
+//! mutation is logged at the WARN level along with the seed, such that a run
+//! can be reproduced by setting the same seed.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+use bytes::BufMut;
+use tracing::{info, warn};
+
+use crate::{
+    codec::WlRawMsg,
+    config::{WlChaosConfig, WlChaosDirection, WlChaosStrategy},
+    glob::glob_match,
+    recorder::WlDirection,
+};
+
+/// SplitMix64; good enough for picking victims, and trivially seedable
+struct WlChaosRng(u64);
+
+impl WlChaosRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// How long [WlChaosStrategy::Reorder] holds a message back at most. Peers
+/// waiting for an answer to it, e.g. a client waiting for its
+/// `wl_display.sync` to be done, would otherwise never send the next one.
+const REORDER_MAX_HOLD: Duration = Duration::from_millis(250);
+
+/// Per-connection chaos state
+pub struct WlChaos {
+    probability: f64,
+    strategies: Vec<WlChaosStrategy>,
+    direction: WlChaosDirection,
+    interfaces: Vec<String>,
+    rng: WlChaosRng,
+    /// Messages held back by [WlChaosStrategy::Reorder], per direction, along
+    /// with when they were
+    held_c2s: Option<(WlRawMsg, Instant)>,
+    held_s2c: Option<(WlRawMsg, Instant)>,
+}
+
+impl WlChaos {
+    /// Returns [None] if chaos mode is disabled
+    pub fn new(config: &WlChaosConfig) -> Option<WlChaos> {
+        if !config.enabled || config.strategies.is_empty() {
+            return None;
+        }
+
+        let seed = config
+            .seed
+            .unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        info!(
+            seed = seed,
+            probability = config.probability,
+            strategies = ?config.strategies,
+            "Chaos mode enabled for this connection"
+        );
+
+        Some(WlChaos {
+            probability: config.probability,
+            strategies: config.strategies.clone(),
+            direction: config.direction,
+            interfaces: config.interfaces.clone(),
+            rng: WlChaosRng(seed),
+            held_c2s: None,
+            held_s2c: None,
+        })
+    }
+
+    fn is_eligible(&self, direction: WlDirection, interface: Option<&str>) -> bool {
+        let direction_matches = match self.direction {
+            WlChaosDirection::Requests => direction == WlDirection::ClientToServer,
+            WlChaosDirection::Events => direction == WlDirection::ServerToClient,
+            WlChaosDirection::Both => true,
+        };
+
+        direction_matches
+            && (self.interfaces.is_empty()
+                || interface.is_some_and(|i| self.interfaces.iter().any(|pat| glob_match(pat, i))))
+    }
+
+    /// Pass an allowed message through chaos. Returns the messages to actually
+    /// forward, in order, in place of `msg`.
+    pub fn apply(
+        &mut self,
+        direction: WlDirection,
+        interface: Option<&str>,
+        msg: WlRawMsg,
+    ) -> Vec<WlRawMsg> {
+        let held = self.held(direction).take().map(|(msg, _)| msg);
+
+        let mut out = Vec::with_capacity(2);
+
+        if self.is_eligible(direction, interface) && self.rng.next_f64() < self.probability {
+            let strategy = self.strategies[self.rng.below(self.strategies.len())];
+            self.mutate(direction, interface, strategy, msg, &mut out);
+        } else {
+            out.push(msg);
+        }
+
+        // Whatever was held back goes out after the message that overtook it
+        out.extend(held);
+        out
+    }
+
+    fn held(&mut self, direction: WlDirection) -> &mut Option<(WlRawMsg, Instant)> {
+        match direction {
+            WlDirection::ClientToServer => &mut self.held_c2s,
+            WlDirection::ServerToClient => &mut self.held_s2c,
+        }
+    }
+
+    /// When the earliest message held back has to be let go, see [Self::release_overdue]
+    pub fn release_deadline(&self) -> Option<Instant> {
+        [&self.held_c2s, &self.held_s2c]
+            .into_iter()
+            .flatten()
+            .map(|(_, since)| *since + REORDER_MAX_HOLD)
+            .min()
+    }
+
+    /// Messages held back for too long with no other message overtaking them
+    pub fn release_overdue(&mut self) -> Vec<(WlDirection, WlRawMsg)> {
+        let now = Instant::now();
+        [WlDirection::ClientToServer, WlDirection::ServerToClient]
+            .into_iter()
+            .filter_map(|direction| {
+                let held = self.held(direction);
+                if held
+                    .as_ref()
+                    .is_some_and(|(_, since)| *since + REORDER_MAX_HOLD <= now)
+                {
+                    let (msg, _) = held.take().unwrap();
+                    warn!(direction = ?direction, "Chaos: releasing held back message");
+                    Some((direction, msg))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn mutate(
+        &mut self,
+        direction: WlDirection,
+        interface: Option<&str>,
+        strategy: WlChaosStrategy,
+        mut msg: WlRawMsg,
+        out: &mut Vec<WlRawMsg>,
+    ) {
+        let (obj_id, opcode) = (msg.obj_id, msg.opcode);
+        let interface = interface.unwrap_or("(unknown)");
+        let mut payload = msg.payload().to_vec();
+
+        macro_rules! log_mutation {
+            ($($arg:tt)*) => {
+                warn!(
+                    direction = ?direction,
+                    obj_id = obj_id,
+                    opcode = opcode,
+                    interface = interface,
+                    strategy = ?strategy,
+                    $($arg)*
+                )
+            };
+        }
+
+        match strategy {
+            WlChaosStrategy::BitFlip
+                if direction == WlDirection::ServerToClient && !payload.is_empty() =>
+            {
+                let bit = self.rng.below(payload.len() * 8);
+                payload[bit / 8] ^= 1 << (bit % 8);
+                log_mutation!(bit = bit, "Chaos: flipped a bit in the arguments");
+                out.push(rebuild(&mut msg, &payload));
+            }
+            WlChaosStrategy::Scramble
+                if direction == WlDirection::ServerToClient && payload.len() >= 4 =>
+            {
+                let word = self.rng.below(payload.len() / 4);
+                let old = u32::from_ne_bytes(payload[word * 4..word * 4 + 4].try_into().unwrap());
+                let new = self.rng.next_u64() as u32;
+                payload[word * 4..word * 4 + 4].copy_from_slice(&new.to_ne_bytes());
+                log_mutation!(
+                    word = word,
+                    old = old,
+                    new = new,
+                    "Chaos: scrambled an argument word"
+                );
+                out.push(rebuild(&mut msg, &payload));
+            }
+            WlChaosStrategy::Duplicate if msg.fds.is_empty() => {
+                log_mutation!("Chaos: duplicated message");
+                out.push(rebuild(&mut msg, &payload));
+                out.push(msg);
+            }
+            WlChaosStrategy::Drop => {
+                log_mutation!(num_fds = msg.fds.len(), "Chaos: dropped message");
+            }
+            WlChaosStrategy::Reorder => {
+                log_mutation!("Chaos: holding message back until the next one");
+                *self.held(direction) = Some((msg, Instant::now()));
+            }
+            // The message can't take this kind of mutation (a request, no
+            // arguments, or fds that can't be duplicated); let it through untouched
+            _ => out.push(msg),
+        }
+    }
+}
+
+/// A copy of `msg` with its arguments replaced, taking over its fds
+fn rebuild(msg: &mut WlRawMsg, payload: &[u8]) -> WlRawMsg {
+    let fds = std::mem::take(&mut msg.fds);
+    WlRawMsg::build(msg.obj_id, msg.opcode, |buf, new_fds| {
+        buf.put_slice(payload);
+        new_fds.extend(fds);
+    })
+}
@@ -61,6 +61,11 @@ impl WlRawMsg {
         &self.msg_buf[8..]
     }
 
+    /// The entire message, including its header
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.msg_buf
+    }
+
     pub fn into_parts(self) -> (Bytes, Box<[OwnedFd]>) {
         (self.msg_buf, self.fds.into_boxed_slice())
     }
@@ -105,6 +110,12 @@ pub struct WlDecoder {
     fds: VecDeque<OwnedFd>,
 }
 
+impl Default for WlDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WlDecoder {
     pub fn new() -> WlDecoder {
         WlDecoder {
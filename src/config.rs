@@ -1,11 +1,19 @@
 use std::{
     collections::{HashMap, HashSet},
-    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use serde::{Deserialize, Deserializer};
+use serde::{
+    Deserializer,
+    de::{self, Deserialize},
+};
 use serde_derive::Deserialize;
 
+use crate::{
+    catalog::WlCatalog, glob::glob_match, objects::WlObjectType, peer::WlPeerInfo, proto,
+    socket::WlSocketAddr,
+};
+
 #[derive(Deserialize)]
 pub struct Config {
     pub socket: WlSockets,
@@ -13,45 +21,263 @@ pub struct Config {
     pub exec: WlExec,
     #[serde(default)]
     pub logging: WlLogging,
+    #[serde(default)]
+    pub recording: WlRecording,
+    #[serde(default)]
+    pub trace: WlTraceConfig,
+    #[serde(default)]
+    pub dump: WlDumpConfig,
+    #[serde(default)]
+    pub control: WlControlConfig,
+    #[serde(default)]
+    pub runtime: WlRuntimeConfig,
+    #[serde(default)]
+    pub sandbox: WlSandboxConfig,
+    #[serde(default)]
+    pub health: WlHealthConfig,
+    #[serde(default)]
+    pub chaos: WlChaosConfig,
+    #[serde(default)]
+    pub objects: WlObjectsConfig,
+    #[serde(default)]
+    pub validation: WlValidationConfig,
+    #[serde(default)]
+    pub learning: WlLearningConfig,
+    #[serde(default)]
+    pub xwayland: WlXwaylandConfig,
+    #[serde(default)]
+    pub status: WlStatusConfig,
+    #[serde(default)]
+    pub alerts: WlAlertsConfig,
+    #[serde(default)]
+    pub stats: WlStatsConfig,
+    #[serde(default)]
+    pub virtual_output: WlVirtualOutputConfig,
+    #[serde(default)]
+    pub capture: WlCaptureConfig,
+    #[serde(default)]
+    pub session: WlSessionConfig,
+    #[serde(default)]
+    pub lock: WlLockConfig,
+    #[serde(default)]
+    pub audit: WlAuditConfig,
+    #[serde(default)]
+    pub i18n: WlI18nConfig,
     pub filter: WlFilter,
+    /// Additional named upstream sockets, selectable through [Config::routes]
+    #[serde(default)]
+    pub upstreams: HashMap<String, String>,
+    #[serde(default)]
+    pub routes: Vec<WlRoute>,
+    /// Further proxies run alongside this one, from `[[proxy]]` entries. Each
+    /// of them is this config with the sections set in its entry replaced.
+    #[serde(skip)]
+    pub proxies: Vec<Arc<Config>>,
+    /// Messages for `desc`s naming them, loaded from [WlI18nConfig::catalog_dir]
+    #[serde(skip)]
+    pub catalog: Arc<WlCatalog>,
+}
+
+/// Sections for the whole process, which `[[proxy]]` entries can't set
+const SHARED_SECTIONS: &[&str] = &[
+    "exec", "logging", "runtime", "sandbox", "control", "health", "dump", "session", "lock",
+    "audit", "i18n", "learning",
+];
+
+impl Config {
+    /// Parse a config file in TOML
+    pub fn parse(s: &str) -> Result<Config, toml::de::Error> {
+        let mut table: toml::Table = toml::from_str(s)?;
+        let entries = match table.remove("proxy") {
+            None => Vec::new(),
+            Some(toml::Value::Array(entries)) => entries,
+            Some(_) => return Err(de::Error::custom("`proxy` must be an array of tables")),
+        };
+
+        let mut config = Self::from_table(table.clone())?;
+        let catalog = WlCatalog::load(&config.i18n)
+            .map_err(|e| de::Error::custom(format!("can't load message catalogs: {}", e)))?;
+        config.catalog = Arc::new(catalog);
+        for entry in entries {
+            let toml::Value::Table(entry) = entry else {
+                return Err(de::Error::custom("`proxy` must be an array of tables"));
+            };
+            if let Some(key) = entry.keys().find(|k| SHARED_SECTIONS.contains(&k.as_str())) {
+                return Err(de::Error::custom(format!(
+                    "`{}` is shared by all proxies and can't be set in [[proxy]]",
+                    key
+                )));
+            }
+
+            let mut merged = table.clone();
+            merged.extend(entry);
+            let mut proxy = Self::from_table(merged)?;
+            proxy.catalog = config.catalog.clone();
+            config.proxies.push(Arc::new(proxy));
+        }
+
+        // Landlock keeps us from inspecting clients, see [crate::sandbox]
+        if config.sandbox.enabled {
+            let configs = std::iter::once(&config).chain(config.proxies.iter().map(|c| &**c));
+            if let Some(key) = configs.filter_map(Config::peer_identity_setting).next() {
+                return Err(de::Error::custom(format!(
+                    "`{}` needs the executable or Flatpak app ID of clients, which can't be resolved with the sandbox enabled",
+                    key
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The first setting telling clients apart by their executable or Flatpak
+    /// app ID, if any
+    fn peer_identity_setting(&self) -> Option<&'static str> {
+        let rules = || self.filter.requests.values().flatten();
+        if self.routes.iter().any(|r| r.exe.is_some()) {
+            Some("routes.exe")
+        } else if self.routes.iter().any(|r| r.flatpak_app_id.is_some()) {
+            Some("routes.flatpak_app_id")
+        } else if rules().any(|r| r.flatpak_app_id.is_some()) {
+            Some("filter.requests.flatpak_app_id")
+        } else if rules().any(|r| r.xwayland.is_some()) {
+            Some("filter.requests.xwayland")
+        } else if self.xwayland.allowed_globals.is_some() {
+            Some("xwayland.allowed_globals")
+        } else if self.learning.dir.is_some() {
+            Some("learning")
+        } else if !self.trace.exe.is_empty() {
+            Some("trace.exe")
+        } else if self.virtual_output.enabled
+            && !(self.virtual_output.exe.is_empty()
+                && self.virtual_output.flatpak_app_id.is_empty())
+        {
+            Some("virtual_output")
+        } else {
+            None
+        }
+    }
+
+    fn from_table(table: toml::Table) -> Result<Config, toml::de::Error> {
+        toml::Value::Table(table).try_into()
+    }
+
+    /// This config and those of [Self::proxies], in order
+    pub fn all_proxies(self: &Arc<Self>) -> Vec<Arc<Config>> {
+        std::iter::once(self.clone())
+            .chain(self.proxies.iter().cloned())
+            .collect()
+    }
+
+    /// Pick the upstream socket for a newly accepted client: the first route
+    /// matching the peer wins, falling back to the default upstream.
+    pub fn upstream_for(&self, peer: &WlPeerInfo) -> WlSocketAddr {
+        self.routes
+            .iter()
+            .find(|r| r.matches(peer))
+            .and_then(|r| self.upstreams.get(&r.upstream))
+            .map(|s| WlSocketAddr::parse(s))
+            .unwrap_or_else(|| self.socket.upstream_socket_addr())
+    }
+
+    /// All upstream sockets we may ever connect to
+    pub fn all_upstreams(&self) -> Vec<WlSocketAddr> {
+        std::iter::once(self.socket.upstream_socket_addr())
+            .chain(self.upstreams.values().map(|s| WlSocketAddr::parse(s)))
+            .collect()
+    }
 }
 
 fn default_upstream_socket() -> String {
     std::env::var("WAYLAND_DISPLAY").unwrap_or_else(|_| "wayland-1".to_string())
 }
 
+fn default_upstream_wait() -> u64 {
+    10
+}
+
 #[derive(Deserialize)]
 pub struct WlSockets {
     listen: String,
     #[serde(default = "default_upstream_socket")]
     upstream: String,
+    #[serde(default)]
+    pub fd_policy: WlFdPolicy,
+    /// Seconds to keep retrying to connect to an upstream that isn't up yet
+    /// before giving up on a client
+    #[serde(default = "default_upstream_wait")]
+    pub upstream_wait: u64,
+    /// Permission bits applied to the listen socket after binding, e.g. `0o660`
+    pub mode: Option<u32>,
+    /// Group (name or numeric gid) to own the listen socket
+    pub group: Option<String>,
+    /// SELinux context to label the listen socket with
+    pub selinux_label: Option<String>,
+    /// SMACK label to label the listen socket with
+    pub smack_label: Option<String>,
+}
+
+/// Route clients matching all of the given conditions to a named upstream.
+/// Conditions that aren't given are not checked.
+#[derive(Deserialize)]
+pub struct WlRoute {
+    /// Name of the upstream, as a key in [Config::upstreams]
+    pub upstream: String,
+    /// Glob pattern matched against the peer's executable path
+    pub exe: Option<String>,
+    /// Glob pattern matched against the peer's LSM security context
+    pub security_context: Option<String>,
+    pub uid: Option<u32>,
+    /// Glob pattern matched against the Flatpak app ID of the peer. Peers
+    /// which aren't Flatpak apps never match.
+    pub flatpak_app_id: Option<String>,
+}
+
+impl WlRoute {
+    pub fn matches(&self, peer: &WlPeerInfo) -> bool {
+        let exe = peer.exe.as_ref().and_then(|p| p.to_str());
+
+        self.exe
+            .as_ref()
+            .is_none_or(|pat| exe.is_some_and(|exe| glob_match(pat, exe)))
+            && self.security_context.as_ref().is_none_or(|pat| {
+                peer.security_context
+                    .as_ref()
+                    .is_some_and(|ctx| glob_match(pat, ctx))
+            })
+            && self.uid.is_none_or(|uid| peer.uid == Some(uid))
+            && self.flatpak_app_id.as_ref().is_none_or(|pat| {
+                peer.flatpak
+                    .as_ref()
+                    .is_some_and(|f| glob_match(pat, &f.app_id))
+            })
+    }
+}
+
+/// What to do with messages carrying fds that need to be forwarded
+/// over a transport which can't pass them (i.e. TCP)
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub enum WlFdPolicy {
+    /// Requests are rejected with an error; events are dropped
+    #[serde(rename = "reject")]
+    #[default]
+    Reject,
+    /// Terminate the connection
+    #[serde(rename = "terminate")]
+    Terminate,
+    /// Translate fds into in-band messages where feasible (shm pools and other
+    /// regular files); fall back to [WlFdPolicy::Reject] otherwise.
+    /// Requires wl-mitm on both sides of the link.
+    #[serde(rename = "translate")]
+    Translate,
 }
 
 impl WlSockets {
-    pub fn upstream_socket_path(&self) -> PathBuf {
-        let p = Path::new(&self.upstream);
-        if p.is_absolute() {
-            p.into()
-        } else {
-            Path::new(
-                &std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| "/run/user/1000".to_string()),
-            )
-            .join(p)
-            .into()
-        }
+    pub fn upstream_socket_addr(&self) -> WlSocketAddr {
+        WlSocketAddr::parse(&self.upstream)
     }
 
-    pub fn listen_socket_path(&self) -> PathBuf {
-        let p = Path::new(&self.listen);
-        if p.is_absolute() {
-            p.into()
-        } else {
-            Path::new(
-                &std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| "/run/user/1000".to_string()),
-            )
-            .join(p)
-            .into()
-        }
+    pub fn listen_socket_addr(&self) -> WlSocketAddr {
+        WlSocketAddr::parse(&self.listen)
     }
 }
 
@@ -64,22 +290,701 @@ pub struct WlLogging {
     pub log_level: Option<String>,
 }
 
+#[derive(Default, Deserialize)]
+pub struct WlRecording {
+    /// Directory to write one recording per connection to. Recording is
+    /// disabled if this is not set.
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub format: WlRecordingFormat,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub enum WlRecordingFormat {
+    #[serde(rename = "jsonl")]
+    #[default]
+    Jsonl,
+    #[serde(rename = "binary")]
+    Binary,
+    /// pcapng for Wireshark, see [crate::pcapng]
+    #[serde(rename = "pcapng")]
+    Pcapng,
+}
+
+/// Human-readable traces of connections, see [crate::trace]
+#[derive(Default, Deserialize)]
+pub struct WlTraceConfig {
+    /// Directory to write one trace per connection to. Tracing is disabled
+    /// if this is not set.
+    pub dir: Option<String>,
+    /// Only trace clients whose executable path matches one of these glob
+    /// patterns. All clients are traced if empty.
+    #[serde(default)]
+    pub exe: Vec<String>,
+}
+
+impl WlTraceConfig {
+    pub fn matches(&self, peer: &WlPeerInfo) -> bool {
+        let exe = peer.exe.as_ref().and_then(|p| p.to_str());
+        self.exe.is_empty()
+            || exe.is_some_and(|exe| self.exe.iter().any(|pat| glob_match(pat, exe)))
+    }
+}
+
+/// State dumps, see [crate::dump]
+#[derive(Default, Deserialize)]
+pub struct WlDumpConfig {
+    /// File to append dumps to, one JSON line per connection.
+    /// Dumps are logged if this is not set.
+    pub file: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct WlControlConfig {
+    /// Socket to serve the control protocol on (see [crate::control]).
+    /// The control socket is disabled if this is not set.
+    pub socket: Option<String>,
+}
+
+/// Tuning of the tokio runtime, see [crate::runtime]
+#[derive(Default, Deserialize)]
+pub struct WlRuntimeConfig {
+    #[serde(default)]
+    pub flavor: WlRuntimeFlavor,
+    /// Number of worker threads of a multi-threaded runtime.
+    /// Defaults to one per CPU.
+    pub worker_threads: Option<usize>,
+    /// CPUs to pin all threads of wl-mitm to. Not pinned if empty.
+    #[serde(default)]
+    pub cpus: Vec<usize>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WlRuntimeFlavor {
+    #[default]
+    MultiThread,
+    /// Run everything on the main thread, for minimal overhead
+    CurrentThread,
+}
+
+/// Restrictions wl-mitm places on itself, see [crate::sandbox]
+#[derive(Default, Deserialize)]
+pub struct WlSandboxConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub seccomp: WlSeccompMode,
+    /// Additional paths wl-mitm may read from
+    #[serde(default)]
+    pub read_paths: Vec<String>,
+    /// Additional paths wl-mitm may write to, and create and remove files under
+    #[serde(default)]
+    pub write_paths: Vec<String>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WlSeccompMode {
+    /// Kill wl-mitm on any syscall outside of the allowlist
+    #[default]
+    Enforce,
+    /// Allow, but log syscalls outside of the allowlist to the audit log.
+    /// Useful to find out what's missing from the allowlist.
+    Log,
+}
+
+/// Liveness reporting for supervisors, see [crate::health]
+#[derive(Deserialize)]
+pub struct WlHealthConfig {
+    /// File to periodically rewrite with the current health status.
+    /// No heartbeat is written if this is not set.
+    pub heartbeat_file: Option<String>,
+    /// Seconds between heartbeats
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+}
+
+impl Default for WlHealthConfig {
+    fn default() -> Self {
+        WlHealthConfig {
+            heartbeat_file: None,
+            heartbeat_interval: default_heartbeat_interval(),
+        }
+    }
+}
+
+fn default_heartbeat_interval() -> u64 {
+    10
+}
+
+/// Limits on what is tracked per connection, see [crate::objects]
+#[derive(Deserialize)]
+pub struct WlObjectsConfig {
+    /// Objects destroyed by the client but never ACK'd by the server with
+    /// `wl_display.delete_id` are forgotten beyond this many...
+    #[serde(default = "default_max_half_destroyed")]
+    pub max_half_destroyed: usize,
+    /// ...or after this many seconds
+    #[serde(default = "default_half_destroyed_timeout")]
+    pub half_destroyed_timeout: u64,
+    /// Connections creating more objects than this are closed
+    #[serde(default = "default_max_objects")]
+    pub max_objects: usize,
+    /// Metadata wl-mitm tracks for objects, e.g. toplevel titles, is dropped
+    /// beyond this many entries
+    #[serde(default = "default_max_extensions")]
+    pub max_extensions: usize,
+    /// Globals announced beyond this many are filtered
+    #[serde(default = "default_max_globals")]
+    pub max_globals: usize,
+    /// Globals are only tracked for this many of a client's wl_registry
+    /// objects, the most recently used ones
+    #[serde(default = "default_max_registries")]
+    pub max_registries: usize,
+}
+
+impl Default for WlObjectsConfig {
+    fn default() -> Self {
+        WlObjectsConfig {
+            max_half_destroyed: default_max_half_destroyed(),
+            half_destroyed_timeout: default_half_destroyed_timeout(),
+            max_objects: default_max_objects(),
+            max_extensions: default_max_extensions(),
+            max_globals: default_max_globals(),
+            max_registries: default_max_registries(),
+        }
+    }
+}
+
+fn default_max_half_destroyed() -> usize {
+    4096
+}
+
+fn default_half_destroyed_timeout() -> u64 {
+    60
+}
+
+fn default_max_objects() -> usize {
+    1 << 20
+}
+
+fn default_max_extensions() -> usize {
+    1 << 16
+}
+
+fn default_max_globals() -> usize {
+    4096
+}
+
+fn default_max_registries() -> usize {
+    16
+}
+
+/// Checking clients against protocol invariants, see [crate::validate]
+#[derive(Default, Deserialize)]
+pub struct WlValidationConfig {
+    #[serde(default)]
+    pub mode: WlValidationMode,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WlValidationMode {
+    #[default]
+    Off,
+    /// Log violations, but forward the offending requests anyway
+    Log,
+    /// Close connections violating an invariant
+    Terminate,
+}
+
+/// Per-app usage profiles, see [crate::learning]
+#[derive(Default, Deserialize)]
+pub struct WlLearningConfig {
+    /// Directory to keep one profile per app in, see
+    /// [crate::learning::app_identity]. Learning is disabled if this is not set.
+    pub dir: Option<String>,
+    /// Glob patterns of apps whose profiles are enforced instead of learned
+    #[serde(default)]
+    pub enforce: Vec<String>,
+}
+
+/// Telling XWayland apart from native clients, see [crate::state::WlMitmState::is_xwayland]
+#[derive(Deserialize)]
+pub struct WlXwaylandConfig {
+    /// Glob patterns of XWayland's executable path. These should only match
+    /// paths no user can write to, as matching clients get XWayland's rules.
+    #[serde(default = "default_xwayland_exe")]
+    pub exe: Vec<String>,
+    /// Globals announced to XWayland, instead of `allowed_globals` under
+    /// `[filter]`. Only works for XWayland recognized by its executable.
+    pub allowed_globals: Option<HashSet<String>>,
+}
+
+impl Default for WlXwaylandConfig {
+    fn default() -> Self {
+        WlXwaylandConfig {
+            exe: default_xwayland_exe(),
+            allowed_globals: None,
+        }
+    }
+}
+
+impl WlXwaylandConfig {
+    pub fn matches(&self, peer: &WlPeerInfo) -> bool {
+        let exe = peer.exe.as_ref().and_then(|p| p.to_str());
+        exe.is_some_and(|exe| self.exe.iter().any(|pat| glob_match(pat, exe)))
+    }
+}
+
+fn default_xwayland_exe() -> Vec<String> {
+    vec![
+        "/usr/bin/Xwayland".to_string(),
+        "/usr/libexec/Xwayland".to_string(),
+    ]
+}
+
+/// The wl_mitm_v1 global wl-mitm offers clients itself, see `proto/wl-mitm-v1.xml`
+#[derive(Default, Deserialize)]
+pub struct WlStatusConfig {
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Alerting on bursts of requests, see [crate::alerts]
+#[derive(Default, Deserialize)]
+pub struct WlAlertsConfig {
+    /// Run `notify_cmd` for every alert
+    #[serde(default)]
+    pub notify: bool,
+    /// `http://` URL to POST every alert to as JSON
+    pub webhook: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<WlAlertRule>,
+}
+
+/// Fire once `count` matching requests were seen on one connection within
+/// `window_secs` seconds
+#[derive(Deserialize, Clone, Debug)]
+pub struct WlAlertRule {
+    pub name: String,
+    /// Glob pattern of interfaces
+    #[serde(default = "default_alert_interface")]
+    pub interface: String,
+    /// Requests to count, `"*"` for all of them
+    #[serde(default = "default_alert_requests")]
+    pub requests: Vec<String>,
+    /// Only count requests with this outcome. All are counted if not set.
+    pub verdict: Option<WlAlertVerdict>,
+    pub count: usize,
+    pub window_secs: u64,
+}
+
+fn default_alert_interface() -> String {
+    "*".to_string()
+}
+
+fn default_alert_requests() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WlAlertVerdict {
+    /// Forwarded, or answered by wl-mitm itself
+    Allowed,
+    /// Filtered, rejected, or terminating the connection
+    Blocked,
+}
+
+/// Per-connection usage statistics, see [crate::stats]
+#[derive(Default, Deserialize)]
+pub struct WlStatsConfig {
+    /// Directory to write one statistics file per connection to. Statistics
+    /// are disabled if this is not set.
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub format: WlStatsFormat,
+    /// Export every this many seconds, on top of dumps and closed connections
+    pub interval_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WlStatsFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Presenting selected clients one synthetic output, see `src/state/output.rs`
+#[derive(Deserialize)]
+pub struct WlVirtualOutputConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Glob patterns of executable paths of the clients to select
+    #[serde(default)]
+    pub exe: Vec<String>,
+    /// Glob patterns of Flatpak app IDs of the clients to select. All clients
+    /// are selected if both this and `exe` are empty.
+    #[serde(default)]
+    pub flatpak_app_id: Vec<String>,
+    #[serde(default = "default_virtual_output_width")]
+    pub width: i32,
+    #[serde(default = "default_virtual_output_height")]
+    pub height: i32,
+    /// Integer scale factor
+    #[serde(default = "default_virtual_output_scale")]
+    pub scale: i32,
+    /// Refresh rate in mHz
+    #[serde(default = "default_virtual_output_refresh")]
+    pub refresh: i32,
+}
+
+impl Default for WlVirtualOutputConfig {
+    fn default() -> Self {
+        WlVirtualOutputConfig {
+            enabled: false,
+            exe: Vec::new(),
+            flatpak_app_id: Vec::new(),
+            width: default_virtual_output_width(),
+            height: default_virtual_output_height(),
+            scale: default_virtual_output_scale(),
+            refresh: default_virtual_output_refresh(),
+        }
+    }
+}
+
+impl WlVirtualOutputConfig {
+    pub fn matches(&self, peer: &WlPeerInfo) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        let exe = peer.exe.as_ref().and_then(|p| p.to_str());
+        let app_id = peer.flatpak.as_ref().map(|f| f.app_id.as_str());
+        (self.exe.is_empty() && self.flatpak_app_id.is_empty())
+            || exe.is_some_and(|exe| self.exe.iter().any(|pat| glob_match(pat, exe)))
+            || app_id.is_some_and(|id| self.flatpak_app_id.iter().any(|pat| glob_match(pat, id)))
+    }
+
+    /// The size of the virtual output in surface-local coordinates
+    pub fn logical_size(&self) -> (i32, i32) {
+        let scale = self.scale.max(1);
+        (self.width / scale, self.height / scale)
+    }
+}
+
+fn default_virtual_output_width() -> i32 {
+    1920
+}
+
+fn default_virtual_output_height() -> i32 {
+    1080
+}
+
+fn default_virtual_output_scale() -> i32 {
+    1
+}
+
+fn default_virtual_output_refresh() -> i32 {
+    60000
+}
+
+/// Frame-rate caps on screen capture, see [crate::pacing]
+#[derive(Default, Deserialize)]
+pub struct WlCaptureConfig {
+    /// Frames per second each client may capture at most, no less than
+    /// [MIN_CAPTURE_FPS]. Unlimited if not set.
+    #[serde(default, deserialize_with = "deserialize_max_fps")]
+    pub max_fps: Option<f64>,
+}
+
+/// The lowest `capture.max_fps`, a frame every 100 seconds
+pub const MIN_CAPTURE_FPS: f64 = 0.01;
+
+fn deserialize_max_fps<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let max_fps = f64::deserialize(deserializer)?;
+    if !(MIN_CAPTURE_FPS..=f64::MAX).contains(&max_fps) {
+        return Err(de::Error::custom(format!(
+            "`max_fps` must be a number of at least {}, not {}",
+            MIN_CAPTURE_FPS, max_fps
+        )));
+    }
+    Ok(Some(max_fps))
+}
+
+/// The logind session wl-mitm belongs to, see [crate::logind]
+#[derive(Deserialize)]
+pub struct WlSessionConfig {
+    /// ID of the logind session. Defaults to `XDG_SESSION_ID`.
+    pub id: Option<String>,
+    /// Close every connection and stop serving once the session ends
+    #[serde(default)]
+    pub terminate_on_end: bool,
+    /// Seconds between checks whether the session is closing
+    #[serde(default = "default_session_poll_secs")]
+    pub poll_secs: u64,
+}
+
+impl Default for WlSessionConfig {
+    fn default() -> Self {
+        WlSessionConfig {
+            id: None,
+            terminate_on_end: false,
+            poll_secs: default_session_poll_secs(),
+        }
+    }
+}
+
+fn default_session_poll_secs() -> u64 {
+    10
+}
+
+/// Denying sensitive requests while the session is locked, see [crate::logind]
+#[derive(Deserialize)]
+pub struct WlLockConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Requests filtered while the session is locked
+    #[serde(default = "default_lock_deny")]
+    pub deny: Vec<WlLockDenyRule>,
+}
+
+impl Default for WlLockConfig {
+    fn default() -> Self {
+        WlLockConfig {
+            enabled: false,
+            deny: default_lock_deny(),
+        }
+    }
+}
+
+impl WlLockConfig {
+    /// Whether `interface::request` is to be filtered while the session is locked
+    pub fn denies(&self, interface: &str, request: &str) -> bool {
+        self.deny.iter().any(|rule| {
+            glob_match(&rule.interface, interface)
+                && rule.requests.iter().any(|r| r == "*" || r == request)
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WlLockDenyRule {
+    /// Glob pattern of interfaces
+    pub interface: String,
+    /// Requests to deny, `"*"` for all of them
+    pub requests: Vec<String>,
+}
+
+/// Reading the clipboard, capturing the screen and injecting input. Requests
+/// creating objects are left alone, so that clients survive the lock.
+fn default_lock_deny() -> Vec<WlLockDenyRule> {
+    let rule = |interface: &str, requests: &[&str]| WlLockDenyRule {
+        interface: interface.to_string(),
+        requests: requests.iter().map(|r| r.to_string()).collect(),
+    };
+    vec![
+        rule("wl_data_offer", &["receive"]),
+        rule("zwp_primary_selection_offer_v1", &["receive"]),
+        rule("zwlr_data_control_offer_v1", &["receive"]),
+        rule("ext_data_control_offer_v1", &["receive"]),
+        rule(
+            "zwlr_data_control_device_v1",
+            &["set_selection", "set_primary_selection"],
+        ),
+        rule(
+            "ext_data_control_device_v1",
+            &["set_selection", "set_primary_selection"],
+        ),
+        rule("zwlr_screencopy_frame_v1", &["copy", "copy_with_damage"]),
+        rule("ext_image_copy_capture_frame_v1", &["capture"]),
+        rule(
+            "zwlr_virtual_pointer_v1",
+            &[
+                "motion",
+                "motion_absolute",
+                "button",
+                "axis",
+                "frame",
+                "axis_source",
+                "axis_stop",
+                "axis_discrete",
+            ],
+        ),
+        rule("zwp_virtual_keyboard_v1", &["keymap", "key", "modifiers"]),
+    ]
+}
+
+/// The tamper-evident log of policy decisions, see [crate::audit]
+#[derive(Default, Deserialize)]
+pub struct WlAuditConfig {
+    /// File to append audit records to. Auditing is disabled if this is not set.
+    pub file: Option<String>,
+    /// File holding the key to sign records with, used as is
+    pub key_file: Option<String>,
+}
+
+/// Localized prompts, see [crate::catalog]
+#[derive(Default, Deserialize)]
+pub struct WlI18nConfig {
+    /// Directory of message catalogs, named `<locale>.toml`
+    pub catalog_dir: Option<String>,
+    /// Locale to show messages in. Defaults to that of the environment.
+    pub locale: Option<String>,
+}
+
+/// Mutation of forwarded messages for robustness testing, see [crate::chaos]
+#[derive(Deserialize)]
+pub struct WlChaosConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seed for the mutation RNG. A random one is picked and logged if this is not set.
+    pub seed: Option<u64>,
+    /// Chance of each eligible message being mutated, between 0 and 1
+    #[serde(default = "default_chaos_probability")]
+    pub probability: f64,
+    #[serde(default = "default_chaos_strategies")]
+    pub strategies: Vec<WlChaosStrategy>,
+    #[serde(default)]
+    pub direction: WlChaosDirection,
+    /// Glob patterns of interfaces whose messages are eligible. All are if empty.
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+impl Default for WlChaosConfig {
+    fn default() -> Self {
+        WlChaosConfig {
+            enabled: false,
+            seed: None,
+            probability: default_chaos_probability(),
+            strategies: default_chaos_strategies(),
+            direction: Default::default(),
+            interfaces: Vec::new(),
+        }
+    }
+}
+
+fn default_chaos_probability() -> f64 {
+    0.01
+}
+
+fn default_chaos_strategies() -> Vec<WlChaosStrategy> {
+    vec![
+        WlChaosStrategy::BitFlip,
+        WlChaosStrategy::Scramble,
+        WlChaosStrategy::Duplicate,
+        WlChaosStrategy::Drop,
+        WlChaosStrategy::Reorder,
+    ]
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WlChaosStrategy {
+    /// Flip a single bit in the message's arguments
+    BitFlip,
+    /// Overwrite one 32-bit argument word with a random value
+    Scramble,
+    /// Forward the message twice. Messages carrying fds are never duplicated.
+    Duplicate,
+    /// Don't forward the message at all
+    Drop,
+    /// Hold the message back and forward it after the next one in the same direction
+    Reorder,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum WlChaosDirection {
+    /// Only mutate requests, to test compositors
+    Requests,
+    /// Only mutate events, to test clients
+    Events,
+    #[default]
+    Both,
+}
+
 #[derive(Default, Deserialize)]
 pub struct WlExec {
     pub ask_cmd: Option<String>,
     pub notify_cmd: Option<String>,
 }
 
+/// `[filter]` as written in the config, before [WlFilter::table] is built
 #[derive(Deserialize)]
+struct RawWlFilter {
+    allowed_globals: HashSet<String>,
+    #[serde(deserialize_with = "deserialize_filter_requests")]
+    requests: HashMap<String, Vec<WlFilterRequest>>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(from = "RawWlFilter")]
 pub struct WlFilter {
     pub allowed_globals: HashSet<String>,
-    #[serde(deserialize_with = "deserialize_filter_requests")]
     pub requests: HashMap<String, Vec<WlFilterRequest>>,
-    #[serde(default)]
     pub dry_run: bool,
+    /// [WlFilter::requests] by the messages they apply to, see [WlFilter::compile]
+    table: HashMap<(WlObjectType, u16), Vec<(String, usize)>>,
 }
 
-#[derive(Deserialize)]
+impl From<RawWlFilter> for WlFilter {
+    fn from(raw: RawWlFilter) -> WlFilter {
+        let mut filter = WlFilter {
+            allowed_globals: raw.allowed_globals,
+            requests: raw.requests,
+            dry_run: raw.dry_run,
+            table: HashMap::new(),
+        };
+        filter.compile();
+        filter
+    }
+}
+
+impl WlFilter {
+    /// Work out, for every known request, which rules could apply to it, so
+    /// that messages no rule mentions cost a single lookup. Done once as the
+    /// filter is deserialized, whatever it is deserialized from.
+    fn compile(&mut self) {
+        self.table = proto::known_requests()
+            .filter_map(|(obj_type, opcode, parser)| {
+                let rules: Vec<_> = [obj_type.interface(), "*"]
+                    .into_iter()
+                    .filter_map(|interface| Some((interface, self.requests.get(interface)?)))
+                    .flat_map(|(interface, rules)| {
+                        rules
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, f)| f.matches_request(parser.msg_name()))
+                            .map(move |(i, _)| (interface.to_string(), i))
+                    })
+                    .collect();
+                (!rules.is_empty()).then_some(((obj_type, opcode), rules))
+            })
+            .collect();
+    }
+
+    /// Rules which may apply to request `opcode` on `obj_type`, in order of
+    /// precedence, as (interface key in [WlFilter::requests], index). Whether
+    /// they do depends on the object, see [WlFilterRequest::matches_version]
+    /// and [WlFilterRequest::descendant_of].
+    pub fn candidates(&self, obj_type: WlObjectType, opcode: u16) -> &[(String, usize)] {
+        self.table
+            .get(&(obj_type, opcode))
+            .map_or(&[], |rules| rules.as_slice())
+    }
+}
+
+#[derive(Deserialize, Debug)]
 pub enum WlFilterRequestAction {
     #[serde(rename = "block")]
     Block,
@@ -113,6 +1018,51 @@ pub struct WlFilterRequest {
     pub block_type: WlFilterRequestBlockType,
     #[serde(default)]
     pub error_code: u32,
+    /// Only apply to objects created, directly or indirectly, through an
+    /// object of this interface. Combined with `interface = "*"` and
+    /// `requests = ["*"]`, this covers everything descended from e.g. a manager.
+    pub descendant_of: Option<String>,
+    /// Only apply to objects of at least this version
+    pub min_version: Option<u32>,
+    /// Only apply to objects of at most this version
+    pub max_version: Option<u32>,
+    /// Only apply to Flatpak apps whose app ID matches this glob pattern
+    pub flatpak_app_id: Option<String>,
+    /// Only apply to clients whose LSM security context matches this glob
+    /// pattern, see [WlPeerInfo::security_context]
+    pub security_context: Option<String>,
+    /// Only apply to XWayland if true, or only to native Wayland clients if false
+    pub xwayland: Option<bool>,
+}
+
+impl WlFilterRequest {
+    /// `"*"` matches every request
+    pub fn matches_request(&self, name: &str) -> bool {
+        self.requests.contains(name) || self.requests.contains("*")
+    }
+
+    pub fn matches_version(&self, version: u32) -> bool {
+        self.min_version.is_none_or(|min| version >= min)
+            && self.max_version.is_none_or(|max| version <= max)
+    }
+
+    /// `flatpak_app_id` is [None] for clients which aren't Flatpak apps
+    pub fn matches_flatpak_app_id(&self, flatpak_app_id: Option<&str>) -> bool {
+        self.flatpak_app_id
+            .as_ref()
+            .is_none_or(|pat| flatpak_app_id.is_some_and(|id| glob_match(pat, id)))
+    }
+
+    /// `security_context` is [None] for clients without one
+    pub fn matches_security_context(&self, security_context: Option<&str>) -> bool {
+        self.security_context
+            .as_ref()
+            .is_none_or(|pat| security_context.is_some_and(|ctx| glob_match(pat, ctx)))
+    }
+
+    pub fn matches_xwayland(&self, xwayland: bool) -> bool {
+        self.xwayland.is_none_or(|x| x == xwayland)
+    }
 }
 
 /// Deserialize an array of [WlFilterRequest]s to a hashmap keyed by interface name
@@ -0,0 +1,680 @@
+//! The control socket: a side channel to inspect and steer a running wl-mitm
+//!
+//! Clients speak newline-delimited JSON. Every line sent to wl-mitm is a
+//! [WlControlRequest]; every line sent back is a [WlControlReply]. After a
+//! [WlControlRequest::Subscribe], a client additionally receives every message
+//! passing through any connection as [WlControlReply::Message], along with
+//! connections coming and going.
+//!
+//! Anyone with access to the control socket can disable filter rules, so it
+//! must never be made reachable from within a sandbox.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::{broadcast, mpsc, oneshot},
+};
+use tracing::{Instrument, Level, debug, error, info, level_filters::LevelFilter, span, warn};
+
+use crate::{
+    codec::WlRawMsg,
+    config::Config,
+    dump::WlDumper,
+    explain::{self, WlExplainQuery, WlExplanation},
+    health::{WlHealth, WlHealthStatus},
+    logging::WlLogLevels,
+    objects::{WlObjectInfo, WlObjects},
+    proto::WaylandProtocolParsingOutcome,
+    recorder::WlDirection,
+    socket::{WlListener, WlSocketAddr, WlSocketLock, WlStream},
+    state::WlMitmVerdict,
+};
+
+/// How many messages may be queued up for a slow subscriber before it starts missing some
+const CONTROL_BROADCAST_CAPACITY: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum WlControlRequest {
+    /// Start streaming messages and connection changes
+    Subscribe,
+    Connections,
+    Objects {
+        conn_id: u64,
+    },
+    Rules,
+    /// Enable or disable one entry of `[[filter.requests]]`, identified by its
+    /// interface and index among the rules for that interface
+    SetRule {
+        interface: String,
+        index: usize,
+        enabled: bool,
+    },
+    /// Check that wl-mitm is alive, and get its [WlHealthStatus]
+    Ping,
+    /// Have every connection dump its state, see [crate::dump]
+    Dump,
+    /// Override the log level of connections selected by exactly one of
+    /// `conn_id` or `app_id`. Omitting `level` reverts to the default.
+    SetLogLevel {
+        conn_id: Option<u64>,
+        app_id: Option<String>,
+        level: Option<String>,
+    },
+    LogLevels,
+    /// Which filter rule applies to a request, see [crate::explain]
+    Explain(WlExplainQuery),
+    /// Revoke a permission connection `conn_id` was granted through the
+    /// wl_mitm_v1 global, see [crate::state::WlMitmState::revoke_permission]
+    RevokePermission {
+        conn_id: u64,
+        interface: String,
+        request: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlControlConnInfo {
+    pub conn_id: u64,
+    pub peer: String,
+    pub upstream: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlControlRuleInfo {
+    pub interface: String,
+    pub index: usize,
+    pub requests: Vec<String>,
+    pub action: String,
+    pub desc: Option<String>,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlControlLogOverride {
+    pub conn_id: Option<u64>,
+    pub app_id: Option<String>,
+    pub level: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlControlMessage {
+    pub conn_id: u64,
+    pub direction: WlDirection,
+    pub obj_id: u32,
+    pub opcode: u16,
+    /// Interface of the object, if known
+    pub interface: Option<String>,
+    /// Version of the object, if known
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// Name of the request or event, if the message could be decoded
+    pub msg_name: Option<String>,
+    /// Arguments of the message as JSON, if the message could be decoded
+    pub args: Option<String>,
+    pub num_fds: usize,
+    pub verdict: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WlControlReply {
+    Message(WlControlMessage),
+    ConnOpened(WlControlConnInfo),
+    ConnClosed {
+        conn_id: u64,
+    },
+    Connections {
+        connections: Vec<WlControlConnInfo>,
+    },
+    Objects {
+        conn_id: u64,
+        objects: Vec<WlObjectInfo>,
+    },
+    Rules {
+        rules: Vec<WlControlRuleInfo>,
+    },
+    Pong(WlHealthStatus),
+    /// A dump has been requested from this many connections
+    Dumped {
+        connections: usize,
+    },
+    LogLevels {
+        default: String,
+        overrides: Vec<WlControlLogOverride>,
+    },
+    /// The subscriber fell behind and missed this many messages
+    Lagged {
+        missed: u64,
+    },
+    Explanation(WlExplanation),
+    /// The session has been locked or unlocked, see [crate::logind]
+    SessionLock {
+        locked: bool,
+    },
+    /// The session has ended, and every connection is being closed
+    SessionEnded,
+    /// `revoked` is false if the permission hadn't been granted
+    PermissionRevoked {
+        conn_id: u64,
+        revoked: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub type WlObjectsSnapshotRequest = oneshot::Sender<Vec<WlObjectInfo>>;
+
+/// Revoke the permission for `interface::request`, replying whether it had been granted
+pub struct WlRevokeRequest {
+    pub interface: String,
+    pub request: String,
+    pub reply: oneshot::Sender<bool>,
+}
+
+/// What a control client wants from a connection, see [WlControlConnHandle::requested]
+pub enum WlConnRequest {
+    /// A snapshot of the object table
+    Objects(WlObjectsSnapshotRequest),
+    Revoke(WlRevokeRequest),
+}
+
+struct WlControlConn {
+    info: WlControlConnInfo,
+    objects_req: mpsc::Sender<WlObjectsSnapshotRequest>,
+    revoke_req: mpsc::Sender<WlRevokeRequest>,
+}
+
+/// State shared between the control socket and all connections
+pub struct WlControl {
+    config: Arc<Config>,
+    health: Arc<WlHealth>,
+    dumper: Arc<WlDumper>,
+    /// Only present if wl-mitm installed its own tracing subscriber
+    log_levels: Option<Arc<WlLogLevels>>,
+    events: broadcast::Sender<WlControlReply>,
+    conns: Mutex<HashMap<u64, WlControlConn>>,
+    /// `(interface, index)` of rules that have been disabled at runtime
+    disabled_rules: Mutex<HashSet<(String, usize)>>,
+}
+
+impl WlControl {
+    pub fn new(
+        config: Arc<Config>,
+        health: Arc<WlHealth>,
+        dumper: Arc<WlDumper>,
+        log_levels: Option<Arc<WlLogLevels>>,
+    ) -> Arc<WlControl> {
+        Arc::new(WlControl {
+            config,
+            health,
+            dumper,
+            log_levels,
+            events: broadcast::channel(CONTROL_BROADCAST_CAPACITY).0,
+            conns: Mutex::new(HashMap::new()),
+            disabled_rules: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn is_rule_enabled(&self, interface: &str, index: usize) -> bool {
+        !self
+            .disabled_rules
+            .lock()
+            .unwrap()
+            .contains(&(interface.to_string(), index))
+    }
+
+    fn has_subscribers(&self) -> bool {
+        self.events.receiver_count() > 0
+    }
+
+    /// Tell subscribers the session has been locked or unlocked
+    pub fn publish_session_lock(&self, locked: bool) {
+        self.events
+            .send(WlControlReply::SessionLock { locked })
+            .ok();
+    }
+
+    /// Tell subscribers the session has ended
+    pub fn publish_session_ended(&self) {
+        self.events.send(WlControlReply::SessionEnded).ok();
+    }
+
+    fn rules(&self) -> Vec<WlControlRuleInfo> {
+        let mut rules: Vec<_> = self
+            .config
+            .filter
+            .requests
+            .iter()
+            .flat_map(|(interface, rules)| {
+                rules.iter().enumerate().map(|(index, r)| {
+                    let mut requests: Vec<_> = r.requests.iter().cloned().collect();
+                    requests.sort();
+                    WlControlRuleInfo {
+                        interface: interface.clone(),
+                        index,
+                        requests,
+                        action: format!("{:?}", r.action).to_lowercase(),
+                        desc: r.desc.clone(),
+                        enabled: self.is_rule_enabled(interface, index),
+                    }
+                })
+            })
+            .collect();
+        rules.sort_by(|a, b| (&a.interface, a.index).cmp(&(&b.interface, b.index)));
+        rules
+    }
+
+    fn log_levels_reply(levels: &WlLogLevels) -> WlControlReply {
+        let conns =
+            levels
+                .conn_overrides()
+                .into_iter()
+                .map(|(conn_id, level)| WlControlLogOverride {
+                    conn_id: Some(conn_id),
+                    app_id: None,
+                    level: level.to_string(),
+                });
+        let app_ids = levels
+            .app_id_overrides()
+            .into_iter()
+            .map(|(app_id, level)| WlControlLogOverride {
+                conn_id: None,
+                app_id: Some(app_id),
+                level: level.to_string(),
+            });
+
+        WlControlReply::LogLevels {
+            default: levels.default_level().to_string(),
+            overrides: conns.chain(app_ids).collect(),
+        }
+    }
+
+    /// Register a new connection. The connection is unregistered once the
+    /// returned handle is dropped.
+    pub fn register_conn(self: &Arc<Self>, info: WlControlConnInfo) -> WlControlConnHandle {
+        let (objects_req, objects_req_rx) = mpsc::channel(4);
+        let (revoke_req, revoke_req_rx) = mpsc::channel(4);
+        let conn_id = info.conn_id;
+
+        self.events
+            .send(WlControlReply::ConnOpened(info.clone()))
+            .ok();
+        self.conns.lock().unwrap().insert(
+            conn_id,
+            WlControlConn {
+                info,
+                objects_req,
+                revoke_req,
+            },
+        );
+
+        WlControlConnHandle {
+            control: self.clone(),
+            conn_id,
+            objects_req_rx,
+            revoke_req_rx,
+        }
+    }
+
+    async fn handle_request(&self, req: WlControlRequest) -> WlControlReply {
+        match req {
+            // Handled by the client loop
+            WlControlRequest::Subscribe => unreachable!(),
+            WlControlRequest::Connections => {
+                let mut connections: Vec<_> = self
+                    .conns
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .map(|c| c.info.clone())
+                    .collect();
+                connections.sort_by_key(|c| c.conn_id);
+                WlControlReply::Connections { connections }
+            }
+            WlControlRequest::Objects { conn_id } => {
+                let objects_req = self
+                    .conns
+                    .lock()
+                    .unwrap()
+                    .get(&conn_id)
+                    .map(|c| c.objects_req.clone());
+                let Some(objects_req) = objects_req else {
+                    return WlControlReply::Error {
+                        message: format!("no such connection {}", conn_id),
+                    };
+                };
+
+                let (tx, rx) = oneshot::channel();
+                if objects_req.send(tx).await.is_err() {
+                    return WlControlReply::Error {
+                        message: format!("connection {} is gone", conn_id),
+                    };
+                }
+
+                match rx.await {
+                    Ok(objects) => WlControlReply::Objects { conn_id, objects },
+                    Err(_) => WlControlReply::Error {
+                        message: format!("connection {} is gone", conn_id),
+                    },
+                }
+            }
+            WlControlRequest::RevokePermission {
+                conn_id,
+                interface,
+                request,
+            } => {
+                let revoke_req = self
+                    .conns
+                    .lock()
+                    .unwrap()
+                    .get(&conn_id)
+                    .map(|c| c.revoke_req.clone());
+                let Some(revoke_req) = revoke_req else {
+                    return WlControlReply::Error {
+                        message: format!("no such connection {}", conn_id),
+                    };
+                };
+
+                let (tx, rx) = oneshot::channel();
+                let req = WlRevokeRequest {
+                    interface,
+                    request,
+                    reply: tx,
+                };
+                if revoke_req.send(req).await.is_err() {
+                    return WlControlReply::Error {
+                        message: format!("connection {} is gone", conn_id),
+                    };
+                }
+
+                match rx.await {
+                    Ok(revoked) => WlControlReply::PermissionRevoked { conn_id, revoked },
+                    Err(_) => WlControlReply::Error {
+                        message: format!("connection {} is gone", conn_id),
+                    },
+                }
+            }
+            WlControlRequest::Explain(query) => {
+                match explain::explain(&self.config, &query, |interface, index| {
+                    self.is_rule_enabled(interface, index)
+                }) {
+                    Ok(explanation) => WlControlReply::Explanation(explanation),
+                    Err(message) => WlControlReply::Error { message },
+                }
+            }
+            WlControlRequest::Rules => WlControlReply::Rules {
+                rules: self.rules(),
+            },
+            WlControlRequest::SetRule {
+                interface,
+                index,
+                enabled,
+            } => {
+                if self
+                    .config
+                    .filter
+                    .requests
+                    .get(&interface)
+                    .is_none_or(|rules| index >= rules.len())
+                {
+                    return WlControlReply::Error {
+                        message: format!("no such rule {}[{}]", interface, index),
+                    };
+                }
+
+                info!(
+                    interface = interface,
+                    index = index,
+                    enabled = enabled,
+                    "Filter rule toggled through control socket"
+                );
+
+                let mut disabled = self.disabled_rules.lock().unwrap();
+                if enabled {
+                    disabled.remove(&(interface, index));
+                } else {
+                    disabled.insert((interface, index));
+                }
+                drop(disabled);
+
+                WlControlReply::Rules {
+                    rules: self.rules(),
+                }
+            }
+            WlControlRequest::Ping => WlControlReply::Pong(self.health.check().await),
+            WlControlRequest::Dump => WlControlReply::Dumped {
+                connections: self.dumper.trigger(),
+            },
+            WlControlRequest::LogLevels | WlControlRequest::SetLogLevel { .. } => {
+                let Some(ref levels) = self.log_levels else {
+                    return WlControlReply::Error {
+                        message: "log levels can't be changed in this instance".into(),
+                    };
+                };
+
+                if let WlControlRequest::SetLogLevel {
+                    conn_id,
+                    app_id,
+                    level,
+                } = req
+                {
+                    let level = match level.as_deref().map(LevelFilter::from_str).transpose() {
+                        Ok(level) => level,
+                        Err(e) => {
+                            return WlControlReply::Error {
+                                message: e.to_string(),
+                            };
+                        }
+                    };
+
+                    match (conn_id, app_id) {
+                        (Some(conn_id), None) => levels.set_conn(conn_id, level),
+                        (None, Some(app_id)) => levels.set_app_id(app_id, level),
+                        _ => {
+                            return WlControlReply::Error {
+                                message: "exactly one of conn_id or app_id is required".into(),
+                            };
+                        }
+                    }
+                }
+
+                Self::log_levels_reply(levels)
+            }
+        }
+    }
+
+    async fn handle_client(self: Arc<Self>, conn: WlStream) -> io::Result<()> {
+        let WlStream::Unix(conn) = conn else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "control socket must be a unix socket",
+            ));
+        };
+        // Only the owner may ever steer wl-mitm
+        let uid = conn.peer_cred()?.uid();
+        if uid != nix::unistd::getuid().as_raw() {
+            warn!(uid = uid, "Refusing control client of another user");
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+
+        let (read, mut write) = conn.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut events: Option<broadcast::Receiver<WlControlReply>> = None;
+
+        loop {
+            let reply = tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else {
+                        return Ok(());
+                    };
+
+                    match serde_json::from_str::<WlControlRequest>(&line) {
+                        Ok(WlControlRequest::Subscribe) => {
+                            events = Some(self.events.subscribe());
+                            continue;
+                        }
+                        Ok(req) => self.handle_request(req).await,
+                        Err(e) => WlControlReply::Error { message: e.to_string() },
+                    }
+                }
+                ev = async { events.as_mut().unwrap().recv().await }, if events.is_some() => {
+                    match ev {
+                        Ok(ev) => ev,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            WlControlReply::Lagged { missed }
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
+                }
+            };
+
+            let mut line = serde_json::to_vec(&reply)?;
+            line.push(b'\n');
+            write.write_all(&line).await?;
+        }
+    }
+
+    /// Accept control clients forever
+    pub async fn serve(self: Arc<Self>, listener: WlListener) {
+        let mut client_id: u64 = 0;
+        while let Ok((conn, _)) = listener.accept().await {
+            let span = span!(Level::INFO, "control", client_id = client_id);
+            let control = self.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = control.handle_client(conn).await {
+                        debug!(error = ?e, "Control client disconnected");
+                    }
+                }
+                .instrument(span),
+            );
+            client_id += 1;
+        }
+    }
+}
+
+/// A connection's registration with [WlControl]
+pub struct WlControlConnHandle {
+    control: Arc<WlControl>,
+    conn_id: u64,
+    objects_req_rx: mpsc::Receiver<WlObjectsSnapshotRequest>,
+    revoke_req_rx: mpsc::Receiver<WlRevokeRequest>,
+}
+
+impl WlControlConnHandle {
+    /// Resolves when a control client wants something from this connection.
+    /// Never resolves once all senders are gone, which makes this safe to use with select!{}.
+    pub async fn requested(&mut self) -> WlConnRequest {
+        tokio::select! {
+            Some(req) = self.objects_req_rx.recv() => WlConnRequest::Objects(req),
+            Some(req) = self.revoke_req_rx.recv() => WlConnRequest::Revoke(req),
+            else => std::future::pending().await,
+        }
+    }
+
+    /// Decode a message for subscribers, if there are any. This has to happen
+    /// before the message is processed, as processing may destroy its object.
+    pub fn decode(
+        &self,
+        objects: &WlObjects,
+        direction: WlDirection,
+        msg: &WlRawMsg,
+    ) -> Option<WlControlMessage> {
+        if !self.control.has_subscribers() {
+            return None;
+        }
+
+        let decoded = match direction {
+            WlDirection::ClientToServer => crate::proto::decode_request(objects, msg),
+            WlDirection::ServerToClient => crate::proto::decode_event(objects, msg),
+        };
+
+        let (msg_name, args) = match decoded {
+            WaylandProtocolParsingOutcome::Ok(decoded) => (
+                Some(decoded.msg_name().to_string()),
+                Some(decoded.to_json()),
+            ),
+            _ => (None, None),
+        };
+
+        Some(WlControlMessage {
+            conn_id: self.conn_id,
+            direction,
+            obj_id: msg.obj_id,
+            opcode: msg.opcode,
+            interface: objects
+                .lookup_object(msg.obj_id)
+                .map(|t| t.interface().to_string()),
+            version: objects.lookup_object_version(msg.obj_id),
+            msg_name,
+            args,
+            num_fds: msg.fds.len(),
+            verdict: String::new(),
+        })
+    }
+
+    /// Publish a message decoded with [Self::decode] along with its verdict
+    pub fn publish(&self, msg: Option<WlControlMessage>, verdict: &WlMitmVerdict) {
+        if let Some(mut msg) = msg {
+            msg.verdict = format!("{:?}", verdict);
+            self.control.events.send(WlControlReply::Message(msg)).ok();
+        }
+    }
+}
+
+impl Drop for WlControlConnHandle {
+    fn drop(&mut self) {
+        self.control.conns.lock().unwrap().remove(&self.conn_id);
+        if let Some(ref levels) = self.control.log_levels {
+            levels.set_conn(self.conn_id, None);
+        }
+        self.control
+            .events
+            .send(WlControlReply::ConnClosed {
+                conn_id: self.conn_id,
+            })
+            .ok();
+    }
+}
+
+/// Bind the control socket configured under `[control]`, if any, along with
+/// the lock guarding it (see [WlSocketAddr::claim])
+pub async fn bind_control_socket(
+    config: &Config,
+    replace: bool,
+) -> io::Result<Option<(WlListener, Option<WlSocketLock>)>> {
+    let Some(ref socket) = config.control.socket else {
+        return Ok(None);
+    };
+
+    // Anyone in the network namespace can connect to abstract sockets
+    let addr = WlSocketAddr::parse(socket);
+    if let WlSocketAddr::Tcp(_) | WlSocketAddr::Abstract(_) = addr {
+        error!("The control socket has to be a unix socket on the filesystem");
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "control socket must be a unix socket on the filesystem",
+        ));
+    }
+
+    let lock = addr.claim(replace).await?;
+    let listener = addr.bind().await?;
+
+    // Only the owner may ever steer wl-mitm
+    if let WlSocketAddr::Path(ref p) = addr {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(p, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    info!(addr = %addr, "Listening on control socket");
+    Ok(Some((listener, lock)))
+}
@@ -0,0 +1,554 @@
+//! Just enough of a D-Bus client to follow logind on the system bus
+//!
+//! wl-mitm only ever needs a handful of logind signals and properties, which
+//! doesn't warrant a D-Bus library and its dependencies. This speaks the wire
+//! protocol directly: EXTERNAL authentication, `Hello`, `AddMatch` and method
+//! calls taking strings, and then reading messages, of which only the header
+//! and the first argument are decoded.
+
+use std::io;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+
+/// Where the system bus is if `DBUS_SYSTEM_BUS_ADDRESS` isn't set
+const DEFAULT_SYSTEM_BUS: &str = "unix:path=/run/dbus/system_bus_socket";
+
+/// Messages longer than this are refused, as the spec does
+const MAX_MESSAGE_LEN: usize = 128 * 1024 * 1024;
+
+const MSG_METHOD_CALL: u8 = 1;
+const MSG_METHOD_RETURN: u8 = 2;
+const MSG_ERROR: u8 = 3;
+const MSG_SIGNAL: u8 = 4;
+
+/// Set on calls whose replies we don't care about
+const FLAG_NO_REPLY_EXPECTED: u8 = 0x1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// The path of the system bus socket, from `DBUS_SYSTEM_BUS_ADDRESS` or the default.
+/// Only `unix:path=` addresses are supported.
+pub fn system_bus_path() -> io::Result<String> {
+    let addr =
+        std::env::var("DBUS_SYSTEM_BUS_ADDRESS").unwrap_or_else(|_| DEFAULT_SYSTEM_BUS.into());
+    addr.split(';')
+        .filter_map(|a| a.strip_prefix("unix:"))
+        .flat_map(|a| a.split(','))
+        .find_map(|kv| kv.strip_prefix("path="))
+        .map(str::to_string)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported D-Bus address {}", addr),
+            )
+        })
+}
+
+/// Escape `s` into one element of an object path, the way sd-bus does
+pub fn encode_path_element(s: &str) -> String {
+    if s.is_empty() {
+        return "_".to_string();
+    }
+
+    let mut out = String::new();
+    for (i, b) in s.bytes().enumerate() {
+        if b.is_ascii_alphabetic() || (b.is_ascii_digit() && i > 0) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("_{:02x}", b));
+        }
+    }
+    out
+}
+
+/// A value in a header field or the body of a message we build
+#[derive(Clone, Copy, Debug)]
+pub enum WlDbusValue<'a> {
+    Str(&'a str),
+    Path(&'a str),
+    Signature(&'a str),
+    U32(u32),
+    Bool(bool),
+    /// A variant holding a string
+    Variant(&'a str),
+    /// A variant holding a boolean
+    VariantBool(bool),
+    /// A dictionary of boolean properties, as in `PropertiesChanged`
+    BoolDict(&'a [(&'a str, bool)]),
+    StrArray(&'a [&'a str]),
+}
+
+impl WlDbusValue<'_> {
+    fn signature(&self) -> &'static str {
+        match self {
+            WlDbusValue::Str(_) => "s",
+            WlDbusValue::Path(_) => "o",
+            WlDbusValue::Signature(_) => "g",
+            WlDbusValue::U32(_) => "u",
+            WlDbusValue::Bool(_) => "b",
+            WlDbusValue::Variant(_) | WlDbusValue::VariantBool(_) => "v",
+            WlDbusValue::BoolDict(_) => "a{sv}",
+            WlDbusValue::StrArray(_) => "as",
+        }
+    }
+
+    fn put(&self, buf: &mut Vec<u8>) {
+        match *self {
+            WlDbusValue::Str(s) | WlDbusValue::Path(s) => put_string(buf, s),
+            WlDbusValue::Signature(s) => put_signature(buf, s),
+            WlDbusValue::U32(v) => put_u32(buf, v),
+            WlDbusValue::Bool(v) => put_u32(buf, v as u32),
+            WlDbusValue::Variant(s) => {
+                put_signature(buf, "s");
+                put_string(buf, s);
+            }
+            WlDbusValue::VariantBool(v) => {
+                put_signature(buf, "b");
+                put_u32(buf, v as u32);
+            }
+            WlDbusValue::BoolDict(entries) => put_array(buf, 8, |buf| {
+                for &(key, value) in entries {
+                    pad(buf, 8);
+                    put_string(buf, key);
+                    WlDbusValue::VariantBool(value).put(buf);
+                }
+            }),
+            WlDbusValue::StrArray(strs) => put_array(buf, 4, |buf| {
+                for s in strs {
+                    put_string(buf, s);
+                }
+            }),
+        }
+    }
+}
+
+fn pad(buf: &mut Vec<u8>, align: usize) {
+    while !buf.len().is_multiple_of(align) {
+        buf.push(0);
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    pad(buf, 4);
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+/// An array of elements aligned to `align`, put by `put_elements`
+fn put_array(buf: &mut Vec<u8>, align: usize, put_elements: impl FnOnce(&mut Vec<u8>)) {
+    put_u32(buf, 0);
+    let len_at = buf.len() - 4;
+    pad(buf, align);
+    let start = buf.len();
+    put_elements(buf);
+    let len = (buf.len() - start) as u32;
+    buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    pad(buf, 4);
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+fn put_signature(buf: &mut Vec<u8>, s: &str) {
+    buf.push(s.len() as u8);
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+/// Marshal a message of `msg_type`, little endian
+fn marshal(
+    msg_type: u8,
+    serial: u32,
+    fields: &[(u8, WlDbusValue)],
+    args: &[WlDbusValue],
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    for arg in args {
+        arg.put(&mut body);
+    }
+
+    let mut buf = vec![b'l', msg_type, 0, 1];
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&serial.to_le_bytes());
+    // Length of the header field array, filled in below
+    buf.extend_from_slice(&[0; 4]);
+
+    let signature: String = args.iter().map(|a| a.signature()).collect();
+    let mut fields = fields.to_vec();
+    if !args.is_empty() {
+        fields.push((FIELD_SIGNATURE, WlDbusValue::Signature(&signature)));
+    }
+    for (code, value) in fields {
+        pad(&mut buf, 8);
+        buf.push(code);
+        put_signature(&mut buf, value.signature());
+        value.put(&mut buf);
+    }
+    let fields_len = (buf.len() - 16) as u32;
+    buf[12..16].copy_from_slice(&fields_len.to_le_bytes());
+
+    pad(&mut buf, 8);
+    buf.extend_from_slice(&body);
+    buf
+}
+
+/// A method call to `destination`
+pub fn method_call(
+    serial: u32,
+    destination: &str,
+    path: &str,
+    interface: &str,
+    member: &str,
+    args: &[WlDbusValue],
+) -> Vec<u8> {
+    marshal(
+        MSG_METHOD_CALL,
+        serial,
+        &[
+            (FIELD_PATH, WlDbusValue::Path(path)),
+            (FIELD_INTERFACE, WlDbusValue::Str(interface)),
+            (FIELD_MEMBER, WlDbusValue::Str(member)),
+            (FIELD_DESTINATION, WlDbusValue::Str(destination)),
+        ],
+        args,
+    )
+}
+
+/// A signal, as a bus would deliver it
+pub fn signal(
+    serial: u32,
+    path: &str,
+    interface: &str,
+    member: &str,
+    args: &[WlDbusValue],
+) -> Vec<u8> {
+    marshal(
+        MSG_SIGNAL,
+        serial,
+        &[
+            (FIELD_PATH, WlDbusValue::Path(path)),
+            (FIELD_INTERFACE, WlDbusValue::Str(interface)),
+            (FIELD_MEMBER, WlDbusValue::Str(member)),
+        ],
+        args,
+    )
+}
+
+/// A successful reply to the call with `reply_serial`
+pub fn method_return(serial: u32, reply_serial: u32, args: &[WlDbusValue]) -> Vec<u8> {
+    marshal(
+        MSG_METHOD_RETURN,
+        serial,
+        &[(FIELD_REPLY_SERIAL, WlDbusValue::U32(reply_serial))],
+        args,
+    )
+}
+
+/// An error in reply to the call with `reply_serial`
+pub fn error(serial: u32, reply_serial: u32, name: &str) -> Vec<u8> {
+    marshal(
+        MSG_ERROR,
+        serial,
+        &[
+            (FIELD_ERROR_NAME, WlDbusValue::Str(name)),
+            (FIELD_REPLY_SERIAL, WlDbusValue::U32(reply_serial)),
+        ],
+        &[],
+    )
+}
+
+/// What wl-mitm cares about in a message: most of its header, and its first
+/// argument if that is a string, an object path, a boolean, or a variant
+/// holding one
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WlDbusMessage {
+    pub msg_type: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    /// Only set on messages sent to us alone, rather than broadcast
+    pub destination: Option<String>,
+    pub arg0: Option<String>,
+    pub arg0_bool: Option<bool>,
+}
+
+impl WlDbusMessage {
+    pub fn is_signal(&self) -> bool {
+        self.msg_type == MSG_SIGNAL
+    }
+
+    /// Whether this is the successful reply to the call with `serial`
+    pub fn is_return_of(&self, serial: u32) -> bool {
+        self.msg_type == MSG_METHOD_RETURN && self.reply_serial == Some(serial)
+    }
+
+    /// Whether this is an error in reply to the call with `serial`
+    pub fn is_error_of(&self, serial: u32) -> bool {
+        self.msg_type == MSG_ERROR && self.reply_serial == Some(serial)
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed D-Bus message: {}", what),
+    )
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Cursor<'_> {
+    fn align(&mut self, align: usize) {
+        self.pos = self.pos.div_ceil(align) * align;
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("truncated"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.align(4);
+        let bytes: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let s = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(s)
+    }
+
+    fn signature(&mut self) -> io::Result<String> {
+        let len = self.take(1)?[0] as usize;
+        let s = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(s)
+    }
+
+    /// A string-like value of type `sig`, or [None] for anything else
+    fn string_of_type(&mut self, sig: &str) -> io::Result<Option<String>> {
+        match sig {
+            "s" | "o" => self.string().map(Some),
+            "g" => self.signature().map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The length of the message at the start of `buf`, if enough of it is there to tell
+fn message_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+
+    let read_u32 = |b: &[u8]| {
+        let b: [u8; 4] = b.try_into().unwrap();
+        match buf[0] {
+            b'B' => u32::from_be_bytes(b),
+            _ => u32::from_le_bytes(b),
+        }
+    };
+    let body_len = read_u32(&buf[4..8]) as usize;
+    let fields_len = read_u32(&buf[12..16]) as usize;
+    let len = (16 + fields_len).div_ceil(8) * 8 + body_len;
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid("too long"));
+    }
+    Ok(Some(len))
+}
+
+/// Remove the first message from `buf`, if all of it is there
+pub fn split_message(buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    match message_len(buf)? {
+        Some(len) if buf.len() >= len => {
+            let rest = buf.split_off(len);
+            Ok(Some(std::mem::replace(buf, rest)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Decode the complete message in `buf`
+pub fn parse_message(buf: &[u8]) -> io::Result<WlDbusMessage> {
+    let big_endian = match buf.first() {
+        Some(b'l') => false,
+        Some(b'B') => true,
+        _ => return Err(invalid("bad endianness")),
+    };
+    let mut cur = Cursor {
+        buf,
+        pos: 8,
+        big_endian,
+    };
+    let serial = cur.u32()?;
+    let fields_end = 16 + cur.u32()? as usize;
+
+    let mut msg = WlDbusMessage {
+        msg_type: buf[1],
+        serial,
+        ..Default::default()
+    };
+    let mut signature = String::new();
+    while cur.pos < fields_end {
+        cur.align(8);
+        let code = cur.take(1)?[0];
+        let sig = cur.signature()?;
+        if sig == "u" {
+            let value = cur.u32()?;
+            if code == FIELD_REPLY_SERIAL {
+                msg.reply_serial = Some(value);
+            }
+            continue;
+        }
+
+        // Every other header field the spec defines is string-like
+        let value = cur
+            .string_of_type(&sig)?
+            .ok_or_else(|| invalid("unexpected header field type"))?;
+        match code {
+            FIELD_PATH => msg.path = Some(value),
+            FIELD_INTERFACE => msg.interface = Some(value),
+            FIELD_MEMBER => msg.member = Some(value),
+            FIELD_ERROR_NAME => msg.error_name = Some(value),
+            FIELD_DESTINATION => msg.destination = Some(value),
+            FIELD_SIGNATURE => signature = value,
+            _ => {}
+        }
+    }
+
+    cur.align(8);
+    let sig = match signature.get(..1) {
+        Some("v") => Some(cur.signature()?),
+        sig => sig.map(str::to_string),
+    };
+    match sig.as_deref() {
+        Some("b") => msg.arg0_bool = Some(cur.u32()? != 0),
+        Some(sig) => msg.arg0 = cur.string_of_type(sig)?,
+        None => {}
+    }
+    Ok(msg)
+}
+
+/// A connection to a bus, authenticated and registered
+pub struct WlDbusConn {
+    stream: BufReader<UnixStream>,
+    serial: u32,
+    /// What has been read of the next message
+    buf: Vec<u8>,
+}
+
+impl WlDbusConn {
+    /// Connect to the bus at `path`, authenticating as our own uid
+    pub async fn connect(path: &str) -> io::Result<WlDbusConn> {
+        let mut stream = BufReader::new(UnixStream::connect(path).await?);
+
+        let uid = unsafe { libc::getuid() };
+        let uid_hex: String = uid
+            .to_string()
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        stream
+            .get_mut()
+            .write_all(format!("\0AUTH EXTERNAL {}\r\n", uid_hex).as_bytes())
+            .await?;
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        if !line.starts_with("OK ") {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("D-Bus authentication failed: {}", line.trim_end()),
+            ));
+        }
+        stream.get_mut().write_all(b"BEGIN\r\n").await?;
+
+        let mut conn = WlDbusConn {
+            stream,
+            serial: 0,
+            buf: Vec::new(),
+        };
+        conn.call_bus("Hello", &[]).await?;
+        Ok(conn)
+    }
+
+    fn next_serial(&mut self) -> u32 {
+        self.serial += 1;
+        self.serial
+    }
+
+    async fn call_bus(&mut self, member: &str, args: &[WlDbusValue<'_>]) -> io::Result<()> {
+        let serial = self.next_serial();
+        let mut msg = method_call(
+            serial,
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            member,
+            args,
+        );
+        msg[2] |= FLAG_NO_REPLY_EXPECTED;
+        self.stream.get_mut().write_all(&msg).await
+    }
+
+    /// Have the bus deliver messages matching `rule` to us
+    pub async fn add_match(&mut self, rule: &str) -> io::Result<()> {
+        self.call_bus("AddMatch", &[WlDbusValue::Str(rule)]).await
+    }
+
+    /// Call a method taking strings. Returns the serial of the call, which
+    /// its reply will refer to.
+    pub async fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &[&str],
+    ) -> io::Result<u32> {
+        let serial = self.next_serial();
+        let args: Vec<_> = args.iter().map(|a| WlDbusValue::Str(a)).collect();
+        let msg = method_call(serial, destination, path, interface, member, &args);
+        self.stream.get_mut().write_all(&msg).await?;
+        Ok(serial)
+    }
+
+    /// The next message on the bus. Cancel safe: a message partially read is
+    /// completed by the next call.
+    pub async fn next_message(&mut self) -> io::Result<WlDbusMessage> {
+        loop {
+            if let Some(msg) = split_message(&mut self.buf)? {
+                return parse_message(&msg);
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! `wl-mitm decode`: decodes captured messages offline, without a live session
+//!
+//! Input is either a recording written by [crate::recorder], in any format
+//! but pcapng, or a raw byte stream of messages in one direction, e.g. as
+//! captured from a socket by other tools. Messages are printed like in
+//! [crate::trace]s:
+//!
+//! ```text
+//! [     12.345] -> wl_surface#3.damage(0, 0, 10, 10)
+//! ```
+//!
+//! Object types are tracked from the start of the input, so a capture that
+//! starts in the middle of a session only decodes messages on objects created
+//! after that. fds aren't part of captures; placeholders are used in their
+//! stead.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::OwnedFd,
+    path::Path,
+};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::{
+    codec::WlRawMsg,
+    objects::WlObjects,
+    proto::{
+        self, AnyWlParsedMessage, WaylandProtocolParsingOutcome, WlDisplayDeleteIdEvent,
+        WlRegistryBindRequest,
+    },
+    recorder::{WlDirection, WlRecordingReader},
+};
+
+/// What `wl-mitm decode` is given
+#[derive(Clone, Copy, Debug)]
+pub enum WlDecodeInput {
+    /// A recording, see [WlRecordingReader]
+    Recording,
+    /// Raw messages, all going in one direction
+    Raw(WlDirection),
+}
+
+/// Keeps track of objects across the messages of one connection, to decode them
+pub struct WlOfflineDecoder {
+    objects: WlObjects,
+    devnull: File,
+}
+
+impl WlOfflineDecoder {
+    pub fn new() -> io::Result<WlOfflineDecoder> {
+        Ok(WlOfflineDecoder {
+            objects: WlObjects::new(),
+            devnull: File::open("/dev/null")?,
+        })
+    }
+
+    /// Describe `msg` like [crate::trace::WlTracer::describe] does, and
+    /// record the objects it creates or destroys
+    pub fn decode(&mut self, direction: WlDirection, msg: &WlRawMsg) -> io::Result<String> {
+        let msg = self.with_placeholder_fds(direction, msg)?;
+        let decoded = match direction {
+            WlDirection::ClientToServer => proto::decode_request(&self.objects, &msg),
+            WlDirection::ServerToClient => proto::decode_event(&self.objects, &msg),
+        };
+
+        let WaylandProtocolParsingOutcome::Ok(decoded) = decoded else {
+            return Ok(format!(
+                "{}#{}.?{}({} bytes)",
+                self.objects
+                    .lookup_object(msg.obj_id)
+                    .map(|t| t.interface())
+                    .unwrap_or("(unknown)"),
+                msg.obj_id,
+                msg.opcode,
+                msg.payload().len(),
+            ));
+        };
+
+        let desc = decoded.to_string();
+        self.track_objects(&*decoded, direction == WlDirection::ClientToServer);
+        Ok(desc)
+    }
+
+    /// A copy of `msg` with as many fds as it needs to be parsed
+    fn with_placeholder_fds(&self, direction: WlDirection, msg: &WlRawMsg) -> io::Result<WlRawMsg> {
+        let num_fds = self
+            .objects
+            .lookup_object(msg.obj_id)
+            .and_then(|t| match direction {
+                WlDirection::ClientToServer => proto::lookup_request_parser(t, msg.opcode),
+                WlDirection::ServerToClient => proto::lookup_event_parser(t, msg.opcode),
+            })
+            .map_or(0, |p| p.num_consumed_fds());
+        let fds = (0..num_fds)
+            .map(|_| self.devnull.try_clone().map(OwnedFd::from))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(WlRawMsg::build(msg.obj_id, msg.opcode, |buf, msg_fds| {
+            buf.put_slice(msg.payload());
+            msg_fds.extend(fds);
+        }))
+    }
+
+    /// Like [crate::state::WlMitmState] does, minus all the checks
+    fn track_objects(&mut self, msg: &dyn AnyWlParsedMessage, from_client: bool) {
+        if let Some(created) = msg.known_objects_created() {
+            for (id, obj_type) in created {
+                self.objects
+                    .record_child_object(obj_type, id, msg.obj_id(), None);
+            }
+        } else if msg.is_destructor() {
+            self.objects.remove_object(msg.obj_id(), from_client);
+        }
+
+        if let Some(msg) = msg.downcast_ref::<WlRegistryBindRequest>()
+            && let Some(obj_type) = proto::lookup_known_object_type(msg.id_interface_name)
+        {
+            self.objects.record_child_object(
+                obj_type,
+                msg.id,
+                msg.obj_id(),
+                Some(msg.id_interface_version),
+            );
+        } else if let Some(msg) = msg.downcast_ref::<WlDisplayDeleteIdEvent>() {
+            self.objects.remove_object(msg.id, false);
+        }
+    }
+}
+
+fn write_line(
+    out: &mut impl Write,
+    offset_ns: Option<u64>,
+    direction: WlDirection,
+    desc: &str,
+) -> io::Result<()> {
+    if let Some(offset_ns) = offset_ns {
+        write!(
+            out,
+            "[{:7}.{:03}] ",
+            offset_ns / 1_000_000,
+            offset_ns / 1_000 % 1000
+        )?;
+    }
+
+    write!(
+        out,
+        "{} {}",
+        match direction {
+            WlDirection::ClientToServer => "->",
+            WlDirection::ServerToClient => "<-",
+        },
+        desc
+    )
+}
+
+/// Decode all messages in `path` and write them to `out`, one per line
+pub fn decode(path: &Path, input: WlDecodeInput, out: &mut impl Write) -> io::Result<()> {
+    let mut decoder = WlOfflineDecoder::new()?;
+
+    match input {
+        WlDecodeInput::Recording => {
+            for entry in WlRecordingReader::open(path)? {
+                let entry = entry?;
+                let mut buf = BytesMut::from(&entry.data[..]);
+                let Some(msg) = WlRawMsg::try_decode(&mut buf, &mut VecDeque::new()) else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "recorded message is truncated",
+                    ));
+                };
+
+                let desc = decoder.decode(entry.direction, &msg)?;
+                write_line(out, Some(entry.offset_ns), entry.direction, &desc)?;
+                if entry.verdict != "Allowed" {
+                    write!(out, " {}", entry.verdict)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        WlDecodeInput::Raw(direction) => {
+            let mut buf = Vec::new();
+            File::open(path)?.read_to_end(&mut buf)?;
+            let mut buf = BytesMut::from(&buf[..]);
+
+            while let Some(msg) = WlRawMsg::try_decode(&mut buf, &mut VecDeque::new()) {
+                if msg.as_bytes().len() < 8 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "message is shorter than its header",
+                    ));
+                }
+
+                let desc = decoder.decode(direction, &msg)?;
+                write_line(out, None, direction, &desc)?;
+                writeln!(out)?;
+            }
+
+            if !buf.is_empty() {
+                writeln!(out, "# {} trailing bytes", buf.len())?;
+            }
+        }
+    }
+
+    out.flush()
+}
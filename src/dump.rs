@@ -0,0 +1,146 @@
+//! Dumps of the state tracked for every connection, for debugging object leaks
+//! and tracking going out of sync with the client in long-running sessions
+//!
+//! A dump is triggered by SIGUSR1 or [crate::control::WlControlRequest::Dump].
+//! Every connection then writes a [WlConnDump] of itself, either to the log or,
+//! if `dump.file` is configured, as one JSON line appended to that file.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use serde_derive::Serialize;
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+use crate::{
+    config::Config,
+    latency::WlConnLatency,
+    objects::{WlObjectInfo, WlObjects, WlObjectsStats},
+    state::{WlSeatInfo, WlToplevelInfo},
+};
+
+#[derive(Serialize, Debug)]
+pub struct WlGlobalInfo {
+    /// The wl_registry the global was announced on
+    pub registry: u32,
+    pub name: u32,
+    pub interface: String,
+}
+
+/// Everything tracked for one connection
+#[derive(Serialize, Debug)]
+pub struct WlConnDump {
+    pub conn_id: u64,
+    /// Whether the client is XWayland
+    pub xwayland: bool,
+    /// Whether the client has bound xwayland_shell_v1
+    pub xwayland_shell: bool,
+    pub objects: Vec<WlObjectInfo>,
+    pub stats: WlObjectsStats,
+    /// IDs of objects destroyed by the client, but not yet by the server
+    pub half_destroyed: Vec<u32>,
+    pub globals: Vec<WlGlobalInfo>,
+    pub toplevels: Vec<WlToplevelInfo>,
+    pub seats: Vec<WlSeatInfo>,
+    /// Messages queued up for the server
+    pub pending_writes_upstream: usize,
+    /// Messages queued up for the client
+    pub pending_writes_downstream: usize,
+    /// Time forwarded messages spent inside wl-mitm, see [crate::latency]
+    pub latency: WlConnLatency,
+}
+
+impl WlConnDump {
+    /// Dump the object table; the rest is filled in by the caller
+    pub fn from_objects(conn_id: u64, objects: &WlObjects) -> WlConnDump {
+        let objects_info = objects.snapshot();
+        let half_destroyed = objects_info
+            .iter()
+            .filter(|o| o.half_destroyed)
+            .map(|o| o.id)
+            .collect();
+
+        let mut globals: Vec<_> = objects
+            .iter_globals()
+            .map(|(registry, name, t)| WlGlobalInfo {
+                registry,
+                name,
+                interface: t.interface().to_string(),
+            })
+            .collect();
+        globals.sort_by_key(|g| (g.registry, g.name));
+
+        WlConnDump {
+            conn_id,
+            objects: objects_info,
+            stats: objects.stats(),
+            half_destroyed,
+            globals,
+            xwayland: false,
+            xwayland_shell: false,
+            toplevels: Vec::new(),
+            seats: Vec::new(),
+            pending_writes_upstream: 0,
+            pending_writes_downstream: 0,
+            latency: Default::default(),
+        }
+    }
+}
+
+/// Triggers dumps, and writes them where they belong
+pub struct WlDumper {
+    config: Arc<Config>,
+    trigger: broadcast::Sender<()>,
+    /// Serializes writes to the dump file
+    file_lock: Mutex<()>,
+}
+
+impl WlDumper {
+    pub fn new(config: Arc<Config>) -> Arc<WlDumper> {
+        Arc::new(WlDumper {
+            config,
+            trigger: broadcast::channel(1).0,
+            file_lock: Mutex::new(()),
+        })
+    }
+
+    /// Get notified whenever a dump is triggered
+    pub fn subscribe(&self) -> broadcast::Receiver<()> {
+        self.trigger.subscribe()
+    }
+
+    /// Ask every connection to dump its state. Returns the number of
+    /// connections asked.
+    pub fn trigger(&self) -> usize {
+        let num_conns = self.trigger.send(()).unwrap_or(0);
+        info!(num_conns = num_conns, "Dumping state of all connections");
+        num_conns
+    }
+
+    /// Write the dump of one connection
+    pub fn write(&self, dump: &WlConnDump) {
+        let Some(ref file) = self.config.dump.file else {
+            info!(dump = ?dump, "State dump");
+            return;
+        };
+
+        if let Err(e) = self.append(file, dump) {
+            error!(error = ?e, file = file, "Failed to write state dump");
+        }
+    }
+
+    fn append(&self, file: &str, dump: &WlConnDump) -> io::Result<()> {
+        let mut line = serde_json::to_vec(dump)?;
+        line.push(b'\n');
+
+        let _guard = self.file_lock.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?
+            .write_all(&line)
+    }
+}
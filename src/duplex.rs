@@ -0,0 +1,705 @@
+//! Forwarding of messages between one client and its upstream server
+
+use std::{
+    io,
+    ops::ControlFlow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{broadcast, oneshot};
+use tracing::{error, info, warn};
+
+use crate::{
+    alerts::{WlAlert, WlAlerts},
+    audit::WlAuditLog,
+    chaos::WlChaos,
+    codec::{self, DecoderOutcome, WlRawMsg},
+    config::{Config, WlFdPolicy},
+    control::{WlConnRequest, WlControl, WlControlConnHandle, WlControlMessage},
+    dump::{WlConnDump, WlDumper},
+    io_util::{WlMsgReader, WlMsgWriter},
+    latency::WlConnLatency,
+    learning::WlLearning,
+    logind::WlSession,
+    pacing::WlCapturePacer,
+    panic::{self, WlConnPanic, WlPanickedMsg},
+    peer::WlPeerInfo,
+    policygen::WlPolicyGenerator,
+    proto::{self, WL_DISPLAY_OBJECT_ID, WlConstructableMessage, WlDisplayErrorEvent},
+    recorder::{WlDirection, WlRecorder},
+    socket::{WlSocketAddr, WlStream},
+    state::{WlMitmOutcome, WlMitmState, WlMitmVerdict},
+    stats::WlConnStats,
+    trace::WlTracer,
+    translate::WlFdTranslator,
+};
+
+macro_rules! control_flow {
+    ($f:expr) => {
+        match $f {
+            ControlFlow::Break(res) => break res,
+            ControlFlow::Continue(_) => continue,
+        }
+    };
+}
+
+/// Forwards messages between one client and its upstream server, passing each
+/// of them through [WlMitmState] for a verdict
+pub struct ConnDuplex<'a> {
+    config: Arc<Config>,
+    conn_id: u64,
+    upstream_read: WlMsgReader<'a>,
+    upstream_write: WlMsgWriter<'a>,
+    downstream_read: WlMsgReader<'a>,
+    downstream_write: WlMsgWriter<'a>,
+    state: WlMitmState,
+    /// Only present with [WlFdPolicy::Translate]
+    fd_translator: Option<WlFdTranslator>,
+    recorder: Option<WlRecorder>,
+    control: Option<WlControlConnHandle>,
+    /// Only present if chaos mode is enabled
+    chaos: Option<WlChaos>,
+    /// Only present if there are alert rules
+    alerts: Option<WlAlerts>,
+    /// Only present if capture is capped
+    pacer: Option<WlCapturePacer>,
+    /// Only present if statistics are enabled, along with the timer for
+    /// periodic exports if configured
+    stats: Option<(WlConnStats, Option<tokio::time::Interval>)>,
+    tracer: Option<WlTracer>,
+    dumper: Option<(Arc<WlDumper>, broadcast::Receiver<()>)>,
+    session: Option<Arc<WlSession>>,
+}
+
+impl<'a> ConnDuplex<'a> {
+    pub fn new(
+        config: Arc<Config>,
+        conn_id: u64,
+        state: WlMitmState,
+        control: Option<WlControlConnHandle>,
+        upstream_conn: &'a mut WlStream,
+        downstream_conn: &'a mut WlStream,
+    ) -> Self {
+        let (upstream_read, upstream_write) = upstream_conn.split();
+        let (downstream_read, downstream_write) = downstream_conn.split();
+
+        let upstream_read = WlMsgReader::new(upstream_read);
+        let downstream_read = WlMsgReader::new(downstream_read);
+
+        let upstream_write = WlMsgWriter::new(upstream_write);
+        let downstream_write = WlMsgWriter::new(downstream_write);
+
+        let fd_translator =
+            matches!(config.socket.fd_policy, WlFdPolicy::Translate).then(WlFdTranslator::new);
+
+        let recorder = config.recording.dir.as_ref().and_then(|dir| {
+            WlRecorder::create(dir.as_ref(), config.recording.format, conn_id)
+                .inspect_err(|e| error!(error = ?e, "Cannot create recording file"))
+                .ok()
+        });
+
+        let chaos = WlChaos::new(&config.chaos);
+        let alerts = WlAlerts::new(&config.alerts);
+        let pacer = WlCapturePacer::new(&config.capture);
+        let stats = WlConnStats::new(&config.stats, conn_id).map(|stats| {
+            let interval = config.stats.interval_secs.map(|secs| {
+                let period = Duration::from_secs(secs.max(1));
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+            });
+            (stats, interval)
+        });
+
+        Self {
+            config,
+            conn_id,
+            upstream_read,
+            upstream_write,
+            downstream_read,
+            downstream_write,
+            state,
+            fd_translator,
+            recorder,
+            control,
+            chaos,
+            alerts,
+            pacer,
+            stats,
+            tracer: None,
+            dumper: None,
+            session: None,
+        }
+    }
+
+    /// Write a decoded trace of this connection, see [crate::trace]
+    pub fn set_tracer(&mut self, tracer: WlTracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Dump the state of this connection whenever `dumper` is triggered
+    pub fn set_dumper(&mut self, dumper: Arc<WlDumper>) {
+        let rx = dumper.subscribe();
+        self.dumper = Some((dumper, rx));
+    }
+
+    /// Tell who the client is, see [WlMitmState::set_peer]
+    pub fn set_peer(&mut self, peer: WlPeerInfo) {
+        self.state.set_peer(peer);
+    }
+
+    /// Note what the client uses for a suggested policy, see [crate::policygen]
+    pub fn set_policy_generator(&mut self, policy: Arc<WlPolicyGenerator>) {
+        self.state.set_policy_generator(policy);
+    }
+
+    /// Learn or enforce the app profile of the client, see [crate::learning]
+    pub fn set_learning(&mut self, learning: Arc<WlLearning>) {
+        self.state.set_learning(learning);
+    }
+
+    /// Filter `lock.deny` requests while `session` is locked, and close the
+    /// connection once it ends, see [crate::logind]
+    pub fn set_session(&mut self, session: Arc<WlSession>) {
+        self.session = Some(session.clone());
+        self.state.set_session(session);
+    }
+
+    /// Append every policy decision to `audit`, see [crate::audit]
+    pub fn set_audit(&mut self, audit: Arc<WlAuditLog>) {
+        self.state.set_audit(audit);
+    }
+
+    /// Wait for a round trip to the server, see [WlMitmState::sync]. The
+    /// receiver resolves once the server has processed everything forwarded
+    /// to it so far, and the client's next `wl_display.sync` as well. It fails
+    /// if the client sends no `wl_display.sync` within `timeout`.
+    pub fn sync_upstream(&mut self, timeout: Duration) -> oneshot::Receiver<()> {
+        self.state.sync(timeout)
+    }
+
+    fn dump(&self) -> WlConnDump {
+        let mut dump = WlConnDump::from_objects(self.conn_id, self.state.objects());
+        dump.xwayland = self.state.is_xwayland();
+        dump.xwayland_shell = self.state.uses_xwayland_shell();
+        dump.toplevels = self.state.toplevels();
+        dump.seats = self.state.seats();
+        dump.pending_writes_upstream = self.upstream_write.pending_writes();
+        dump.pending_writes_downstream = self.downstream_write.pending_writes();
+        dump.latency = self.latency();
+        dump
+    }
+
+    /// Time forwarded messages spent inside wl-mitm, see [crate::latency]
+    fn latency(&self) -> WlConnLatency {
+        WlConnLatency {
+            c2s: self.upstream_write.latency().summary(),
+            s2c: self.downstream_write.latency().summary(),
+        }
+    }
+
+    /// Send the client what wl-mitm answered its requests with, see [WlMitmVerdict::Answered],
+    /// or replaced events with, and the server what replaced requests, see
+    /// [WlMitmVerdict::Replaced]
+    fn queue_replies(&mut self) {
+        for msg in self.state.take_replies() {
+            self.downstream_write.queue_write(msg);
+        }
+        for msg in self.state.take_replacements() {
+            self.upstream_write.queue_write(msg);
+        }
+    }
+
+    /// Queue an allowed message for the other side, through chaos mode if enabled.
+    /// `received` is when it was decoded, see [crate::latency]. The message is
+    /// held back until `not_before`, if given, see [crate::pacing].
+    fn forward(
+        &mut self,
+        direction: WlDirection,
+        msg: WlRawMsg,
+        received: Instant,
+        not_before: Option<Instant>,
+    ) {
+        let dest = match direction {
+            WlDirection::ClientToServer => &mut self.upstream_write,
+            WlDirection::ServerToClient => &mut self.downstream_write,
+        };
+        let mut queue = |msg| match not_before {
+            Some(not_before) => dest.queue_write_after(msg, received, not_before),
+            None => dest.queue_write_since(msg, received),
+        };
+
+        let Some(ref mut chaos) = self.chaos else {
+            queue(msg);
+            return;
+        };
+
+        let interface = self
+            .state
+            .objects()
+            .lookup_object(msg.obj_id)
+            .map(|t| t.interface());
+        for msg in chaos.apply(direction, interface, msg) {
+            queue(msg);
+        }
+    }
+
+    /// Let go of messages chaos mode has held back for too long, see [crate::chaos]
+    fn release_chaos(&mut self) {
+        let Some(ref mut chaos) = self.chaos else {
+            return;
+        };
+
+        for (direction, msg) in chaos.release_overdue() {
+            match direction {
+                WlDirection::ClientToServer => self.upstream_write.queue_write(msg),
+                WlDirection::ServerToClient => self.downstream_write.queue_write(msg),
+            }
+        }
+    }
+
+    fn record(
+        &mut self,
+        direction: WlDirection,
+        msg: &WlRawMsg,
+        trace_desc: Option<String>,
+        verdict: &WlMitmVerdict,
+    ) {
+        if let Some(ref mut recorder) = self.recorder
+            && let Err(e) = recorder.record(direction, msg, verdict)
+        {
+            error!(error = ?e, "Failed to write recording; stopping recording");
+            self.recorder = None;
+        }
+
+        if let (Some(tracer), Some(desc)) = (self.tracer.as_mut(), trace_desc)
+            && let Err(e) = tracer.trace(direction, &desc, verdict)
+        {
+            error!(error = ?e, "Failed to write trace; stopping tracing");
+            self.tracer = None;
+        }
+    }
+
+    /// `(interface, message)` of a message, if it is on a known object and
+    /// anyone is interested. This has to be looked up before processing the
+    /// message, which may destroy the object.
+    fn msg_name(
+        &self,
+        direction: WlDirection,
+        msg: &WlRawMsg,
+    ) -> Option<(&'static str, &'static str)> {
+        if self.alerts.is_none() && self.stats.is_none() && self.pacer.is_none() {
+            return None;
+        }
+
+        let obj_type = self.state.objects().lookup_object(msg.obj_id)?;
+        let parser = match direction {
+            WlDirection::ClientToServer => proto::lookup_request_parser(obj_type, msg.opcode),
+            WlDirection::ServerToClient => proto::lookup_event_parser(obj_type, msg.opcode),
+        }?;
+        Some((obj_type.interface(), parser.msg_name()))
+    }
+
+    /// Write out the statistics of this connection, see [crate::stats]
+    fn export_stats(&self) {
+        let Some((ref stats, _)) = self.stats else {
+            return;
+        };
+
+        let mut export = stats.snapshot();
+        let peer = self.state.peer();
+        export.pid = peer.pid;
+        export.exe = peer.exe.as_ref().map(|p| p.display().to_string());
+        export.app_id = self.state.app_id();
+        export.latency = self.latency();
+        if let Err(e) = stats.write(&export) {
+            error!(error = ?e, path = ?stats.path(), "Failed to write statistics");
+        }
+    }
+
+    /// Count the request named `name` towards alert rules, and send off
+    /// alerts for those that fire
+    async fn check_alerts(&mut self, name: (&str, &str), verdict: &WlMitmVerdict) {
+        let Some(ref mut alerts) = self.alerts else {
+            return;
+        };
+
+        let (interface, request) = name;
+        let blocked = !matches!(
+            verdict,
+            WlMitmVerdict::Allowed | WlMitmVerdict::Answered | WlMitmVerdict::Replaced
+        );
+        let fired: Vec<_> = alerts
+            .record(interface, request, blocked, Instant::now())
+            .into_iter()
+            .map(|rule| WlAlert::new(rule, self.conn_id, interface, request))
+            .collect();
+        for alert in fired {
+            self.state.alert(alert).await;
+        }
+    }
+
+    /// Messages that carry fds can't be forwarded to a peer on the other side of
+    /// a transport unable to pass them. Translate them if configured to, or apply
+    /// the configured [WlFdPolicy] to them otherwise.
+    fn prepare_for_transport(
+        &mut self,
+        msg: &mut WlRawMsg,
+        verdict: WlMitmVerdict,
+        from_client: bool,
+    ) -> WlMitmVerdict {
+        let dest = if from_client {
+            &mut self.upstream_write
+        } else {
+            &mut self.downstream_write
+        };
+
+        if !verdict.is_allowed() || dest.can_pass_fds() {
+            return verdict;
+        }
+
+        let transportable = match self.fd_translator {
+            Some(ref mut translator) => {
+                translator.translate_outgoing(self.state.objects(), msg, dest)
+            }
+            None => msg.fds.is_empty(),
+        };
+
+        if transportable {
+            return verdict;
+        }
+
+        warn!(
+            obj_id = msg.obj_id,
+            opcode = msg.opcode,
+            num_fds = msg.fds.len(),
+            fd_policy = ?self.config.socket.fd_policy,
+            "Message carries fds which can't be passed to the other side"
+        );
+
+        match self.config.socket.fd_policy {
+            WlFdPolicy::Reject | WlFdPolicy::Translate if from_client => WlMitmVerdict::Rejected(0),
+            WlFdPolicy::Reject | WlFdPolicy::Translate => WlMitmVerdict::Filtered,
+            WlFdPolicy::Terminate => WlMitmVerdict::Terminate,
+        }
+    }
+
+    /// Consume in-band messages and reconstruct fds for messages read from a transport
+    /// unable to pass fds. Returns true if the message has been consumed.
+    fn translate_incoming(&mut self, msg: &mut WlRawMsg, from_client: bool) -> io::Result<bool> {
+        let src = if from_client {
+            &self.downstream_read
+        } else {
+            &self.upstream_read
+        };
+
+        let Some(ref mut translator) = self.fd_translator else {
+            return Ok(false);
+        };
+
+        if src.can_pass_fds() {
+            return Ok(false);
+        }
+
+        translator.handle_incoming(msg)
+    }
+
+    /// Decode a message for the control socket and the trace, and reach a
+    /// verdict on it. A panic while doing so is turned into a [WlConnPanic] error.
+    async fn process(
+        &mut self,
+        direction: WlDirection,
+        msg: &WlRawMsg,
+    ) -> io::Result<(Option<WlControlMessage>, Option<String>, WlMitmOutcome)> {
+        let res = panic::catch_unwind(async {
+            let control_msg = self
+                .control
+                .as_ref()
+                .and_then(|c| c.decode(self.state.objects(), direction, msg));
+            let trace_desc = self
+                .tracer
+                .as_ref()
+                .map(|_| WlTracer::describe(self.state.objects(), direction, msg));
+            let outcome = match direction {
+                WlDirection::ClientToServer => self.state.on_c2s_request(msg).await,
+                WlDirection::ServerToClient => self.state.on_s2c_event(msg).await,
+            };
+            (control_msg, trace_desc, outcome)
+        })
+        .await;
+
+        res.map_err(|payload| {
+            let interface = self
+                .state
+                .objects()
+                .lookup_object(msg.obj_id)
+                .map(|t| t.interface());
+            WlConnPanic::new(payload, Some(WlPanickedMsg::new(direction, interface, msg))).into()
+        })
+    }
+
+    async fn handle_s2c_event(
+        &mut self,
+        decoded_raw: DecoderOutcome,
+    ) -> io::Result<ControlFlow<()>> {
+        match decoded_raw {
+            codec::DecoderOutcome::Decoded(mut wl_raw_msg) => {
+                let received = Instant::now();
+                if self.translate_incoming(&mut wl_raw_msg, false)? {
+                    return Ok(ControlFlow::Continue(()));
+                }
+
+                let name = self.msg_name(WlDirection::ServerToClient, &wl_raw_msg);
+                let (control_msg, trace_desc, WlMitmOutcome(num_consumed_fds, mut verdict)) = self
+                    .process(WlDirection::ServerToClient, &wl_raw_msg)
+                    .await?;
+                self.upstream_read
+                    .return_unused_fds(&mut wl_raw_msg, num_consumed_fds);
+
+                if !verdict.is_allowed()
+                    && !matches!(verdict, WlMitmVerdict::Replaced)
+                    && self.config.filter.dry_run
+                {
+                    warn!(
+                        verdict = ?verdict,
+                        "Last event would have been filtered! (see prior logs for reason)"
+                    );
+                    verdict = WlMitmVerdict::Allowed;
+                }
+
+                let verdict = self.prepare_for_transport(&mut wl_raw_msg, verdict, false);
+                self.queue_replies();
+                if let (Some((interface, event)), Some((stats, _))) = (name, self.stats.as_mut()) {
+                    stats.record(WlDirection::ServerToClient, interface, event, &verdict);
+                }
+                self.record(
+                    WlDirection::ServerToClient,
+                    &wl_raw_msg,
+                    trace_desc,
+                    &verdict,
+                );
+                if let Some(ref control) = self.control {
+                    control.publish(control_msg, &verdict);
+                }
+
+                match verdict {
+                    WlMitmVerdict::Allowed => {
+                        self.forward(WlDirection::ServerToClient, wl_raw_msg, received, None);
+                    }
+                    WlMitmVerdict::Terminate => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "aborting connection",
+                        ));
+                    }
+                    _ => {}
+                };
+            }
+            codec::DecoderOutcome::Eof => return Ok(ControlFlow::Break(())),
+            _ => {}
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    async fn handle_c2s_request(
+        &mut self,
+        decoded_raw: DecoderOutcome,
+    ) -> io::Result<ControlFlow<()>> {
+        match decoded_raw {
+            codec::DecoderOutcome::Decoded(mut wl_raw_msg) => {
+                let received = Instant::now();
+                if self.translate_incoming(&mut wl_raw_msg, true)? {
+                    return Ok(ControlFlow::Continue(()));
+                }
+
+                let name = self.msg_name(WlDirection::ClientToServer, &wl_raw_msg);
+                let (control_msg, trace_desc, WlMitmOutcome(num_consumed_fds, mut verdict)) = self
+                    .process(WlDirection::ClientToServer, &wl_raw_msg)
+                    .await?;
+                self.downstream_read
+                    .return_unused_fds(&mut wl_raw_msg, num_consumed_fds);
+
+                if !verdict.is_allowed()
+                    && !matches!(verdict, WlMitmVerdict::Answered | WlMitmVerdict::Replaced)
+                    && self.config.filter.dry_run
+                {
+                    warn!(
+                        verdict = ?verdict,
+                        "Last request would have been filtered! (see prior logs for reason)"
+                    );
+                    verdict = WlMitmVerdict::Allowed;
+                }
+
+                let verdict = self.prepare_for_transport(&mut wl_raw_msg, verdict, true);
+                self.queue_replies();
+                if let Some((interface, request)) = name {
+                    if let Some((ref mut stats, _)) = self.stats {
+                        stats.record(WlDirection::ClientToServer, interface, request, &verdict);
+                    }
+                    self.check_alerts((interface, request), &verdict).await;
+                }
+                self.record(
+                    WlDirection::ClientToServer,
+                    &wl_raw_msg,
+                    trace_desc,
+                    &verdict,
+                );
+                if let Some(ref control) = self.control {
+                    control.publish(control_msg, &verdict);
+                }
+
+                match verdict {
+                    WlMitmVerdict::Allowed => {
+                        let not_before = match (name, self.pacer.as_mut()) {
+                            (Some((interface, request)), Some(pacer)) => {
+                                pacer.schedule(interface, request, received)
+                            }
+                            _ => None,
+                        };
+                        self.state.on_forwarded_request(&wl_raw_msg);
+                        self.forward(
+                            WlDirection::ClientToServer,
+                            wl_raw_msg,
+                            received,
+                            not_before,
+                        );
+                    }
+                    WlMitmVerdict::Rejected(error_code) => {
+                        self.downstream_write.queue_write(
+                            WlDisplayErrorEvent::new(
+                                WL_DISPLAY_OBJECT_ID,
+                                wl_raw_msg.obj_id,
+                                error_code,
+                                "Rejected by wl-mitm",
+                            )
+                            .build(),
+                        );
+                    }
+                    WlMitmVerdict::Terminate => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "aborting connection",
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            codec::DecoderOutcome::Eof => return Ok(ControlFlow::Break(())),
+            _ => {}
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn run_to_completion(mut self) -> io::Result<()> {
+        let res = self.run().await;
+        // The final numbers, however the connection ended
+        self.export_stats();
+        let latency = self.latency();
+        info!(
+            requests = latency.c2s.count,
+            request_p50_us = latency.c2s.p50_us,
+            request_p99_us = latency.c2s.p99_us,
+            events = latency.s2c.count,
+            event_p50_us = latency.s2c.p50_us,
+            event_p99_us = latency.s2c.p99_us,
+            "Connection closed"
+        );
+        res
+    }
+
+    async fn run(&mut self) -> io::Result<()> {
+        loop {
+            let chaos_deadline = self.chaos.as_ref().and_then(WlChaos::release_deadline);
+            let sync_deadline = self.state.sync_deadline();
+            tokio::select! {
+                biased;
+
+                res = self.downstream_write.dequeue_write() => res?,
+                res = self.upstream_write.dequeue_write() => res?,
+
+                msg = self.upstream_read.read() => {
+                    control_flow!(self.handle_s2c_event(msg?).await?);
+                }
+                // Don't pile up requests behind one held back, see [crate::pacing]
+                msg = self.downstream_read.read(), if !self.upstream_write.is_holding() => {
+                    control_flow!(self.handle_c2s_request(msg?).await?);
+                }
+                req = async { self.control.as_mut().unwrap().requested().await }, if self.control.is_some() => {
+                    match req {
+                        WlConnRequest::Objects(reply) => {
+                            reply.send(self.state.objects().snapshot()).ok();
+                        }
+                        WlConnRequest::Revoke(req) => {
+                            let revoked = self.state.revoke_permission(&req.interface, &req.request);
+                            self.queue_replies();
+                            req.reply.send(revoked).ok();
+                        }
+                    }
+                }
+                res = async { self.dumper.as_mut().unwrap().1.recv().await }, if self.dumper.is_some() => {
+                    match res {
+                        // Triggers coalesced while we were busy still deserve one dump
+                        Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                            let (dumper, _) = self.dumper.as_ref().unwrap();
+                            dumper.write(&self.dump());
+                            self.export_stats();
+                        }
+                        Err(broadcast::error::RecvError::Closed) => self.dumper = None,
+                    }
+                }
+                _ = async { self.stats.as_mut().unwrap().1.as_mut().unwrap().tick().await },
+                    if self.stats.as_ref().is_some_and(|(_, i)| i.is_some()) =>
+                {
+                    self.export_stats();
+                }
+                _ = async { tokio::time::sleep_until(chaos_deadline.unwrap().into()).await },
+                    if chaos_deadline.is_some() =>
+                {
+                    self.release_chaos();
+                }
+                _ = async { tokio::time::sleep_until(sync_deadline.unwrap().into()).await },
+                    if sync_deadline.is_some() =>
+                {
+                    self.state.expire_syncs();
+                }
+                _ = async { self.session.as_ref().unwrap().ended().await }, if self.session.is_some() => {
+                    warn!("Closing connection as the session has ended");
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Connect to `src_addr` and proxy `downstream_conn` to it. `setup` gets to
+/// attach optional parts, such as a tracer, before any message is processed.
+pub async fn handle_conn(
+    config: Arc<Config>,
+    control: Option<Arc<WlControl>>,
+    control_handle: Option<WlControlConnHandle>,
+    conn_id: u64,
+    src_addr: WlSocketAddr,
+    setup: impl FnOnce(&mut ConnDuplex<'_>),
+    mut downstream_conn: WlStream,
+) -> io::Result<()> {
+    let mut upstream_conn = src_addr
+        .connect_with_wait(Duration::from_secs(config.socket.upstream_wait))
+        .await?;
+    let state = WlMitmState::new(config.clone(), control);
+
+    let mut duplex = ConnDuplex::new(
+        config,
+        conn_id,
+        state,
+        control_handle,
+        &mut upstream_conn,
+        &mut downstream_conn,
+    );
+    setup(&mut duplex);
+
+    duplex.run_to_completion().await
+}
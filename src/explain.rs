@@ -0,0 +1,380 @@
+//! `wl-mitm explain`: which filter rule applies to a request, and why
+//!
+//! This is worked out from the config alone, without generating any traffic.
+//! Some rule conditions depend on the object a request is sent on, i.e. its
+//! version and the objects it was created through. They are checked against
+//! what the [WlExplainQuery] says about the object. A rule with conditions
+//! the query leaves open only [WlRuleMatch::MayMatch]es.
+
+use std::fmt;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, WlFilterRequest, WlFilterRequestAction, WlFilterRequestBlockType},
+    proto,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WlExplainQuery {
+    pub interface: String,
+    pub request: String,
+    /// Version of the object; [None] leaves `min_version` and `max_version` open
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// Interfaces of the objects the object was created through; [None]
+    /// leaves `descendant_of` open
+    #[serde(default)]
+    pub ancestors: Option<Vec<String>>,
+    /// app_id of the client. No rule condition depends on it, but `ask_cmd`
+    /// and `notify_cmd` get to see it.
+    #[serde(default)]
+    pub app_id: Option<String>,
+    /// Flatpak app ID of the client, or `""` if it isn't a Flatpak app;
+    /// [None] leaves `flatpak_app_id` open
+    #[serde(default)]
+    pub flatpak_app_id: Option<String>,
+    /// LSM security context of the client, or `""` if it has none; [None]
+    /// leaves `security_context` open
+    #[serde(default)]
+    pub security_context: Option<String>,
+    /// Whether the client is XWayland; [None] leaves `xwayland` open
+    #[serde(default)]
+    pub xwayland: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WlRuleMatch {
+    Matches,
+    /// Depends on what the query leaves open about the object
+    MayMatch,
+    /// A condition of the rule doesn't hold
+    Skipped,
+    /// Disabled through the control socket
+    Disabled,
+    /// An earlier rule matches
+    Shadowed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlExplainedRule {
+    /// Interface key of the rule in [crate::config::WlFilter::requests], and its index there
+    pub interface: String,
+    pub index: usize,
+    pub action: String,
+    pub desc: Option<String>,
+    /// All conditions of the rule, as written in the config
+    pub conditions: Vec<String>,
+    pub result: WlRuleMatch,
+    /// Why the rule does, may or doesn't match
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlExplanation {
+    pub interface: String,
+    pub request: String,
+    /// Every rule mentioning the request, in order of precedence
+    pub rules: Vec<WlExplainedRule>,
+    /// What happens to the request, unless a rule that only
+    /// [WlRuleMatch::MayMatch]es before the matching one applies
+    pub verdict: String,
+    pub notes: Vec<String>,
+}
+
+fn describe_action(config: &Config, rule: &WlFilterRequest) -> String {
+    let blocked = match rule.block_type {
+        WlFilterRequestBlockType::Ignore => "blocked (ignored)".to_string(),
+        WlFilterRequestBlockType::Reject => {
+            format!("blocked (rejected with error {})", rule.error_code)
+        }
+    };
+
+    match rule.action {
+        WlFilterRequestAction::Block => blocked,
+        WlFilterRequestAction::Ask if config.exec.ask_cmd.is_some() => {
+            format!("allowed if ask_cmd succeeds, {} otherwise", blocked)
+        }
+        WlFilterRequestAction::Ask => format!("{}, as ask_cmd is not set", blocked),
+        WlFilterRequestAction::Notify if config.exec.notify_cmd.is_some() => {
+            "allowed, after running notify_cmd".to_string()
+        }
+        WlFilterRequestAction::Notify => "allowed, notify_cmd is not set".to_string(),
+    }
+}
+
+fn describe_conditions(interface: &str, rule: &WlFilterRequest) -> Vec<String> {
+    let mut requests: Vec<_> = rule.requests.iter().map(|r| format!("{:?}", r)).collect();
+    requests.sort();
+
+    let mut conditions = vec![
+        format!("interface = {:?}", interface),
+        format!("requests = [{}]", requests.join(", ")),
+    ];
+    if let Some(min) = rule.min_version {
+        conditions.push(format!("min_version = {}", min));
+    }
+    if let Some(max) = rule.max_version {
+        conditions.push(format!("max_version = {}", max));
+    }
+    if let Some(ref ancestor) = rule.descendant_of {
+        conditions.push(format!("descendant_of = {:?}", ancestor));
+    }
+    if let Some(ref app_id) = rule.flatpak_app_id {
+        conditions.push(format!("flatpak_app_id = {:?}", app_id));
+    }
+    if let Some(ref ctx) = rule.security_context {
+        conditions.push(format!("security_context = {:?}", ctx));
+    }
+    if let Some(xwayland) = rule.xwayland {
+        conditions.push(format!("xwayland = {}", xwayland));
+    }
+    conditions
+}
+
+/// Check the conditions of `rule` which depend on the object or the client,
+/// returning whether they all hold and why
+fn check_object(query: &WlExplainQuery, rule: &WlFilterRequest) -> (WlRuleMatch, Vec<String>) {
+    let mut result = WlRuleMatch::Matches;
+    let mut reasons = vec![];
+
+    if rule.min_version.is_some() || rule.max_version.is_some() {
+        match query.version {
+            Some(version) if rule.matches_version(version) => {
+                reasons.push(format!("version {} is in range", version))
+            }
+            Some(version) => {
+                return (
+                    WlRuleMatch::Skipped,
+                    vec![format!("version {} is out of range", version)],
+                );
+            }
+            None => {
+                result = WlRuleMatch::MayMatch;
+                reasons.push("depends on the version of the object".to_string());
+            }
+        }
+    }
+
+    if let Some(ref ancestor) = rule.descendant_of {
+        match query.ancestors {
+            Some(ref ancestors) if ancestors.contains(ancestor) => {
+                reasons.push(format!("created through {}", ancestor))
+            }
+            Some(_) => {
+                return (
+                    WlRuleMatch::Skipped,
+                    vec![format!("not created through {}", ancestor)],
+                );
+            }
+            None => {
+                result = WlRuleMatch::MayMatch;
+                reasons.push(format!(
+                    "depends on whether the object descends from {}",
+                    ancestor
+                ));
+            }
+        }
+    }
+
+    if let Some(ref pattern) = rule.flatpak_app_id {
+        match query.flatpak_app_id.as_deref() {
+            Some("") => {
+                return (
+                    WlRuleMatch::Skipped,
+                    vec!["the client is not a Flatpak app".to_string()],
+                );
+            }
+            Some(app_id) if rule.matches_flatpak_app_id(Some(app_id)) => {
+                reasons.push(format!("Flatpak app {} matches {:?}", app_id, pattern))
+            }
+            Some(app_id) => {
+                return (
+                    WlRuleMatch::Skipped,
+                    vec![format!(
+                        "Flatpak app {} doesn't match {:?}",
+                        app_id, pattern
+                    )],
+                );
+            }
+            None => {
+                result = WlRuleMatch::MayMatch;
+                reasons.push("depends on the Flatpak app ID of the client".to_string());
+            }
+        }
+    }
+
+    if let Some(ref pattern) = rule.security_context {
+        match query.security_context.as_deref() {
+            Some("") => {
+                return (
+                    WlRuleMatch::Skipped,
+                    vec!["the client has no security context".to_string()],
+                );
+            }
+            Some(ctx) if rule.matches_security_context(Some(ctx)) => {
+                reasons.push(format!("security context {} matches {:?}", ctx, pattern))
+            }
+            Some(ctx) => {
+                return (
+                    WlRuleMatch::Skipped,
+                    vec![format!(
+                        "security context {} doesn't match {:?}",
+                        ctx, pattern
+                    )],
+                );
+            }
+            None => {
+                result = WlRuleMatch::MayMatch;
+                reasons.push("depends on the security context of the client".to_string());
+            }
+        }
+    }
+
+    if let Some(xwayland) = rule.xwayland {
+        let client = |x| match x {
+            true => "XWayland",
+            false => "a native Wayland client",
+        };
+        match query.xwayland {
+            Some(x) if rule.matches_xwayland(x) => {
+                reasons.push(format!("the client is {}", client(x)))
+            }
+            Some(x) => {
+                return (
+                    WlRuleMatch::Skipped,
+                    vec![format!("the client is {}", client(x))],
+                );
+            }
+            None => {
+                result = WlRuleMatch::MayMatch;
+                reasons.push(format!(
+                    "depends on whether the client is {}",
+                    client(xwayland)
+                ));
+            }
+        }
+    }
+
+    (result, reasons)
+}
+
+/// Explain what `config` does with the request in `query`. `is_rule_enabled`
+/// tells which rules have been disabled at runtime, by interface key and index.
+pub fn explain(
+    config: &Config,
+    query: &WlExplainQuery,
+    is_rule_enabled: impl Fn(&str, usize) -> bool,
+) -> Result<WlExplanation, String> {
+    let obj_type = proto::lookup_known_object_type(&query.interface)
+        .ok_or_else(|| format!("unknown interface {}", query.interface))?;
+    let opcode = proto::known_requests()
+        .find(|(t, _, parser)| *t == obj_type && parser.msg_name() == query.request)
+        .map(|(_, opcode, _)| opcode)
+        .ok_or_else(|| format!("{} has no request {}", query.interface, query.request))?;
+
+    let mut verdict = None;
+    let rules: Vec<_> = config
+        .filter
+        .candidates(obj_type, opcode)
+        .iter()
+        .map(|(interface, index)| {
+            let rule = &config.filter.requests[interface][*index];
+            let (result, reasons) = if verdict.is_some() {
+                (
+                    WlRuleMatch::Shadowed,
+                    vec!["an earlier rule matches".to_string()],
+                )
+            } else if !is_rule_enabled(interface, *index) {
+                (
+                    WlRuleMatch::Disabled,
+                    vec!["disabled through the control socket".to_string()],
+                )
+            } else {
+                let (result, mut reasons) = check_object(query, rule);
+                if result != WlRuleMatch::Skipped {
+                    let request = if rule.requests.contains(&query.request) {
+                        &query.request
+                    } else {
+                        "*"
+                    };
+                    reasons.insert(0, format!("requests includes {:?}", request));
+                }
+                if result == WlRuleMatch::Matches {
+                    verdict = Some(describe_action(config, rule));
+                }
+                (result, reasons)
+            };
+
+            WlExplainedRule {
+                interface: interface.clone(),
+                index: *index,
+                action: format!("{:?}", rule.action).to_lowercase(),
+                desc: rule.desc.clone(),
+                conditions: describe_conditions(interface, rule),
+                result,
+                reason: reasons.join("; "),
+            }
+        })
+        .collect();
+
+    let verdict = verdict.unwrap_or_else(|| "allowed, no rule matches".to_string());
+
+    let mut notes = vec![];
+    if rules.iter().any(|r| r.result == WlRuleMatch::MayMatch) {
+        notes.push("Rules that may match come first, and decide instead if they apply".to_string());
+    }
+    if config.filter.dry_run && verdict.starts_with("blocked") {
+        notes.push("dry_run is set: blocked requests are only logged, then allowed".to_string());
+    }
+    if let Some(ref app_id) = query.app_id {
+        notes.push(format!(
+            "No rule depends on the app_id; ask_cmd and notify_cmd get {:?} as WL_MITM_LAST_TOPLEVEL_APP_ID",
+            app_id
+        ));
+    }
+
+    Ok(WlExplanation {
+        interface: query.interface.clone(),
+        request: query.request.clone(),
+        rules,
+        verdict,
+        notes,
+    })
+}
+
+impl fmt::Display for WlRuleMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WlRuleMatch::Matches => "matches",
+            WlRuleMatch::MayMatch => "may match",
+            WlRuleMatch::Skipped => "skipped",
+            WlRuleMatch::Disabled => "disabled",
+            WlRuleMatch::Shadowed => "shadowed",
+        })
+    }
+}
+
+impl fmt::Display for WlExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}.{}: {}", self.interface, self.request, self.verdict)?;
+
+        for rule in &self.rules {
+            writeln!(
+                f,
+                "  {}[{}] {} ({}): {}",
+                rule.interface, rule.index, rule.action, rule.result, rule.reason
+            )?;
+            writeln!(f, "    {}", rule.conditions.join(", "))?;
+            if let Some(ref desc) = rule.desc {
+                writeln!(f, "    desc = {:?}", desc)?;
+            }
+        }
+
+        for note in &self.notes {
+            writeln!(f, "note: {}", note)?;
+        }
+        Ok(())
+    }
+}
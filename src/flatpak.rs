@@ -0,0 +1,85 @@
+//! Identification of Flatpak apps
+//!
+//! Flatpak puts a `/.flatpak-info` keyfile into the root of every sandbox,
+//! describing the app and the permissions it was started with. Unlike the
+//! app_id a client sets on its toplevels, the app can't change it, so it
+//! can be trusted for policy decisions.
+
+use std::path::Path;
+
+use serde_derive::Serialize;
+
+/// What `/.flatpak-info` says about an app
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct WlFlatpakInfo {
+    /// The Flatpak app ID, e.g. `org.mozilla.firefox`
+    pub app_id: String,
+    pub runtime: Option<String>,
+    pub branch: Option<String>,
+    pub instance_id: Option<String>,
+    /// Permissions from the `[Context]` group
+    pub shared: Vec<String>,
+    pub sockets: Vec<String>,
+    pub devices: Vec<String>,
+    pub filesystems: Vec<String>,
+}
+
+impl WlFlatpakInfo {
+    /// Read the Flatpak info of process `pid`, through its root directory.
+    /// Returns [None] if it is not a Flatpak app.
+    pub fn for_pid(pid: i32) -> Option<WlFlatpakInfo> {
+        Self::read(
+            &Path::new("/proc")
+                .join(pid.to_string())
+                .join("root/.flatpak-info"),
+        )
+    }
+
+    pub fn read(path: &Path) -> Option<WlFlatpakInfo> {
+        Self::parse(&std::fs::read_to_string(path).ok()?)
+    }
+
+    /// Parse the contents of a `.flatpak-info` keyfile. Returns [None] if it
+    /// doesn't name an app.
+    pub fn parse(s: &str) -> Option<WlFlatpakInfo> {
+        let mut info = WlFlatpakInfo::default();
+        let mut group = "";
+
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                group = name;
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let list = || {
+                value
+                    .split(';')
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            };
+
+            match (group, key) {
+                ("Application", "name") => info.app_id = value.to_string(),
+                ("Application", "runtime") => info.runtime = Some(value.to_string()),
+                ("Instance", "branch") => info.branch = Some(value.to_string()),
+                ("Instance", "instance-id") => info.instance_id = Some(value.to_string()),
+                ("Context", "shared") => info.shared = list(),
+                ("Context", "sockets") => info.sockets = list(),
+                ("Context", "devices") => info.devices = list(),
+                ("Context", "filesystems") => info.filesystems = list(),
+                _ => {}
+            }
+        }
+
+        (!info.app_id.is_empty()).then_some(info)
+    }
+}
@@ -0,0 +1,32 @@
+//! Minimal shell-style glob matching used by config matchers
+
+/// Match `s` against `pattern`, where `*` matches any (possibly empty) sequence
+/// of characters and `?` matches exactly one character. There are no character
+/// classes or escapes.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    let (mut pi, mut si) = (0, 0);
+    // Position of the last `*` seen in the pattern, and where in `s` it started matching
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, si));
+            pi += 1;
+        } else if let Some((star_pi, star_si)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            pi = star_pi + 1;
+            si = star_si + 1;
+            backtrack = Some((star_pi, star_si + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|c| *c == '*')
+}
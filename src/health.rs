@@ -0,0 +1,184 @@
+//! Health and liveness reporting, for supervisors
+//!
+//! A proxy that's merely idle and one that's wedged look the same from the
+//! outside. To tell them apart, wl-mitm answers `ping` on the control socket
+//! with its [WlHealthStatus], and can periodically rewrite a heartbeat file
+//! with the same status. Both are driven by the tokio runtime that proxies
+//! connections, so a stale heartbeat or an unanswered ping means the proxy is
+//! stuck rather than just quiet.
+
+use std::{
+    io,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::{error, warn};
+
+use crate::{
+    config::Config,
+    control::{WlControlReply, WlControlRequest},
+    socket::{WlSocketAddr, WlStream},
+};
+
+/// How long an upstream may take to accept a connection before it's considered unreachable
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlUpstreamHealth {
+    pub addr: String,
+    pub reachable: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlHealthStatus {
+    /// Listening, and every upstream is reachable
+    pub ready: bool,
+    pub listening: bool,
+    pub upstreams: Vec<WlUpstreamHealth>,
+    pub uptime_secs: u64,
+    /// Connections closed because handling them panicked, see [crate::panic]
+    #[serde(default)]
+    pub conn_panics: u64,
+    /// Seconds since the Unix epoch at which this status was taken
+    pub timestamp: u64,
+}
+
+pub struct WlHealth {
+    config: Arc<Config>,
+    started: Instant,
+    listening: AtomicBool,
+    conn_panics: AtomicU64,
+}
+
+impl WlHealth {
+    pub fn new(config: Arc<Config>) -> Arc<WlHealth> {
+        Arc::new(WlHealth {
+            config,
+            started: Instant::now(),
+            listening: AtomicBool::new(false),
+            conn_panics: AtomicU64::new(0),
+        })
+    }
+
+    /// Record whether the listen socket is bound and accepting clients
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
+    /// Count a connection closed because of a panic
+    pub fn record_conn_panic(&self) {
+        self.conn_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn probe(addr: &WlSocketAddr) -> bool {
+        // The connection is dropped right away; compositors treat that as a
+        // client that went away before saying anything.
+        matches!(
+            tokio::time::timeout(HEALTH_PROBE_TIMEOUT, addr.connect()).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Take a fresh status, probing every upstream
+    pub async fn check(&self) -> WlHealthStatus {
+        let mut addrs = Vec::new();
+        for config in self.config.all_proxies() {
+            for addr in config.all_upstreams() {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+
+        let mut upstreams = Vec::new();
+        for addr in addrs {
+            upstreams.push(WlUpstreamHealth {
+                reachable: Self::probe(&addr).await,
+                addr: addr.to_string(),
+            });
+        }
+
+        let listening = self.listening.load(Ordering::Relaxed);
+        WlHealthStatus {
+            ready: listening && upstreams.iter().all(|u| u.reachable),
+            listening,
+            upstreams,
+            uptime_secs: self.started.elapsed().as_secs(),
+            conn_panics: self.conn_panics.load(Ordering::Relaxed),
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Rewrite the configured heartbeat file with a fresh status every
+    /// interval, forever. Returns immediately if no heartbeat file is configured.
+    pub async fn run_heartbeat(self: Arc<Self>) {
+        let Some(ref file) = self.config.health.heartbeat_file else {
+            return;
+        };
+        let interval = Duration::from_secs(self.config.health.heartbeat_interval.max(1));
+
+        loop {
+            let status = self.check().await;
+            if !status.ready {
+                warn!(status = ?status, "Not ready");
+            }
+
+            if let Err(e) = write_heartbeat(Path::new(file), &status).await {
+                error!(error = ?e, file = file, "Failed to write heartbeat file");
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+/// Replace the heartbeat file atomically, so readers never see a partial status
+async fn write_heartbeat(file: &Path, status: &WlHealthStatus) -> io::Result<()> {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let mut contents = serde_json::to_vec(status)?;
+    contents.push(b'\n');
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, file).await
+}
+
+/// Ping a running wl-mitm through its control socket
+pub async fn ping(control: &WlSocketAddr) -> io::Result<WlHealthStatus> {
+    let WlStream::Unix(conn) = control.connect().await? else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "control socket must be a unix socket",
+        ));
+    };
+
+    let (read, mut write) = conn.into_split();
+    let mut req = serde_json::to_vec(&WlControlRequest::Ping)?;
+    req.push(b'\n');
+    write.write_all(&req).await?;
+
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no reply to ping"))?;
+
+    match serde_json::from_str(&line)? {
+        WlControlReply::Pong(status) => Ok(status),
+        WlControlReply::Error { message } => Err(io::Error::other(message)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected reply to ping",
+        )),
+    }
+}
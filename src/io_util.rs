@@ -1,24 +1,84 @@
 use std::{
-    future::poll_fn,
+    future::{Future, poll_fn},
     io,
     ops::Deref,
-    os::fd::{FromRawFd, OwnedFd},
-    task::{Context, Poll},
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Instant,
 };
 
 use bytes::Bytes;
 use sendfd::{RecvWithFd, SendWithFd};
-use tokio::net::unix::{ReadHalf, WriteHalf};
+use tokio::net::{tcp, unix};
 
-use crate::codec::{DecoderOutcome, WlDecoder, WlRawMsg};
+use crate::{
+    codec::{DecoderOutcome, WlDecoder, WlRawMsg},
+    latency::WlLatencyHistogram,
+};
+
+/// Read half of a [crate::socket::WlStream]
+pub enum WlReadHalf<'a> {
+    Unix(unix::ReadHalf<'a>),
+    Tcp(tcp::ReadHalf<'a>),
+}
+
+impl WlReadHalf<'_> {
+    async fn readable(&self) -> io::Result<()> {
+        match self {
+            WlReadHalf::Unix(r) => r.readable().await,
+            WlReadHalf::Tcp(r) => r.readable().await,
+        }
+    }
+
+    /// Returns (read_bytes, read_fds). TCP streams never yield any fd.
+    fn try_recv_with_fd(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        match self {
+            WlReadHalf::Unix(r) => r.recv_with_fd(buf, fds),
+            WlReadHalf::Tcp(r) => r.try_read(buf).map(|n| (n, 0)),
+        }
+    }
+
+    fn can_pass_fds(&self) -> bool {
+        matches!(self, WlReadHalf::Unix(_))
+    }
+}
+
+/// Write half of a [crate::socket::WlStream]
+pub enum WlWriteHalf<'a> {
+    Unix(unix::WriteHalf<'a>),
+    Tcp(tcp::WriteHalf<'a>),
+}
+
+impl WlWriteHalf<'_> {
+    fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            WlWriteHalf::Unix(w) => w.as_ref().poll_write_ready(cx),
+            WlWriteHalf::Tcp(w) => w.as_ref().poll_write_ready(cx),
+        }
+    }
+
+    /// Callers must make sure not to pass any fd when [Self::can_pass_fds] is false;
+    /// they would be silently dropped otherwise.
+    fn try_send_with_fd(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        match self {
+            WlWriteHalf::Unix(w) => w.send_with_fd(buf, fds),
+            WlWriteHalf::Tcp(w) => w.try_write(buf),
+        }
+    }
+
+    fn can_pass_fds(&self) -> bool {
+        matches!(self, WlWriteHalf::Unix(_))
+    }
+}
 
 pub struct WlMsgReader<'a> {
-    ingress: ReadHalf<'a>,
+    ingress: WlReadHalf<'a>,
     decoder: WlDecoder,
 }
 
 impl<'a> WlMsgReader<'a> {
-    pub fn new(ingress: ReadHalf<'a>) -> Self {
+    pub fn new(ingress: WlReadHalf<'a>) -> Self {
         WlMsgReader {
             ingress,
             decoder: WlDecoder::new(),
@@ -29,6 +89,11 @@ impl<'a> WlMsgReader<'a> {
         self.decoder.return_unused_fds(msg, num_consumed);
     }
 
+    /// Whether the underlying stream is able to carry fds alongside messages
+    pub fn can_pass_fds(&self) -> bool {
+        self.ingress.can_pass_fds()
+    }
+
     pub async fn read(&mut self) -> io::Result<DecoderOutcome> {
         if let Some(DecoderOutcome::Decoded(msg)) = self.decoder.decode_buf() {
             return Ok(DecoderOutcome::Decoded(msg));
@@ -40,12 +105,12 @@ impl<'a> WlMsgReader<'a> {
             let mut tmp_buf = [0u8; 128];
             let mut tmp_fds = [0i32; 128];
 
-            let (read_bytes, read_fds) = match self.ingress.recv_with_fd(&mut tmp_buf, &mut tmp_fds)
-            {
-                Ok(res) => res,
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                Err(e) => return Err(e),
-            };
+            let (read_bytes, read_fds) =
+                match self.ingress.try_recv_with_fd(&mut tmp_buf, &mut tmp_fds) {
+                    Ok(res) => res,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                };
 
             let mut fd_vec: Vec<OwnedFd> = Vec::with_capacity(read_fds);
             for fd in &tmp_fds[0..read_fds] {
@@ -59,22 +124,41 @@ impl<'a> WlMsgReader<'a> {
     }
 }
 
+/// A message in the queue of a [WlMsgWriter]
+struct WlQueuedMsg {
+    msg: WlRawMsg,
+    /// When it was received, if it is to be timed
+    since: Option<Instant>,
+    /// Not to be written before then
+    not_before: Option<Instant>,
+}
+
 pub struct WlMsgWriter<'a> {
-    egress: WriteHalf<'a>,
-    write_queue: Vec<WlRawMsg>,
+    egress: WlWriteHalf<'a>,
+    write_queue: Vec<WlQueuedMsg>,
+    /// Number of queued messages with [WlQueuedMsg::not_before] set
+    held: usize,
+    /// Armed while the message at the head of the queue is held back
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
     cur_write_buf: Option<Bytes>,
     cur_write_buf_pos: usize,
     cur_write_fds: Option<Box<[OwnedFd]>>,
+    cur_write_since: Option<Instant>,
+    latency: WlLatencyHistogram,
 }
 
 impl<'a> WlMsgWriter<'a> {
-    pub fn new(egress: WriteHalf<'a>) -> Self {
+    pub fn new(egress: WlWriteHalf<'a>) -> Self {
         WlMsgWriter {
             egress,
             write_queue: Vec::new(),
+            held: 0,
+            delay: None,
             cur_write_buf: None,
             cur_write_buf_pos: 0,
             cur_write_fds: None,
+            cur_write_since: None,
+            latency: WlLatencyHistogram::default(),
         }
     }
 
@@ -89,22 +173,24 @@ impl<'a> WlMsgWriter<'a> {
         // If we don't have a partially written buffer, try remove one from the write queue
         if self.cur_write_buf.is_none() && !self.write_queue.is_empty() {
             // Don't use pop(), wl messages need to be in order!!
-            let (buf, fds) = self.write_queue.remove(0).into_parts();
+            let WlQueuedMsg { msg, since, .. } = self.write_queue.remove(0);
+            let (buf, fds) = msg.into_parts();
 
             self.cur_write_buf = Some(buf);
             self.cur_write_buf_pos = 0;
             self.cur_write_fds = Some(fds);
+            self.cur_write_since = since;
         }
 
         if let Some(buf) = self.cur_write_buf.take() {
             let send_res = if let Some(fds) = self.cur_write_fds.take() {
                 self.egress
-                    .send_with_fd(&buf[self.cur_write_buf_pos..], unsafe {
+                    .try_send_with_fd(&buf[self.cur_write_buf_pos..], unsafe {
                         std::mem::transmute(fds.deref())
                     })
             } else {
                 self.egress
-                    .send_with_fd(&buf[self.cur_write_buf_pos..], &[])
+                    .try_send_with_fd(&buf[self.cur_write_buf_pos..], &[])
             };
 
             if let Ok(written) = send_res {
@@ -113,6 +199,8 @@ impl<'a> WlMsgWriter<'a> {
                 if self.cur_write_buf_pos + written < buf.len() {
                     self.cur_write_buf = Some(buf);
                     self.cur_write_buf_pos += written;
+                } else if let Some(since) = self.cur_write_since.take() {
+                    self.latency.record(since.elapsed());
                 }
             }
 
@@ -129,7 +217,24 @@ impl<'a> WlMsgWriter<'a> {
             return Poll::Pending;
         }
 
-        while self.egress.as_ref().poll_write_ready(cx).is_ready() {
+        // Nothing after a held message may overtake it
+        if self.cur_write_buf.is_none()
+            && let Some(not_before) = self.write_queue[0].not_before
+        {
+            let delay = self
+                .delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(not_before.into())));
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+            self.held -= 1;
+
+            // Time held back on purpose isn't added by the proxy as such
+            let head = &mut self.write_queue[0];
+            head.not_before = None;
+            head.since = head.since.map(|since| since.max(not_before));
+        }
+
+        while self.egress.poll_write_ready(cx).is_ready() {
             match self.try_poll_write() {
                 Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => continue,
                 Poll::Ready(res) => return Poll::Ready(res),
@@ -140,9 +245,60 @@ impl<'a> WlMsgWriter<'a> {
         Poll::Pending
     }
 
+    /// Whether the underlying stream is able to carry fds alongside messages
+    pub fn can_pass_fds(&self) -> bool {
+        self.egress.can_pass_fds()
+    }
+
+    /// Whether there are queued or partially written messages left to flush
+    pub fn has_pending_writes(&self) -> bool {
+        self.can_write()
+    }
+
+    /// Number of messages queued up or partially written
+    pub fn pending_writes(&self) -> usize {
+        self.write_queue.len() + self.cur_write_buf.is_some() as usize
+    }
+
+    /// Whether a queued message is being held back, see [Self::queue_write_after]
+    pub fn is_holding(&self) -> bool {
+        self.held > 0
+    }
+
     /// Queue a message up for writing, but doesn't do anything right away.
     pub fn queue_write(&mut self, msg: WlRawMsg) {
-        self.write_queue.push(msg);
+        self.write_queue.push(WlQueuedMsg {
+            msg,
+            since: None,
+            not_before: None,
+        });
+    }
+
+    /// Like [Self::queue_write], and time the message from `since` until it
+    /// is written out in full, see [Self::latency]
+    pub fn queue_write_since(&mut self, msg: WlRawMsg, since: Instant) {
+        self.write_queue.push(WlQueuedMsg {
+            msg,
+            since: Some(since),
+            not_before: None,
+        });
+    }
+
+    /// Like [Self::queue_write_since], but hold the message, and everything
+    /// queued after it, back until `not_before`. Time spent held back isn't
+    /// counted towards [Self::latency].
+    pub fn queue_write_after(&mut self, msg: WlRawMsg, since: Instant, not_before: Instant) {
+        self.held += 1;
+        self.write_queue.push(WlQueuedMsg {
+            msg,
+            since: Some(since),
+            not_before: Some(not_before),
+        });
+    }
+
+    /// How long messages queued with [Self::queue_write_since] took
+    pub fn latency(&self) -> &WlLatencyHistogram {
+        &self.latency
     }
 
     /// Try to make progress by flushing some of the queued up messages into the stream.
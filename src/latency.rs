@@ -0,0 +1,114 @@
+//! How long messages spend inside wl-mitm, from being decoded to being
+//! written out in full to the other side
+//!
+//! This covers everything the proxy adds to a message's trip: decoding,
+//! handlers and filter rules, waiting on `ask_cmd`, and the write queue.
+//! Every connection keeps a [WlLatencyHistogram] per direction, which is
+//! summarized in dumps, in statistics exports and when the connection closes.
+//! Messages that are not forwarded are not counted, and neither is the time
+//! capture requests are held back for, see [crate::pacing].
+
+use std::time::Duration;
+
+use serde_derive::Serialize;
+
+/// Buckets per power of two, above [LINEAR_BUCKETS]. Percentiles are off by
+/// at most 1/8 of the value this way.
+const SUB_BUCKETS: u64 = 8;
+/// Values below this many microseconds get a bucket each
+const LINEAR_BUCKETS: u64 = SUB_BUCKETS;
+/// Longer latencies (about 12 days) all share the last bucket
+const MAX_BUCKETED_US: u64 = 1 << 40;
+
+/// A log-linear histogram of durations at microsecond resolution
+#[derive(Default, Clone, Debug)]
+pub struct WlLatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max_us: u64,
+}
+
+fn bucket_of(us: u64) -> usize {
+    if us < LINEAR_BUCKETS {
+        return us as usize;
+    }
+    // At least 3, since us >= 8
+    let exp = 63 - us.leading_zeros() as u64;
+    ((exp - 2) * SUB_BUCKETS + ((us >> (exp - 3)) & (SUB_BUCKETS - 1))) as usize
+}
+
+/// The largest value in `bucket`
+fn bucket_upper_bound(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < LINEAR_BUCKETS {
+        return bucket;
+    }
+    let exp = bucket / SUB_BUCKETS + 2;
+    let sub = bucket % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub + 1) << (exp - 3)) - 1
+}
+
+impl WlLatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = bucket_of(us.min(MAX_BUCKETED_US));
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The latency `p` (between 0 and 1) of messages took at most, in
+    /// microseconds. Returns 0 if nothing has been recorded.
+    pub fn percentile_us(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let rank = ((self.count as f64 * p).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        // The last bucket holds the maximum, which is known exactly
+        for (bucket, n) in self.buckets[..self.buckets.len() - 1].iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return bucket_upper_bound(bucket).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    pub fn summary(&self) -> WlLatencySummary {
+        WlLatencySummary {
+            count: self.count,
+            p50_us: self.percentile_us(0.5),
+            p90_us: self.percentile_us(0.9),
+            p99_us: self.percentile_us(0.99),
+            max_us: self.max_us,
+        }
+    }
+}
+
+/// Percentiles of a [WlLatencyHistogram], in microseconds
+#[derive(Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct WlLatencySummary {
+    pub count: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Latency added to one connection's messages in each direction
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct WlConnLatency {
+    /// Requests, from the client to the server
+    pub c2s: WlLatencySummary,
+    /// Events, from the server to the client
+    pub s2c: WlLatencySummary,
+}
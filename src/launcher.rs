@@ -0,0 +1,262 @@
+//! `wl-mitm exec`: run a sandboxed app behind a wl-mitm socket of its own
+//!
+//! A socket is created just for the app, and the sandbox command line is
+//! extended such that the app only ever sees that socket:
+//!
+//! - bubblewrap gets the socket bind-mounted into the sandbox, with
+//!   `WAYLAND_DISPLAY` pointing to it, and `--die-with-parent`
+//! - firejail gets the socket whitelisted and the upstream socket blacklisted
+//! - anything else only gets `WAYLAND_DISPLAY` set, and is not isolated at all
+//!
+//! The socket is removed once the app exits, and wl-mitm exits along with it.
+//! With `session.terminate_on_end`, the app is killed once the session ends.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Arc,
+};
+
+use tracing::{info, warn};
+
+use crate::{Proxy, config::Config, logind::WlSession, socket::WlSocketAddr};
+
+/// Name of the socket inside a bubblewrap sandbox, relative to `$XDG_RUNTIME_DIR`
+const BWRAP_SOCKET_NAME: &str = "wayland-0";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WlSandboxLauncher {
+    Bwrap,
+    Firejail,
+    /// Not a sandbox we know how to confine
+    Other,
+}
+
+impl WlSandboxLauncher {
+    /// Tell which sandbox `program` starts, by its file name
+    pub fn detect(program: &str) -> WlSandboxLauncher {
+        match Path::new(program).file_name().and_then(|n| n.to_str()) {
+            Some("bwrap") => WlSandboxLauncher::Bwrap,
+            Some("firejail") => WlSandboxLauncher::Firejail,
+            _ => WlSandboxLauncher::Other,
+        }
+    }
+}
+
+fn runtime_dir() -> PathBuf {
+    std::env::var("XDG_RUNTIME_DIR")
+        .unwrap_or_else(|_| format!("/run/user/{}", nix::unistd::getuid()))
+        .into()
+}
+
+/// Options of bubblewrap taking more than the option itself, by how many
+/// arguments they take. Anything else is taken to be a flag.
+const BWRAP_OPTION_ARGS: &[(&str, usize)] = &[
+    ("--args", 1),
+    ("--argv0", 1),
+    ("--userns", 1),
+    ("--userns2", 1),
+    ("--pidns", 1),
+    ("--uid", 1),
+    ("--gid", 1),
+    ("--hostname", 1),
+    ("--chdir", 1),
+    ("--unsetenv", 1),
+    ("--lock-file", 1),
+    ("--sync-fd", 1),
+    ("--remount-ro", 1),
+    ("--exec-label", 1),
+    ("--file-label", 1),
+    ("--proc", 1),
+    ("--dev", 1),
+    ("--tmpfs", 1),
+    ("--mqueue", 1),
+    ("--dir", 1),
+    ("--seccomp", 1),
+    ("--add-seccomp-fd", 1),
+    ("--block-fd", 1),
+    ("--userns-block-fd", 1),
+    ("--info-fd", 1),
+    ("--json-status-fd", 1),
+    ("--cap-add", 1),
+    ("--cap-drop", 1),
+    ("--perms", 1),
+    ("--size", 1),
+    ("--overlay-src", 1),
+    ("--tmp-overlay", 1),
+    ("--ro-overlay", 1),
+    ("--setenv", 2),
+    ("--bind", 2),
+    ("--bind-try", 2),
+    ("--dev-bind", 2),
+    ("--dev-bind-try", 2),
+    ("--ro-bind", 2),
+    ("--ro-bind-try", 2),
+    ("--bind-fd", 2),
+    ("--ro-bind-fd", 2),
+    ("--file", 2),
+    ("--bind-data", 2),
+    ("--ro-bind-data", 2),
+    ("--symlink", 2),
+    ("--chmod", 2),
+    ("--overlay", 3),
+];
+
+/// Where in the arguments of `launcher` (without the program itself) its
+/// options end, i.e. the index of `--` or the sandboxed command
+fn options_end(launcher: WlSandboxLauncher, args: &[String]) -> usize {
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if arg == "--" || !arg.starts_with('-') {
+            break;
+        }
+        i += 1;
+        if launcher == WlSandboxLauncher::Bwrap {
+            i += BWRAP_OPTION_ARGS
+                .iter()
+                .find(|(option, _)| option == arg)
+                .map_or(0, |(_, n)| *n);
+        }
+    }
+    i.min(args.len())
+}
+
+/// The socket for one `wl-mitm exec` of `profile`
+pub fn exec_socket_path(profile: &str) -> PathBuf {
+    runtime_dir().join(format!("wl-mitm-{}-{}", profile, std::process::id()))
+}
+
+/// `command` with what it needs to use `socket`, and only `socket`, as its
+/// Wayland display. Returns the arguments along with environment variables
+/// to set. Sandbox options are added after those in `command`, so that none
+/// of those can hide or replace the socket.
+pub fn sandbox_command(
+    command: &[String],
+    socket: &Path,
+    upstream: &WlSocketAddr,
+) -> (Vec<String>, Vec<(String, String)>) {
+    let Some((program, args)) = command.split_first() else {
+        return (vec![], vec![]);
+    };
+    let socket_str = socket.to_string_lossy().into_owned();
+
+    let launcher = WlSandboxLauncher::detect(program);
+    let injected: Vec<String> = match launcher {
+        WlSandboxLauncher::Bwrap => {
+            let inner = runtime_dir().join(BWRAP_SOCKET_NAME);
+            let inner = inner.to_string_lossy().into_owned();
+            vec![
+                "--die-with-parent".into(),
+                "--bind".into(),
+                socket_str,
+                inner.clone(),
+                "--setenv".into(),
+                "WAYLAND_DISPLAY".into(),
+                inner,
+            ]
+        }
+        WlSandboxLauncher::Firejail => {
+            let mut injected = vec![
+                format!("--whitelist={}", socket_str),
+                format!("--env=WAYLAND_DISPLAY={}", socket_str),
+            ];
+            if let WlSocketAddr::Path(upstream) = upstream {
+                injected.push(format!("--blacklist={}", upstream.display()));
+            }
+            injected
+        }
+        WlSandboxLauncher::Other => {
+            return (
+                command.to_vec(),
+                vec![("WAYLAND_DISPLAY".into(), socket_str)],
+            );
+        }
+    };
+
+    let (options, rest) = args.split_at(options_end(launcher, args));
+    let args = std::iter::once(program.clone())
+        .chain(options.iter().cloned())
+        .chain(injected)
+        .chain(rest.iter().cloned())
+        .collect();
+    (args, vec![])
+}
+
+/// Warn about arguments likely to expose the upstream socket in the sandbox
+fn check_exposure(command: &[String], upstream: &WlSocketAddr) {
+    let runtime_dir = runtime_dir();
+    let exposed = command.iter().find(|arg| {
+        let path = Path::new(arg.rsplit('=').next().unwrap_or(arg));
+        path == runtime_dir || matches!(upstream, WlSocketAddr::Path(p) if path == p)
+    });
+
+    if let Some(arg) = exposed {
+        warn!(
+            arg = arg,
+            "The sandbox may get access to the upstream socket, bypassing wl-mitm"
+        );
+    }
+}
+
+/// Serve a socket of its own to `command`, run it, and clean up after it.
+/// Returns how the command exited.
+pub async fn run(config: Arc<Config>, profile: &str, command: &[String]) -> io::Result<ExitStatus> {
+    if command.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no command to run",
+        ));
+    }
+
+    let socket = exec_socket_path(profile);
+    let addr = WlSocketAddr::Path(socket.clone());
+    let upstream = config.socket.upstream_socket_addr();
+    let mut proxy = Proxy::builder().config(config.clone());
+    if let Some(session) = WlSession::new(&config, None) {
+        tokio::spawn(session.clone().run_watcher());
+        proxy = proxy.session(session);
+    }
+    let proxy = proxy.build()?;
+
+    let lock = addr.claim(false).await?;
+    let listener = addr.bind().await?;
+
+    let (args, env) = sandbox_command(command, &socket, &upstream);
+    if WlSandboxLauncher::detect(&args[0]) == WlSandboxLauncher::Other {
+        warn!(
+            program = args[0],
+            "Not a sandbox wl-mitm knows; the app could still reach the upstream socket"
+        );
+    }
+    check_exposure(command, &upstream);
+
+    let res = async {
+        addr.apply_permissions(&config.socket)?;
+
+        info!(socket = ?socket, command = ?args, "Starting sandboxed app");
+        let mut child = tokio::process::Command::new(&args[0])
+            .args(&args[1..])
+            .envs(env)
+            .kill_on_drop(true)
+            .spawn()?;
+
+        tokio::select! {
+            status = child.wait() => status,
+            res = proxy.serve(listener) => {
+                // Serving only stops on errors or once the session ends, and
+                // the app is of no use without it
+                child.kill().await.ok();
+                res?;
+                child.wait().await
+            }
+        }
+    }
+    .await;
+
+    // The socket only lives as long as the app
+    addr.remove();
+    drop(lock);
+
+    res
+}
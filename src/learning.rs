@@ -0,0 +1,330 @@
+//! Per-app usage profiles, learned from what apps do and optionally enforced
+//!
+//! Like AppArmor's complain and enforce modes: while an app is being learned,
+//! every global it binds and every request it sends is added to its profile,
+//! kept as `<app>.json` under `learning.dir`. Once the app matches
+//! `learning.enforce`, the profile stops growing and anything outside of it is
+//! filtered instead.
+//!
+//! Apps are told apart by what they can't choose themselves, see
+//! [app_identity], rather than the app_id they set on their toplevels, which
+//! would let an app pass for any other. A client that can't be identified
+//! isn't learned, and while any app is enforced, everything it does is
+//! filtered.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::{config::Config, glob::glob_match, peer::WlPeerInfo};
+
+/// The app `peer` is, as profiles are kept under: its Flatpak app ID, or else
+/// the path of its executable. Returns [None] if neither is known.
+pub fn app_identity(peer: &WlPeerInfo) -> Option<String> {
+    match peer.flatpak {
+        Some(ref flatpak) => Some(flatpak.app_id.clone()),
+        None => peer
+            .exe
+            .as_ref()
+            .map(|exe| exe.to_string_lossy().into_owned()),
+    }
+}
+
+/// Everything an app is known to use
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct WlAppProfile {
+    /// Interfaces of the globals bound
+    #[serde(default)]
+    pub globals: BTreeSet<String>,
+    /// Requests sent, by interface of the object they were sent on
+    #[serde(default)]
+    pub requests: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl WlAppProfile {
+    pub fn has_global(&self, interface: &str) -> bool {
+        self.globals.contains(interface)
+    }
+
+    pub fn has_request(&self, interface: &str, request: &str) -> bool {
+        self.requests
+            .get(interface)
+            .is_some_and(|r| r.contains(request))
+    }
+
+    /// Add everything in `other`. Returns whether anything was new.
+    fn merge(&mut self, other: &WlAppProfile) -> bool {
+        let mut changed = false;
+        for global in &other.globals {
+            changed |= self.globals.insert(global.clone());
+        }
+        for (interface, requests) in &other.requests {
+            let known = self.requests.entry(interface.clone()).or_default();
+            for request in requests {
+                changed |= known.insert(request.clone());
+            }
+        }
+        changed
+    }
+}
+
+struct WlStoredProfile {
+    profile: WlAppProfile,
+    /// Whether the profile changed since it was last written
+    dirty: bool,
+}
+
+/// The profiles of all apps, shared by all connections
+pub struct WlLearning {
+    dir: PathBuf,
+    enforce: Vec<String>,
+    profiles: Mutex<HashMap<String, WlStoredProfile>>,
+}
+
+impl WlLearning {
+    /// Returns [None] if learning is not enabled
+    pub fn new(config: &Config) -> Option<Arc<WlLearning>> {
+        let dir = config.learning.dir.as_ref()?;
+        Some(Arc::new(WlLearning {
+            dir: dir.into(),
+            enforce: config.learning.enforce.clone(),
+            profiles: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Whether the profile of `app`, see [app_identity], is enforced
+    pub fn is_enforced(&self, app: &str) -> bool {
+        self.enforce.iter().any(|pat| glob_match(pat, app))
+    }
+
+    /// Whether any profile is enforced
+    pub fn is_enforcing(&self) -> bool {
+        !self.enforce.is_empty()
+    }
+
+    /// The file the profile of `app` is kept in. Anything but alphanumerics,
+    /// `-`, `_` and non-leading `.` is replaced, so an app can't escape the directory.
+    pub fn profile_path(&self, app: &str) -> PathBuf {
+        let name: String = app
+            .chars()
+            .enumerate()
+            .map(|(i, c)| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                '.' if i > 0 => c,
+                _ => '_',
+            })
+            .collect();
+        self.dir.join(format!("{}.json", name))
+    }
+
+    fn load(path: &Path) -> io::Result<WlAppProfile> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Run `f` on the profile of `app`, loading it from disk first if needed
+    fn with_profile<T>(&self, app: &str, f: impl FnOnce(&mut WlStoredProfile) -> T) -> T {
+        let mut profiles = self.profiles.lock().unwrap();
+        let stored = profiles.entry(app.to_string()).or_insert_with(|| {
+            let path = self.profile_path(app);
+            let profile = Self::load(&path).unwrap_or_else(|e| {
+                error!(error = ?e, path = ?path, "Failed to load app profile; starting over");
+                Default::default()
+            });
+            WlStoredProfile {
+                profile,
+                dirty: false,
+            }
+        });
+        f(stored)
+    }
+
+    /// The current profile of `app`
+    pub fn profile(&self, app: &str) -> WlAppProfile {
+        self.with_profile(app, |stored| stored.profile.clone())
+    }
+
+    /// Add `usage` to the profile of `app`
+    pub fn learn(&self, app: &str, usage: &WlAppProfile) {
+        self.with_profile(app, |stored| {
+            if stored.profile.merge(usage) {
+                stored.dirty = true;
+            }
+        })
+    }
+
+    /// Write every profile that changed since it was last written
+    pub async fn flush(&self) -> io::Result<()> {
+        let dirty: Vec<_> = self
+            .profiles
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(_, stored)| stored.dirty)
+            .map(|(app, stored)| {
+                stored.dirty = false;
+                (app.clone(), serde_json::to_vec_pretty(&stored.profile))
+            })
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let mut res = Ok(());
+        for (app, contents) in dirty {
+            let path = self.profile_path(&app);
+            let written = async {
+                // Replace the file atomically, so it is never seen half-written
+                let mut tmp = path.as_os_str().to_owned();
+                tmp.push(".tmp");
+                tokio::fs::write(&tmp, contents?).await?;
+                tokio::fs::rename(&tmp, &path).await
+            }
+            .await;
+
+            if let Err(e) = written {
+                // Try again next time
+                self.with_profile(&app, |stored| stored.dirty = true);
+                res = Err(e);
+            } else {
+                debug!(app = app, path = ?path, "Wrote app profile");
+            }
+        }
+        res
+    }
+
+    /// Flush every `interval`, forever
+    pub async fn run_writer(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.flush().await {
+                error!(error = ?e, dir = ?self.dir, "Failed to write app profiles");
+            }
+        }
+    }
+}
+
+/// Learning and enforcement for one connection
+pub struct WlConnLearning {
+    learning: Arc<WlLearning>,
+    /// The app the client is, see [app_identity]
+    app: Option<String>,
+    /// The profile being enforced, if any
+    enforced: Option<WlAppProfile>,
+    /// What was used before the client was identified
+    pending: WlAppProfile,
+    /// What was already learned through this connection, to spare the lock
+    seen_globals: HashSet<&'static str>,
+    seen_requests: HashSet<(&'static str, &'static str)>,
+}
+
+impl WlConnLearning {
+    pub fn new(learning: Arc<WlLearning>) -> WlConnLearning {
+        WlConnLearning {
+            learning,
+            app: None,
+            enforced: None,
+            pending: Default::default(),
+            seen_globals: HashSet::new(),
+            seen_requests: HashSet::new(),
+        }
+    }
+
+    /// Attribute everything to the app `peer` is from now on. Only the first
+    /// peer that can be identified counts.
+    pub fn set_peer(&mut self, peer: &WlPeerInfo) {
+        if self.app.is_some() {
+            return;
+        }
+        let Some(app) = app_identity(peer) else {
+            match self.learning.is_enforcing() {
+                true => warn!("Client can't be identified, filtering everything it does"),
+                false => debug!("Client can't be identified, not learning its app profile"),
+            }
+            return;
+        };
+
+        let pending = std::mem::take(&mut self.pending);
+        if self.learning.is_enforced(&app) {
+            let profile = self.learning.profile(&app);
+            if profile == WlAppProfile::default() {
+                warn!(
+                    app = app,
+                    "No profile learned for app, enforcing an empty one"
+                );
+            }
+            info!(app = app, "Enforcing app profile");
+            self.enforced = Some(profile);
+        } else {
+            info!(app = app, "Learning app profile");
+            self.learning.learn(&app, &pending);
+        }
+        self.app = Some(app);
+    }
+
+    /// Whether what the client does is filtered for lack of knowing who it is
+    fn is_unidentified(&self) -> bool {
+        self.app.is_none() && self.learning.is_enforcing()
+    }
+
+    /// A client binds a global of `interface`. Returns false if that is not
+    /// in the enforced profile.
+    pub fn on_bind(&mut self, interface: &'static str) -> bool {
+        if let Some(ref profile) = self.enforced {
+            return profile.has_global(interface);
+        }
+        if self.is_unidentified() {
+            return false;
+        }
+
+        if self.seen_globals.insert(interface) {
+            let usage = WlAppProfile {
+                globals: [interface.to_string()].into(),
+                ..Default::default()
+            };
+            self.learn(usage);
+        }
+        true
+    }
+
+    /// A client sends `request` on an object of `interface`. Returns false
+    /// if that is not in the enforced profile.
+    pub fn on_request(&mut self, interface: &'static str, request: &'static str) -> bool {
+        if let Some(ref profile) = self.enforced {
+            return profile.has_request(interface, request);
+        }
+        if self.is_unidentified() {
+            return false;
+        }
+
+        if self.seen_requests.insert((interface, request)) {
+            let usage = WlAppProfile {
+                requests: [(interface.to_string(), [request.to_string()].into())].into(),
+                ..Default::default()
+            };
+            self.learn(usage);
+        }
+        true
+    }
+
+    fn learn(&mut self, usage: WlAppProfile) {
+        match self.app {
+            Some(ref app) => self.learning.learn(app, &usage),
+            None => {
+                self.pending.merge(&usage);
+            }
+        }
+    }
+}
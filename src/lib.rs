@@ -0,0 +1,61 @@
+//! The wl-mitm proxy engine, for embedding into sandbox runtimes and compositors.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use wl_mitm::{Proxy, config::Config, socket::WlSocketAddr};
+//!
+//! let config: Config = Config::parse(&std::fs::read_to_string("config.toml")?)
+//!     .map_err(std::io::Error::other)?;
+//! let listener = WlSocketAddr::parse("wayland-proxied").bind().await?;
+//!
+//! Proxy::builder().config(config).serve(listener).await
+//! # }
+//! ```
+
+pub mod alerts;
+pub mod audit;
+pub mod bench;
+pub mod catalog;
+pub mod chaos;
+pub mod codec;
+pub mod config;
+pub mod control;
+pub mod dbus;
+pub mod decode;
+pub mod dump;
+pub mod duplex;
+pub mod explain;
+pub mod flatpak;
+mod glob;
+pub mod health;
+pub mod io_util;
+pub mod latency;
+pub mod launcher;
+pub mod learning;
+pub mod logging;
+pub mod logind;
+pub mod objects;
+pub mod pacing;
+pub mod panic;
+pub mod pcapng;
+pub mod peer;
+pub mod policygen;
+#[macro_use]
+pub mod proto;
+pub mod proxy;
+pub mod recorder;
+pub mod replay;
+pub mod runtime;
+pub mod sandbox;
+pub mod socket;
+pub mod spawner;
+pub mod state;
+pub mod stats;
+pub mod trace;
+mod translate;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod validate;
+
+pub use duplex::ConnDuplex;
+pub use proxy::{Proxy, ProxyBuilder};
@@ -0,0 +1,248 @@
+//! Log setup, with per-connection verbosity that can be raised at runtime
+//!
+//! Everything a connection does happens inside its `conn` span (see
+//! [conn_span]), which carries the identity of the peer. [WlLogLevels] holds
+//! log levels overriding the default for single connections, selected by
+//! connection id or by the app_id of the client's most recent toplevel. They
+//! are adjusted through the control socket, which makes it practical to debug
+//! one noisy app on a busy session.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use tracing::{
+    Level, Metadata, Span, Subscriber,
+    field::{self, Field, Visit},
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+};
+use tracing_subscriber::{
+    Layer,
+    layer::{Context, Filter, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+use crate::{config::Config, peer::WlPeerInfo};
+
+/// Name of the span every connection runs in
+const CONN_SPAN_NAME: &str = "conn";
+
+/// Create the span a connection should run in
+pub fn conn_span(conn_id: u64, peer: &WlPeerInfo) -> Span {
+    tracing::span!(
+        Level::INFO,
+        CONN_SPAN_NAME,
+        conn_id = conn_id,
+        pid = peer.pid,
+        uid = peer.uid,
+        exe = peer.exe.as_deref().and_then(Path::to_str),
+        flatpak_app_id = peer.flatpak.as_ref().map(|f| f.app_id.as_str()),
+        app_id = field::Empty,
+        xwayland = field::Empty,
+    )
+}
+
+/// Default log level, plus overrides for single connections
+pub struct WlLogLevels {
+    default: LevelFilter,
+    conns: RwLock<HashMap<u64, LevelFilter>>,
+    app_ids: RwLock<HashMap<String, LevelFilter>>,
+    /// Fast path: skip looking up the current connection if nothing is overridden
+    has_overrides: AtomicBool,
+}
+
+impl WlLogLevels {
+    pub fn new(default: LevelFilter) -> Arc<WlLogLevels> {
+        Arc::new(WlLogLevels {
+            default,
+            conns: RwLock::new(HashMap::new()),
+            app_ids: RwLock::new(HashMap::new()),
+            has_overrides: AtomicBool::new(false),
+        })
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    fn update_has_overrides(&self) {
+        self.has_overrides.store(
+            !self.conns.read().unwrap().is_empty() || !self.app_ids.read().unwrap().is_empty(),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Override the level of one connection. [None] reverts to the default.
+    pub fn set_conn(&self, conn_id: u64, level: Option<LevelFilter>) {
+        match level {
+            Some(level) => self.conns.write().unwrap().insert(conn_id, level),
+            None => self.conns.write().unwrap().remove(&conn_id),
+        };
+        self.update_has_overrides();
+    }
+
+    /// Override the level of every connection whose latest toplevel has this
+    /// app_id. [None] reverts to the default.
+    pub fn set_app_id(&self, app_id: String, level: Option<LevelFilter>) {
+        match level {
+            Some(level) => self.app_ids.write().unwrap().insert(app_id, level),
+            None => self.app_ids.write().unwrap().remove(&app_id),
+        };
+        self.update_has_overrides();
+    }
+
+    pub fn conn_overrides(&self) -> Vec<(u64, LevelFilter)> {
+        let mut conns: Vec<_> = self
+            .conns
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        conns.sort_by_key(|(k, _)| *k);
+        conns
+    }
+
+    pub fn app_id_overrides(&self) -> Vec<(String, LevelFilter)> {
+        let mut app_ids: Vec<_> = self
+            .app_ids
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        app_ids.sort();
+        app_ids
+    }
+
+    fn level_for(&self, identity: &WlConnIdentity) -> LevelFilter {
+        let by_conn = identity
+            .conn_id
+            .and_then(|id| self.conns.read().unwrap().get(&id).copied());
+        let by_app_id = identity
+            .app_id
+            .as_ref()
+            .and_then(|id| self.app_ids.read().unwrap().get(id).copied());
+
+        // The most verbose of all that apply
+        [Some(self.default), by_conn, by_app_id]
+            .into_iter()
+            .flatten()
+            .max()
+            .unwrap()
+    }
+}
+
+/// Identity fields of a `conn` span, stored in its extensions
+#[derive(Default)]
+struct WlConnIdentity {
+    conn_id: Option<u64>,
+    app_id: Option<String>,
+}
+
+impl Visit for WlConnIdentity {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "conn_id" {
+            self.conn_id = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "app_id" {
+            self.app_id = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// A per-layer filter applying [WlLogLevels]
+pub struct WlConnLogFilter(pub Arc<WlLogLevels>);
+
+impl<S> Filter<S> for WlConnLogFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        if *meta.level() <= self.0.default {
+            return true;
+        }
+
+        if !self.0.has_overrides.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let Some(span) = cx.lookup_current() else {
+            return false;
+        };
+
+        span.scope()
+            .find_map(|s| {
+                s.extensions()
+                    .get::<WlConnIdentity>()
+                    .map(|identity| *meta.level() <= self.0.level_for(identity))
+            })
+            .unwrap_or(false)
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if *meta.level() <= self.0.default {
+            Interest::always()
+        } else {
+            // May be enabled for some connections later on
+            Interest::sometimes()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::TRACE)
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, cx: Context<'_, S>) {
+        if attrs.metadata().name() != CONN_SPAN_NAME {
+            return;
+        }
+
+        let mut identity = WlConnIdentity::default();
+        attrs.record(&mut identity);
+        if let Some(span) = cx.span(id) {
+            span.extensions_mut().insert(identity);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, cx: Context<'_, S>) {
+        let Some(span) = cx.span(id) else {
+            return;
+        };
+
+        if let Some(identity) = span.extensions_mut().get_mut::<WlConnIdentity>() {
+            values.record(identity);
+        }
+    }
+}
+
+/// Install the global tracing subscriber, honoring `log_level` from the config
+pub fn init(config: &Config) -> Arc<WlLogLevels> {
+    let default = config
+        .logging
+        .log_level
+        .as_ref()
+        .map(|level| LevelFilter::from_str(level).expect("Invalid log level"))
+        .unwrap_or(LevelFilter::INFO);
+
+    let levels = WlLogLevels::new(default);
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(WlConnLogFilter(levels.clone())))
+        .init();
+
+    levels
+}
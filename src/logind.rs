@@ -0,0 +1,306 @@
+//! Following the logind session wl-mitm runs in: its lock state and its end
+//!
+//! logind emits `Lock` and `Unlock` on a session's object whenever it is
+//! asked to lock it, e.g. through `loginctl lock-session` or on suspend, and
+//! screen lockers act on them. With `lock.enabled`, wl-mitm listens for them
+//! as well: while the session is locked, the requests under `lock.deny`,
+//! by default reading the clipboard, capturing the screen and injecting
+//! input, are filtered on every connection, including those already open.
+//! Screen lockers also report the session locked through its `LockedHint`
+//! property, which is read once connected to the bus, so that a session
+//! locked before wl-mitm started isn't taken for unlocked, and followed
+//! through `PropertiesChanged` from then on. Without a known session,
+//! there's no single `LockedHint` to follow, and only the signals count.
+//!
+//! With `session.terminate_on_end`, every connection is closed and serving
+//! stops once the session ends, so that clients left behind by a logout
+//! can't keep talking to the compositor. A session ends when logind removes
+//! it, or when it starts closing: with `KillUserProcesses=no`, a session
+//! whose user logged out lingers as long as any of its processes do, which
+//! includes exactly the clients in question. Closing is not signalled, and
+//! is polled for every `session.poll_secs` instead.
+//!
+//! Transitions are logged, and published on the control socket. Only
+//! broadcast signals are honoured, whose sender the bus vouches for.
+
+use std::{
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::Config,
+    control::WlControl,
+    dbus::{self, WlDbusConn},
+};
+
+const LOGIND_SERVICE: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// How long to wait before reconnecting to the system bus
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The session as far as wl-mitm is concerned, shared by all connections
+pub struct WlSession {
+    id: Option<String>,
+    /// Object path of the session, or [None] to follow all of them
+    path: Option<String>,
+    follow_lock: bool,
+    follow_end: bool,
+    poll_interval: Duration,
+    locked: AtomicBool,
+    ended: watch::Sender<bool>,
+    control: Option<Arc<WlControl>>,
+}
+
+impl WlSession {
+    /// Returns [None] if neither `lock.enabled` nor `session.terminate_on_end`
+    /// is set. Transitions are published to `control`, if present.
+    pub fn new(config: &Config, control: Option<Arc<WlControl>>) -> Option<Arc<WlSession>> {
+        let id = config
+            .session
+            .id
+            .clone()
+            .or_else(|| std::env::var("XDG_SESSION_ID").ok());
+
+        let mut follow_end = config.session.terminate_on_end;
+        if id.is_none() {
+            if follow_end {
+                warn!("No logind session known, connections outlive the session");
+                follow_end = false;
+            }
+            if config.lock.enabled {
+                warn!("No logind session known, following the lock state of every session");
+            }
+        }
+        if !config.lock.enabled && !follow_end {
+            return None;
+        }
+
+        Some(Arc::new(WlSession {
+            path: id.as_deref().map(session_path),
+            id,
+            follow_lock: config.lock.enabled,
+            follow_end,
+            poll_interval: Duration::from_secs(config.session.poll_secs.max(1)),
+            locked: AtomicBool::new(false),
+            ended: watch::channel(false).0,
+            control,
+        }))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Switch every connection to or from the stricter policy
+    pub fn set_locked(&self, locked: bool) {
+        if self.locked.swap(locked, Ordering::Relaxed) == locked {
+            return;
+        }
+
+        match locked {
+            true => warn!(
+                session = ?self.id,
+                "Session locked, denying `lock.deny` requests until it is unlocked"
+            ),
+            false => warn!(
+                session = ?self.id,
+                "Session unlocked, `lock.deny` requests are allowed again"
+            ),
+        }
+
+        if let Some(ref control) = self.control {
+            control.publish_session_lock(locked);
+        }
+    }
+
+    pub fn is_ended(&self) -> bool {
+        *self.ended.borrow()
+    }
+
+    /// Close every connection and stop serving, for good
+    pub fn set_ended(&self) {
+        if self.ended.send_replace(true) {
+            return;
+        }
+
+        warn!(session = ?self.id, "Session ended, closing all connections");
+        if let Some(ref control) = self.control {
+            control.publish_session_ended();
+        }
+    }
+
+    /// Resolves once the session has ended, which may be never
+    pub async fn ended(&self) {
+        self.ended.subscribe().wait_for(|ended| *ended).await.ok();
+    }
+
+    /// Follow the session on the system bus until it ends, reconnecting
+    /// whenever the connection is lost. Signals sent while disconnected are
+    /// missed; the lock state is kept as it was until then, or until
+    /// `LockedHint` is read again.
+    pub async fn run_watcher(self: Arc<Self>) {
+        while !self.is_ended() {
+            let res = match dbus::system_bus_path() {
+                Ok(bus) => self.watch(&bus).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                warn!(error = ?e, "Lost connection to logind, reconnecting");
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Follow the session through the bus at `bus` until it ends, or the
+    /// connection fails
+    pub async fn watch(&self, bus: &str) -> io::Result<()> {
+        let mut conn = WlDbusConn::connect(bus).await?;
+        let sender = format!("type='signal',sender='{}'", LOGIND_SERVICE);
+        if self.follow_lock {
+            let mut rule = format!("{},interface='{}'", sender, SESSION_INTERFACE);
+            if let Some(ref path) = self.path {
+                rule.push_str(&format!(",path='{}'", path));
+            }
+            conn.add_match(&rule).await?;
+            if let Some(ref path) = self.path {
+                conn.add_match(&format!(
+                    "{},path='{}',interface='{}',member='PropertiesChanged',arg0='{}'",
+                    sender, path, PROPERTIES_INTERFACE, SESSION_INTERFACE
+                ))
+                .await?;
+            }
+        }
+        if self.follow_end {
+            conn.add_match(&format!(
+                "{},path='{}',interface='{}',member='SessionRemoved'",
+                sender, LOGIND_PATH, MANAGER_INTERFACE
+            ))
+            .await?;
+        }
+        info!(session = ?self.id, "Following the logind session");
+
+        let mut poll = tokio::time::interval(self.poll_interval);
+        // Serial of the pending query of the session's state
+        let mut pending = None;
+        // Serials of the pending queries of `LockedHint`, oldest first
+        let mut lock_queries = Vec::new();
+        // Whether it was locked before we subscribed
+        if self.follow_lock && self.path.is_some() {
+            lock_queries.push(self.get_property(&mut conn, "LockedHint").await?);
+        }
+        loop {
+            tokio::select! {
+                _ = poll.tick(), if self.follow_end && pending.is_none() => {
+                    pending = Some(self.get_property(&mut conn, "State").await?);
+                }
+                msg = conn.next_message() => {
+                    let msg = msg?;
+                    let is_reply_to = |serial: u32| msg.is_return_of(serial) || msg.is_error_of(serial);
+                    if let Some(serial) = pending
+                        && is_reply_to(serial)
+                    {
+                        pending = None;
+                        self.on_state(&msg);
+                    } else if let Some(i) = lock_queries.iter().position(|s| is_reply_to(*s)) {
+                        lock_queries.remove(i);
+                        self.on_locked_hint(&msg);
+                    } else if msg.is_signal()
+                        && msg.destination.is_none()
+                        && self.on_signal(&msg)
+                    {
+                        lock_queries.push(self.get_property(&mut conn, "LockedHint").await?);
+                    }
+                }
+            }
+
+            if self.is_ended() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Query the session's property `name`. Returns the serial of the call.
+    async fn get_property(&self, conn: &mut WlDbusConn, name: &str) -> io::Result<u32> {
+        let path = self.path.as_deref().unwrap();
+        conn.call(
+            LOGIND_SERVICE,
+            path,
+            PROPERTIES_INTERFACE,
+            "Get",
+            &[SESSION_INTERFACE, name],
+        )
+        .await
+    }
+
+    /// Handle the reply to a query of the session's `LockedHint`
+    fn on_locked_hint(&self, msg: &dbus::WlDbusMessage) {
+        match (msg.arg0_bool, msg.error_name.as_deref()) {
+            (Some(locked), _) => self.set_locked(locked),
+            (_, Some(error)) => warn!(
+                error = error,
+                "Cannot query the logind session's lock state"
+            ),
+            (None, None) => warn!("logind session's LockedHint isn't a boolean"),
+        }
+    }
+
+    /// Handle the reply to a query of the session's `State`
+    fn on_state(&self, msg: &dbus::WlDbusMessage) {
+        match (msg.arg0.as_deref(), msg.error_name.as_deref()) {
+            (Some("closing"), _) => self.set_ended(),
+            (_, Some("org.freedesktop.login1.NoSuchSession")) => self.set_ended(),
+            (_, Some(error)) => warn!(error = error, "Cannot query the logind session"),
+            (state, None) => debug!(state = ?state, "logind session state"),
+        }
+    }
+
+    /// Returns whether `LockedHint` may have changed, and needs to be read
+    fn on_signal(&self, msg: &dbus::WlDbusMessage) -> bool {
+        match (msg.interface.as_deref(), msg.member.as_deref()) {
+            (Some(SESSION_INTERFACE), Some(member @ ("Lock" | "Unlock")))
+                if self.follow_lock && (self.path.is_none() || msg.path == self.path) =>
+            {
+                self.set_locked(member == "Lock");
+            }
+            // Rather than picking the new value out of the signal, it is read
+            // again, which also covers it being only invalidated
+            (Some(PROPERTIES_INTERFACE), Some("PropertiesChanged"))
+                if self.follow_lock
+                    && self.path.is_some()
+                    && msg.path == self.path
+                    && msg.arg0.as_deref() == Some(SESSION_INTERFACE) =>
+            {
+                return true;
+            }
+            (Some(MANAGER_INTERFACE), Some("SessionRemoved"))
+                if self.follow_end && msg.arg0 == self.id =>
+            {
+                self.set_ended();
+            }
+            (interface, member) => {
+                debug!(interface = ?interface, member = ?member, "Ignoring logind signal")
+            }
+        }
+        false
+    }
+}
+
+/// The object path logind gives the session with ID `id`
+pub fn session_path(id: &str) -> String {
+    format!(
+        "/org/freedesktop/login1/session/{}",
+        dbus::encode_path_element(id)
+    )
+}
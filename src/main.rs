@@ -1,245 +1,638 @@
-mod codec;
-mod io_util;
-mod objects;
-#[macro_use]
-mod proto;
-mod config;
-mod state;
-
-use std::{io, ops::ControlFlow, path::Path, str::FromStr, sync::Arc};
-
-use codec::DecoderOutcome;
-use config::Config;
-use io_util::{WlMsgReader, WlMsgWriter};
-use proto::{WL_DISPLAY_OBJECT_ID, WlConstructableMessage, WlDisplayErrorEvent};
-use state::{WlMitmOutcome, WlMitmState, WlMitmVerdict};
-use tokio::net::{UnixListener, UnixStream};
-use tracing::{Instrument, Level, error, info, level_filters::LevelFilter, span, warn};
-
-#[tokio::main]
-async fn main() {
-    let mut conf_file = "config.toml";
+use std::{
+    os::fd::OwnedFd,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tracing::{error, info, level_filters::LevelFilter, warn};
+use wl_mitm::{
+    Proxy, audit,
+    bench::{self, WlBenchMsg, WlBenchOptions},
+    config::Config,
+    control::{self, WlControl},
+    decode::{self, WlDecodeInput},
+    dump::WlDumper,
+    explain::{self, WlExplainQuery},
+    health::{self, WlHealth},
+    launcher,
+    learning::WlLearning,
+    logging::{self, WlLogLevels},
+    logind::WlSession,
+    pcapng,
+    policygen::WlPolicyGenerator,
+    recorder::WlDirection,
+    replay::{self, WlReplayOptions},
+    runtime, sandbox,
+    socket::WlSocketAddr,
+    spawner,
+};
+
+/// A runtime with default settings, for subcommands
+fn default_runtime() -> tokio::runtime::Runtime {
+    runtime::build(&Default::default()).expect("Failed to start the tokio runtime")
+}
 
+fn main() {
     let args: Vec<_> = std::env::args().collect();
-    if args.len() >= 2 {
-        conf_file = &args[1];
+    match args.get(1).map(String::as_str) {
+        Some("replay") => return default_runtime().block_on(replay_main(&args[2..])),
+        Some("pcapng") => return pcapng_main(&args[2..]),
+        Some("decode") => return decode_main(&args[2..]),
+        Some("explain") => return explain_main(&args[2..]),
+        Some("verify-audit") => return verify_audit_main(&args[2..]),
+        Some("tui") => return tui_main(&args[2..]),
+        Some("bench") => return bench_main(&args[2..]),
+        Some("health") => return default_runtime().block_on(health_main(&args[2..])),
+        Some("exec") => return exec_main(&args[2..]),
+        _ => {}
     }
 
-    let conf_str = tokio::fs::read_to_string(conf_file)
-        .await
-        .expect("Can't read config file");
-    let config: Arc<Config> =
-        Arc::new(toml::from_str(&conf_str).expect("Can't decode config file"));
-
-    let mut tracing_builder = tracing_subscriber::fmt();
+    // wl-mitm [--replace] [--generate-policy <output>] [<config>]
+    let mut conf_file = "config.toml";
+    let mut replace = false;
+    let mut policy = None;
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replace" => replace = true,
+            "--generate-policy" => match args.next() {
+                Some(output) => policy = Some(WlPolicyGenerator::new(output)),
+                None => {
+                    eprintln!("Usage: wl-mitm [--replace] [--generate-policy <output>] [<config>]");
+                    std::process::exit(1);
+                }
+            },
+            _ => conf_file = arg,
+        }
+    }
 
-    if let Some(ref level) = config.logging.log_level {
-        tracing_builder = tracing_builder
-            .with_max_level(LevelFilter::from_str(level).expect("Invalid log level"));
+    let conf_str = std::fs::read_to_string(conf_file).expect("Can't read config file");
+    let mut config = Config::parse(&conf_str).expect("Can't decode config file");
+    if let Some(ref policy) = policy {
+        // The sandbox has to let us write the suggested policy
+        if let Some(dir) = policy.output().parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            config
+                .sandbox
+                .write_paths
+                .push(dir.to_string_lossy().into_owned());
+        }
     }
+    let config = Arc::new(config);
 
-    tracing_builder.init();
+    let log_levels = logging::init(&config);
 
-    let src = config.socket.upstream_socket_path();
-    let proxied = config.socket.listen_socket_path();
+    // Both have to happen while we're still single-threaded
+    let mut spawn_helper = None;
+    if config.sandbox.enabled {
+        if config.exec.ask_cmd.is_some() || config.exec.notify_cmd.is_some() {
+            spawn_helper =
+                Some(spawner::fork_helper(&config.exec).expect("Failed to fork spawn helper"));
+        }
 
-    if src == proxied {
-        error!("downstream and upstream sockets should not be the same");
-        return;
+        sandbox::restrict_filesystem(&config).expect("Failed to restrict filesystem access");
     }
 
-    if proxied.exists() {
-        tokio::fs::remove_file(&proxied)
-            .await
-            .expect("Cannot unlink existing socket");
+    runtime::build(&config.runtime)
+        .expect("Failed to start the tokio runtime")
+        .block_on(proxy_main(
+            config,
+            log_levels,
+            replace,
+            policy,
+            spawn_helper,
+        ));
+}
+
+/// How often a suggested policy is rewritten while clients are still connected
+const POLICY_WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often changed app profiles are written while their apps are still connected
+const PROFILE_WRITE_INTERVAL: Duration = Duration::from_secs(30);
+
+async fn proxy_main(
+    config: Arc<Config>,
+    log_levels: Arc<WlLogLevels>,
+    replace: bool,
+    policy: Option<Arc<WlPolicyGenerator>>,
+    spawn_helper: Option<OwnedFd>,
+) {
+    let configs = config.all_proxies();
+    let listen: Vec<_> = configs
+        .iter()
+        .map(|c| c.socket.listen_socket_addr())
+        .collect();
+
+    for (i, (proxied, config)) in listen.iter().zip(&configs).enumerate() {
+        if configs.iter().any(|c| c.all_upstreams().contains(proxied)) {
+            error!("downstream and upstream sockets should not be the same");
+            return;
+        }
+
+        if listen[..i].contains(proxied) {
+            error!(addr = %proxied, "Proxies have to listen on distinct sockets");
+            return;
+        }
+
+        if let Some(route) = config
+            .routes
+            .iter()
+            .find(|r| !config.upstreams.contains_key(&r.upstream))
+        {
+            error!(
+                upstream = route.upstream,
+                "Route refers to an unknown upstream"
+            );
+            return;
+        }
     }
 
-    let listener = UnixListener::bind(&proxied).expect("Failed to bind to target socket");
+    let mut locks = Vec::new();
+    let mut listeners = Vec::new();
+    for (proxied, config) in listen.iter().zip(&configs) {
+        match proxied.claim(replace).await {
+            Ok(lock) => locks.push(lock),
+            Err(e) => {
+                error!(addr = %proxied, "Cannot claim listen socket: {}", e);
+                std::process::exit(1);
+            }
+        }
 
-    info!(path = ?proxied, "Listening on socket");
+        listeners.push(
+            proxied
+                .bind()
+                .await
+                .expect("Failed to bind to target socket"),
+        );
 
-    let mut conn_id = 0;
-    while let Ok((conn, addr)) = listener.accept().await {
-        info!(conn_id = conn_id, "Accepted new client {:?}", addr);
-        let span = span!(Level::INFO, "conn", conn_id = conn_id);
-        let _config = config.clone();
-        let _src = src.clone();
-        tokio::spawn(
-            async move {
-                if let Err(e) = handle_conn(_config, _src, conn).await {
-                    error!(error = ?e, "Failure handling connection");
-                }
+        proxied
+            .apply_permissions(&config.socket)
+            .expect("Failed to apply permissions to target socket");
+
+        info!(path = ?proxied, "Listening on socket");
+    }
+
+    let health = WlHealth::new(config.clone());
+    tokio::spawn(health.clone().run_heartbeat());
+
+    let dumper = WlDumper::new(config.clone());
+    let mut sigusr1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+        .expect("Failed to listen for SIGUSR1");
+    tokio::spawn({
+        let dumper = dumper.clone();
+        async move {
+            while sigusr1.recv().await.is_some() {
+                dumper.trigger();
             }
-            .instrument(span),
+        }
+    });
+
+    let (control_listener, _control_lock) = control::bind_control_socket(&config, replace)
+        .await
+        .expect("Failed to bind to control socket")
+        .unzip();
+    let control = control_listener.map(|listener| {
+        let control = WlControl::new(
+            config.clone(),
+            health.clone(),
+            dumper.clone(),
+            Some(log_levels),
+        );
+        tokio::spawn(control.clone().serve(listener));
+        control
+    });
+
+    if let Some(fd) = spawn_helper {
+        spawner::start(fd, &config.exec).expect("Failed to start spawn helper");
+    }
+
+    if config.sandbox.enabled {
+        sandbox::restrict_syscalls(&config).expect("Failed to restrict syscalls");
+    }
+
+    let learning = WlLearning::new(&config);
+    let session = WlSession::new(&config, control.clone());
+
+    let mut proxy = Proxy::builder()
+        .config(config)
+        .health(health)
+        .dumper(dumper);
+    if let Some(control) = control {
+        proxy = proxy.control(control);
+    }
+    if let Some(policy) = policy {
+        warn!(
+            output = ?policy.output(),
+            "Generating a policy; every known global is exposed to clients"
         );
-        conn_id += 1;
+        tokio::spawn(policy.clone().run_writer(POLICY_WRITE_INTERVAL));
+        proxy = proxy.policy_generator(policy);
+    }
+    if let Some(learning) = learning {
+        tokio::spawn(learning.clone().run_writer(PROFILE_WRITE_INTERVAL));
+        proxy = proxy.learning(learning);
+    }
+    if let Some(ref session) = session {
+        tokio::spawn(session.clone().run_watcher());
+        proxy = proxy.session(session.clone());
+    }
+
+    let proxy = proxy.build().expect("Failed to set up the proxy");
+    let mut serving = tokio::task::JoinSet::new();
+    for (config, listener) in configs.into_iter().zip(listeners) {
+        let proxy = proxy.with_config(config);
+        serving.spawn(async move { proxy.serve(listener).await });
+    }
+
+    // Each proxy only stops once accepting fails, or all of them once the session ends
+    if let Some(Ok(Err(e))) = serving.join_next().await {
+        error!(error = ?e, "Failed to accept new clients");
+    }
+
+    if session.is_some_and(|s| s.is_ended()) {
+        for addr in &listen {
+            addr.remove();
+        }
+        info!("Removed listen sockets as the session has ended");
     }
 }
 
-macro_rules! control_flow {
-    ($f:expr) => {
-        match $f {
-            ControlFlow::Break(res) => break res,
-            ControlFlow::Continue(_) => continue,
+/// wl-mitm replay [--fast] [--socket <socket>] <recording>
+async fn replay_main(args: &[String]) {
+    tracing_subscriber::fmt().init();
+
+    let mut fast = false;
+    let mut socket = None;
+    let mut recording = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fast" => fast = true,
+            "--socket" => socket = args.next().map(|s| WlSocketAddr::parse(s)),
+            _ if recording.is_none() => recording = Some(arg),
+            _ => {
+                error!(arg = arg, "Unexpected argument");
+                std::process::exit(1);
+            }
         }
+    }
+
+    let Some(recording) = recording else {
+        error!("Usage: wl-mitm replay [--fast] [--socket <socket>] <recording>");
+        std::process::exit(1);
     };
-}
 
-struct ConnDuplex<'a> {
-    config: Arc<Config>,
-    upstream_read: WlMsgReader<'a>,
-    upstream_write: WlMsgWriter<'a>,
-    downstream_read: WlMsgReader<'a>,
-    downstream_write: WlMsgWriter<'a>,
-    state: WlMitmState,
+    let socket = socket.unwrap_or_else(|| {
+        WlSocketAddr::parse(&std::env::var("WAYLAND_DISPLAY").unwrap_or("wayland-0".into()))
+    });
+
+    if let Err(e) = replay::replay(Path::new(recording), WlReplayOptions { fast, socket }).await {
+        error!(error = ?e, "Replay failed");
+        std::process::exit(1);
+    }
 }
 
-impl<'a> ConnDuplex<'a> {
-    pub fn new(
-        config: Arc<Config>,
-        state: WlMitmState,
-        upstream_conn: &'a mut UnixStream,
-        downstream_conn: &'a mut UnixStream,
-    ) -> Self {
-        let (upstream_read, upstream_write) = upstream_conn.split();
-        let (downstream_read, downstream_write) = downstream_conn.split();
+/// wl-mitm pcapng <output> <recording>...
+fn pcapng_main(args: &[String]) {
+    tracing_subscriber::fmt().init();
+
+    let [output, recordings @ ..] = args else {
+        error!("Usage: wl-mitm pcapng <output> <recording>...");
+        std::process::exit(1);
+    };
 
-        let upstream_read = WlMsgReader::new(upstream_read);
-        let downstream_read = WlMsgReader::new(downstream_read);
+    if recordings.is_empty() {
+        error!("Usage: wl-mitm pcapng <output> <recording>...");
+        std::process::exit(1);
+    }
 
-        let upstream_write = WlMsgWriter::new(upstream_write);
-        let downstream_write = WlMsgWriter::new(downstream_write);
+    let recordings: Vec<PathBuf> = recordings.iter().map(PathBuf::from).collect();
+    if let Err(e) = pcapng::export(&recordings, Path::new(output)) {
+        error!(error = ?e, "Failed to export pcapng");
+        std::process::exit(1);
+    }
 
-        Self {
-            config,
-            upstream_read,
-            upstream_write,
-            downstream_read,
-            downstream_write,
-            state,
-        }
-    }
-
-    async fn handle_s2c_event(
-        &mut self,
-        decoded_raw: DecoderOutcome,
-    ) -> io::Result<ControlFlow<()>> {
-        match decoded_raw {
-            codec::DecoderOutcome::Decoded(mut wl_raw_msg) => {
-                let WlMitmOutcome(num_consumed_fds, mut verdict) =
-                    self.state.on_s2c_event(&wl_raw_msg).await;
-                self.upstream_read
-                    .return_unused_fds(&mut wl_raw_msg, num_consumed_fds);
-
-                if !verdict.is_allowed() && self.config.filter.dry_run {
-                    warn!(
-                        verdict = ?verdict,
-                        "Last event would have been filtered! (see prior logs for reason)"
-                    );
-                    verdict = WlMitmVerdict::Allowed;
-                }
+    info!(
+        output = output,
+        "Exported {} recording(s)",
+        recordings.len()
+    );
+}
+
+/// wl-mitm decode [--raw-requests | --raw-events] <file>
+fn decode_main(args: &[String]) {
+    let usage = "Usage: wl-mitm decode [--raw-requests | --raw-events] <file>";
+
+    let (input, file) = match args {
+        [file] => (WlDecodeInput::Recording, file),
+        [flag, file] if flag == "--raw-requests" => {
+            (WlDecodeInput::Raw(WlDirection::ClientToServer), file)
+        }
+        [flag, file] if flag == "--raw-events" => {
+            (WlDecodeInput::Raw(WlDirection::ServerToClient), file)
+        }
+        _ => {
+            eprintln!("{}", usage);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = decode::decode(Path::new(file), input, &mut std::io::stdout().lock()) {
+        eprintln!("wl-mitm decode: {} ({})", e, file);
+        std::process::exit(1);
+    }
+}
 
-                match verdict {
-                    WlMitmVerdict::Allowed => {
-                        self.downstream_write.queue_write(wl_raw_msg);
-                    }
-                    WlMitmVerdict::Terminate => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::ConnectionAborted,
-                            "aborting connection",
-                        ));
-                    }
-                    _ => {}
-                };
+/// wl-mitm verify-audit [--key-file <file>] [--head <mac>] <file>
+fn verify_audit_main(args: &[String]) {
+    let usage = "Usage: wl-mitm verify-audit [--key-file <file>] [--head <mac>] <file>";
+
+    let mut key_file = None;
+    let mut head = None;
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--key-file" => args.next().map(|k| key_file = Some(k)),
+            "--head" => args.next().map(|h| head = Some(h.as_str())),
+            _ if !arg.starts_with("--") && file.is_none() => {
+                file = Some(arg);
+                Some(())
             }
-            codec::DecoderOutcome::Eof => return Ok(ControlFlow::Break(())),
-            _ => {}
-        }
-
-        Ok(ControlFlow::Continue(()))
-    }
-
-    async fn handle_c2s_request(
-        &mut self,
-        decoded_raw: DecoderOutcome,
-    ) -> io::Result<ControlFlow<()>> {
-        match decoded_raw {
-            codec::DecoderOutcome::Decoded(mut wl_raw_msg) => {
-                let WlMitmOutcome(num_consumed_fds, mut verdict) =
-                    self.state.on_c2s_request(&wl_raw_msg).await;
-                self.downstream_read
-                    .return_unused_fds(&mut wl_raw_msg, num_consumed_fds);
-
-                if !verdict.is_allowed() && self.config.filter.dry_run {
-                    warn!(
-                        verdict = ?verdict,
-                        "Last request would have been filtered! (see prior logs for reason)"
-                    );
-                    verdict = WlMitmVerdict::Allowed;
-                }
+            _ => None,
+        };
 
-                match verdict {
-                    WlMitmVerdict::Allowed => {
-                        self.upstream_write.queue_write(wl_raw_msg);
-                    }
-                    WlMitmVerdict::Rejected(error_code) => {
-                        self.downstream_write.queue_write(
-                            WlDisplayErrorEvent::new(
-                                WL_DISPLAY_OBJECT_ID,
-                                wl_raw_msg.obj_id,
-                                error_code,
-                                "Rejected by wl-mitm",
-                            )
-                            .build(),
-                        );
-                    }
-                    WlMitmVerdict::Terminate => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::ConnectionAborted,
-                            "aborting connection",
-                        ));
-                    }
-                    _ => {}
-                }
+        if parsed.is_none() {
+            eprintln!("{}", usage);
+            std::process::exit(1);
+        }
+    }
+    let Some(file) = file else {
+        eprintln!("{}", usage);
+        std::process::exit(1);
+    };
+
+    let read = |path: &String| {
+        std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("wl-mitm verify-audit: {} ({})", e, path);
+            std::process::exit(1);
+        })
+    };
+    let key = key_file.map(read);
+    let contents = String::from_utf8_lossy(&read(file)).into_owned();
+
+    match audit::verify(&contents, key.as_deref(), head) {
+        Ok(summary) => match summary.head {
+            Some((seq, mac)) => println!(
+                "{} records intact, the last one ({}) has mac {}",
+                summary.records, seq, mac
+            ),
+            None => println!("No records"),
+        },
+        Err(e) => {
+            eprintln!("wl-mitm verify-audit: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// wl-mitm explain [--config <config>] [--version <n>] [--descendant-of <interface>]...
+///                  [--app-id <app_id>] [--flatpak-app-id <app_id>] [--security-context <context>]
+///                  [--xwayland <true|false>] <interface> <request>
+///
+/// Passing `--descendant-of` at all means the object descends from no other interface.
+/// An empty `--flatpak-app-id` means the client isn't a Flatpak app, and an empty
+/// `--security-context` that it has none.
+fn explain_main(args: &[String]) {
+    let usage = "Usage: wl-mitm explain [--config <config>] [--version <n>] [--descendant-of <interface>]... \
+                 [--app-id <app_id>] [--flatpak-app-id <app_id>] [--security-context <context>] \
+                 [--xwayland <true|false>] <interface> <request>";
+
+    let mut conf_file = "config.toml";
+    let mut query = WlExplainQuery::default();
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--config" => args.next().map(|c| conf_file = c),
+            "--version" => args
+                .next()
+                .and_then(|v| v.parse().ok())
+                .map(|v| query.version = Some(v)),
+            "--descendant-of" => args.next().map(|a| {
+                query
+                    .ancestors
+                    .get_or_insert_with(Vec::new)
+                    .push(a.to_string())
+            }),
+            "--app-id" => args.next().map(|a| query.app_id = Some(a.to_string())),
+            "--flatpak-app-id" => args
+                .next()
+                .map(|a| query.flatpak_app_id = Some(a.to_string())),
+            "--security-context" => args
+                .next()
+                .map(|c| query.security_context = Some(c.to_string())),
+            "--xwayland" => args
+                .next()
+                .and_then(|a| a.parse().ok())
+                .map(|x| query.xwayland = Some(x)),
+            _ if !arg.starts_with("--") => {
+                positional.push(arg.to_string());
+                Some(())
             }
-            codec::DecoderOutcome::Eof => return Ok(ControlFlow::Break(())),
-            _ => {}
+            _ => None,
+        };
+
+        if parsed.is_none() {
+            eprintln!("{}", usage);
+            std::process::exit(1);
         }
+    }
+
+    let [interface, request] = &positional[..] else {
+        eprintln!("{}", usage);
+        std::process::exit(1);
+    };
+    query.interface = interface.clone();
+    query.request = request.clone();
 
-        Ok(ControlFlow::Continue(()))
+    let conf_str = std::fs::read_to_string(conf_file).expect("Can't read config file");
+    let config = Config::parse(&conf_str).expect("Can't decode config file");
+
+    match explain::explain(&config, &query, |_, _| true) {
+        Ok(explanation) => print!("{}", explanation),
+        Err(e) => {
+            eprintln!("wl-mitm explain: {}", e);
+            std::process::exit(1);
+        }
     }
+}
 
-    #[tracing::instrument(skip_all)]
-    pub async fn run_to_completion(mut self) -> io::Result<()> {
-        loop {
-            tokio::select! {
-                biased;
+/// wl-mitm exec [--profile <name> | --config <config>] -- <command>...
+///
+/// Profiles are configs under `$XDG_CONFIG_HOME/wl-mitm/<name>.toml`. Exits
+/// with the exit code of the command.
+fn exec_main(args: &[String]) {
+    let usage = "Usage: wl-mitm exec [--profile <name> | --config <config>] -- <command>...";
 
-                res = self.downstream_write.dequeue_write() => res?,
-                res = self.upstream_write.dequeue_write() => res?,
+    let Some(sep) = args.iter().position(|a| a == "--") else {
+        eprintln!("{}", usage);
+        std::process::exit(1);
+    };
+    let (options, command) = (&args[..sep], &args[sep + 1..]);
+
+    let (profile, conf_file) = match options {
+        [] => ("default".to_string(), PathBuf::from("config.toml")),
+        [flag, name] if flag == "--profile" => {
+            let config_home = std::env::var("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| {
+                    Path::new(&std::env::var("HOME").unwrap_or_default()).join(".config")
+                });
+            (
+                name.clone(),
+                config_home.join("wl-mitm").join(format!("{}.toml", name)),
+            )
+        }
+        [flag, file] if flag == "--config" => ("default".to_string(), PathBuf::from(file)),
+        _ => {
+            eprintln!("{}", usage);
+            std::process::exit(1);
+        }
+    };
 
-                msg = self.upstream_read.read() => {
-                    control_flow!(self.handle_s2c_event(msg?).await?);
-                }
-                msg = self.downstream_read.read() => {
-                    control_flow!(self.handle_c2s_request(msg?).await?);
-                }
-            }
+    if command.is_empty() {
+        eprintln!("{}", usage);
+        std::process::exit(1);
+    }
+
+    let conf_str = std::fs::read_to_string(&conf_file).unwrap_or_else(|e| {
+        eprintln!("wl-mitm exec: {} ({})", e, conf_file.display());
+        std::process::exit(1);
+    });
+    let config = Arc::new(Config::parse(&conf_str).expect("Can't decode config file"));
+    logging::init(&config);
+
+    let rt = runtime::build(&config.runtime).expect("Failed to start the tokio runtime");
+    match rt.block_on(launcher::run(config, &profile, command)) {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            error!(error = ?e, "Failed to run sandboxed app");
+            std::process::exit(1);
         }
+    }
+}
+
+/// wl-mitm bench [--messages <n>] [--mix <kind>=<weight>,...] [--config <config>]
+///
+/// The benchmark runs on the runtime configured under `[runtime]` in the config,
+/// so that runtime tuning can be measured too.
+fn bench_main(args: &[String]) {
+    tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::WARN)
+        .init();
+
+    let usage = "Usage: wl-mitm bench [--messages <n>] [--mix <kind>=<weight>,...] [--config <config>]\n\
+                 kinds: damage, commit (requests), motion, frame (events)";
+
+    let mut messages = 100000;
+    let mut mix = vec![
+        (WlBenchMsg::Damage, 2),
+        (WlBenchMsg::Commit, 1),
+        (WlBenchMsg::Motion, 4),
+        (WlBenchMsg::Frame, 4),
+    ];
+    let mut conf_str = bench::BENCH_DEFAULT_CONFIG.to_string();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--messages" => args
+                .next()
+                .and_then(|n| n.parse().ok())
+                .map(|n| messages = n),
+            "--mix" => args
+                .next()
+                .and_then(|m| {
+                    m.split(',')
+                        .map(|entry| {
+                            let (kind, weight) = entry.split_once('=').unwrap_or((entry, "1"));
+                            Some((WlBenchMsg::parse(kind)?, weight.parse().ok()?))
+                        })
+                        .collect::<Option<Vec<_>>>()
+                })
+                .map(|m| mix = m),
+            "--config" => args
+                .next()
+                .and_then(|f| std::fs::read_to_string(f).ok())
+                .map(|c| conf_str = c),
+            _ => None,
+        };
+
+        if parsed.is_none() {
+            error!(arg = arg, "{}", usage);
+            std::process::exit(1);
+        }
+    }
 
-        Ok(())
+    let config = Arc::new(Config::parse(&conf_str).expect("Can't decode config file"));
+    let rt = runtime::build(&config.runtime).expect("Failed to start the tokio runtime");
+    if let Err(e) = rt.block_on(bench::bench(WlBenchOptions {
+        messages,
+        mix,
+        config,
+    })) {
+        error!(error = ?e, "Benchmark failed");
+        std::process::exit(1);
     }
 }
 
-pub async fn handle_conn(
-    config: Arc<Config>,
-    src_path: impl AsRef<Path>,
-    mut downstream_conn: UnixStream,
-) -> io::Result<()> {
-    let mut upstream_conn = UnixStream::connect(src_path).await?;
-    let state = WlMitmState::new(config.clone());
+/// wl-mitm health [<control socket>]
+///
+/// Exits with 0 if wl-mitm is ready, 1 if it isn't, and 2 if it can't be reached
+async fn health_main(args: &[String]) {
+    let addr = WlSocketAddr::parse(
+        args.first()
+            .map(String::as_str)
+            .unwrap_or("wl-mitm-control"),
+    );
+
+    match health::ping(&addr).await {
+        Ok(status) => {
+            println!("{}", serde_json::to_string_pretty(&status).unwrap());
+            std::process::exit(if status.ready { 0 } else { 1 });
+        }
+        Err(e) => {
+            eprintln!("wl-mitm health: {} ({})", e, addr);
+            std::process::exit(2);
+        }
+    }
+}
 
-    let duplex = ConnDuplex::new(config, state, &mut upstream_conn, &mut downstream_conn);
+/// wl-mitm tui [<control socket>]
+#[cfg(feature = "tui")]
+fn tui_main(args: &[String]) {
+    let addr = WlSocketAddr::parse(
+        args.first()
+            .map(String::as_str)
+            .unwrap_or("wl-mitm-control"),
+    );
+
+    if let Err(e) = wl_mitm::tui::run(&addr) {
+        eprintln!("wl-mitm tui: {} ({})", e, addr);
+        std::process::exit(1);
+    }
+}
 
-    duplex.run_to_completion().await
+#[cfg(not(feature = "tui"))]
+fn tui_main(_args: &[String]) {
+    eprintln!("wl-mitm was built without the \"tui\" feature");
+    std::process::exit(1);
 }
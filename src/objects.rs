@@ -1,10 +1,18 @@
 use std::{
     any::{Any, TypeId},
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::proto::{WL_DISPLAY, WL_DISPLAY_OBJECT_ID};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    config::WlObjectsConfig,
+    proto::{WL_DISPLAY, WL_DISPLAY_OBJECT_ID, WL_KEYBOARD, WL_POINTER, WL_SEAT, WL_TOUCH},
+};
 
 /// A type ID to be implemented by _private structs_ acting as
 /// discriminants for Wayland object types
@@ -50,42 +58,549 @@ impl Hash for WlObjectType {
     }
 }
 
+/// What we know about a live object
+#[derive(Clone)]
+struct WlObjectEntry {
+    obj_type: WlObjectType,
+    /// The version the object was bound with, or inherited from the object
+    /// that created it
+    version: u32,
+    generation: u32,
+    /// The object through which this one was created (or bound)
+    parent: Option<WlObjectHandle>,
+    /// Types of the parent, its parent and so on, as they were at creation.
+    /// Unlike [Self::parent], this survives the ancestors being destroyed.
+    lineage: Arc<[WlObjectType]>,
+    created_at: SystemTime,
+    provenance: Option<WlObjectProvenance>,
+}
+
+impl WlObjectEntry {
+    fn created_at_millis(&self) -> u64 {
+        self.created_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+}
+
+/// What wl-mitm did with the message creating an object
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum WlProvenanceVerdict {
+    /// No filter rule matched
+    Allowed,
+    /// Allowed by `ask_cmd`
+    Approved,
+    /// Allowed, and `notify_cmd` ran
+    Notified,
+    /// Filtered or rejected. The object may still be around if the server
+    /// never learnt about the message.
+    Blocked,
+}
+
+/// The message which created an object, see [WlObjects::set_provenance]
+#[derive(Clone, Debug)]
+pub struct WlObjectProvenance {
+    /// Interface of the object the message was sent on
+    pub interface: &'static str,
+    pub message: &'static str,
+    pub verdict: WlProvenanceVerdict,
+    /// The `[[filter.requests]]` rule the message matched, if any
+    pub rule: Option<Arc<str>>,
+}
+
+impl WlObjectProvenance {
+    pub fn new(interface: &'static str, message: &'static str) -> WlObjectProvenance {
+        WlObjectProvenance {
+            interface,
+            message,
+            verdict: WlProvenanceVerdict::Allowed,
+            rule: None,
+        }
+    }
+}
+
+/// One entry of [WlObjects::provenance_chain]
+#[derive(Serialize, Clone, Debug)]
+pub struct WlProvenanceRecord {
+    pub id: u32,
+    pub interface: String,
+    /// Milliseconds since the Unix epoch
+    pub created_at: u64,
+    /// The message which created the object, as `interface::message`. Not
+    /// known for wl_display and objects created before wl-mitm tracked them.
+    pub created_by: Option<String>,
+    pub verdict: Option<WlProvenanceVerdict>,
+    pub rule: Option<String>,
+}
+
+/// An object ID along with its generation. IDs are reused by clients after
+/// `wl_display.delete_id`; a handle only ever refers to the object that held
+/// the ID when the handle was taken, see [WlObjects::handle].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct WlObjectHandle {
+    pub id: u32,
+    pub generation: u32,
+}
+
+/// Typed metadata attached to an object, at most one per type. See
+/// [WlObjects::put_extension].
+pub trait WlObjectExtension: Any + Send {
+    /// Called when the object this is attached to dies, or when its ID turns
+    /// out to have been reused. Use this to clean up or emit notifications.
+    fn on_destroy(&mut self, _obj: WlObjectHandle) {}
+
+    /// A one-line summary for [WlObjectInfo::extensions]
+    fn describe(&self) -> String {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name).to_string()
+    }
+}
+
+/// Sizes of what [WlObjects] tracks, and how often its limits were hit. See
+/// [WlObjectsConfig] for the limits.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WlObjectsStats {
+    pub objects: usize,
+    pub half_destroyed: usize,
+    pub extensions: usize,
+    pub globals: usize,
+    /// wl_registry objects whose globals are tracked
+    #[serde(default)]
+    pub registries: usize,
+    pub objects_refused: u64,
+    pub extensions_refused: u64,
+    pub globals_refused: u64,
+    /// Half-destroyed objects forgotten because the server never ACK'd them
+    pub half_destroyed_expired: u64,
+    /// Registries whose globals are no longer tracked, see [WlObjectsConfig::max_registries]
+    #[serde(default)]
+    pub registries_forgotten: u64,
+}
+
+/// One entry of [WlObjects::snapshot]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlObjectInfo {
+    pub id: u32,
+    pub interface: String,
+    #[serde(default)]
+    pub version: u32,
+    /// The object this one was created through, if still alive
+    #[serde(default)]
+    pub parent: Option<u32>,
+    /// Milliseconds since the Unix epoch
+    #[serde(default)]
+    pub created_at: u64,
+    pub half_destroyed: bool,
+    /// [WlObjectExtension::describe] of each extension attached to the object
+    #[serde(default)]
+    pub extensions: Vec<String>,
+}
+
+/// Globals announced on one wl_registry
+struct WlRegistryGlobals {
+    /// u32 "name"s of globals mapped to their object types
+    names: HashMap<u32, WlObjectType>,
+    /// [WlObjects::registry_seq] as of when the registry was last announced
+    /// a global on or bound through
+    last_used: u64,
+}
+
+/// Extensions of one object, tagged with the generation they belong to
+struct WlObjectExtensions {
+    generation: u32,
+    extensions: HashMap<TypeId, Box<dyn WlObjectExtension>>,
+}
+
 pub struct WlObjects {
-    objects: HashMap<u32, WlObjectType>,
+    objects: HashMap<u32, WlObjectEntry>,
     /// Objects that have been destroyed by the client, but not yet ACK'd by the server
     /// Objects in this state may still receive events from the server.
-    objects_half_destroyed: HashMap<u32, WlObjectType>,
-    object_extensions: HashMap<u32, HashMap<TypeId, Box<dyn Any + Send>>>,
-    /// u32 "name"s of globals mapped to their object types
-    global_names: HashMap<u32, WlObjectType>,
+    objects_half_destroyed: HashMap<u32, WlObjectEntry>,
+    /// Half-destroyed objects in the order they were destroyed, for expiring
+    /// those never ACK'd by a buggy server. Entries may be stale.
+    half_destroyed_queue: VecDeque<(Instant, WlObjectHandle)>,
+    max_half_destroyed: usize,
+    half_destroyed_timeout: Duration,
+    max_objects: usize,
+    max_extensions: usize,
+    max_globals: usize,
+    max_registries: usize,
+    num_extensions: usize,
+    /// Only the counters are kept up to date, see [Self::stats]
+    stats: WlObjectsStats,
+    object_extensions: HashMap<u32, WlObjectExtensions>,
+    /// The last generation each ID was recorded with. Kept after objects are
+    /// removed, such that a reused ID always gets a new generation.
+    generations: HashMap<u32, u32>,
+    /// IDs of objects created through each object. Entries may be stale;
+    /// a child only counts if its own parent handle is still current.
+    children: HashMap<u32, HashSet<u32>>,
+    /// IDs of live (not half-destroyed) objects of each type
+    by_type: HashMap<WlObjectType, HashSet<u32>>,
+    /// Globals for each wl_registry they were announced on
+    registries: HashMap<u32, WlRegistryGlobals>,
+    registry_seq: u64,
+    /// Registries beyond [WlObjectsConfig::max_registries] whose globals are
+    /// no longer tracked. The client has likely leaked them: wl_registry has
+    /// no destructor, so the server keeps them, and announces to them, until
+    /// the connection is closed.
+    forgotten_registries: HashSet<u32>,
+    /// Names of globals filtered on any registry. Filtering only depends on
+    /// the interface, so this holds on all registries. Never cleaned up, but
+    /// servers don't hand out many global names.
+    filtered_globals: HashSet<u32>,
+}
+
+impl Default for WlObjects {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WlObjects {
     pub fn new() -> WlObjects {
+        Self::with_config(&Default::default())
+    }
+
+    pub fn with_config(config: &WlObjectsConfig) -> WlObjects {
         let mut objects = HashMap::new();
-        objects.insert(WL_DISPLAY_OBJECT_ID, WL_DISPLAY);
+        objects.insert(
+            WL_DISPLAY_OBJECT_ID,
+            WlObjectEntry {
+                obj_type: WL_DISPLAY,
+                version: 1,
+                generation: 0,
+                parent: None,
+                lineage: Arc::new([]),
+                created_at: SystemTime::now(),
+                provenance: None,
+            },
+        );
 
         WlObjects {
             objects,
             objects_half_destroyed: HashMap::new(),
+            half_destroyed_queue: VecDeque::new(),
+            max_half_destroyed: config.max_half_destroyed,
+            half_destroyed_timeout: Duration::from_secs(config.half_destroyed_timeout),
+            max_objects: config.max_objects,
+            max_extensions: config.max_extensions,
+            max_globals: config.max_globals,
+            max_registries: config.max_registries,
+            num_extensions: 0,
+            stats: Default::default(),
             object_extensions: HashMap::new(),
-            global_names: Default::default(),
+            generations: HashMap::new(),
+            children: HashMap::new(),
+            by_type: HashMap::from([(WL_DISPLAY, HashSet::from([WL_DISPLAY_OBJECT_ID]))]),
+            registries: HashMap::new(),
+            registry_seq: 0,
+            forgotten_registries: HashSet::new(),
+            filtered_globals: HashSet::new(),
         }
     }
 
-    pub fn record_object(&mut self, obj_type: WlObjectType, id: u32) {
-        self.objects.insert(id, obj_type);
-        self.object_extensions.remove(&id);
+    /// Whether another object may be recorded without exceeding
+    /// [WlObjectsConfig::max_objects]. The connection should be closed if
+    /// not, since the client and server would disagree on what exists.
+    pub fn check_object_limit(&mut self) -> bool {
+        if self.objects.len() + self.objects_half_destroyed.len() < self.max_objects {
+            return true;
+        }
+
+        self.stats.objects_refused += 1;
+        warn!(
+            max_objects = self.max_objects,
+            "Too many objects on this connection"
+        );
+        false
+    }
+
+    pub fn record_object(&mut self, obj_type: WlObjectType, id: u32, version: u32) {
+        self.insert_entry(obj_type, id, version, None, Arc::new([]));
+    }
+
+    /// Record an object created through (or bound from) `parent`. Unless
+    /// bound with a version of its own, it shares the version of its parent.
+    pub fn record_child_object(
+        &mut self,
+        obj_type: WlObjectType,
+        id: u32,
+        parent: u32,
+        version: Option<u32>,
+    ) {
+        let parent_handle = self.handle(parent);
+        let version = version
+            .or_else(|| self.lookup_object_version(parent))
+            .unwrap_or(1);
+        let lineage = self
+            .lookup_entry(parent)
+            .map(|e| std::iter::once(e.obj_type).chain(e.lineage.iter().copied()))
+            .into_iter()
+            .flatten()
+            .collect();
+        self.insert_entry(obj_type, id, version, parent_handle, lineage);
+
+        if parent_handle.is_some() {
+            self.children.entry(parent).or_default().insert(id);
+        }
+    }
+
+    fn insert_entry(
+        &mut self,
+        obj_type: WlObjectType,
+        id: u32,
+        version: u32,
+        parent: Option<WlObjectHandle>,
+        lineage: Arc<[WlObjectType]>,
+    ) {
+        let generation = self.generations.entry(id).or_default();
+        *generation = generation.wrapping_add(1);
+
+        let old_entry = self.objects.insert(
+            id,
+            WlObjectEntry {
+                obj_type,
+                version,
+                generation: *generation,
+                parent,
+                lineage,
+                created_at: SystemTime::now(),
+                provenance: None,
+            },
+        );
+        if let Some(old_entry) = old_entry {
+            self.unindex(old_entry.obj_type, id);
+        }
+        self.by_type.entry(obj_type).or_default().insert(id);
+        self.drop_extensions(id);
+        // Children of a previous object with this ID are orphans now
+        self.children.remove(&id);
     }
 
     /// Returns [Some] if we have a record of that object ID. However,
     /// that object could have been destroyed by the client but not yet ACK'd
     /// by the server -- in that case, use [Self::is_half_destroyed]!
     pub fn lookup_object(&self, id: u32) -> Option<WlObjectType> {
+        self.lookup_entry(id).map(|e| e.obj_type)
+    }
+
+    /// The version of an object, with the same caveats as [Self::lookup_object]
+    pub fn lookup_object_version(&self, id: u32) -> Option<u32> {
+        self.lookup_entry(id).map(|e| e.version)
+    }
+
+    /// A handle to the object currently holding `id`, with the same caveats
+    /// as [Self::lookup_object]
+    pub fn handle(&self, id: u32) -> Option<WlObjectHandle> {
+        self.lookup_entry(id).map(|e| WlObjectHandle {
+            id,
+            generation: e.generation,
+        })
+    }
+
+    /// Whether `handle` still refers to a live (or half-destroyed) object,
+    /// i.e. its ID hasn't been freed or reused since
+    pub fn is_current(&self, handle: WlObjectHandle) -> bool {
+        self.handle(handle.id) == Some(handle)
+    }
+
+    /// The object `id` was created through, if that is still alive
+    pub fn lookup_parent(&self, id: u32) -> Option<u32> {
+        let parent = self.lookup_entry(id)?.parent?;
+        self.is_current(parent).then_some(parent.id)
+    }
+
+    /// The parent of `id`, its parent and so on, up to the object bound from
+    /// the registry (or the first one that's gone)
+    pub fn ancestors(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        std::iter::successors(self.lookup_parent(id), |id| self.lookup_parent(*id))
+    }
+
+    /// Objects created directly through `id` that are still alive
+    pub fn children(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        let handle = self.handle(id);
+        self.children
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(move |child| {
+                handle.is_some() && self.lookup_entry(*child).and_then(|e| e.parent) == handle
+            })
+    }
+
+    /// All objects created directly or indirectly through `id`, parents
+    /// before their children
+    pub fn descendants(&self, id: u32) -> Vec<u32> {
+        let mut descendants: Vec<_> = self.children(id).collect();
+        let mut i = 0;
+        while i < descendants.len() {
+            descendants.extend(self.children(descendants[i]));
+            i += 1;
+        }
+        descendants
+    }
+
+    /// The closest object of `interface` that `id` has been created through,
+    /// directly or indirectly
+    pub fn find_ancestor(&self, id: u32, interface: &str) -> Option<u32> {
+        self.ancestors(id).find(|a| {
+            self.lookup_object(*a)
+                .is_some_and(|t| t.interface() == interface)
+        })
+    }
+
+    /// Whether `id` has been created, directly or indirectly, through an
+    /// object of `interface`. Unlike [Self::find_ancestor], this still holds
+    /// once that object is gone.
+    pub fn has_ancestor(&self, id: u32, interface: &str) -> bool {
+        self.lookup_entry(id)
+            .is_some_and(|e| e.lineage.iter().any(|t| t.interface() == interface))
+    }
+
+    /// The wl_seat `id` belongs to: either `id` itself, or the seat it (e.g.
+    /// a wl_pointer) has been created through
+    pub fn seat_of(&self, id: u32) -> Option<u32> {
+        if self.lookup_object(id) == Some(WL_SEAT) {
+            Some(id)
+        } else {
+            self.find_ancestor(id, WL_SEAT.interface())
+        }
+    }
+
+    /// All live wl_seat objects, sorted by ID. A client binding the same seat
+    /// global more than once gets one object per bind.
+    pub fn seats(&self) -> Vec<u32> {
+        let mut seats: Vec<_> = self.objects_of_type(WL_SEAT).collect();
+        seats.sort();
+        seats
+    }
+
+    /// IDs of all live objects of `obj_type`, in no particular order.
+    /// Half-destroyed objects are not included.
+    pub fn objects_of_type(&self, obj_type: WlObjectType) -> impl Iterator<Item = u32> + '_ {
+        self.by_type.get(&obj_type).into_iter().flatten().copied()
+    }
+
+    fn unindex(&mut self, obj_type: WlObjectType, id: u32) {
+        if let Some(ids) = self.by_type.get_mut(&obj_type) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_type.remove(&obj_type);
+            }
+        }
+    }
+
+    /// Live input devices (wl_pointer, wl_keyboard and wl_touch) created
+    /// through `seat`
+    pub fn seat_devices(&self, seat: u32) -> impl Iterator<Item = (u32, WlObjectType)> + '_ {
+        self.children(seat).filter_map(|id| {
+            if self.is_half_destroyed(id) {
+                return None;
+            }
+            let obj_type = self.lookup_object(id)?;
+            [WL_POINTER, WL_KEYBOARD, WL_TOUCH]
+                .contains(&obj_type)
+                .then_some((id, obj_type))
+        })
+    }
+
+    fn lookup_entry(&self, id: u32) -> Option<&WlObjectEntry> {
         self.objects
             .get(&id)
             .or_else(|| self.objects_half_destroyed.get(&id))
-            .cloned()
+    }
+
+    /// All objects we know of, including half-destroyed ones
+    pub fn iter_objects(&self) -> impl Iterator<Item = (u32, WlObjectType)> + '_ {
+        self.objects
+            .iter()
+            .chain(self.objects_half_destroyed.iter())
+            .map(|(id, e)| (*id, e.obj_type))
+    }
+
+    /// Record the message which created `id`
+    pub fn set_provenance(&mut self, id: u32, provenance: WlObjectProvenance) {
+        if let Some(entry) = self.objects.get_mut(&id) {
+            entry.provenance = Some(provenance);
+        }
+    }
+
+    pub fn provenance(&self, id: u32) -> Option<&WlObjectProvenance> {
+        self.lookup_entry(id)?.provenance.as_ref()
+    }
+
+    pub fn provenance_mut(&mut self, id: u32) -> Option<&mut WlObjectProvenance> {
+        self.objects.get_mut(&id)?.provenance.as_mut()
+    }
+
+    /// How `id` and each object it was created through came to be, starting
+    /// with `id` itself. Used to audit filtered actions, e.g. a screencopy
+    /// frame created through a manager that was bound after being approved.
+    pub fn provenance_chain(&self, id: u32) -> Vec<WlProvenanceRecord> {
+        std::iter::once(id)
+            .chain(self.ancestors(id))
+            .filter_map(|id| {
+                let entry = self.lookup_entry(id)?;
+                let provenance = entry.provenance.as_ref();
+                Some(WlProvenanceRecord {
+                    id,
+                    interface: entry.obj_type.interface().to_string(),
+                    created_at: entry.created_at_millis(),
+                    created_by: provenance.map(|p| format!("{}::{}", p.interface, p.message)),
+                    verdict: provenance.map(|p| p.verdict),
+                    rule: provenance.and_then(|p| p.rule.as_deref().map(str::to_string)),
+                })
+            })
+            .collect()
+    }
+
+    /// The live object table, sorted by ID
+    pub fn snapshot(&self) -> Vec<WlObjectInfo> {
+        let mut snapshot: Vec<_> = self
+            .objects
+            .iter()
+            .chain(self.objects_half_destroyed.iter())
+            .map(|(id, entry)| {
+                let mut extensions: Vec<_> = self
+                    .current_extensions(*id)
+                    .into_iter()
+                    .flat_map(|e| e.values())
+                    .map(|e| e.describe())
+                    .collect();
+                extensions.sort();
+
+                WlObjectInfo {
+                    id: *id,
+                    interface: entry.obj_type.interface().to_string(),
+                    version: entry.version,
+                    parent: self.lookup_parent(*id),
+                    created_at: entry.created_at_millis(),
+                    half_destroyed: self.is_half_destroyed(*id),
+                    extensions,
+                }
+            })
+            .collect();
+        snapshot.sort_by_key(|o| o.id);
+        snapshot
+    }
+
+    pub fn stats(&self) -> WlObjectsStats {
+        WlObjectsStats {
+            objects: self.objects.len(),
+            half_destroyed: self.objects_half_destroyed.len(),
+            extensions: self.num_extensions,
+            globals: self.num_globals(),
+            registries: self.registries.len(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// [Self::snapshot] as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.snapshot()).unwrap()
     }
 
     pub fn is_half_destroyed(&self, id: u32) -> bool {
@@ -102,50 +617,298 @@ impl WlObjects {
             let Some(old_entry) = self.objects.remove(&id) else {
                 return;
             };
+            self.unindex(old_entry.obj_type, id);
+            let handle = WlObjectHandle {
+                id,
+                generation: old_entry.generation,
+            };
             self.objects_half_destroyed.insert(id, old_entry);
-            self.object_extensions.remove(&id);
+            self.drop_extensions(id);
+
+            self.half_destroyed_queue
+                .push_back((Instant::now(), handle));
+            self.expire_half_destroyed();
         } else {
-            self.objects.remove(&id);
-            self.objects_half_destroyed.remove(&id);
-            self.object_extensions.remove(&id);
+            let entry = self.objects.remove(&id);
+            if let Some(ref entry) = entry {
+                self.unindex(entry.obj_type, id);
+            }
+            let half_destroyed_entry = self.objects_half_destroyed.remove(&id);
+            let entry = entry.or(half_destroyed_entry);
+            self.drop_extensions(id);
+
+            // Remaining children become orphans; Wayland objects don't die
+            // with their parents
+            self.children.remove(&id);
+            self.registries.remove(&id);
+            self.forgotten_registries.remove(&id);
+            if let Some(parent) = entry.and_then(|e| e.parent)
+                && let Some(siblings) = self.children.get_mut(&parent.id)
+            {
+                siblings.remove(&id);
+            }
         }
     }
 
-    pub fn put_object_extension<T: Any + Send>(&mut self, id: u32, extension: T) {
-        if self.lookup_object(id).is_none() {
+    /// Drop half-destroyed objects the server should have long ACK'd, or
+    /// the oldest ones once there are too many of them
+    fn expire_half_destroyed(&mut self) {
+        while let Some((destroyed_at, handle)) = self.half_destroyed_queue.front().copied() {
+            let is_stale = self
+                .objects_half_destroyed
+                .get(&handle.id)
+                .is_none_or(|e| e.generation != handle.generation);
+            if is_stale {
+                self.half_destroyed_queue.pop_front();
+                continue;
+            }
+
+            let age = destroyed_at.elapsed();
+            let over_limit = self.objects_half_destroyed.len() > self.max_half_destroyed;
+            if age < self.half_destroyed_timeout && !over_limit {
+                break;
+            }
+
+            self.half_destroyed_queue.pop_front();
+            self.stats.half_destroyed_expired += 1;
+            warn!(
+                obj_id = handle.id,
+                interface = self.objects_half_destroyed[&handle.id].obj_type.interface(),
+                age = ?age,
+                num_half_destroyed = self.objects_half_destroyed.len(),
+                "Server never ACK'd destruction of object, forgetting it"
+            );
+            self.remove_object(handle.id, false);
+        }
+    }
+
+    /// Attach `extension` to the object currently holding `id`, returning the
+    /// extension of the same type it replaces. Extensions beyond
+    /// [WlObjectsConfig::max_extensions] are dropped.
+    pub fn put_extension<T: WlObjectExtension>(&mut self, id: u32, extension: T) -> Option<T> {
+        let Some(handle) = self.handle(id) else {
             // This should not happen but let's ignore extensions on non-existent objects
-            return;
+            return None;
+        };
+
+        if self
+            .object_extensions
+            .get(&id)
+            .is_some_and(|e| e.generation != handle.generation)
+        {
+            // Left over from a previous object with the same ID
+            self.drop_extensions(id);
+        }
+
+        let is_new = self
+            .current_extensions(id)
+            .is_none_or(|e| !e.contains_key(&TypeId::of::<T>()));
+        if is_new {
+            if self.num_extensions >= self.max_extensions {
+                self.stats.extensions_refused += 1;
+                warn!(
+                    obj_id = id,
+                    max_extensions = self.max_extensions,
+                    "Too many object extensions on this connection, dropping {}",
+                    extension.describe()
+                );
+                return None;
+            }
+            self.num_extensions += 1;
         }
 
-        self.object_extensions
+        let old = self
+            .object_extensions
             .entry(id)
-            .or_default()
-            .insert(extension.type_id(), Box::new(extension));
+            .or_insert_with(|| WlObjectExtensions {
+                generation: handle.generation,
+                extensions: HashMap::new(),
+            })
+            .extensions
+            .insert(TypeId::of::<T>(), Box::new(extension))?;
+        (old as Box<dyn Any>).downcast().ok().map(|old| *old)
     }
 
-    pub fn get_object_extension<T: Any + Send>(&self, id: u32) -> Option<&T> {
-        self.object_extensions
-            .get(&id)?
-            .get(&TypeId::of::<T>())?
-            .downcast_ref()
+    /// Extensions of the object currently holding `id`. Extensions of
+    /// previous objects with the same ID are never returned.
+    pub fn extension<T: WlObjectExtension>(&self, id: u32) -> Option<&T> {
+        let ext = self.current_extensions(id)?.get(&TypeId::of::<T>())?;
+        (ext.as_ref() as &dyn Any).downcast_ref()
+    }
+
+    pub fn extension_mut<T: WlObjectExtension>(&mut self, id: u32) -> Option<&mut T> {
+        let generation = self.handle(id)?.generation;
+        let entry = self.object_extensions.get_mut(&id)?;
+        if entry.generation != generation {
+            return None;
+        }
+        let ext = entry.extensions.get_mut(&TypeId::of::<T>())?;
+        (ext.as_mut() as &mut dyn Any).downcast_mut()
+    }
+
+    /// Like [Self::extension], but only if `handle` is still current
+    pub fn handle_extension<T: WlObjectExtension>(&self, handle: WlObjectHandle) -> Option<&T> {
+        if !self.is_current(handle) {
+            return None;
+        }
+        self.extension(handle.id)
+    }
+
+    /// Detach an extension from its object, without running its
+    /// [WlObjectExtension::on_destroy] hook
+    pub fn take_extension<T: WlObjectExtension>(&mut self, id: u32) -> Option<T> {
+        let generation = self.handle(id)?.generation;
+        let entry = self.object_extensions.get_mut(&id)?;
+        if entry.generation != generation {
+            return None;
+        }
+        let ext = entry.extensions.remove(&TypeId::of::<T>())?;
+        self.num_extensions -= 1;
+        (ext as Box<dyn Any>).downcast().ok().map(|ext| *ext)
+    }
+
+    /// All live objects with an extension of type `T`, in no particular order
+    pub fn iter_extensions<T: WlObjectExtension>(&self) -> impl Iterator<Item = (u32, &T)> + '_ {
+        self.object_extensions.iter().filter_map(|(id, entry)| {
+            if self.handle(*id)?.generation != entry.generation {
+                return None;
+            }
+            let ext = entry.extensions.get(&TypeId::of::<T>())?;
+            Some((*id, (ext.as_ref() as &dyn Any).downcast_ref()?))
+        })
+    }
+
+    fn current_extensions(&self, id: u32) -> Option<&HashMap<TypeId, Box<dyn WlObjectExtension>>> {
+        let generation = self.handle(id)?.generation;
+        let entry = self.object_extensions.get(&id)?;
+        (entry.generation == generation).then_some(&entry.extensions)
+    }
+
+    /// Remove all extensions of `id`, running their destroy hooks
+    fn drop_extensions(&mut self, id: u32) {
+        let Some(entry) = self.object_extensions.remove(&id) else {
+            return;
+        };
+
+        let handle = WlObjectHandle {
+            id,
+            generation: entry.generation,
+        };
+        self.num_extensions -= entry.extensions.len();
+        for (_, mut ext) in entry.extensions {
+            ext.on_destroy(handle);
+        }
+    }
+
+    fn num_globals(&self) -> usize {
+        self.registries.values().map(|r| r.names.len()).sum()
+    }
+
+    /// The globals of `registry`, to record one more. If that makes for more
+    /// than [WlObjectsConfig::max_registries], the least recently used other
+    /// registry is forgotten.
+    fn registry_entry(&mut self, registry: u32) -> &mut WlRegistryGlobals {
+        self.registry_seq += 1;
+        if !self.registries.contains_key(&registry) {
+            while self.registries.len() >= self.max_registries.max(1) {
+                let (&lru, _) = self
+                    .registries
+                    .iter()
+                    .min_by_key(|(_, r)| r.last_used)
+                    .unwrap();
+                self.registries.remove(&lru);
+                self.forgotten_registries.insert(lru);
+                self.stats.registries_forgotten += 1;
+                warn!(
+                    registry = lru,
+                    max_registries = self.max_registries,
+                    "Too many registries on this connection, forgetting the least recently used one"
+                );
+            }
+        }
+
+        let entry = self
+            .registries
+            .entry(registry)
+            .or_insert_with(|| WlRegistryGlobals {
+                names: HashMap::new(),
+                last_used: 0,
+            });
+        entry.last_used = self.registry_seq;
+        entry
+    }
+
+    /// Returns false, without recording the global, if that would exceed
+    /// [WlObjectsConfig::max_globals]. Nothing is recorded for forgotten
+    /// registries.
+    pub fn record_global(&mut self, registry: u32, name: u32, interface: WlObjectType) -> bool {
+        if self.forgotten_registries.contains(&registry) {
+            self.filtered_globals.remove(&name);
+            return true;
+        }
+
+        if self.num_globals() >= self.max_globals {
+            self.stats.globals_refused += 1;
+            warn!(
+                name = name,
+                interface = interface.interface(),
+                max_globals = self.max_globals,
+                "Too many globals on this connection"
+            );
+            return false;
+        }
+
+        self.registry_entry(registry).names.insert(name, interface);
+        self.filtered_globals.remove(&name);
+        true
+    }
+
+    /// Remember that the global `name` was hidden from the client
+    pub fn record_filtered_global(&mut self, name: u32) {
+        self.filtered_globals.insert(name);
+    }
+
+    /// Whether the global `name` was hidden from the client
+    pub fn is_filtered_global(&self, name: u32) -> bool {
+        self.filtered_globals.contains(&name)
+    }
+
+    /// Whether the globals of `registry` were forgotten, see [WlObjectsConfig::max_registries]
+    pub fn is_registry_forgotten(&self, registry: u32) -> bool {
+        self.forgotten_registries.contains(&registry)
+    }
+
+    /// Count `registry` as used by the client, which makes it the last one to forget
+    pub fn registry_used(&mut self, registry: u32) {
+        self.registry_seq += 1;
+        if let Some(entry) = self.registries.get_mut(&registry) {
+            entry.last_used = self.registry_seq;
+        }
     }
 
-    pub fn get_object_extension_mut<T: Any + Send>(&mut self, id: u32) -> Option<&mut T> {
-        self.object_extensions
-            .get_mut(&id)?
-            .get_mut(&TypeId::of::<T>())?
-            .downcast_mut()
+    /// A global as announced on `registry`
+    pub fn lookup_global(&self, registry: u32, name: u32) -> Option<WlObjectType> {
+        self.registries.get(&registry)?.names.get(&name).copied()
     }
 
-    pub fn record_global(&mut self, name: u32, interface: WlObjectType) {
-        self.global_names.insert(name, interface);
+    /// A global as announced on any registry. Names are shared by all
+    /// registries of a client.
+    pub fn lookup_global_any(&self, name: u32) -> Option<WlObjectType> {
+        self.registries
+            .values()
+            .find_map(|r| r.names.get(&name))
+            .copied()
     }
 
-    pub fn lookup_global(&self, name: u32) -> Option<WlObjectType> {
-        self.global_names.get(&name).copied()
+    /// All globals announced to the client, as `(registry, name, type)`
+    pub fn iter_globals(&self) -> impl Iterator<Item = (u32, u32, WlObjectType)> + '_ {
+        self.registries
+            .iter()
+            .flat_map(|(registry, r)| r.names.iter().map(move |(name, t)| (*registry, *name, *t)))
     }
 
-    pub fn remove_global(&mut self, name: u32) {
-        self.global_names.remove(&name);
+    /// Returns the removed global, if it was known
+    pub fn remove_global(&mut self, registry: u32, name: u32) -> Option<WlObjectType> {
+        self.registries.get_mut(&registry)?.names.remove(&name)
     }
 }
@@ -0,0 +1,71 @@
+//! Frame-rate caps on screen capture
+//!
+//! Letting a client capture the screen at all is one decision; how often it
+//! may do so is another. With `capture.max_fps`, every allowed request for a
+//! frame, i.e. a screencopy `copy`, an image-copy `capture` or a DMA-BUF
+//! export, is held back until the client's next slot. Capture requests are
+//! counted per client, across all outputs and protocols.
+//!
+//! Held requests stay in the write queue in order, see
+//! [crate::io_util::WlMsgWriter::queue_write_after], so requests sent after
+//! one are held back with it. Meanwhile no further requests are read from the
+//! client, which bounds the queue.
+
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::config::WlCaptureConfig;
+
+/// Requests for a single frame, as `(interface, request)`
+const CAPTURE_REQUESTS: &[(&str, &str)] = &[
+    ("zwlr_screencopy_frame_v1", "copy"),
+    ("zwlr_screencopy_frame_v1", "copy_with_damage"),
+    ("ext_image_copy_capture_frame_v1", "capture"),
+    ("hyprland_toplevel_export_frame_v1", "copy"),
+    ("zwlr_export_dmabuf_manager_v1", "capture_output"),
+];
+
+/// Per-connection pacing of capture requests
+pub struct WlCapturePacer {
+    interval: Duration,
+    /// When the next frame may be requested
+    next: Option<Instant>,
+}
+
+impl WlCapturePacer {
+    /// Returns [None] if capture isn't capped
+    pub fn new(config: &WlCaptureConfig) -> Option<WlCapturePacer> {
+        let max_fps = config.max_fps?;
+        Some(WlCapturePacer {
+            interval: Duration::from_secs_f64(1.0 / max_fps),
+            next: None,
+        })
+    }
+
+    pub fn is_capture(interface: &str, request: &str) -> bool {
+        CAPTURE_REQUESTS.contains(&(interface, request))
+    }
+
+    /// Take the next slot for an allowed `interface::request` received at
+    /// `now`. Returns when to forward it, or [None] if right away.
+    pub fn schedule(&mut self, interface: &str, request: &str, now: Instant) -> Option<Instant> {
+        if !Self::is_capture(interface, request) {
+            return None;
+        }
+
+        let at = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(at + self.interval);
+        if at <= now {
+            return None;
+        }
+
+        debug!(
+            interface = interface,
+            request = request,
+            delay_ms = (at - now).as_millis() as u64,
+            "Holding back capture request to keep to capture.max_fps"
+        );
+        Some(at)
+    }
+}
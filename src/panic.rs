@@ -0,0 +1,119 @@
+//! Containing panics to the connection they happen on
+//!
+//! A bug in parsing or state handling that panics shouldn't take every other
+//! client down with it. Message processing runs under [catch_unwind], and a
+//! panic turns into a [WlConnPanic] error that closes only the affected
+//! connection. The report carries the raw message being processed at the time,
+//! such that the panic can be reproduced.
+//!
+//! This relies on panics unwinding, so wl-mitm must not be built with
+//! `panic = "abort"`.
+
+use std::{
+    any::Any,
+    error::Error,
+    fmt::{self, Write},
+    io,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use serde_derive::Serialize;
+
+use crate::{codec::WlRawMsg, recorder::WlDirection};
+
+/// Future returned by [catch_unwind]
+pub struct CatchUnwind<F>(F);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the inner future is pinned structurally and never moved out
+        let inner = unsafe { self.map_unchecked_mut(|s| &mut s.0) };
+        match std::panic::catch_unwind(AssertUnwindSafe(|| inner.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(out)) => Poll::Ready(Ok(out)),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// Run `fut`, catching any panic it raises. Whatever `fut` borrows may be left
+/// inconsistent by a panic; the connection it belongs to should be closed.
+pub fn catch_unwind<F: Future>(fut: F) -> CatchUnwind<F> {
+    CatchUnwind(fut)
+}
+
+/// The message that was being processed when a connection panicked
+#[derive(Serialize, Debug)]
+pub struct WlPanickedMsg {
+    pub direction: WlDirection,
+    pub obj_id: u32,
+    pub opcode: u16,
+    pub interface: Option<String>,
+    pub num_fds: usize,
+    /// The whole message, hex-encoded
+    pub raw: String,
+}
+
+impl WlPanickedMsg {
+    pub fn new(direction: WlDirection, interface: Option<&str>, msg: &WlRawMsg) -> WlPanickedMsg {
+        let mut raw = String::with_capacity(msg.as_bytes().len() * 2);
+        for b in msg.as_bytes() {
+            write!(raw, "{:02x}", b).unwrap();
+        }
+
+        WlPanickedMsg {
+            direction,
+            obj_id: msg.obj_id,
+            opcode: msg.opcode,
+            interface: interface.map(str::to_string),
+            num_fds: msg.fds.len(),
+            raw,
+        }
+    }
+}
+
+/// Report of a panic that closed a connection
+#[derive(Serialize, Debug)]
+pub struct WlConnPanic {
+    /// The panic message
+    pub message: String,
+    /// Not known for panics outside of message processing
+    pub msg: Option<WlPanickedMsg>,
+}
+
+impl WlConnPanic {
+    pub fn new(payload: Box<dyn Any + Send>, msg: Option<WlPanickedMsg>) -> WlConnPanic {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "(non-string panic payload)".to_string()
+        };
+
+        WlConnPanic { message, msg }
+    }
+
+    /// The panic report behind `e`, if it's one
+    pub fn from_io_error(e: &io::Error) -> Option<&WlConnPanic> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for WlConnPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection panicked: {}", self.message)
+    }
+}
+
+impl Error for WlConnPanic {}
+
+impl From<WlConnPanic> for io::Error {
+    fn from(p: WlConnPanic) -> Self {
+        io::Error::other(p)
+    }
+}
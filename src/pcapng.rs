@@ -0,0 +1,162 @@
+//! Minimal pcapng writer for exporting Wayland traffic to Wireshark
+//!
+//! Every Wayland message becomes one packet using `LINKTYPE_WIRESHARK_UPPER_PDU`,
+//! prefixed with an exported-PDU tag that hands it straight to the dissector named
+//! `wayland`. Each connection gets its own interface so that Wireshark can tell
+//! them apart, and the direction of each message is carried in the packet flags:
+//! requests are outbound and events are inbound, as seen from the client.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::recorder::{WlDirection, WlRecordEntry, WlRecordingReader};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const LINKTYPE_WIRESHARK_UPPER_PDU: u16 = 252;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const EPB_FLAG_INBOUND: u32 = 0b01;
+const EPB_FLAG_OUTBOUND: u32 = 0b10;
+
+const EXP_PDU_TAG_END_OF_OPT: u16 = 0;
+const EXP_PDU_TAG_DISSECTOR_NAME: u16 = 12;
+
+/// Name of the dissector packets are handed to
+pub const WAYLAND_DISSECTOR_NAME: &str = "wayland";
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Assemble a block from its type and body, adding the length fields and padding
+fn write_block(w: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total_len = (12 + padded_len(body.len())) as u32;
+    w.write_u32::<LittleEndian>(block_type)?;
+    w.write_u32::<LittleEndian>(total_len)?;
+    w.write_all(body)?;
+    w.write_all(&[0u8; 3][..padded_len(body.len()) - body.len()])?;
+    w.write_u32::<LittleEndian>(total_len)
+}
+
+fn put_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.write_u16::<LittleEndian>(code).unwrap();
+    body.write_u16::<LittleEndian>(value.len() as u16).unwrap();
+    body.extend_from_slice(value);
+    body.resize(padded_len(body.len()), 0);
+}
+
+fn end_options(body: &mut Vec<u8>) {
+    put_option(body, OPT_END, &[]);
+}
+
+/// Start a new section. Must be written once at the start of every file.
+pub fn write_section_header(w: &mut impl Write) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+    body.write_u16::<LittleEndian>(1)?;
+    body.write_u16::<LittleEndian>(0)?;
+    // Section length unknown
+    body.write_i64::<LittleEndian>(-1)?;
+    put_option(&mut body, OPT_SHB_USERAPPL, b"wl-mitm");
+    end_options(&mut body);
+    write_block(w, BLOCK_SECTION_HEADER, &body)
+}
+
+/// Describe a new interface. Interfaces are numbered from 0 in the order they
+/// are written within a section.
+pub fn write_interface(w: &mut impl Write, name: &str) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.write_u16::<LittleEndian>(LINKTYPE_WIRESHARK_UPPER_PDU)?;
+    body.write_u16::<LittleEndian>(0)?;
+    // No snap length limit
+    body.write_u32::<LittleEndian>(0)?;
+    put_option(&mut body, OPT_IF_NAME, name.as_bytes());
+    // Nanosecond timestamps
+    put_option(&mut body, OPT_IF_TSRESOL, &[9]);
+    end_options(&mut body);
+    write_block(w, BLOCK_INTERFACE_DESCRIPTION, &body)
+}
+
+fn exported_pdu(data: &[u8]) -> Vec<u8> {
+    let mut pdu = Vec::with_capacity(16 + data.len());
+    // Exported PDU tags are always big-endian. String values are NUL-padded
+    // to a multiple of 4 bytes, and the padding counts towards their length.
+    let name_len = padded_len(WAYLAND_DISSECTOR_NAME.len() + 1);
+    pdu.extend_from_slice(&EXP_PDU_TAG_DISSECTOR_NAME.to_be_bytes());
+    pdu.extend_from_slice(&(name_len as u16).to_be_bytes());
+    pdu.extend_from_slice(WAYLAND_DISSECTOR_NAME.as_bytes());
+    pdu.resize(4 + name_len, 0);
+    pdu.extend_from_slice(&EXP_PDU_TAG_END_OF_OPT.to_be_bytes());
+    pdu.extend_from_slice(&0u16.to_be_bytes());
+    pdu.extend_from_slice(data);
+    pdu
+}
+
+/// Write one recorded message as a packet on interface `iface`
+pub fn write_packet(w: &mut impl Write, iface: u32, entry: &WlRecordEntry) -> io::Result<()> {
+    let packet = exported_pdu(&entry.data);
+
+    let mut body = Vec::with_capacity(64 + packet.len());
+    body.write_u32::<LittleEndian>(iface)?;
+    body.write_u32::<LittleEndian>((entry.time_ns >> 32) as u32)?;
+    body.write_u32::<LittleEndian>(entry.time_ns as u32)?;
+    body.write_u32::<LittleEndian>(packet.len() as u32)?;
+    body.write_u32::<LittleEndian>(packet.len() as u32)?;
+    body.extend_from_slice(&packet);
+    body.resize(padded_len(body.len()), 0);
+
+    let flags = match entry.direction {
+        WlDirection::ClientToServer => EPB_FLAG_OUTBOUND,
+        WlDirection::ServerToClient => EPB_FLAG_INBOUND,
+    };
+    put_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+
+    let mut comment = format!("verdict: {}", entry.verdict);
+    if !entry.fds.is_empty() {
+        comment.push_str(&format!(", fds: {}", entry.fds.len()));
+    }
+    put_option(&mut body, OPT_COMMENT, comment.as_bytes());
+    end_options(&mut body);
+
+    write_block(w, BLOCK_ENHANCED_PACKET, &body)
+}
+
+/// Convert recordings in any format [WlRecordingReader] understands into a single
+/// pcapng file, with one interface per recording and messages ordered by time
+pub fn export(recordings: &[PathBuf], output: &Path) -> io::Result<()> {
+    let mut entries = Vec::new();
+    for (iface, path) in recordings.iter().enumerate() {
+        for entry in WlRecordingReader::open(path)? {
+            entries.push((iface as u32, entry?));
+        }
+    }
+
+    // Stable, so messages with identical timestamps stay in recorded order
+    entries.sort_by_key(|(_, entry)| entry.time_ns);
+
+    let mut w = BufWriter::new(File::create(output)?);
+    write_section_header(&mut w)?;
+    for path in recordings.iter() {
+        write_interface(&mut w, &path.to_string_lossy())?;
+    }
+    for (iface, entry) in entries.iter() {
+        write_packet(&mut w, *iface, entry)?;
+    }
+
+    w.flush()
+}
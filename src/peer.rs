@@ -0,0 +1,88 @@
+//! Identity of the clients connecting to wl-mitm
+
+use std::{
+    ffi::CStr,
+    io,
+    os::fd::{AsRawFd, RawFd},
+    path::PathBuf,
+};
+
+use crate::{flatpak::WlFlatpakInfo, socket::WlStream};
+
+/// What we know about the process on the other end of a downstream connection,
+/// gathered once when the connection is accepted.
+///
+/// Everything is optional: none of this is available for TCP peers, and
+/// processes in other PID namespaces may not be resolvable.
+#[derive(Debug, Default, Clone)]
+pub struct WlPeerInfo {
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Resolved from `/proc/<pid>/exe`
+    pub exe: Option<PathBuf>,
+    /// LSM security context of the peer socket (`SO_PEERSEC`), e.g. an SELinux
+    /// context or an AppArmor profile name
+    pub security_context: Option<String>,
+    /// Present if the peer is a Flatpak app
+    pub flatpak: Option<WlFlatpakInfo>,
+}
+
+impl WlPeerInfo {
+    pub fn from_stream(stream: &WlStream) -> WlPeerInfo {
+        let WlStream::Unix(stream) = stream else {
+            return Default::default();
+        };
+
+        let mut info = WlPeerInfo::default();
+
+        if let Ok(cred) = stream.peer_cred() {
+            info.pid = cred.pid();
+            info.uid = Some(cred.uid());
+            info.gid = Some(cred.gid());
+        }
+
+        info.exe = info
+            .pid
+            .and_then(|pid| std::fs::read_link(format!("/proc/{}/exe", pid)).ok());
+        info.security_context = peer_security_context(stream.as_raw_fd());
+        info.flatpak = info.pid.and_then(WlFlatpakInfo::for_pid);
+
+        info
+    }
+}
+
+fn peer_security_context(fd: RawFd) -> Option<String> {
+    let mut buf = vec![0u8; 256];
+    loop {
+        let mut len = buf.len() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERSEC,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res == 0 {
+            buf.truncate(len as usize);
+            break;
+        }
+
+        // The buffer was too small, and `len` is what it takes
+        let too_small = io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE);
+        if !too_small || len as usize <= buf.len() {
+            return None;
+        }
+        buf.resize(len as usize, 0);
+    }
+
+    // The returned context may or may not be NUL-terminated
+    let ctx = match CStr::from_bytes_until_nul(&buf) {
+        Ok(s) => s.to_string_lossy().into_owned(),
+        Err(_) => String::from_utf8_lossy(&buf).into_owned(),
+    };
+
+    Some(ctx).filter(|s| !s.is_empty())
+}
@@ -0,0 +1,256 @@
+//! `wl-mitm --generate-policy`: a suggested config, from what clients actually use
+//!
+//! While generating a policy, every known global is announced to clients
+//! regardless of `allowed_globals`, and wl-mitm notes which globals clients
+//! bind and which requests they send. From that, it suggests a `[filter]`
+//! section: `allowed_globals` lists the globals that were bound, and requests
+//! of sensitive interfaces that were used get candidate `[[filter.requests]]`
+//! entries. The suggestion is rewritten as the session goes on, and is a
+//! starting point to be reviewed, not a policy to be used as-is.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{error, info};
+
+/// Requests worth a filter rule if a client uses them, as
+/// (interface, requests, action, desc)
+const SENSITIVE_REQUESTS: &[(&str, &[&str], &str, &str)] = &[
+    (
+        "zwlr_screencopy_manager_v1",
+        &["capture_output", "capture_output_region"],
+        "ask",
+        "capturing the screen",
+    ),
+    (
+        "zwlr_export_dmabuf_manager_v1",
+        &["capture_output"],
+        "ask",
+        "capturing the screen",
+    ),
+    (
+        "ext_image_copy_capture_manager_v1",
+        &["create_session", "create_pointer_cursor_session"],
+        "ask",
+        "capturing the screen or a window",
+    ),
+    (
+        "hyprland_toplevel_export_manager_v1",
+        &[
+            "capture_toplevel",
+            "capture_toplevel_with_wlr_toplevel_handle",
+        ],
+        "ask",
+        "capturing a window",
+    ),
+    (
+        "zwlr_data_control_offer_v1",
+        &["receive"],
+        "ask",
+        "pasting from clipboard (from background)",
+    ),
+    (
+        "zwlr_data_control_device_v1",
+        &["set_selection", "set_primary_selection"],
+        "ask",
+        "overriding clipboard selection",
+    ),
+    (
+        "ext_data_control_offer_v1",
+        &["receive"],
+        "ask",
+        "pasting from clipboard (from background)",
+    ),
+    (
+        "ext_data_control_device_v1",
+        &["set_selection", "set_primary_selection"],
+        "ask",
+        "overriding clipboard selection",
+    ),
+    (
+        "wl_data_offer",
+        &["receive"],
+        "notify",
+        "pasted from clipboard or accepted drag and drop",
+    ),
+    (
+        "zwp_primary_selection_offer_v1",
+        &["receive"],
+        "notify",
+        "pasted from primary selection",
+    ),
+    (
+        "zwlr_virtual_pointer_manager_v1",
+        &[
+            "create_virtual_pointer",
+            "create_virtual_pointer_with_output",
+        ],
+        "ask",
+        "emulating pointer input",
+    ),
+    (
+        "zwp_virtual_keyboard_manager_v1",
+        &["create_virtual_keyboard"],
+        "ask",
+        "emulating keyboard input",
+    ),
+    (
+        "zwlr_gamma_control_manager_v1",
+        &["get_gamma_control"],
+        "ask",
+        "changing display gamma",
+    ),
+    (
+        "zwp_keyboard_shortcuts_inhibit_manager_v1",
+        &["inhibit_shortcuts"],
+        "notify",
+        "inhibiting compositor keyboard shortcuts",
+    ),
+];
+
+/// The (interface, requests) of every candidate rule, for checking them
+/// against the known protocols
+pub fn sensitive_requests() -> impl Iterator<Item = (&'static str, &'static [&'static str])> {
+    SENSITIVE_REQUESTS
+        .iter()
+        .map(|(interface, requests, _, _)| (*interface, *requests))
+}
+
+#[derive(Default)]
+struct WlObservedUsage {
+    globals: BTreeSet<&'static str>,
+    requests: BTreeMap<&'static str, BTreeSet<&'static str>>,
+    /// Whether anything new was seen since the suggestion was last written
+    dirty: bool,
+}
+
+/// Collects what clients of all connections use, and writes the suggested config
+pub struct WlPolicyGenerator {
+    output: PathBuf,
+    usage: Mutex<WlObservedUsage>,
+}
+
+impl WlPolicyGenerator {
+    pub fn new(output: impl Into<PathBuf>) -> Arc<WlPolicyGenerator> {
+        Arc::new(WlPolicyGenerator {
+            output: output.into(),
+            usage: Mutex::new(Default::default()),
+        })
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// A client bound a global of `interface`
+    pub fn record_bind(&self, interface: &'static str) {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.globals.insert(interface) {
+            usage.dirty = true;
+        }
+    }
+
+    /// A client sent `request` on an object of `interface`
+    pub fn record_request(&self, interface: &'static str, request: &'static str) {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.requests.entry(interface).or_default().insert(request) {
+            usage.dirty = true;
+        }
+    }
+
+    /// The suggested config, as TOML
+    pub fn generate(&self) -> String {
+        let usage = self.usage.lock().unwrap();
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "# Suggested by `wl-mitm --generate-policy` from the globals and requests\n\
+             # clients used. Review it, then merge it into a config with a [socket] section."
+        )
+        .unwrap();
+
+        out.push_str("\n[filter]\nallowed_globals = [\n");
+        for global in &usage.globals {
+            writeln!(out, "    {:?},", global).unwrap();
+        }
+        out.push_str("]\n");
+
+        if !usage.requests.is_empty() {
+            out.push_str("\n# Requests used, by interface:\n");
+            for (interface, requests) in &usage.requests {
+                let requests: Vec<_> = requests.iter().copied().collect();
+                writeln!(out, "#   {}: {}", interface, requests.join(", ")).unwrap();
+            }
+        }
+
+        let candidates = SENSITIVE_REQUESTS
+            .iter()
+            .filter(|(interface, requests, _, _)| {
+                usage
+                    .requests
+                    .get(interface)
+                    .is_some_and(|used| requests.iter().any(|r| used.contains(r)))
+            });
+        for (interface, requests, action, desc) in candidates {
+            let requests: Vec<_> = requests.iter().map(|r| format!("{:?}", r)).collect();
+            writeln!(
+                out,
+                "\n[[filter.requests]]\ninterface = {:?}\nrequests = [{}]\naction = {:?}\ndesc = {:?}",
+                interface,
+                requests.join(", "),
+                action,
+                desc
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    /// Rewrite the suggested config if anything new was seen since the last time
+    pub async fn flush(&self) -> io::Result<()> {
+        let contents = {
+            let mut usage = self.usage.lock().unwrap();
+            if !usage.dirty {
+                return Ok(());
+            }
+            usage.dirty = false;
+            drop(usage);
+            self.generate()
+        };
+
+        // Replace the file atomically, so it is never seen half-written
+        let mut tmp = self.output.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let res = async {
+            tokio::fs::write(&tmp, contents).await?;
+            tokio::fs::rename(&tmp, &self.output).await
+        }
+        .await;
+
+        if res.is_err() {
+            // Try again next time
+            self.usage.lock().unwrap().dirty = true;
+        } else {
+            info!(output = ?self.output, "Wrote suggested policy");
+        }
+        res
+    }
+
+    /// Flush every `interval`, forever
+    pub async fn run_writer(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.flush().await {
+                error!(error = ?e, output = ?self.output, "Failed to write suggested policy");
+            }
+        }
+    }
+}
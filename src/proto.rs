@@ -1,6 +1,8 @@
 //! Protocol definitions necessary for this MITM proxy
 
-use std::{any::TypeId, collections::HashMap, os::fd::OwnedFd, sync::LazyLock};
+use std::{any::TypeId, collections::HashMap, fmt, os::fd::OwnedFd, sync::LazyLock};
+
+use byteorder::{ByteOrder, NativeEndian};
 
 use crate::{
     codec::WlRawMsg,
@@ -40,7 +42,7 @@ mod __private {
 }
 
 #[allow(private_bounds, private_interfaces)]
-pub trait WlParsedMessage<'a>: __private::WlParsedMessagePrivate {
+pub trait WlParsedMessage<'a>: __private::WlParsedMessagePrivate + fmt::Display {
     fn opcode() -> u16;
     fn object_type() -> WlObjectType;
     fn msg_name() -> &'static str;
@@ -106,7 +108,7 @@ pub unsafe trait DowncastableWlParsedMessage<'a>: Send + WlParsedMessage<'a> {
 
 /// The implementation of dyn-available methods and downcasting for
 /// [DowncastableWlParsedMessage]
-pub trait AnyWlParsedMessage: Send {
+pub trait AnyWlParsedMessage: Send + fmt::Display {
     fn static_type_id(&self) -> TypeId;
     fn opcode(&self) -> u16;
     fn object_type(&self) -> WlObjectType;
@@ -166,9 +168,7 @@ impl<'out, 'data: 'out> dyn AnyWlParsedMessage + 'data {
     /// 1. 'data outlives 'out (guaranteed by the trait bound above)
     /// 2. The type implementing [DowncastableWlParsedMessage] does not contain any lifetime other than
     ///    'data (or 'a in the trait's definition).
-    pub fn downcast_ref<
-        T: AnyWlParsedMessage + DowncastableWlParsedMessage<'data> + 'data,
-    >(
+    pub fn downcast_ref<T: AnyWlParsedMessage + DowncastableWlParsedMessage<'data> + 'data>(
         &'data self,
     ) -> Option<&'out T> {
         if self.static_type_id() != TypeId::of::<T::Static>() {
@@ -196,6 +196,59 @@ pub trait WlMsgParserFn: Send + Sync {
         objects: &'obj WlObjects,
         msg: &'msg WlRawMsg,
     ) -> WaylandProtocolParsingOutcome<Box<dyn AnyWlParsedMessage + 'msg>>;
+
+    /// Name of the message this parses, without having to parse one
+    fn msg_name(&self) -> &'static str;
+    /// [AnyWlParsedMessage::static_type_id] of the messages this parses
+    fn static_type_id(&self) -> TypeId;
+    fn num_consumed_fds(&self) -> usize;
+    fn is_destructor(&self) -> bool;
+    /// Whether the message has `new_id` arguments of a fixed interface,
+    /// i.e. [AnyWlParsedMessage::known_objects_created] returns `Some`
+    fn creates_objects(&self) -> bool;
+    /// How the arguments are laid out, see [is_well_formed]
+    fn signature(&self) -> &'static [WlWireArg];
+}
+
+/// The shape of an argument on the wire
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WlWireArg {
+    /// Any 32-bit argument: int, uint, fixed, object, new_id or enum
+    Word,
+    /// A length-prefixed, NUL-terminated string padded to 32 bits
+    String,
+    /// A length-prefixed byte array padded to 32 bits
+    Array,
+    /// Passed out of band; nothing in the payload
+    Fd,
+}
+
+/// Whether `payload` holds exactly the arguments in `signature`, without
+/// parsing them. Used for messages which are passed along unparsed.
+pub fn is_well_formed(signature: &[WlWireArg], payload: &[u8]) -> bool {
+    let mut pos = 0usize;
+    for arg in signature {
+        match arg {
+            WlWireArg::Fd => {}
+            WlWireArg::Word => pos += 4,
+            WlWireArg::String | WlWireArg::Array => {
+                let Some(prefix) = payload.get(pos..pos + 4) else {
+                    return false;
+                };
+                let len = NativeEndian::read_u32(prefix) as usize;
+                pos += 4;
+                // Strings have to end where their length says they do
+                if *arg == WlWireArg::String && len > 0 && payload.get(pos + len - 1) != Some(&0) {
+                    return false;
+                }
+                pos += len.next_multiple_of(4);
+            }
+        }
+        if pos > payload.len() {
+            return false;
+        }
+    }
+    pos == payload.len()
 }
 
 /// Messages that can be converted back to [WlRawMsg]
@@ -271,6 +324,38 @@ pub fn lookup_known_object_type(name: &str) -> Option<WlObjectType> {
     WL_KNOWN_OBJECT_TYPES.get(name).copied()
 }
 
+/// The parser for a request, to tell what it is without parsing it
+pub fn lookup_request_parser(
+    obj_type: WlObjectType,
+    opcode: u16,
+) -> Option<&'static dyn WlMsgParserFn> {
+    WL_EVENT_REQUEST_PARSERS.1.get(&(obj_type, opcode)).copied()
+}
+
+/// The parser for an event, to tell what it is without parsing it
+pub fn lookup_event_parser(
+    obj_type: WlObjectType,
+    opcode: u16,
+) -> Option<&'static dyn WlMsgParserFn> {
+    WL_EVENT_REQUEST_PARSERS.0.get(&(obj_type, opcode)).copied()
+}
+
+/// All known requests, as (object type, opcode, parser)
+pub fn known_requests() -> impl Iterator<Item = (WlObjectType, u16, &'static dyn WlMsgParserFn)> {
+    WL_EVENT_REQUEST_PARSERS
+        .1
+        .iter()
+        .map(|((obj_type, opcode), parser)| (*obj_type, *opcode, *parser))
+}
+
+/// All known events, as (object type, opcode, parser)
+pub fn known_events() -> impl Iterator<Item = (WlObjectType, u16, &'static dyn WlMsgParserFn)> {
+    WL_EVENT_REQUEST_PARSERS
+        .0
+        .iter()
+        .map(|((obj_type, opcode), parser)| (*obj_type, *opcode, *parser))
+}
+
 /// The default object ID of wl_display
 pub const WL_DISPLAY_OBJECT_ID: u32 = 1;
 
@@ -0,0 +1,327 @@
+//! The entry point for embedding wl-mitm: accepts clients and proxies each of them
+
+use std::{
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tracing::{Instrument, error, info};
+
+use crate::{
+    audit::WlAuditLog,
+    config::Config,
+    control::{WlControl, WlControlConnInfo},
+    dump::WlDumper,
+    duplex,
+    health::WlHealth,
+    learning::WlLearning,
+    logging,
+    logind::WlSession,
+    panic::{self, WlConnPanic},
+    peer::WlPeerInfo,
+    policygen::WlPolicyGenerator,
+    socket::{WlListener, WlSocketAddr, WlStream},
+    trace::WlTracer,
+};
+
+/// A configured proxy, cheap to clone. Build one with [Proxy::builder].
+#[derive(Clone)]
+pub struct Proxy {
+    config: Arc<Config>,
+    control: Option<Arc<WlControl>>,
+    health: Arc<WlHealth>,
+    dumper: Arc<WlDumper>,
+    /// Overrides the upstreams picked through [Config::upstream_for]
+    upstream: Option<WlSocketAddr>,
+    policy: Option<Arc<WlPolicyGenerator>>,
+    learning: Option<Arc<WlLearning>>,
+    session: Option<Arc<WlSession>>,
+    audit: Option<Arc<WlAuditLog>>,
+    next_conn_id: Arc<AtomicU64>,
+}
+
+impl Proxy {
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::default()
+    }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    /// Another proxy sharing everything with this one but the config, e.g.
+    /// for one of [Config::proxies]. Connection IDs are unique across both.
+    pub fn with_config(&self, config: Arc<Config>) -> Proxy {
+        Proxy {
+            config,
+            ..self.clone()
+        }
+    }
+
+    pub fn control(&self) -> Option<&Arc<WlControl>> {
+        self.control.as_ref()
+    }
+
+    pub fn health(&self) -> &Arc<WlHealth> {
+        &self.health
+    }
+
+    pub fn dumper(&self) -> &Arc<WlDumper> {
+        &self.dumper
+    }
+
+    /// Present if learning is enabled in the config
+    pub fn learning(&self) -> Option<&Arc<WlLearning>> {
+        self.learning.as_ref()
+    }
+
+    /// Accept clients from `listener` until accepting fails or the session
+    /// ends, proxying each of them in its own task
+    pub async fn serve(&self, listener: WlListener) -> io::Result<()> {
+        self.health.set_listening(true);
+        let res = match self.session {
+            Some(ref session) => tokio::select! {
+                res = self.accept_loop(listener) => res,
+                _ = session.ended() => Ok(()),
+            },
+            None => self.accept_loop(listener).await,
+        };
+        self.health.set_listening(false);
+        res
+    }
+
+    async fn accept_loop(&self, listener: WlListener) -> io::Result<()> {
+        loop {
+            let (conn, addr) = listener.accept().await?;
+            let conn_id = self.next_conn_id.fetch_add(1, Ordering::Relaxed);
+            let peer = WlPeerInfo::from_stream(&conn);
+            let span = logging::conn_span(conn_id, &peer);
+            let proxy = self.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = proxy.run_conn(conn_id, peer, conn, &addr).await {
+                        error!(error = ?e, "Failure handling connection");
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    /// Proxy a single client connection accepted by the caller (or one end
+    /// of a socket pair) until either side closes it
+    pub async fn handle_conn(&self, conn: WlStream) -> io::Result<()> {
+        let conn_id = self.next_conn_id.fetch_add(1, Ordering::Relaxed);
+        let peer = WlPeerInfo::from_stream(&conn);
+        let span = logging::conn_span(conn_id, &peer);
+        self.run_conn(conn_id, peer, conn, "(embedded)")
+            .instrument(span)
+            .await
+    }
+
+    async fn run_conn(
+        &self,
+        conn_id: u64,
+        peer: WlPeerInfo,
+        conn: WlStream,
+        addr: &str,
+    ) -> io::Result<()> {
+        let upstream = self
+            .upstream
+            .clone()
+            .unwrap_or_else(|| self.config.upstream_for(&peer));
+        info!(
+            conn_id = conn_id,
+            peer = ?peer,
+            upstream = %upstream,
+            "Accepted new client {}",
+            addr
+        );
+
+        let control_handle = self.control.as_ref().map(|c| {
+            c.register_conn(WlControlConnInfo {
+                conn_id,
+                peer: format!("{:?}", peer),
+                upstream: upstream.to_string(),
+            })
+        });
+
+        let tracer = WlTracer::create(&self.config.trace, conn_id, &peer)
+            .inspect_err(|e| error!(error = ?e, "Cannot create trace file"))
+            .ok()
+            .flatten();
+
+        let dumper = self.dumper.clone();
+        let policy = self.policy.clone();
+        let learning = self.learning.clone();
+        let session = self.session.clone();
+        let audit = self.audit.clone();
+
+        // Panics outside of message processing are caught here, without the
+        // message that caused them
+        let res = panic::catch_unwind(duplex::handle_conn(
+            self.config.clone(),
+            self.control.clone(),
+            control_handle,
+            conn_id,
+            upstream,
+            |duplex| {
+                if let Some(tracer) = tracer {
+                    duplex.set_tracer(tracer);
+                }
+                duplex.set_dumper(dumper);
+                duplex.set_peer(peer);
+                if let Some(policy) = policy {
+                    duplex.set_policy_generator(policy);
+                }
+                if let Some(learning) = learning {
+                    duplex.set_learning(learning);
+                }
+                if let Some(session) = session {
+                    duplex.set_session(session);
+                }
+                if let Some(audit) = audit {
+                    duplex.set_audit(audit);
+                }
+            },
+            conn,
+        ))
+        .await
+        .unwrap_or_else(|payload| Err(WlConnPanic::new(payload, None).into()));
+
+        if let Some(ref policy) = self.policy
+            && let Err(e) = policy.flush().await
+        {
+            error!(error = ?e, output = ?policy.output(), "Failed to write suggested policy");
+        }
+
+        if let Some(ref learning) = self.learning
+            && let Err(e) = learning.flush().await
+        {
+            error!(error = ?e, "Failed to write app profiles");
+        }
+
+        if let Some(report) = res.as_ref().err().and_then(WlConnPanic::from_io_error) {
+            self.health.record_conn_panic();
+            error!(
+                conn_id = conn_id,
+                report = %serde_json::to_string(report).unwrap_or_default(),
+                "Connection panicked, closing it"
+            );
+        }
+
+        res
+    }
+}
+
+#[derive(Default)]
+pub struct ProxyBuilder {
+    config: Option<Arc<Config>>,
+    control: Option<Arc<WlControl>>,
+    health: Option<Arc<WlHealth>>,
+    dumper: Option<Arc<WlDumper>>,
+    upstream: Option<WlSocketAddr>,
+    policy: Option<Arc<WlPolicyGenerator>>,
+    learning: Option<Arc<WlLearning>>,
+    session: Option<Arc<WlSession>>,
+    audit: Option<Arc<WlAuditLog>>,
+}
+
+impl ProxyBuilder {
+    /// The config to use. Required.
+    pub fn config(mut self, config: impl Into<Arc<Config>>) -> Self {
+        self.config = Some(config.into());
+        self
+    }
+
+    /// Report to and take runtime rule changes from this [WlControl]
+    pub fn control(mut self, control: Arc<WlControl>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Report listening state to this [WlHealth], e.g. one shared with a [WlControl]
+    pub fn health(mut self, health: Arc<WlHealth>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Dump connection state whenever this [WlDumper] is triggered, e.g. one
+    /// shared with a [WlControl]
+    pub fn dumper(mut self, dumper: Arc<WlDumper>) -> Self {
+        self.dumper = Some(dumper);
+        self
+    }
+
+    /// Connect every client to this upstream, ignoring the upstreams and
+    /// routes from the config
+    pub fn upstream(mut self, upstream: WlSocketAddr) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Note what clients use in this [WlPolicyGenerator], announcing every
+    /// known global to them regardless of `allowed_globals`
+    pub fn policy_generator(mut self, policy: Arc<WlPolicyGenerator>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Keep app profiles in this [WlLearning], e.g. one whose profiles are
+    /// also written periodically. One is created if learning is enabled in
+    /// the config.
+    pub fn learning(mut self, learning: Arc<WlLearning>) -> Self {
+        self.learning = Some(learning);
+        self
+    }
+
+    /// Filter `lock.deny` requests while this [WlSession] is locked, and
+    /// stop serving once it ends. Its watcher has to be run separately, see
+    /// [WlSession::run_watcher].
+    pub fn session(mut self, session: Arc<WlSession>) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Append every policy decision to this [WlAuditLog]. One is opened if
+    /// auditing is enabled in the config.
+    pub fn audit(mut self, audit: Arc<WlAuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn build(self) -> io::Result<Proxy> {
+        let config = self
+            .config
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "a config is required"))?;
+
+        let health = self.health.unwrap_or_else(|| WlHealth::new(config.clone()));
+        let dumper = self.dumper.unwrap_or_else(|| WlDumper::new(config.clone()));
+        let learning = self.learning.or_else(|| WlLearning::new(&config));
+        let audit = match self.audit {
+            Some(audit) => Some(audit),
+            None => WlAuditLog::open(&config.audit)?,
+        };
+
+        Ok(Proxy {
+            config,
+            control: self.control,
+            health,
+            dumper,
+            upstream: self.upstream,
+            policy: self.policy,
+            learning,
+            session: self.session,
+            audit,
+            next_conn_id: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Shorthand for [Self::build] followed by [Proxy::serve]
+    pub async fn serve(self, listener: WlListener) -> io::Result<()> {
+        self.build()?.serve(listener).await
+    }
+}
@@ -0,0 +1,360 @@
+//! Recording of all messages passing through a connection, for offline analysis
+
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::fs::{DirBuilderExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nix::sys::stat::{SFlag, fstat};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{codec::WlRawMsg, config::WlRecordingFormat, pcapng, state::WlMitmVerdict};
+
+/// Magic bytes at the start of every binary recording
+pub const WL_RECORDING_MAGIC: &[u8; 8] = b"WLMITMR1";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WlDirection {
+    /// Client -> server (a request)
+    #[serde(rename = "c2s")]
+    ClientToServer,
+    /// Server -> client (an event)
+    #[serde(rename = "s2c")]
+    ServerToClient,
+}
+
+/// What kind of file an fd attached to a message refers to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WlFdKind {
+    Regular,
+    Fifo,
+    Socket,
+    Char,
+    Other,
+}
+
+impl WlFdKind {
+    fn from_u8(v: u8) -> WlFdKind {
+        match v {
+            0 => WlFdKind::Regular,
+            1 => WlFdKind::Fifo,
+            2 => WlFdKind::Socket,
+            3 => WlFdKind::Char,
+            _ => WlFdKind::Other,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlFdMeta {
+    pub kind: WlFdKind,
+    /// Size of the file, if it's a regular file
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub size: Option<u64>,
+}
+
+impl WlFdMeta {
+    pub fn from_fd(fd: &OwnedFd) -> WlFdMeta {
+        let Ok(st) = fstat(fd.as_raw_fd()) else {
+            return WlFdMeta {
+                kind: WlFdKind::Other,
+                size: None,
+            };
+        };
+
+        let kind = match SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT {
+            SFlag::S_IFREG => WlFdKind::Regular,
+            SFlag::S_IFIFO => WlFdKind::Fifo,
+            SFlag::S_IFSOCK => WlFdKind::Socket,
+            SFlag::S_IFCHR => WlFdKind::Char,
+            _ => WlFdKind::Other,
+        };
+
+        WlFdMeta {
+            kind,
+            size: (kind == WlFdKind::Regular).then_some(st.st_size as u64),
+        }
+    }
+}
+
+/// One recorded message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WlRecordEntry {
+    /// Wall-clock time, in nanoseconds since the Unix epoch
+    pub time_ns: u64,
+    /// Monotonic time since the connection was accepted, in nanoseconds
+    pub offset_ns: u64,
+    pub conn_id: u64,
+    pub direction: WlDirection,
+    pub obj_id: u32,
+    pub opcode: u16,
+    /// The verdict reached by wl-mitm, in its debug representation (e.g. "Allowed")
+    pub verdict: String,
+    pub fds: Vec<WlFdMeta>,
+    /// The full message including its header, hex-encoded in JSONL recordings
+    #[serde(with = "hex_bytes")]
+    pub data: Vec<u8>,
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(
+            &data
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    .ok_or_else(|| D::Error::custom("invalid hex string"))
+            })
+            .collect()
+    }
+}
+
+/// Writes every message of one connection to a file, either as JSON lines,
+/// as pcapng (see [crate::pcapng]), or in a compact binary format:
+///
+/// ```text
+/// header: magic (8 bytes) | conn_id (u64) | start time_ns (u64)
+/// record: offset_ns (u64) | direction (u8) | verdict len (u8) | verdict (utf8)
+///         | num_fds (u16) | (kind (u8) | size (u64)) * num_fds | len (u32) | data
+/// ```
+///
+/// All integers in the binary format are little-endian. Message data itself
+/// is recorded as-is, i.e. in the recording host's native endianness.
+pub struct WlRecorder {
+    writer: BufWriter<File>,
+    format: WlRecordingFormat,
+    conn_id: u64,
+    start: Instant,
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl WlRecorder {
+    /// Create a new recording file for connection `conn_id` under `dir`.
+    /// Recordings contain every key pressed, and are only readable by us.
+    pub fn create(dir: &Path, format: WlRecordingFormat, conn_id: u64) -> io::Result<WlRecorder> {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+        let start_time_ns = now_ns();
+        let ext = match format {
+            WlRecordingFormat::Jsonl => "jsonl",
+            WlRecordingFormat::Binary => "wlrec",
+            WlRecordingFormat::Pcapng => "pcapng",
+        };
+        let path: PathBuf = dir.join(format!(
+            "wl-mitm-{}-{}-{}.{}",
+            std::process::id(),
+            conn_id,
+            start_time_ns / 1_000_000,
+            ext
+        ));
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+
+        match format {
+            WlRecordingFormat::Jsonl => {}
+            WlRecordingFormat::Binary => {
+                writer.write_all(WL_RECORDING_MAGIC)?;
+                writer.write_u64::<LittleEndian>(conn_id)?;
+                writer.write_u64::<LittleEndian>(start_time_ns)?;
+            }
+            WlRecordingFormat::Pcapng => {
+                pcapng::write_section_header(&mut writer)?;
+                pcapng::write_interface(&mut writer, &format!("wl-mitm conn {}", conn_id))?;
+            }
+        }
+
+        Ok(WlRecorder {
+            writer,
+            format,
+            conn_id,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        direction: WlDirection,
+        msg: &WlRawMsg,
+        verdict: &WlMitmVerdict,
+    ) -> io::Result<()> {
+        let entry = WlRecordEntry {
+            time_ns: now_ns(),
+            offset_ns: self.start.elapsed().as_nanos() as u64,
+            conn_id: self.conn_id,
+            direction,
+            obj_id: msg.obj_id,
+            opcode: msg.opcode,
+            verdict: format!("{:?}", verdict),
+            fds: msg.fds.iter().map(WlFdMeta::from_fd).collect(),
+            data: msg.as_bytes().to_vec(),
+        };
+
+        match self.format {
+            WlRecordingFormat::Jsonl => {
+                serde_json::to_writer(&mut self.writer, &entry)?;
+                self.writer.write_all(b"\n")?;
+            }
+            WlRecordingFormat::Binary => self.write_binary(&entry)?,
+            WlRecordingFormat::Pcapng => pcapng::write_packet(&mut self.writer, 0, &entry)?,
+        }
+
+        // Flush every message so that recordings are complete even if we crash
+        self.writer.flush()
+    }
+
+    fn write_binary(&mut self, entry: &WlRecordEntry) -> io::Result<()> {
+        let w = &mut self.writer;
+        w.write_u64::<LittleEndian>(entry.offset_ns)?;
+        w.write_u8(match entry.direction {
+            WlDirection::ClientToServer => 0,
+            WlDirection::ServerToClient => 1,
+        })?;
+        w.write_u8(entry.verdict.len() as u8)?;
+        w.write_all(entry.verdict.as_bytes())?;
+        w.write_u16::<LittleEndian>(entry.fds.len() as u16)?;
+        for fd in entry.fds.iter() {
+            w.write_u8(fd.kind as u8)?;
+            w.write_u64::<LittleEndian>(fd.size.unwrap_or(0))?;
+        }
+        w.write_u32::<LittleEndian>(entry.data.len() as u32)?;
+        w.write_all(&entry.data)
+    }
+}
+
+/// Reads back recordings written by [WlRecorder] in either format
+pub enum WlRecordingReader {
+    Jsonl(io::Lines<BufReader<File>>),
+    Binary {
+        reader: BufReader<File>,
+        conn_id: u64,
+        start_time_ns: u64,
+    },
+}
+
+impl WlRecordingReader {
+    /// Open a recording, detecting its format from its first bytes
+    pub fn open(path: &Path) -> io::Result<WlRecordingReader> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        if !reader.fill_buf()?.starts_with(WL_RECORDING_MAGIC) {
+            return Ok(WlRecordingReader::Jsonl(reader.lines()));
+        }
+
+        reader.consume(WL_RECORDING_MAGIC.len());
+        let conn_id = reader.read_u64::<LittleEndian>()?;
+        let start_time_ns = reader.read_u64::<LittleEndian>()?;
+
+        Ok(WlRecordingReader::Binary {
+            reader,
+            conn_id,
+            start_time_ns,
+        })
+    }
+
+    fn read_binary(
+        reader: &mut BufReader<File>,
+        conn_id: u64,
+        start_time_ns: u64,
+    ) -> io::Result<Option<WlRecordEntry>> {
+        let offset_ns = match reader.read_u64::<LittleEndian>() {
+            Ok(offset_ns) => offset_ns,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let direction = match reader.read_u8()? {
+            0 => WlDirection::ClientToServer,
+            _ => WlDirection::ServerToClient,
+        };
+
+        let mut verdict = vec![0u8; reader.read_u8()? as usize];
+        reader.read_exact(&mut verdict)?;
+
+        let num_fds = reader.read_u16::<LittleEndian>()?;
+        let mut fds = Vec::with_capacity(num_fds as usize);
+        for _ in 0..num_fds {
+            let kind = WlFdKind::from_u8(reader.read_u8()?);
+            let size = reader.read_u64::<LittleEndian>()?;
+            fds.push(WlFdMeta {
+                kind,
+                size: (kind == WlFdKind::Regular).then_some(size),
+            });
+        }
+
+        let mut data = vec![0u8; reader.read_u32::<LittleEndian>()? as usize];
+        reader.read_exact(&mut data)?;
+
+        let (obj_id, opcode) = if data.len() >= 8 {
+            (
+                u32::from_ne_bytes(data[0..4].try_into().unwrap()),
+                (u32::from_ne_bytes(data[4..8].try_into().unwrap()) & 0xFFFF) as u16,
+            )
+        } else {
+            (0, 0)
+        };
+
+        Ok(Some(WlRecordEntry {
+            time_ns: start_time_ns + offset_ns,
+            offset_ns,
+            conn_id,
+            direction,
+            obj_id,
+            opcode,
+            verdict: String::from_utf8_lossy(&verdict).into_owned(),
+            fds,
+            data,
+        }))
+    }
+}
+
+impl Iterator for WlRecordingReader {
+    type Item = io::Result<WlRecordEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            WlRecordingReader::Jsonl(lines) => loop {
+                match lines.next()? {
+                    Ok(line) if line.trim().is_empty() => continue,
+                    Ok(line) => return Some(serde_json::from_str(&line).map_err(io::Error::from)),
+                    Err(e) => return Some(Err(e)),
+                }
+            },
+            WlRecordingReader::Binary {
+                reader,
+                conn_id,
+                start_time_ns,
+            } => Self::read_binary(reader, *conn_id, *start_time_ns).transpose(),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! `wl-mitm replay`: replays the client side of a recorded session against a compositor
+
+use std::{
+    fs::File,
+    io,
+    os::fd::OwnedFd,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use bytes::BufMut;
+use nix::sys::memfd::{MemFdCreateFlag, memfd_create};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    codec::{DecoderOutcome, WlRawMsg},
+    io_util::{WlMsgReader, WlMsgWriter},
+    objects::WlObjects,
+    proto::{
+        WL_DISPLAY_OBJECT_ID, WaylandProtocolParsingOutcome, WlDisplayErrorEvent, WlParsedMessage,
+    },
+    recorder::{WlDirection, WlFdKind, WlFdMeta, WlRecordEntry, WlRecordingReader},
+    socket::WlSocketAddr,
+};
+
+/// How long to keep listening for events (errors in particular) after the
+/// last request has been sent
+const REPLAY_LINGER: Duration = Duration::from_secs(1);
+
+pub struct WlReplayOptions {
+    /// Send requests as fast as possible instead of preserving recorded timing
+    pub fast: bool,
+    pub socket: WlSocketAddr,
+}
+
+/// Only requests that actually made it to the server are replayed.
+fn is_replayable(entry: &WlRecordEntry) -> bool {
+    entry.direction == WlDirection::ClientToServer && entry.verdict == "Allowed"
+}
+
+/// fds themselves aren't part of recordings, so make up ones that look like
+/// the originals as far as the compositor can tell: zero-filled memfds of the
+/// same size for regular files (e.g. shm pools) and pipes for fifos.
+fn placeholder_fd(meta: &WlFdMeta) -> io::Result<OwnedFd> {
+    match meta.kind {
+        WlFdKind::Regular => {
+            let file = File::from(memfd_create(
+                c"wl-mitm-replay",
+                MemFdCreateFlag::MFD_CLOEXEC,
+            )?);
+            file.set_len(meta.size.unwrap_or(0))?;
+            Ok(file.into())
+        }
+        WlFdKind::Fifo => {
+            // Clients hand out the write end of pipes (e.g. wl_data_offer.receive);
+            // nobody is going to read what gets written to it.
+            let (_, writer) = io::pipe()?;
+            Ok(writer.into())
+        }
+        _ => Ok(File::open("/dev/null")?.into()),
+    }
+}
+
+fn entry_to_msg(entry: &WlRecordEntry) -> io::Result<WlRawMsg> {
+    if entry.data.len() < 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "recorded message is shorter than a header",
+        ));
+    }
+
+    let fds = entry
+        .fds
+        .iter()
+        .map(placeholder_fd)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok(WlRawMsg::build(
+        entry.obj_id,
+        entry.opcode,
+        |buf, msg_fds| {
+            buf.put_slice(&entry.data[8..]);
+            msg_fds.extend(fds);
+        },
+    ))
+}
+
+/// Log errors sent by the compositor; everything else is just drained
+fn handle_event(objects: &WlObjects, outcome: DecoderOutcome) -> io::Result<()> {
+    match outcome {
+        DecoderOutcome::Decoded(msg) => {
+            if msg.obj_id != WL_DISPLAY_OBJECT_ID {
+                return Ok(());
+            }
+
+            if let WaylandProtocolParsingOutcome::Ok(err) =
+                WlDisplayErrorEvent::try_from_msg(objects, &msg)
+            {
+                error!(
+                    object_id = err.object_id,
+                    code = err.code,
+                    "Compositor sent an error: {}",
+                    err.message
+                );
+                return Err(io::Error::other("compositor sent an error"));
+            }
+
+            Ok(())
+        }
+        DecoderOutcome::Eof => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "compositor closed the connection",
+        )),
+        DecoderOutcome::Incomplete => Ok(()),
+    }
+}
+
+pub async fn replay(recording: &Path, opts: WlReplayOptions) -> io::Result<()> {
+    let entries = WlRecordingReader::open(recording)?
+        .filter(|e| e.as_ref().is_ok_and(is_replayable) || e.is_err())
+        .collect::<io::Result<Vec<_>>>()?;
+
+    info!(
+        recording = ?recording,
+        socket = %opts.socket,
+        num_requests = entries.len(),
+        fast = opts.fast,
+        "Replaying recording"
+    );
+
+    let mut conn = opts.socket.connect().await?;
+    let (read, write) = conn.split();
+    let mut reader = WlMsgReader::new(read);
+    let mut writer = WlMsgWriter::new(write);
+
+    if !writer.can_pass_fds() && entries.iter().any(|e| !e.fds.is_empty()) {
+        warn!("Recording contains fds, but the target socket can't carry them");
+    }
+
+    // Only wl_display is ever needed to parse its error event
+    let objects = WlObjects::new();
+    let start = Instant::now();
+    let first_offset = entries.first().map(|e| e.offset_ns).unwrap_or(0);
+
+    for entry in entries.iter() {
+        if !opts.fast {
+            let due = start + Duration::from_nanos(entry.offset_ns.saturating_sub(first_offset));
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(due.into()) => break,
+                    msg = reader.read() => handle_event(&objects, msg?)?,
+                }
+            }
+        }
+
+        debug!(
+            obj_id = entry.obj_id,
+            opcode = entry.opcode,
+            "Replaying request"
+        );
+        writer.queue_write(entry_to_msg(entry)?);
+
+        while writer.has_pending_writes() {
+            tokio::select! {
+                biased;
+
+                res = writer.dequeue_write() => res?,
+                msg = reader.read() => handle_event(&objects, msg?)?,
+            }
+        }
+    }
+
+    let linger = tokio::time::sleep(REPLAY_LINGER);
+    tokio::pin!(linger);
+    loop {
+        tokio::select! {
+            _ = &mut linger => break,
+            msg = reader.read() => handle_event(&objects, msg?)?,
+        }
+    }
+
+    info!("Replay finished without errors");
+    Ok(())
+}
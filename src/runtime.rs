@@ -0,0 +1,62 @@
+//! Construction of the tokio runtime according to `[runtime]` in the config
+//!
+//! A Wayland proxy sits on the input-to-photon path of every client, so it's
+//! latency-sensitive, but it rarely needs much parallelism. A small runtime,
+//! or even a single thread, pinned to dedicated CPUs often serves it better
+//! than tokio's default of one worker per CPU.
+
+use std::io;
+
+use nix::{
+    sched::{CpuSet, sched_setaffinity},
+    unistd::Pid,
+};
+use tokio::runtime::{Builder, Runtime};
+use tracing::{info, warn};
+
+use crate::config::{WlRuntimeConfig, WlRuntimeFlavor};
+
+/// Pin the calling thread to `cpus`. Threads it spawns afterwards inherit this.
+fn pin_to_cpus(cpus: &[usize]) -> io::Result<()> {
+    let mut set = CpuSet::new();
+    for cpu in cpus {
+        set.set(*cpu).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CPU {} is out of range", cpu),
+            )
+        })?;
+    }
+    sched_setaffinity(Pid::from_raw(0), &set)?;
+    Ok(())
+}
+
+/// Build the runtime described by `conf`.
+///
+/// CPU pinning applies to the calling thread, which then becomes the parent of
+/// every runtime thread, so that worker and blocking threads (and processes
+/// spawned from them, e.g. `ask_cmd`) are all confined to the same CPUs.
+pub fn build(conf: &WlRuntimeConfig) -> io::Result<Runtime> {
+    if !conf.cpus.is_empty() {
+        pin_to_cpus(&conf.cpus)?;
+        info!(cpus = ?conf.cpus, "Pinned to CPUs");
+    }
+
+    let mut builder = match conf.flavor {
+        WlRuntimeFlavor::MultiThread => {
+            let mut builder = Builder::new_multi_thread();
+            if let Some(n) = conf.worker_threads {
+                builder.worker_threads(n.max(1));
+            }
+            builder
+        }
+        WlRuntimeFlavor::CurrentThread => {
+            if conf.worker_threads.is_some() {
+                warn!("worker_threads has no effect on a current_thread runtime");
+            }
+            Builder::new_current_thread()
+        }
+    };
+
+    builder.enable_all().thread_name("wl-mitm-worker").build()
+}
@@ -0,0 +1,499 @@
+//! Self-sandboxing with Landlock and seccomp
+//!
+//! wl-mitm parses untrusted input from both ends of every connection. To
+//! contain a parser bug that turns into code execution, it can confine itself
+//! once it's set up:
+//!
+//! - [restrict_filesystem] installs a Landlock ruleset limiting filesystem
+//!   access to the directories of the sockets, heartbeat and recordings, plus
+//!   whatever is configured in `sandbox.read_paths` and `sandbox.write_paths`.
+//!   This has to happen on the main thread before the runtime is started, such
+//!   that every thread inherits the ruleset.
+//! - [restrict_syscalls] installs a seccomp filter allowing only the syscalls
+//!   needed to keep proxying, on all threads. Notably, `execve` isn't one of
+//!   them; `ask_cmd` and `notify_cmd` are run through [crate::spawner].
+//!
+//! Both are built from raw syscalls, as the kernel interfaces are small and
+//! stable. Kernels without Landlock are tolerated with a warning; seccomp is
+//! required. The syscall allowlist is only known for x86_64 and aarch64,
+//! elsewhere the sandbox can't be enabled.
+
+use std::{
+    ffi::CString,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{config::Config, socket::WlSocketAddr};
+
+// Landlock filesystem access rights, from <linux/landlock.h>
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+const ACCESS_FS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const ACCESS_FS_WRITE: u64 = ACCESS_FS_READ
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_TRUNCATE;
+/// The only rights that apply to files rather than directories
+const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Every filesystem access right known to the given Landlock ABI version
+fn handled_access_fs(abi: i64) -> u64 {
+    match abi {
+        1 => (1 << 13) - 1,
+        2 => (1 << 14) - 1,
+        3 | 4 => (1 << 15) - 1,
+        _ => (1 << 16) - 1,
+    }
+}
+
+/// Paths wl-mitm has to be able to create and remove files in, namely the
+/// directories of everything it binds or writes
+fn writable_paths(config: &Config) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    let sockets = [
+        Some(config.socket.listen_socket_addr()),
+        config.control.socket.as_deref().map(WlSocketAddr::parse),
+    ];
+    for addr in sockets.into_iter().flatten() {
+        if let WlSocketAddr::Path(p) = addr {
+            paths.extend(p.parent().map(Path::to_path_buf));
+        }
+    }
+
+    for file in [
+        &config.health.heartbeat_file,
+        &config.dump.file,
+        &config.audit.file,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        paths.extend(Path::new(file).parent().map(Path::to_path_buf));
+    }
+
+    for dir in [
+        &config.recording.dir,
+        &config.trace.dir,
+        &config.learning.dir,
+        &config.stats.dir,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        paths.push(dir.into());
+    }
+
+    paths.extend(config.sandbox.write_paths.iter().map(PathBuf::from));
+    for proxy in &config.proxies {
+        paths.extend(writable_paths(proxy));
+    }
+    paths
+}
+
+fn readable_paths(config: &Config) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = config
+        .sandbox
+        .read_paths
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    paths.extend(config.audit.key_file.as_ref().map(PathBuf::from));
+
+    // Group names are resolved through NSS, which reads its configuration and
+    // may load modules
+    if config
+        .socket
+        .group
+        .as_ref()
+        .is_some_and(|g| g.parse::<u32>().is_err())
+    {
+        paths.push("/etc".into());
+        paths.push("/usr".into());
+    }
+
+    for proxy in &config.proxies {
+        paths.extend(readable_paths(proxy));
+    }
+    paths
+}
+
+fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::NotFound {
+            debug!(path = ?path, "Not adding sandbox rule for nonexistent path");
+            return Ok(());
+        }
+        return Err(e);
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let is_dir = std::fs::metadata(path)?.is_dir();
+    let attr = LandlockPathBeneathAttr {
+        allowed_access: if is_dir {
+            access
+        } else {
+            access & ACCESS_FS_FILE
+        },
+        parent_fd: fd.as_raw_fd(),
+    };
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const LandlockPathBeneathAttr,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    debug!(path = ?path, access = access, "Added sandbox rule");
+    Ok(())
+}
+
+/// Restrict filesystem access of the calling thread, and all threads it spawns
+/// from now on, using Landlock
+pub fn restrict_filesystem(config: &Config) -> io::Result<()> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        let e = io::Error::last_os_error();
+        if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP)) {
+            warn!("Landlock is not supported by this kernel, filesystem access is not restricted");
+            return Ok(());
+        }
+        return Err(e);
+    }
+
+    let handled = handled_access_fs(abi);
+    let attr = LandlockRulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const LandlockRulesetAttr,
+            std::mem::size_of::<LandlockRulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+
+    // Recordings and traces are written into directories created on demand
+    let configs = std::iter::once(config).chain(config.proxies.iter().map(|c| &**c));
+    for dir in configs
+        .flat_map(|c| [&c.recording.dir, &c.trace.dir])
+        .flatten()
+    {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    for path in writable_paths(config) {
+        add_rule(&ruleset, &path, ACCESS_FS_WRITE & handled)?;
+    }
+    for path in readable_paths(config) {
+        add_rule(&ruleset, &path, ACCESS_FS_READ & handled)?;
+    }
+
+    set_no_new_privs()?;
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Landlock also keeps us from inspecting processes outside of the sandbox,
+    // which includes reading /proc/<pid>/exe and /proc/<pid>/root of clients.
+    // Configs depending on either are refused by [Config::parse].
+    info!(abi = abi, "Filesystem access restricted with Landlock");
+    Ok(())
+}
+
+fn set_no_new_privs() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC00000B7;
+
+/// Syscalls wl-mitm needs once it's initialized
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // Plain I/O
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    // Sockets, including upstream connections and the control socket
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_shutdown,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    // The tokio reactor
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    // Threads and memory
+    libc::SYS_futex,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_prctl,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Signals
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_restart_syscall,
+    // Time
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    // Files: recordings, the heartbeat, peer info from /proc, and sockets
+    // removed on shutdown
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_ftruncate,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchownat,
+    // Fds sent to replayed clients
+    libc::SYS_memfd_create,
+];
+
+/// Legacy variants of the above, which only exist on x86_64
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_SYSCALLS_ARCH: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_poll,
+    libc::SYS_epoll_wait,
+    libc::SYS_accept,
+    libc::SYS_pipe,
+    libc::SYS_dup2,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_unlink,
+    libc::SYS_mkdir,
+    libc::SYS_chmod,
+];
+#[cfg(target_arch = "aarch64")]
+const ALLOWED_SYSCALLS_ARCH: &[libc::c_long] = &[];
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn bpf_jeq(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Build a classic BPF program that allows the listed syscalls and returns
+/// `default` for everything else
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn build_filter(syscalls: &[libc::c_long], default: u32) -> Vec<libc::sock_filter> {
+    // Offsets into struct seccomp_data
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const LOAD: u32 = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    const RET: u32 = libc::BPF_RET | libc::BPF_K;
+
+    let mut prog = vec![
+        bpf_stmt(LOAD, ARCH),
+        bpf_jeq(AUDIT_ARCH, 1, 0),
+        bpf_stmt(RET, libc::SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(LOAD, NR),
+    ];
+
+    for nr in syscalls {
+        prog.push(bpf_jeq(*nr as u32, 0, 1));
+        prog.push(bpf_stmt(RET, libc::SECCOMP_RET_ALLOW));
+    }
+
+    prog.push(bpf_stmt(RET, default));
+    prog
+}
+
+/// Restrict every thread of wl-mitm to [ALLOWED_SYSCALLS] using seccomp
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn restrict_syscalls(config: &Config) -> io::Result<()> {
+    use crate::config::WlSeccompMode;
+
+    let default = match config.sandbox.seccomp {
+        WlSeccompMode::Enforce => libc::SECCOMP_RET_KILL_PROCESS,
+        WlSeccompMode::Log => libc::SECCOMP_RET_LOG,
+    };
+
+    let syscalls: Vec<_> = ALLOWED_SYSCALLS
+        .iter()
+        .chain(ALLOWED_SYSCALLS_ARCH)
+        .copied()
+        .collect();
+    let mut filter = build_filter(&syscalls, default);
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    set_no_new_privs()?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    // With TSYNC, a positive return value is the id of a thread that couldn't
+    // be synchronized
+    if ret != 0 {
+        return Err(if ret < 0 {
+            io::Error::last_os_error()
+        } else {
+            io::Error::other(format!("could not apply seccomp filter to thread {}", ret))
+        });
+    }
+
+    info!(mode = ?config.sandbox.seccomp, "Syscalls restricted with seccomp");
+    Ok(())
+}
+
+/// Fails, as there is no syscall allowlist for this architecture
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn restrict_syscalls(_config: &Config) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "no seccomp filter for {}, the sandbox can't be enabled",
+            std::env::consts::ARCH
+        ),
+    ))
+}
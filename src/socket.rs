@@ -0,0 +1,418 @@
+//! Addressing and setup of the sockets wl-mitm listens on and connects to
+
+use std::{
+    ffi::CString,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            fs::{OpenOptionsExt, PermissionsExt},
+            net::SocketAddr,
+        },
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    sys::signal::{Signal, kill},
+    unistd::{Group, Pid},
+};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tracing::{info, warn};
+
+use crate::{
+    config::WlSockets,
+    io_util::{WlReadHalf, WlWriteHalf},
+};
+
+/// Address of a socket wl-mitm can listen on or connect to.
+///
+/// Unix sockets can either live on the filesystem or in the Linux abstract
+/// socket namespace. Abstract sockets are written with a leading `@` in the
+/// config file, mirroring how tools like `ss` display them.
+///
+/// TCP endpoints are written as `tcp:<host>:<port>`. They are meant for
+/// linking two wl-mitm instances across a VM or container boundary, and
+/// can't carry fds (see [crate::config::WlFdPolicy]). vsock isn't supported;
+/// a VM reachable only through vsock needs a relay to TCP, such as socat.
+#[derive(Clone, PartialEq, Eq)]
+pub enum WlSocketAddr {
+    Path(PathBuf),
+    Abstract(Vec<u8>),
+    Tcp(String),
+}
+
+impl WlSocketAddr {
+    /// Parse a socket address from the config file. Relative paths are
+    /// resolved against `$XDG_RUNTIME_DIR`.
+    pub fn parse(s: &str) -> WlSocketAddr {
+        if let Some(name) = s.strip_prefix('@') {
+            return WlSocketAddr::Abstract(name.as_bytes().to_vec());
+        }
+
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            return WlSocketAddr::Tcp(addr.to_string());
+        }
+        if s.starts_with("vsock:") {
+            warn!(
+                addr = s,
+                "vsock isn't supported, taking the address for a path"
+            );
+        }
+
+        let p = Path::new(s);
+        if p.is_absolute() {
+            WlSocketAddr::Path(p.into())
+        } else {
+            WlSocketAddr::Path(
+                Path::new(
+                    &std::env::var("XDG_RUNTIME_DIR")
+                        .unwrap_or_else(|_| "/run/user/1000".to_string()),
+                )
+                .join(p),
+            )
+        }
+    }
+
+    /// Connect, retrying for up to `timeout` while the socket doesn't exist or
+    /// nobody is listening on it yet. This covers wl-mitm being started before
+    /// the compositor it proxies.
+    pub async fn connect_with_wait(&self, timeout: Duration) -> io::Result<WlStream> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_millis(50);
+        let mut waiting = false;
+
+        loop {
+            match self.connect().await {
+                Ok(stream) => {
+                    if waiting {
+                        info!(addr = %self, "Upstream is up");
+                    }
+                    return Ok(stream);
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                    ) && Instant::now() < deadline =>
+                {
+                    if !waiting {
+                        info!(addr = %self, error = %e, "Waiting for upstream to come up");
+                        waiting = true;
+                    }
+                    tokio::time::sleep(backoff.min(deadline - Instant::now())).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(1));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Make sure no other instance is serving this address before we bind to it.
+    ///
+    /// For sockets on the filesystem, this takes an exclusive lock on `<socket>.lock`
+    /// (which also records our pid), the same way Wayland compositors guard their
+    /// sockets. If another instance holds the lock, we refuse unless `replace` is
+    /// set, in which case that instance is asked to terminate and we wait for it
+    /// to release the lock. A socket file left over without a lock is only
+    /// removed if nothing answers on it, or if `replace` is set.
+    ///
+    /// Abstract and TCP sockets need none of this: they can't go stale, and
+    /// binding to one already in use simply fails.
+    ///
+    /// The returned lock must be kept alive for as long as we serve this address.
+    pub async fn claim(&self, replace: bool) -> io::Result<Option<WlSocketLock>> {
+        let WlSocketAddr::Path(p) = self else {
+            return Ok(None);
+        };
+
+        let mut lock_path = p.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock = WlSocketLock::acquire(PathBuf::from(lock_path), self, replace).await?;
+
+        if p.exists() {
+            if std::os::unix::net::UnixStream::connect(p).is_ok() {
+                if !replace {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!(
+                            "something is already serving {} without holding its lock; pass --replace to take over",
+                            self
+                        ),
+                    ));
+                }
+                warn!(addr = %self, "Taking over socket from an unknown server");
+            }
+            tokio::fs::remove_file(p).await?;
+        }
+
+        Ok(Some(lock))
+    }
+
+    /// Restrict access to a socket we've bound to according to the mode, group,
+    /// and security labels configured in `conf`.
+    ///
+    /// Only sockets on the filesystem can carry these; they are ignored with
+    /// a warning for abstract and TCP sockets.
+    pub fn apply_permissions(&self, conf: &WlSockets) -> io::Result<()> {
+        let configured = conf.mode.is_some()
+            || conf.group.is_some()
+            || conf.selinux_label.is_some()
+            || conf.smack_label.is_some();
+
+        let WlSocketAddr::Path(p) = self else {
+            if configured {
+                warn!(
+                    addr = %self,
+                    "Socket permissions and labels only apply to sockets on the filesystem; ignoring"
+                );
+            }
+            return Ok(());
+        };
+
+        if let Some(mode) = conf.mode {
+            std::fs::set_permissions(p, std::fs::Permissions::from_mode(mode))?;
+            info!(path = ?p, "Set socket mode to {:o}", mode);
+        }
+
+        if let Some(ref group) = conf.group {
+            let gid = match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => Group::from_name(group)?
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("group {} not found", group),
+                        )
+                    })?
+                    .gid
+                    .as_raw(),
+            };
+            std::os::unix::fs::chown(p, None, Some(gid))?;
+            info!(path = ?p, gid = gid, "Set socket group");
+        }
+
+        if let Some(ref label) = conf.selinux_label {
+            set_xattr(p, "security.selinux", label)?;
+            info!(path = ?p, label = label, "Set SELinux label of socket");
+        }
+
+        if let Some(ref label) = conf.smack_label {
+            set_xattr(p, "security.SMACK64", label)?;
+            info!(path = ?p, label = label, "Set SMACK label of socket");
+        }
+
+        Ok(())
+    }
+
+    pub async fn bind(&self) -> io::Result<WlListener> {
+        match self {
+            WlSocketAddr::Path(p) => Ok(WlListener::Unix(UnixListener::bind(p)?)),
+            WlSocketAddr::Abstract(name) => {
+                let addr = SocketAddr::from_abstract_name(name)?;
+                let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+                listener.set_nonblocking(true)?;
+                Ok(WlListener::Unix(UnixListener::from_std(listener)?))
+            }
+            WlSocketAddr::Tcp(addr) => Ok(WlListener::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    /// Remove a socket we've bound to from the filesystem, along with its
+    /// lock file, which must still be held. Nothing to do for abstract and
+    /// TCP sockets.
+    pub fn remove(&self) {
+        if let WlSocketAddr::Path(p) = self {
+            std::fs::remove_file(p).ok();
+            let mut lock_path = p.clone().into_os_string();
+            lock_path.push(".lock");
+            std::fs::remove_file(lock_path).ok();
+        }
+    }
+
+    pub async fn connect(&self) -> io::Result<WlStream> {
+        match self {
+            WlSocketAddr::Path(p) => Ok(WlStream::Unix(UnixStream::connect(p).await?)),
+            WlSocketAddr::Abstract(name) => {
+                // tokio can only connect to paths, so go through std instead.
+                // That blocks while the listener's backlog is full, which
+                // mustn't hold up the runtime's other connections.
+                let addr = SocketAddr::from_abstract_name(name)?;
+                let stream = tokio::task::spawn_blocking(move || {
+                    std::os::unix::net::UnixStream::connect_addr(&addr)
+                })
+                .await??;
+                stream.set_nonblocking(true)?;
+                Ok(WlStream::Unix(UnixStream::from_std(stream)?))
+            }
+            WlSocketAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Ok(WlStream::Tcp(stream))
+            }
+        }
+    }
+}
+
+/// How long to wait for a replaced instance to go away
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An exclusive lock on a socket path, see [WlSocketAddr::claim].
+/// Released when dropped, or when we exit.
+pub struct WlSocketLock {
+    _lock: Flock<File>,
+}
+
+impl WlSocketLock {
+    fn try_lock(path: &Path) -> io::Result<Result<Flock<File>, File>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)?;
+
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => Ok(Ok(lock)),
+            Err((file, Errno::EWOULDBLOCK)) => Ok(Err(file)),
+            Err((_, e)) => Err(e.into()),
+        }
+    }
+
+    async fn acquire(path: PathBuf, addr: &WlSocketAddr, replace: bool) -> io::Result<Self> {
+        let mut lock = match Self::try_lock(&path)? {
+            Ok(lock) => lock,
+            Err(mut file) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                let pid = pid.trim().parse::<i32>().ok();
+
+                if !replace {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!(
+                            "another instance (pid {}) is serving {}; pass --replace to take over",
+                            pid.map(|p| p.to_string()).unwrap_or("unknown".into()),
+                            addr
+                        ),
+                    ));
+                }
+
+                let Some(pid) = pid else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is locked by an unknown process", path.display()),
+                    ));
+                };
+
+                info!(pid = pid, addr = %addr, "Asking running instance to terminate");
+                kill(Pid::from_raw(pid), Signal::SIGTERM)?;
+
+                let deadline = Instant::now() + REPLACE_TIMEOUT;
+                loop {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    if let Ok(lock) = Self::try_lock(&path)? {
+                        break lock;
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("instance (pid {}) did not terminate", pid),
+                        ));
+                    }
+                }
+            }
+        };
+
+        lock.set_len(0)?;
+        lock.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+
+        Ok(WlSocketLock { _lock: lock })
+    }
+}
+
+fn set_xattr(p: &Path, name: &str, value: &str) -> io::Result<()> {
+    let path = CString::new(p.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    let res = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub enum WlListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl WlListener {
+    /// Accept a new connection, returning the stream and a printable peer address
+    pub async fn accept(&self) -> io::Result<(WlStream, String)> {
+        match self {
+            WlListener::Unix(l) => {
+                let (stream, addr) = l.accept().await?;
+                Ok((WlStream::Unix(stream), format!("{:?}", addr)))
+            }
+            WlListener::Tcp(l) => {
+                let (stream, addr) = l.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok((WlStream::Tcp(stream), addr.to_string()))
+            }
+        }
+    }
+}
+
+/// A connected stream carrying Wayland messages, either from a client or to a server
+pub enum WlStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl WlStream {
+    pub fn split(&mut self) -> (WlReadHalf<'_>, WlWriteHalf<'_>) {
+        match self {
+            WlStream::Unix(s) => {
+                let (r, w) = s.split();
+                (WlReadHalf::Unix(r), WlWriteHalf::Unix(w))
+            }
+            WlStream::Tcp(s) => {
+                let (r, w) = s.split();
+                (WlReadHalf::Tcp(r), WlWriteHalf::Tcp(w))
+            }
+        }
+    }
+}
+
+impl fmt::Display for WlSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WlSocketAddr::Path(p) => write!(f, "{}", p.display()),
+            WlSocketAddr::Abstract(name) => write!(f, "@{}", String::from_utf8_lossy(name)),
+            WlSocketAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+impl fmt::Debug for WlSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
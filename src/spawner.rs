@@ -0,0 +1,294 @@
+//! Running `ask_cmd` and `notify_cmd` from outside the sandbox
+//!
+//! seccomp filters and Landlock rulesets are inherited across `execve()`, so
+//! commands spawned by a sandboxed wl-mitm would be confined just as tightly,
+//! which no prompt or notification tool is going to survive. Instead, a helper
+//! process is forked off before the sandbox is applied. It runs commands on
+//! behalf of wl-mitm and reports back how they exited.
+//!
+//! The helper isn't sandboxed, so it mustn't do whatever a wl-mitm taken over
+//! asks it to. It reads `ask_cmd` and `notify_cmd` from the config before it
+//! is forked, and only ever runs those: requests name one of them by index,
+//! along with its arguments and `WL_MITM_*` variables. Any other variables are
+//! dropped.
+//!
+//! The helper speaks newline-delimited JSON over a socket pair: a
+//! [WlSpawnRequest] for every command, and a [WlSpawnReply] for those that are
+//! waited for. It exits when wl-mitm goes away.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    os::{fd::OwnedFd, unix::net::UnixStream as StdUnixStream},
+    process::{Command, ExitStatus},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
+    net::{UnixStream, unix::OwnedWriteHalf},
+    sync::oneshot,
+};
+use tracing::{debug, error, warn};
+
+use crate::config::WlExec;
+
+/// Prefix of the only environment variables passed on to commands
+const ENV_PREFIX: &str = "WL_MITM_";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct WlSpawnRequest {
+    id: u64,
+    /// Index into [commands]
+    cmd: usize,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    /// Whether to reply once the command exits
+    wait: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct WlSpawnReply {
+    id: u64,
+    status: WlExitStatus,
+}
+
+/// How a spawned command exited
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WlExitStatus {
+    success: bool,
+    desc: String,
+}
+
+impl WlExitStatus {
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    fn failed(e: &io::Error) -> WlExitStatus {
+        WlExitStatus {
+            success: false,
+            desc: format!("failed to run: {}", e),
+        }
+    }
+}
+
+impl From<ExitStatus> for WlExitStatus {
+    fn from(status: ExitStatus) -> Self {
+        WlExitStatus {
+            success: status.success(),
+            desc: status.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for WlExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.desc)
+    }
+}
+
+/// Set up once the runtime is up, if a helper was forked
+static SPAWNER: OnceLock<WlSpawner> = OnceLock::new();
+
+/// The commands the helper may run, in the order requests refer to them by
+fn commands(exec: &WlExec) -> [Option<String>; 2] {
+    [exec.ask_cmd.clone(), exec.notify_cmd.clone()]
+}
+
+/// The wl-mitm side of the helper
+struct WlSpawner {
+    commands: [Option<String>; 2],
+    write: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<WlExitStatus>>>>,
+    next_id: AtomicU64,
+}
+
+/// Fork off the helper. This must happen while wl-mitm is still single-threaded,
+/// i.e. before the tokio runtime is started. Returns our end of the socket
+/// pair, to be handed to [start] once the runtime is up.
+pub fn fork_helper(exec: &WlExec) -> io::Result<OwnedFd> {
+    let (ours, theirs) = StdUnixStream::pair()?;
+    let commands = commands(exec);
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(ours);
+            run_helper(theirs, commands);
+            std::process::exit(0);
+        }
+        _ => Ok(ours.into()),
+    }
+}
+
+fn run_helper(conn: StdUnixStream, commands: [Option<String>; 2]) {
+    let write = Arc::new(Mutex::new(
+        conn.try_clone().expect("Can't clone helper socket"),
+    ));
+
+    for line in BufReader::new(conn).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let req: WlSpawnRequest = match serde_json::from_str(&line) {
+            Ok(req) => req,
+            Err(e) => {
+                error!(error = %e, "Invalid request to spawn helper");
+                continue;
+            }
+        };
+
+        let child = match commands.get(req.cmd).and_then(Option::as_ref) {
+            Some(program) => Command::new(program)
+                .args(&req.args)
+                .envs(req.env.iter().filter_map(|(k, v)| {
+                    if !k.starts_with(ENV_PREFIX) {
+                        warn!(var = k, "Dropping environment variable from spawn request");
+                        return None;
+                    }
+                    Some((k, v))
+                }))
+                .spawn(),
+            None => {
+                error!(cmd = req.cmd, "Request to spawn a command not configured");
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "no such command",
+                ))
+            }
+        };
+
+        let write = write.clone();
+        // Every child is waited for, if only to reap it
+        std::thread::spawn(move || {
+            let status = match child.and_then(|mut c| c.wait()) {
+                Ok(status) => status.into(),
+                Err(e) => WlExitStatus::failed(&e),
+            };
+
+            if req.wait {
+                let mut reply = serde_json::to_vec(&WlSpawnReply { id: req.id, status }).unwrap();
+                reply.push(b'\n');
+                write.lock().unwrap().write_all(&reply).ok();
+            }
+        });
+    }
+}
+
+/// Start talking to the helper forked with [fork_helper], with the same
+/// `exec`. Must be called from within the runtime. Commands are run directly
+/// if this was never called.
+pub fn start(fd: OwnedFd, exec: &WlExec) -> io::Result<()> {
+    let std_stream = StdUnixStream::from(fd);
+    std_stream.set_nonblocking(true)?;
+    let (read, write) = UnixStream::from_std(std_stream)?.into_split();
+
+    let pending: Arc<Mutex<HashMap<u64, oneshot::Sender<WlExitStatus>>>> = Default::default();
+    let reader_pending = pending.clone();
+    tokio::spawn(async move {
+        let mut lines = AsyncBufReader::new(read).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match serde_json::from_str::<WlSpawnReply>(&line) {
+                Ok(reply) => {
+                    if let Some(tx) = reader_pending.lock().unwrap().remove(&reply.id) {
+                        tx.send(reply.status).ok();
+                    }
+                }
+                Err(e) => error!(error = %e, "Invalid reply from spawn helper"),
+            }
+        }
+        error!("Spawn helper went away");
+        // Fail everyone still waiting
+        reader_pending.lock().unwrap().clear();
+    });
+
+    SPAWNER
+        .set(WlSpawner {
+            commands: commands(exec),
+            write: tokio::sync::Mutex::new(write),
+            pending,
+            next_id: AtomicU64::new(0),
+        })
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "spawner already started"))
+}
+
+impl WlSpawner {
+    async fn request(&self, cmd: &Command, wait: bool) -> io::Result<Option<WlExitStatus>> {
+        let program = cmd.get_program().to_string_lossy();
+        let Some(index) = self
+            .commands
+            .iter()
+            .position(|c| c.as_deref() == Some(&*program))
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "only ask_cmd and notify_cmd can be spawned",
+            ));
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let req = WlSpawnRequest {
+            id,
+            cmd: index,
+            args: cmd
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect(),
+            env: cmd
+                .get_envs()
+                .filter(|(k, _)| k.to_string_lossy().starts_with(ENV_PREFIX))
+                .filter_map(|(k, v)| {
+                    Some((
+                        k.to_string_lossy().into_owned(),
+                        v?.to_string_lossy().into_owned(),
+                    ))
+                })
+                .collect(),
+            wait,
+        };
+        debug!(req = ?req, "Spawning through helper");
+
+        let rx = wait.then(|| {
+            let (tx, rx) = oneshot::channel();
+            self.pending.lock().unwrap().insert(id, tx);
+            rx
+        });
+
+        let mut line = serde_json::to_vec(&req)?;
+        line.push(b'\n');
+        self.write.lock().await.write_all(&line).await?;
+
+        match rx {
+            Some(rx) => rx
+                .await
+                .map(Some)
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "spawn helper went away")),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Run `cmd` to completion, through the helper if there is one
+pub async fn status(mut cmd: tokio::process::Command) -> io::Result<WlExitStatus> {
+    match SPAWNER.get() {
+        Some(spawner) => spawner
+            .request(cmd.as_std(), true)
+            .await
+            .map(|s| s.unwrap()),
+        None => cmd.status().await.map(Into::into),
+    }
+}
+
+/// Start `cmd` without waiting for it, through the helper if there is one
+pub async fn spawn(mut cmd: tokio::process::Command) -> io::Result<()> {
+    match SPAWNER.get() {
+        Some(spawner) => spawner.request(cmd.as_std(), false).await.map(|_| ()),
+        None => cmd.spawn().map(|_| ()),
+    }
+}
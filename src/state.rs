@@ -1,19 +1,94 @@
-use std::sync::Arc;
+use std::{
+    any::TypeId,
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, LazyLock},
+    time::Instant,
+};
 
-use tracing::{debug, error, info, warn};
+use tokio::sync::oneshot;
+use tracing::{Span, debug, error, info, warn};
 
 use crate::{
+    alerts::{self, WlAlert},
+    audit::{WlAuditEntry, WlAuditLog},
     codec::WlRawMsg,
-    config::{Config, WlFilterRequestAction, WlFilterRequestBlockType},
-    objects::WlObjects,
+    config::{Config, WlFilterRequest, WlFilterRequestAction, WlFilterRequestBlockType},
+    control::WlControl,
+    learning::{WlConnLearning, WlLearning},
+    logind::WlSession,
+    objects::{WlObjectProvenance, WlObjectType, WlObjects, WlProvenanceVerdict},
+    peer::WlPeerInfo,
+    policygen::WlPolicyGenerator,
     proto::{
-        AnyWlParsedMessage, WaylandProtocolParsingOutcome, WlDisplayDeleteIdEvent,
-        WlKeyboardEnterEvent, WlPointerEnterEvent, WlRegistryBindRequest, WlRegistryGlobalEvent,
-        WlRegistryGlobalRemoveEvent, WlTouchDownEvent, XdgSurfaceGetToplevelRequest,
-        XdgToplevelSetAppIdRequest, XdgToplevelSetTitleRequest, XdgWmBaseGetXdgSurfaceRequest,
+        AnyWlParsedMessage, WL_MITM_V1, WaylandProtocolParsingOutcome, WlDisplayDeleteIdEvent,
+        WlMitmV1RequestPermissionRequest, WlMsgParserFn, WlRegistryBindRequest,
     },
+    spawner,
+    validate::WlValidator,
 };
 
+/// Register `$handler`, a `fn(&mut WlMitmState, &$msg) -> Option<WlMitmVerdict>`,
+/// to be called for every `$msg` in [WlHandlers::requests] or [WlHandlers::events]
+macro_rules! handle {
+    ($handlers:expr, $dir:ident, $msg:ident => $handler:expr) => {
+        $handlers
+            .$dir
+            .entry(std::any::TypeId::of::<$msg<'static>>())
+            .or_default()
+            .push(|state, msg| $handler(state, msg.downcast_ref::<$msg>().unwrap()))
+    };
+}
+
+mod output;
+mod registry;
+mod seat;
+mod status;
+mod sync;
+mod xdg;
+mod xwayland;
+
+pub use seat::{WlSeatDeviceInfo, WlSeatInfo};
+pub use status::{STATUS_GLOBAL_NAME, WlPermission};
+pub(crate) use xdg::SurfaceXdgAssociation;
+pub use xdg::WlToplevelInfo;
+
+use seat::{FocusKind, SeatInfo};
+use xdg::ToplevelSurfaceInfo;
+
+/// A hook for one type of message. Returning a verdict stops handling of
+/// the message there; the filter rules are not consulted for it anymore.
+type WlHandlerFn = fn(&mut WlMitmState, &dyn AnyWlParsedMessage) -> Option<WlMitmVerdict>;
+
+/// Protocol-specific handling of messages, keyed by the [TypeId] of the
+/// message. Each submodule of this one registers what it needs with
+/// [handle!]; handlers for the same message run in order of registration.
+#[derive(Default)]
+struct WlHandlers {
+    requests: HashMap<TypeId, Vec<WlHandlerFn>>,
+    events: HashMap<TypeId, Vec<WlHandlerFn>>,
+}
+
+static HANDLERS: LazyLock<WlHandlers> = LazyLock::new(|| {
+    let mut handlers = WlHandlers::default();
+    handle!(handlers, events, WlDisplayDeleteIdEvent => on_delete_id);
+    // Binds of our own global must not reach the registry handlers
+    status::register(&mut handlers);
+    registry::register(&mut handlers);
+    seat::register(&mut handlers);
+    sync::register(&mut handlers);
+    xdg::register(&mut handlers);
+    xwayland::register(&mut handlers);
+    // Has to see binds and global removals the registry handlers let through
+    output::register(&mut handlers);
+    handlers
+});
+
+fn on_delete_id(state: &mut WlMitmState, msg: &WlDisplayDeleteIdEvent) -> Option<WlMitmVerdict> {
+    // Server has acknowledged deletion of an object
+    state.objects.remove_object(msg.id, false);
+    None
+}
+
 /// What to do for a message?
 #[derive(Debug)]
 pub enum WlMitmVerdict {
@@ -25,6 +100,13 @@ pub enum WlMitmVerdict {
     Rejected(u32),
     /// Terminate this entire session. Something is off.
     Terminate,
+    /// This message was meant for wl-mitm itself, which has answered it (see
+    /// [WlMitmState::take_replies]). It is never forwarded, not even in dry run mode.
+    Answered,
+    /// This message has been replaced by others (see [WlMitmState::take_replies]
+    /// for events, and [WlMitmState::take_replacements] for requests), which are
+    /// sent in its place. It is never forwarded, not even in dry run mode.
+    Replaced,
 }
 
 impl WlMitmVerdict {
@@ -71,42 +153,196 @@ impl WlMitmOutcome {
         self.1 = WlMitmVerdict::Rejected(error_code);
         self
     }
+
+    fn verdict(mut self, verdict: WlMitmVerdict) -> Self {
+        self.1 = verdict;
+        self
+    }
+}
+
+/// Messages which have to be parsed, by (object type, opcode): those creating
+/// objects, and those seen by [HANDLERS], the validator, or filter rules.
+/// Everything else is passed along after a look at its header and the shape
+/// of its arguments, see [crate::proto::is_well_formed].
+struct WlInterest {
+    requests: HashSet<(WlObjectType, u16)>,
+    events: HashSet<(WlObjectType, u16)>,
 }
 
-/// Association between a wl_surface and an xdg_surface, to facilitate
-/// lookup for [ToplevelSurfaceInfo] from a wl_surface
-struct SurfaceXdgAssociation(u32);
-/// Association between an xdg_surface and an xdg_toplevel
-struct XdgToplevelAssociation(u32);
-
-/// A struct to track information about an app's top-level surfaces (windows)
-/// This gets passed down to ask and notify scripts to produce user-friendly
-/// messages.
-#[derive(Default, Debug)]
-struct ToplevelSurfaceInfo {
-    pub title: Option<String>,
-    pub app_id: Option<String>,
+impl WlInterest {
+    fn new(config: &Config, validator: &WlValidator) -> WlInterest {
+        let interested = |parser: &dyn WlMsgParserFn, handlers: &HashMap<TypeId, _>| {
+            parser.creates_objects()
+                || handlers.contains_key(&parser.static_type_id())
+                || validator.is_interested(parser.static_type_id())
+        };
+
+        WlInterest {
+            requests: crate::proto::known_requests()
+                .filter(|(obj_type, opcode, parser)| {
+                    // The compositor doesn't know our own objects at all
+                    *obj_type == WL_MITM_V1
+                        || interested(*parser, &HANDLERS.requests)
+                        || !config.filter.candidates(*obj_type, *opcode).is_empty()
+                })
+                .map(|(obj_type, opcode, _)| (obj_type, opcode))
+                .collect(),
+            events: crate::proto::known_events()
+                .filter(|(_, _, parser)| interested(*parser, &HANDLERS.events))
+                .map(|(obj_type, opcode, _)| (obj_type, opcode))
+                .collect(),
+        }
+    }
 }
 
 /// Tracks state for _one_ Wayland connection.
 pub struct WlMitmState {
     config: Arc<Config>,
     objects: WlObjects,
-    /// The last toplevel object ID (NOT the underlying wl_surface) that was "active"
-    /// for this connection.
-    /// This is used to hint the ask and notify scripts about the app's id and name,
-    /// even though this can never actually be perfect -- we can't track precisely
-    /// what might have caused the last filtered request to happen!
-    last_toplevel: Option<u32>,
+    /// Sequence number of the last enter event on any seat, see [SeatFocus::seq]
+    focus_seq: u64,
+    /// Used to check for filter rules disabled at runtime, if the control socket is enabled
+    control: Option<Arc<WlControl>>,
+    /// The connection's span (see [crate::logging::conn_span]), to tag with the app_id once known
+    conn_span: Span,
+    validator: WlValidator,
+    interest: WlInterest,
+    /// Round trips waiting for the client's next `wl_display.sync`, with
+    /// when to give up on it
+    pending_syncs: Vec<(oneshot::Sender<()>, Instant)>,
+    /// wl_callback objects of the client's `wl_display.sync` requests, mapped
+    /// to the round trips waiting for them to be done
+    syncs: HashMap<u32, Vec<oneshot::Sender<()>>>,
+    /// Only present while generating a policy, see [crate::policygen]
+    policy: Option<Arc<WlPolicyGenerator>>,
+    /// Only present if learning is enabled, see [crate::learning]
+    learning: Option<WlConnLearning>,
+    /// Only present if the session is followed, see [crate::logind]
+    session: Option<Arc<WlSession>>,
+    /// Only present if auditing is enabled, see [crate::audit]
+    audit: Option<Arc<WlAuditLog>>,
+    /// The client, if known
+    peer: WlPeerInfo,
+    /// See [WlMitmState::is_xwayland]
+    xwayland: bool,
+    /// See [WlMitmState::uses_xwayland_shell]
+    xwayland_shell: bool,
+    /// Interfaces of globals hidden from the client
+    hidden_globals: BTreeSet<String>,
+    /// `(interface, index)` of ask rules the client has been granted
+    /// through wl_mitm_v1 for the rest of the connection
+    granted: HashSet<(String, usize)>,
+    /// Events for the client answering its requests to wl-mitm itself
+    replies: Vec<WlRawMsg>,
+    /// Requests for the server in place of one with the verdict
+    /// [WlMitmVerdict::Replaced]
+    replacements: Vec<WlRawMsg>,
+    /// IDs of wl_mitm_v1 objects whose placeholder the server hasn't
+    /// deleted yet, see [status]
+    status_placeholders: HashSet<u32>,
+    /// See [WlMitmState::has_virtual_output]
+    virtual_output: bool,
+    /// Name of the wl_output global presented as the virtual output
+    virtual_output_global: Option<u32>,
 }
 
 impl WlMitmState {
-    pub fn new(config: Arc<Config>) -> WlMitmState {
-        WlMitmState {
+    pub fn new(config: Arc<Config>, control: Option<Arc<WlControl>>) -> WlMitmState {
+        let validator = WlValidator::new(&config.validation);
+        let mut state = WlMitmState {
+            objects: WlObjects::with_config(&config.objects),
+            interest: WlInterest::new(&config, &validator),
+            validator,
             config,
-            objects: WlObjects::new(),
-            last_toplevel: None,
+            focus_seq: 0,
+            control,
+            conn_span: Span::current(),
+            pending_syncs: Vec::new(),
+            syncs: HashMap::new(),
+            policy: None,
+            learning: None,
+            session: None,
+            audit: None,
+            peer: Default::default(),
+            xwayland: false,
+            xwayland_shell: false,
+            hidden_globals: BTreeSet::new(),
+            granted: HashSet::new(),
+            replies: Vec::new(),
+            replacements: Vec::new(),
+            status_placeholders: HashSet::new(),
+            virtual_output: false,
+            virtual_output_global: None,
+        };
+        // Selecting every client takes no knowing who it is
+        state.detect_virtual_output();
+        state
+    }
+
+    pub fn objects(&self) -> &WlObjects {
+        &self.objects
+    }
+
+    /// Tell who the client is, for filter rules and prompts depending on it
+    pub fn set_peer(&mut self, peer: WlPeerInfo) {
+        if let Some(ref mut learning) = self.learning {
+            learning.set_peer(&peer);
         }
+        self.peer = peer;
+        self.detect_xwayland();
+        self.detect_virtual_output();
+    }
+
+    /// The client, if known
+    pub fn peer(&self) -> &WlPeerInfo {
+        &self.peer
+    }
+
+    /// Note what the client uses in `policy`. This also announces every
+    /// known global to the client, whether allowed or not.
+    pub fn set_policy_generator(&mut self, policy: Arc<WlPolicyGenerator>) {
+        self.policy = Some(policy);
+    }
+
+    /// Learn what the client uses into, or enforce on it, its app profile in `learning`
+    pub fn set_learning(&mut self, learning: Arc<WlLearning>) {
+        let mut learning = WlConnLearning::new(learning);
+        learning.set_peer(&self.peer);
+        self.learning = Some(learning);
+    }
+
+    /// Filter `lock.deny` requests whenever `session` is locked
+    pub fn set_session(&mut self, session: Arc<WlSession>) {
+        self.session = Some(session);
+    }
+
+    /// Append every policy decision to `audit`
+    pub fn set_audit(&mut self, audit: Arc<WlAuditLog>) {
+        self.audit = Some(audit);
+    }
+
+    /// Events to send to the client, in answer to messages with the verdict
+    /// [WlMitmVerdict::Answered]
+    pub fn take_replies(&mut self) -> Vec<WlRawMsg> {
+        std::mem::take(&mut self.replies)
+    }
+
+    /// Requests to send the server in place of those with the verdict
+    /// [WlMitmVerdict::Replaced]
+    pub fn take_replacements(&mut self) -> Vec<WlRawMsg> {
+        std::mem::take(&mut self.replacements)
+    }
+
+    /// Run the handlers registered for the type of `msg`, up to the first one with a verdict
+    fn run_handlers(
+        &mut self,
+        handlers: &HashMap<TypeId, Vec<WlHandlerFn>>,
+        msg: &dyn AnyWlParsedMessage,
+    ) -> Option<WlMitmVerdict> {
+        handlers
+            .get(&msg.static_type_id())?
+            .iter()
+            .find_map(|handler| handler(self, msg))
     }
 
     /// Handle messages which register new objects with known interfaces or deletes them.
@@ -138,6 +374,10 @@ impl WlMitmState {
                         return false;
                     }
 
+                    if !self.objects.check_object_limit() {
+                        return false;
+                    }
+
                     debug!(
                         parent_obj_id = msg.obj_id(),
                         obj_type = tt.interface(),
@@ -146,7 +386,11 @@ impl WlMitmState {
                         parent_obj.interface(),
                         msg.msg_name()
                     );
-                    self.objects.record_object(tt, id);
+                    self.objects.record_child_object(tt, id, msg.obj_id(), None);
+                    self.objects.set_provenance(
+                        id,
+                        WlObjectProvenance::new(parent_obj.interface(), msg.msg_name()),
+                    );
                 }
             } else {
                 error!("Parent object ID {} not found!", msg.obj_id());
@@ -167,31 +411,215 @@ impl WlMitmState {
             );
 
             self.objects.remove_object(msg.obj_id(), from_client);
+        }
 
-            if self.last_toplevel.is_some_and(|id| id == msg.obj_id()) {
-                self.last_toplevel = None;
-            }
+        true
+    }
+
+    /// Pass on a message outside of [WlInterest] without parsing it. It can
+    /// still destroy its object, and has to come with the fds it needs and
+    /// arguments that fit its signature.
+    ///
+    /// Returns false if the connection shall be terminated.
+    fn pass_uninteresting(
+        &mut self,
+        raw_msg: &WlRawMsg,
+        obj_type: WlObjectType,
+        parser: &dyn WlMsgParserFn,
+        from_client: bool,
+    ) -> bool {
+        if raw_msg.fds.len() < parser.num_consumed_fds() {
+            error!(
+                obj_id = raw_msg.obj_id,
+                num_fds = raw_msg.fds.len(),
+                num_consumed_fds = parser.num_consumed_fds(),
+                "Missing fds for {}::{}",
+                obj_type.interface(),
+                parser.msg_name()
+            );
+            return false;
+        }
+
+        if !crate::proto::is_well_formed(parser.signature(), raw_msg.payload()) {
+            error!(
+                obj_id = raw_msg.obj_id,
+                len = raw_msg.payload().len(),
+                "Malformed {}::{}",
+                obj_type.interface(),
+                parser.msg_name()
+            );
+            return false;
+        }
+
+        if parser.is_destructor() {
+            debug!(
+                obj_id = raw_msg.obj_id,
+                "Object destructed via destructor {}::{}",
+                obj_type.interface(),
+                parser.msg_name()
+            );
+            self.objects.remove_object(raw_msg.obj_id, from_client);
         }
 
         true
     }
 
+    /// Whether `rule`, the `index`th for `interface`, is enabled and applies
+    /// to this client, leaving aside conditions on the object
+    fn applies_to_client(&self, interface: &str, index: usize, rule: &WlFilterRequest) -> bool {
+        let flatpak_app_id = self.peer.flatpak.as_ref().map(|f| f.app_id.as_str());
+        rule.matches_flatpak_app_id(flatpak_app_id)
+            && rule.matches_security_context(self.peer.security_context.as_deref())
+            && rule.matches_xwayland(self.xwayland)
+            && self
+                .control
+                .as_ref()
+                .is_none_or(|c| c.is_rule_enabled(interface, index))
+    }
+
+    /// The first enabled `[[filter.requests]]` rule matching `msg`, along with
+    /// its interface key and index. Rules for the interface of the object take
+    /// precedence over rules for `"*"`.
+    fn find_filter_rule<'c>(
+        &self,
+        config: &'c Config,
+        msg: &dyn AnyWlParsedMessage,
+    ) -> Option<(&'c str, usize, &'c WlFilterRequest)> {
+        let candidates = config.filter.candidates(msg.object_type(), msg.opcode());
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let version = self
+            .objects
+            .lookup_object_version(msg.obj_id())
+            .unwrap_or(1);
+
+        candidates
+            .iter()
+            .map(|(interface, i)| (interface, *i, &config.filter.requests[interface][*i]))
+            .find(|(interface, i, f)| {
+                f.matches_version(version)
+                    && f.descendant_of
+                        .as_ref()
+                        .is_none_or(|a| self.objects.has_ancestor(msg.obj_id(), a))
+                    && self.applies_to_client(interface, *i, f)
+            })
+            .map(|(interface, i, f)| (interface.as_str(), i, f))
+    }
+
     fn prepare_command(
         &self,
         msg: &dyn AnyWlParsedMessage,
         cmd_str: &str,
         desc: &str,
+        provenance: &str,
     ) -> tokio::process::Command {
-        let mut cmd = tokio::process::Command::new(cmd_str);
-        cmd.arg(msg.object_type().interface());
-        cmd.arg(msg.msg_name());
-        cmd.arg(desc);
+        let mut cmd = self.prepare_client_command(
+            msg.object_type().interface(),
+            msg.msg_name(),
+            cmd_str,
+            desc,
+            Self::focus_kind_for(msg),
+        );
         cmd.env("WL_MITM_MSG_JSON", msg.to_json());
+        cmd.env("WL_MITM_PROVENANCE_JSON", provenance);
+        if let Some(version) = self.objects.lookup_object_version(msg.obj_id()) {
+            cmd.env("WL_MITM_OBJECT_VERSION", version.to_string());
+        }
+        cmd
+    }
+
+    /// The Flatpak app ID of the client, or the app_id of its first toplevel having one
+    pub fn app_id(&self) -> Option<String> {
+        match self.peer.flatpak {
+            Some(ref flatpak) => Some(flatpak.app_id.clone()),
+            None => self.toplevels().into_iter().find_map(|t| t.app_id),
+        }
+    }
+
+    /// Tell who the client is in `alert`, and send it off, see [crate::alerts]
+    pub async fn alert(&mut self, mut alert: WlAlert) {
+        alert.pid = self.peer.pid;
+        alert.exe = self.peer.exe.as_ref().map(|p| p.display().to_string());
+        alert.app_id = self.app_id();
+
+        if self.config.alerts.notify
+            && let Some(ref notify_cmd) = self.config.exec.notify_cmd
+        {
+            let mut cmd = self.prepare_client_command(
+                &alert.interface,
+                &alert.request,
+                notify_cmd,
+                &alert.describe(),
+                FocusKind::Keyboard,
+            );
+            cmd.env("WL_MITM_ALERT", &alert.rule);
+            cmd.env("WL_MITM_ALERT_JSON", serde_json::to_string(&alert).unwrap());
+            spawner::spawn(cmd).await.ok();
+        }
+
+        alerts::dispatch(&self.config.alerts, alert);
+    }
+
+    /// The part of [Self::prepare_command] which doesn't depend on the
+    /// message: who the client is, and which of its windows has `focus_kind`
+    fn prepare_client_command(
+        &self,
+        interface: &str,
+        request: &str,
+        cmd_str: &str,
+        desc: &str,
+        focus_kind: FocusKind,
+    ) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new(cmd_str);
+        cmd.arg(interface);
+        cmd.arg(request);
+        let desc = self.config.catalog.resolve(desc);
+        cmd.arg(desc.text);
+        if let Some(key) = desc.key {
+            cmd.env("WL_MITM_DESC_KEY", key);
+            if let Some(locale) = self.config.catalog.locale() {
+                cmd.env("WL_MITM_LOCALE", locale);
+            }
+        }
+        if let Some(ref flatpak) = self.peer.flatpak {
+            cmd.env("WL_MITM_FLATPAK_APP_ID", &flatpak.app_id);
+            cmd.env(
+                "WL_MITM_FLATPAK_INFO_JSON",
+                serde_json::to_string(flatpak).unwrap(),
+            );
+        }
+        if let Some(ref ctx) = self.peer.security_context {
+            cmd.env("WL_MITM_SECURITY_CONTEXT", ctx);
+        }
+        if self.xwayland {
+            cmd.env("WL_MITM_XWAYLAND", "1");
+            if let Some(serial) = self.attributed_x11_focus(focus_kind) {
+                cmd.env("WL_MITM_FOCUS_X11_SERIAL", serial.to_string());
+            }
+        }
+
+        if let Some((seat, kind, toplevel)) = self.attributed_focus(focus_kind) {
+            cmd.env(
+                "WL_MITM_FOCUS",
+                match kind {
+                    FocusKind::Keyboard => "keyboard",
+                    FocusKind::Pointer => "pointer",
+                },
+            );
+
+            if let Some(name) = self
+                .objects
+                .extension::<SeatInfo>(seat)
+                .and_then(|i| i.name.as_ref())
+            {
+                cmd.env("WL_MITM_FOCUS_SEAT", name);
+            }
 
-        if let Some(last_toplevel) = self.last_toplevel {
             if let Some(info) = self
                 .objects
-                .get_object_extension::<ToplevelSurfaceInfo>(last_toplevel)
+                .handle_extension::<ToplevelSurfaceInfo>(toplevel)
             {
                 if let Some(ref title) = info.title {
                     cmd.env("WL_MITM_LAST_TOPLEVEL_TITLE", title);
@@ -206,219 +634,335 @@ impl WlMitmState {
         cmd
     }
 
-    fn update_last_active_surface(&mut self, surface: u32) {
-        if let Some(SurfaceXdgAssociation(xdg_surface)) = self.objects.get_object_extension(surface)
-        {
-            if let Some(XdgToplevelAssociation(xdg_toplevel)) =
-                self.objects.get_object_extension(*xdg_surface)
-            {
-                self.last_toplevel = Some(*xdg_toplevel);
+    /// Remember the verdict on `msg`, which matched `rule`, in the provenance
+    /// of the objects it created
+    fn record_rule_verdict(
+        &mut self,
+        msg: &dyn AnyWlParsedMessage,
+        rule: &WlFilterRequest,
+        verdict: WlProvenanceVerdict,
+    ) {
+        let rule: Arc<str> = match rule.desc {
+            Some(ref desc) => desc.as_str().into(),
+            None => format!("{}::{}", msg.object_type().interface(), msg.msg_name()).into(),
+        };
+        self.audit(msg, &rule, verdict);
+
+        let mut created: Vec<u32> = msg
+            .known_objects_created()
+            .into_iter()
+            .flatten()
+            .map(|(id, _)| id)
+            .collect();
+        if let Some(msg) = msg.downcast_ref::<WlRegistryBindRequest>() {
+            created.push(msg.id);
+        }
+
+        for id in created {
+            if let Some(provenance) = self.objects.provenance_mut(id) {
+                provenance.verdict = verdict;
+                provenance.rule = Some(rule.clone());
             }
         }
     }
 
+    /// Append the decision on `msg` to the audit log, if enabled
+    fn audit(&self, msg: &dyn AnyWlParsedMessage, rule: &str, verdict: WlProvenanceVerdict) {
+        self.audit_request(
+            msg.object_type().interface(),
+            msg.msg_name(),
+            msg.obj_id(),
+            rule,
+            verdict,
+        );
+    }
+
+    /// Like [Self::audit], for a request on `obj_id` which wasn't parsed
+    fn audit_request(
+        &self,
+        interface: &str,
+        request: &str,
+        obj_id: u32,
+        rule: &str,
+        verdict: WlProvenanceVerdict,
+    ) {
+        let Some(ref audit) = self.audit else {
+            return;
+        };
+
+        audit.record(&WlAuditEntry {
+            pid: self.peer.pid,
+            uid: self.peer.uid,
+            exe: self.peer.exe.as_ref().map(|p| p.display().to_string()),
+            app_id: self.app_id(),
+            flatpak_app_id: self.peer.flatpak.as_ref().map(|f| f.app_id.clone()),
+            security_context: self.peer.security_context.clone(),
+            interface: interface.to_string(),
+            request: request.to_string(),
+            rule: rule.to_string(),
+            verdict,
+            provenance: self.objects.provenance_chain(obj_id),
+        });
+    }
+
     /// Returns the number of fds consumed while parsing the message as a concrete Wayland type, and a verdict
     #[tracing::instrument(skip_all)]
     pub async fn on_c2s_request(&mut self, raw_msg: &WlRawMsg) -> WlMitmOutcome {
         let mut outcome: WlMitmOutcome = Default::default();
-        let msg = match crate::proto::decode_request(&self.objects, raw_msg) {
-            WaylandProtocolParsingOutcome::Ok(msg) => msg,
-            _ => {
-                let obj_type = self
-                    .objects
-                    .lookup_object(raw_msg.obj_id)
-                    .map(|t| t.interface());
-
-                error!(
-                    obj_id = raw_msg.obj_id,
-                    obj_type = ?obj_type,
-                    opcode = raw_msg.opcode,
-                    num_fds = raw_msg.fds.len(),
-                    "Malformed or unknown request"
-                );
-                return outcome.terminate();
-            }
+        let obj_type = self.objects.lookup_object(raw_msg.obj_id);
+        let Some((obj_type, parser)) = obj_type
+            .and_then(|t| Some((t, crate::proto::lookup_request_parser(t, raw_msg.opcode)?)))
+        else {
+            error!(
+                obj_id = raw_msg.obj_id,
+                obj_type = ?obj_type.map(|t| t.interface()),
+                opcode = raw_msg.opcode,
+                num_fds = raw_msg.fds.len(),
+                "Unknown request"
+            );
+            return outcome.terminate();
         };
 
-        outcome.set_consumed_fds(msg.num_consumed_fds());
+        outcome.set_consumed_fds(parser.num_consumed_fds());
 
         if self.config.logging.log_all_requests {
             debug!(
-                obj_id = msg.obj_id(),
+                obj_id = raw_msg.obj_id,
                 raw_payload_bytes = ?raw_msg.payload(),
                 num_fds = raw_msg.fds.len(),
-                num_consumed_fds = msg.num_consumed_fds(),
+                num_consumed_fds = parser.num_consumed_fds(),
                 "{}::{}",
-                msg.object_type().interface(),
-                msg.msg_name(),
+                obj_type.interface(),
+                parser.msg_name(),
             )
         }
 
         // To get here, the object referred to in raw_msg must exist, but it might already be destroyed by the client
         // In that case, the client is broken!
-        if self.objects.is_half_destroyed(msg.obj_id()) {
+        if self.objects.is_half_destroyed(raw_msg.obj_id) {
             error!(
-                obj_id = msg.obj_id(),
-                opcode = msg.opcode(),
+                obj_id = raw_msg.obj_id,
+                opcode = raw_msg.opcode,
                 "Client request detected on object already scheduled for destruction; aborting!"
             );
             return outcome.terminate();
         }
 
+        if let Some(ref policy) = self.policy {
+            policy.record_request(obj_type.interface(), parser.msg_name());
+        }
+
+        // Whether the request is in the enforced app profile; it is filtered
+        // once the object table has been brought up to date
+        let learned = self
+            .learning
+            .as_mut()
+            .is_none_or(|l| l.on_request(obj_type.interface(), parser.msg_name()));
+        // Likewise for requests denied while the session is locked
+        let lock_denied = self.session.as_ref().is_some_and(|s| s.is_locked())
+            && self
+                .config
+                .lock
+                .denies(obj_type.interface(), parser.msg_name());
+
+        if !self.interest.requests.contains(&(obj_type, raw_msg.opcode)) {
+            let (interface, request) = (obj_type.interface(), parser.msg_name());
+            return match self.pass_uninteresting(raw_msg, obj_type, parser, true) {
+                true if !learned => {
+                    warn!(
+                        "Blocked {}::{} as it is not in the app profile",
+                        interface, request
+                    );
+                    self.audit_request(
+                        interface,
+                        request,
+                        raw_msg.obj_id,
+                        "learning",
+                        WlProvenanceVerdict::Blocked,
+                    );
+                    outcome.filtered()
+                }
+                true if lock_denied => {
+                    warn!(
+                        "Blocked {}::{} while the session is locked",
+                        interface, request
+                    );
+                    self.audit_request(
+                        interface,
+                        request,
+                        raw_msg.obj_id,
+                        "lock.deny",
+                        WlProvenanceVerdict::Blocked,
+                    );
+                    outcome.filtered()
+                }
+                true => outcome.allowed(),
+                false => outcome.terminate(),
+            };
+        }
+
+        let WaylandProtocolParsingOutcome::Ok(msg) =
+            crate::proto::decode_request(&self.objects, raw_msg)
+        else {
+            error!(
+                obj_id = raw_msg.obj_id,
+                obj_type = obj_type.interface(),
+                opcode = raw_msg.opcode,
+                num_fds = raw_msg.fds.len(),
+                "Malformed request"
+            );
+            return outcome.terminate();
+        };
+
         if !self.handle_created_or_destroyed_objects(&*msg, true) {
             return outcome.terminate();
         }
 
-        // The bind request doesn't create interface with a fixed type; handle it separately.
-        if let Some(msg) = msg.downcast_ref::<WlRegistryBindRequest>() {
-            // If we have blocked this global, this lookup should return None, thus blocking client attempts
-            // to bind to a blocked global.
-            // Note that because we've removed said global from the registry, a client _SHOULD NOT_ be attempting
-            // to bind to it; if it does, it's likely a malicious client!
-            // So, we simply remove these messages from the stream, which will cause the Wayland server to error out.
-            let Some(obj_type) = self.objects.lookup_global(msg.name) else {
-                warn!(
-                    interface = msg.name,
-                    version = msg.id_interface_version,
-                    obj_id = msg.id,
-                    "Client binding non-existent or filtered interface"
-                );
-                return outcome.terminate();
-            };
+        // Asking the user can't happen in a handler, which can't wait
+        if let Some(msg) = msg.downcast_ref::<WlMitmV1RequestPermissionRequest>() {
+            return outcome.verdict(self.on_request_permission(msg).await);
+        }
 
-            if obj_type.interface() != msg.id_interface_name {
-                error!(
-                    "Client binding to interface {}, but the interface name {} should correspond to {}",
-                    msg.id_interface_name,
-                    msg.name,
-                    obj_type.interface()
-                );
-                return outcome.terminate();
-            }
+        if let Some(verdict) = self.run_handlers(&HANDLERS.requests, &*msg) {
+            return outcome.verdict(verdict);
+        }
+
+        if !self.validator.on_request(&mut self.objects, &*msg) {
+            return outcome.terminate();
+        }
 
-            info!(
-                interface = obj_type.interface(),
-                version = msg.id_interface_version,
-                obj_id = msg.id,
-                "Client binding interface"
+        if !learned {
+            warn!(
+                "Blocked {}::{} as it is not in the app profile",
+                msg.object_type().interface(),
+                msg.msg_name()
             );
+            self.audit(&*msg, "learning", WlProvenanceVerdict::Blocked);
+            return outcome.filtered();
+        }
 
-            self.objects.record_object(obj_type, msg.id);
-        } else if let Some(msg) = msg.downcast_ref::<XdgWmBaseGetXdgSurfaceRequest>() {
-            self.objects
-                .put_object_extension(msg.surface, SurfaceXdgAssociation(msg.id));
-        } else if let Some(msg) = msg.downcast_ref::<XdgSurfaceGetToplevelRequest>() {
-            self.objects
-                .put_object_extension(msg.obj_id(), XdgToplevelAssociation(msg.id));
-            self.objects
-                .put_object_extension(msg.id, ToplevelSurfaceInfo::default());
-        } else if let Some(msg) = msg.downcast_ref::<XdgToplevelSetAppIdRequest>() {
-            if let Some(info) = self
-                .objects
-                .get_object_extension_mut::<ToplevelSurfaceInfo>(msg.obj_id())
-            {
-                info.app_id = Some(msg.app_id.to_string());
-            }
-        } else if let Some(msg) = msg.downcast_ref::<XdgToplevelSetTitleRequest>() {
-            if let Some(info) = self
-                .objects
-                .get_object_extension_mut::<ToplevelSurfaceInfo>(msg.obj_id())
-            {
-                info.title = Some(msg.title.to_string());
-            }
+        if lock_denied {
+            warn!(
+                "Blocked {}::{} while the session is locked",
+                msg.object_type().interface(),
+                msg.msg_name()
+            );
+            self.audit(&*msg, "lock.deny", WlProvenanceVerdict::Blocked);
+            return outcome.filtered();
         }
 
         // Handle requests configured to be filtered
-        if let Some(filtered_requests) = self
-            .config
-            .filter
-            .requests
-            .get(msg.object_type().interface())
-        {
-            if let Some(filtered) = filtered_requests
-                .iter()
-                .find(|f| f.requests.contains(msg.msg_name()))
-            {
-                match filtered.action {
-                    WlFilterRequestAction::Ask => {
-                        if let Some(ref ask_cmd) = self.config.exec.ask_cmd {
-                            info!(
-                                ask_cmd = ask_cmd,
-                                "Running ask command for {}::{}",
-                                msg.object_type().interface(),
-                                msg.msg_name()
-                            );
-
-                            let mut cmd = self.prepare_command(
-                                &*msg,
-                                ask_cmd,
-                                filtered.desc.as_deref().unwrap_or_else(|| ""),
-                            );
-
-                            if let Ok(status) = cmd.status().await {
-                                if !status.success() {
-                                    warn!(
-                                        "Blocked {}::{} because of return status {}",
-                                        msg.object_type().interface(),
-                                        msg.msg_name(),
-                                        status
-                                    );
-
-                                    return match filtered.block_type {
-                                        WlFilterRequestBlockType::Ignore => outcome.filtered(),
-                                        WlFilterRequestBlockType::Reject => {
-                                            outcome.rejected(filtered.error_code)
-                                        }
-                                    };
-                                } else {
-                                    return outcome.allowed();
-                                }
-                            }
-                        }
+        let config = self.config.clone();
+        if let Some((interface, index, filtered)) = self.find_filter_rule(&config, &*msg) {
+            // Where the object this is sent on came from, for the audit trail
+            let provenance =
+                serde_json::to_string(&self.objects.provenance_chain(msg.obj_id())).unwrap();
 
-                        warn!(
-                            "Blocked {}::{} because of missing ask_cmd",
+            match filtered.action {
+                WlFilterRequestAction::Ask
+                    if self.granted.contains(&(interface.to_string(), index)) =>
+                {
+                    debug!(
+                        "Allowed {}::{} as permission was granted before",
+                        msg.object_type().interface(),
+                        msg.msg_name()
+                    );
+                    self.record_rule_verdict(&*msg, filtered, WlProvenanceVerdict::Approved);
+                }
+                WlFilterRequestAction::Ask => {
+                    if let Some(ref ask_cmd) = self.config.exec.ask_cmd {
+                        info!(
+                            ask_cmd = ask_cmd,
+                            provenance = provenance,
+                            "Running ask command for {}::{}",
                             msg.object_type().interface(),
                             msg.msg_name()
                         );
-                        return match filtered.block_type {
-                            WlFilterRequestBlockType::Ignore => outcome.filtered(),
-                            WlFilterRequestBlockType::Reject => {
-                                outcome.rejected(filtered.error_code)
+
+                        let cmd = self.prepare_command(
+                            &*msg,
+                            ask_cmd,
+                            filtered.desc.as_deref().unwrap_or_else(|| ""),
+                            &provenance,
+                        );
+
+                        if let Ok(status) = spawner::status(cmd).await {
+                            if !status.success() {
+                                warn!(
+                                    provenance = provenance,
+                                    "Blocked {}::{} because of return status {}",
+                                    msg.object_type().interface(),
+                                    msg.msg_name(),
+                                    status
+                                );
+
+                                self.record_rule_verdict(
+                                    &*msg,
+                                    filtered,
+                                    WlProvenanceVerdict::Blocked,
+                                );
+                                return match filtered.block_type {
+                                    WlFilterRequestBlockType::Ignore => outcome.filtered(),
+                                    WlFilterRequestBlockType::Reject => {
+                                        outcome.rejected(filtered.error_code)
+                                    }
+                                };
+                            } else {
+                                self.record_rule_verdict(
+                                    &*msg,
+                                    filtered,
+                                    WlProvenanceVerdict::Approved,
+                                );
+                                return outcome.allowed();
                             }
-                        };
-                    }
-                    WlFilterRequestAction::Notify => {
-                        if let Some(ref notify_cmd) = self.config.exec.notify_cmd {
-                            info!(
-                                notify_cmd = notify_cmd,
-                                "Running notify command for {}::{}",
-                                msg.object_type().interface(),
-                                msg.msg_name()
-                            );
-
-                            let mut cmd = self.prepare_command(
-                                &*msg,
-                                notify_cmd,
-                                filtered.desc.as_deref().unwrap_or_else(|| ""),
-                            );
-
-                            cmd.spawn().ok();
                         }
                     }
-                    WlFilterRequestAction::Block => {
-                        warn!(
-                            "Blocked {}::{}",
+
+                    warn!(
+                        provenance = provenance,
+                        "Blocked {}::{} because of missing ask_cmd",
+                        msg.object_type().interface(),
+                        msg.msg_name()
+                    );
+                    self.record_rule_verdict(&*msg, filtered, WlProvenanceVerdict::Blocked);
+                    return match filtered.block_type {
+                        WlFilterRequestBlockType::Ignore => outcome.filtered(),
+                        WlFilterRequestBlockType::Reject => outcome.rejected(filtered.error_code),
+                    };
+                }
+                WlFilterRequestAction::Notify => {
+                    if let Some(ref notify_cmd) = self.config.exec.notify_cmd {
+                        info!(
+                            notify_cmd = notify_cmd,
+                            provenance = provenance,
+                            "Running notify command for {}::{}",
                             msg.object_type().interface(),
                             msg.msg_name()
                         );
-                        return match filtered.block_type {
-                            WlFilterRequestBlockType::Ignore => outcome.filtered(),
-                            WlFilterRequestBlockType::Reject => {
-                                outcome.rejected(filtered.error_code)
-                            }
-                        };
+
+                        let cmd = self.prepare_command(
+                            &*msg,
+                            notify_cmd,
+                            filtered.desc.as_deref().unwrap_or_else(|| ""),
+                            &provenance,
+                        );
+
+                        spawner::spawn(cmd).await.ok();
                     }
+                    self.record_rule_verdict(&*msg, filtered, WlProvenanceVerdict::Notified);
+                }
+                WlFilterRequestAction::Block => {
+                    warn!(
+                        provenance = provenance,
+                        "Blocked {}::{}",
+                        msg.object_type().interface(),
+                        msg.msg_name()
+                    );
+                    self.record_rule_verdict(&*msg, filtered, WlProvenanceVerdict::Blocked);
+                    return match filtered.block_type {
+                        WlFilterRequestBlockType::Ignore => outcome.filtered(),
+                        WlFilterRequestBlockType::Reject => outcome.rejected(filtered.error_code),
+                    };
                 }
             }
         }
@@ -429,87 +973,65 @@ impl WlMitmState {
     #[tracing::instrument(skip_all)]
     pub async fn on_s2c_event(&mut self, raw_msg: &WlRawMsg) -> WlMitmOutcome {
         let mut outcome: WlMitmOutcome = Default::default();
-        let msg = match crate::proto::decode_event(&self.objects, raw_msg) {
-            WaylandProtocolParsingOutcome::Ok(msg) => msg,
-            _ => {
-                let obj_type = self
-                    .objects
-                    .lookup_object(raw_msg.obj_id)
-                    .map(|t| t.interface());
-
-                error!(
-                    obj_id = raw_msg.obj_id,
-                    obj_type = ?obj_type,
-                    opcode = raw_msg.opcode,
-                    num_fds = raw_msg.fds.len(),
-                    "Malformed or unknown event"
-                );
-                return outcome.terminate();
-            }
+        // Before our own idea of the object gets in the way
+        if let Some(verdict) = self.on_status_placeholder_event(raw_msg) {
+            return outcome.verdict(verdict);
+        }
+        let obj_type = self.objects.lookup_object(raw_msg.obj_id);
+        let Some((obj_type, parser)) =
+            obj_type.and_then(|t| Some((t, crate::proto::lookup_event_parser(t, raw_msg.opcode)?)))
+        else {
+            error!(
+                obj_id = raw_msg.obj_id,
+                obj_type = ?obj_type.map(|t| t.interface()),
+                opcode = raw_msg.opcode,
+                num_fds = raw_msg.fds.len(),
+                "Unknown event"
+            );
+            return outcome.terminate();
         };
 
-        outcome.set_consumed_fds(msg.num_consumed_fds());
+        outcome.set_consumed_fds(parser.num_consumed_fds());
 
         if self.config.logging.log_all_events {
             debug!(
-                obj_id = msg.obj_id(),
+                obj_id = raw_msg.obj_id,
                 raw_payload_bytes = ?raw_msg.payload(),
                 num_fds = raw_msg.fds.len(),
-                num_consumed_fds = msg.num_consumed_fds(),
+                num_consumed_fds = parser.num_consumed_fds(),
                 "{}::{}",
-                msg.object_type().interface(),
-                msg.msg_name(),
+                obj_type.interface(),
+                parser.msg_name(),
             )
         }
 
-        if !self.handle_created_or_destroyed_objects(&*msg, false) {
-            return outcome.terminate();
+        if !self.interest.events.contains(&(obj_type, raw_msg.opcode)) {
+            return match self.pass_uninteresting(raw_msg, obj_type, parser, false) {
+                true => outcome.allowed(),
+                false => outcome.terminate(),
+            };
         }
 
-        if let Some(msg) = msg.downcast_ref::<WlRegistryGlobalEvent>() {
-            // This event is how Wayland servers announce globals -- and they are the entrypoint to
-            // most extensions! You need at least one global registered for clients to be able to
-            // access methods from that extension; but those methods _could_ create more objects.
-            debug!(
-                interface = msg.interface,
-                name = msg.name,
-                version = msg.version,
-                "got global"
+        let WaylandProtocolParsingOutcome::Ok(msg) =
+            crate::proto::decode_event(&self.objects, raw_msg)
+        else {
+            error!(
+                obj_id = raw_msg.obj_id,
+                obj_type = obj_type.interface(),
+                opcode = raw_msg.opcode,
+                num_fds = raw_msg.fds.len(),
+                "Malformed event"
             );
+            return outcome.terminate();
+        };
 
-            let Some(obj_type) = crate::proto::lookup_known_object_type(msg.interface) else {
-                error!(
-                    interface = msg.interface,
-                    "Unknown interface removed! If required, please include its XML when building wl-mitm!"
-                );
-
-                return outcome.filtered();
-            };
-
-            // To block entire extensions, we just need to filter out their announced global objects.
-            if !self.config.filter.allowed_globals.contains(msg.interface) {
-                info!(
-                    interface = msg.interface,
-                    "Removing interface from published globals"
-                );
-                return outcome.filtered();
-            }
+        if !self.handle_created_or_destroyed_objects(&*msg, false) {
+            return outcome.terminate();
+        }
+        self.validator.on_event(&mut self.objects, &*msg);
 
-            // Else, record the global object. These are the only ones we're ever going to allow through.
-            // We block bind requests on any interface that's not recorded here.
-            self.objects.record_global(msg.name, obj_type);
-        } else if let Some(msg) = msg.downcast_ref::<WlRegistryGlobalRemoveEvent>() {
-            // Remove globals that the server has removed
-            self.objects.remove_global(msg.name);
-        } else if let Some(msg) = msg.downcast_ref::<WlDisplayDeleteIdEvent>() {
-            // Server has acknowledged deletion of an object
-            self.objects.remove_object(msg.id, false);
-        } else if let Some(msg) = msg.downcast_ref::<WlPointerEnterEvent>() {
-            self.update_last_active_surface(msg.surface);
-        } else if let Some(msg) = msg.downcast_ref::<WlKeyboardEnterEvent>() {
-            self.update_last_active_surface(msg.surface);
-        } else if let Some(msg) = msg.downcast_ref::<WlTouchDownEvent>() {
-            self.update_last_active_surface(msg.surface);
+        if let Some(verdict) = self.run_handlers(&HANDLERS.events, &*msg) {
+            return outcome.verdict(verdict);
         }
 
         outcome.allowed()
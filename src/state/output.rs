@@ -0,0 +1,303 @@
+//! The virtual output: one synthetic wl_output in place of the real ones
+//!
+//! For clients selected by `[virtual_output]` in the config, only the first
+//! wl_output global is announced, and everything describing it is replaced
+//! with the configured resolution and scale: wl_output and xdg_output events,
+//! preferred scales, and toplevel bounds. The compositor's own enter and
+//! leave events are dropped. Instead, every surface enters the virtual output
+//! once it has a buffer attached, and stays on it.
+//!
+//! Replaced events get [WlMitmVerdict::Replaced], with their replacements
+//! among [WlMitmState::take_replies].
+
+use tracing::{debug, info};
+
+use crate::{
+    codec::WlRawMsg,
+    objects::WlObjectExtension,
+    proto::{
+        AnyWlParsedMessage, WL_OUTPUT, WlConstructableMessage, WlOutputDescriptionEvent,
+        WlOutputGeometryEvent, WlOutputModeEvent, WlOutputNameEvent, WlOutputScaleEvent,
+        WlRegistryBindRequest, WlRegistryGlobalRemoveEvent, WlSurfaceAttachRequest,
+        WlSurfaceEnterEvent, WlSurfaceLeaveEvent, WlSurfacePreferredBufferScaleEvent,
+        WpFractionalScaleV1PreferredScaleEvent, XdgToplevelConfigureBoundsEvent,
+        ZxdgOutputV1DescriptionEvent, ZxdgOutputV1LogicalPositionEvent,
+        ZxdgOutputV1LogicalSizeEvent, ZxdgOutputV1NameEvent,
+    },
+};
+
+use super::{WlHandlers, WlMitmState, WlMitmVerdict};
+
+const VIRTUAL_OUTPUT_NAME: &str = "WL-MITM-1";
+const VIRTUAL_OUTPUT_DESCRIPTION: &str = "wl-mitm virtual output";
+const VIRTUAL_OUTPUT_MAKE: &str = "wl-mitm";
+const VIRTUAL_OUTPUT_MODEL: &str = "Virtual Output";
+
+/// `wl_output.mode` flags
+const MODE_CURRENT: u32 = 0x1;
+const MODE_PREFERRED: u32 = 0x2;
+
+/// Marks wl_surfaces which have entered the virtual output
+struct OnVirtualOutput;
+
+impl WlObjectExtension for OnVirtualOutput {}
+
+pub(super) fn register(handlers: &mut WlHandlers) {
+    handle!(handlers, requests, WlRegistryBindRequest => on_bind);
+    handle!(handlers, requests, WlSurfaceAttachRequest => on_attach);
+    handle!(handlers, events, WlRegistryGlobalRemoveEvent => on_global_remove);
+    handle!(handlers, events, WlOutputGeometryEvent => on_geometry);
+    handle!(handlers, events, WlOutputModeEvent => on_mode);
+    handle!(handlers, events, WlOutputScaleEvent => on_scale);
+    handle!(handlers, events, WlOutputNameEvent => on_name);
+    handle!(handlers, events, WlOutputDescriptionEvent => on_description);
+    handle!(handlers, events, ZxdgOutputV1LogicalPositionEvent => on_logical_position);
+    handle!(handlers, events, ZxdgOutputV1LogicalSizeEvent => on_logical_size);
+    handle!(handlers, events, ZxdgOutputV1NameEvent => on_xdg_name);
+    handle!(handlers, events, ZxdgOutputV1DescriptionEvent => on_xdg_description);
+    handle!(handlers, events, WlSurfaceEnterEvent => on_enter_or_leave);
+    handle!(handlers, events, WlSurfaceLeaveEvent => on_enter_or_leave);
+    handle!(handlers, events, WlSurfacePreferredBufferScaleEvent => on_preferred_buffer_scale);
+    handle!(handlers, events, WpFractionalScaleV1PreferredScaleEvent => on_preferred_scale);
+    handle!(handlers, events, XdgToplevelConfigureBoundsEvent => on_configure_bounds);
+}
+
+/// Runs after [super::registry]'s handler has let the bind through. Surfaces
+/// already on the virtual output enter the new wl_output object as well.
+fn on_bind(state: &mut WlMitmState, msg: &WlRegistryBindRequest) -> Option<WlMitmVerdict> {
+    if !state.virtual_output || msg.id_interface_name != WL_OUTPUT.interface() {
+        return None;
+    }
+
+    let surfaces: Vec<_> = state
+        .objects
+        .iter_extensions::<OnVirtualOutput>()
+        .map(|(id, _)| id)
+        .collect();
+    for surface in surfaces {
+        state
+            .replies
+            .push(WlSurfaceEnterEvent::new(surface, msg.id).build());
+    }
+    None
+}
+
+fn on_attach(state: &mut WlMitmState, msg: &WlSurfaceAttachRequest) -> Option<WlMitmVerdict> {
+    if !state.virtual_output
+        || msg.buffer == 0
+        || state
+            .objects
+            .extension::<OnVirtualOutput>(msg.obj_id())
+            .is_some()
+    {
+        return None;
+    }
+
+    debug!(
+        surface = msg.obj_id(),
+        "Surface entering the virtual output"
+    );
+    state.objects.put_extension(msg.obj_id(), OnVirtualOutput);
+    for output in state.virtual_outputs() {
+        state
+            .replies
+            .push(WlSurfaceEnterEvent::new(msg.obj_id(), output).build());
+    }
+    None
+}
+
+/// Runs after [super::registry]'s handler
+fn on_global_remove(
+    state: &mut WlMitmState,
+    msg: &WlRegistryGlobalRemoveEvent,
+) -> Option<WlMitmVerdict> {
+    if state.virtual_output_global == Some(msg.name) {
+        info!(
+            name = msg.name,
+            "Output behind the virtual output removed; the next one announced takes its place"
+        );
+        state.virtual_output_global = None;
+    }
+    None
+}
+
+fn on_geometry(state: &mut WlMitmState, msg: &WlOutputGeometryEvent) -> Option<WlMitmVerdict> {
+    state.replace_event(|_| {
+        // The physical size is unknown, as for projectors
+        WlOutputGeometryEvent::new(
+            msg.obj_id(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            VIRTUAL_OUTPUT_MAKE,
+            VIRTUAL_OUTPUT_MODEL,
+            0,
+        )
+        .build()
+    })
+}
+
+fn on_mode(state: &mut WlMitmState, msg: &WlOutputModeEvent) -> Option<WlMitmVerdict> {
+    if !state.virtual_output {
+        return None;
+    }
+
+    // The virtual output has no modes but the current one
+    if msg.flags & MODE_CURRENT == 0 {
+        return Some(WlMitmVerdict::Filtered);
+    }
+    state.replace_event(|config| {
+        WlOutputModeEvent::new(
+            msg.obj_id(),
+            MODE_CURRENT | MODE_PREFERRED,
+            config.width,
+            config.height,
+            config.refresh,
+        )
+        .build()
+    })
+}
+
+fn on_scale(state: &mut WlMitmState, msg: &WlOutputScaleEvent) -> Option<WlMitmVerdict> {
+    state.replace_event(|config| WlOutputScaleEvent::new(msg.obj_id(), config.scale).build())
+}
+
+fn on_name(state: &mut WlMitmState, msg: &WlOutputNameEvent) -> Option<WlMitmVerdict> {
+    state.replace_event(|_| WlOutputNameEvent::new(msg.obj_id(), VIRTUAL_OUTPUT_NAME).build())
+}
+
+fn on_description(
+    state: &mut WlMitmState,
+    msg: &WlOutputDescriptionEvent,
+) -> Option<WlMitmVerdict> {
+    state.replace_event(|_| {
+        WlOutputDescriptionEvent::new(msg.obj_id(), VIRTUAL_OUTPUT_DESCRIPTION).build()
+    })
+}
+
+fn on_logical_position(
+    state: &mut WlMitmState,
+    msg: &ZxdgOutputV1LogicalPositionEvent,
+) -> Option<WlMitmVerdict> {
+    state.replace_event(|_| ZxdgOutputV1LogicalPositionEvent::new(msg.obj_id(), 0, 0).build())
+}
+
+fn on_logical_size(
+    state: &mut WlMitmState,
+    msg: &ZxdgOutputV1LogicalSizeEvent,
+) -> Option<WlMitmVerdict> {
+    state.replace_event(|config| {
+        let (width, height) = config.logical_size();
+        ZxdgOutputV1LogicalSizeEvent::new(msg.obj_id(), width, height).build()
+    })
+}
+
+fn on_xdg_name(state: &mut WlMitmState, msg: &ZxdgOutputV1NameEvent) -> Option<WlMitmVerdict> {
+    state.replace_event(|_| ZxdgOutputV1NameEvent::new(msg.obj_id(), VIRTUAL_OUTPUT_NAME).build())
+}
+
+fn on_xdg_description(
+    state: &mut WlMitmState,
+    msg: &ZxdgOutputV1DescriptionEvent,
+) -> Option<WlMitmVerdict> {
+    state.replace_event(|_| {
+        ZxdgOutputV1DescriptionEvent::new(msg.obj_id(), VIRTUAL_OUTPUT_DESCRIPTION).build()
+    })
+}
+
+/// Surfaces enter the virtual output on their own, see [on_attach]
+fn on_enter_or_leave(
+    state: &mut WlMitmState,
+    _msg: &dyn AnyWlParsedMessage,
+) -> Option<WlMitmVerdict> {
+    state.virtual_output.then_some(WlMitmVerdict::Filtered)
+}
+
+fn on_preferred_buffer_scale(
+    state: &mut WlMitmState,
+    msg: &WlSurfacePreferredBufferScaleEvent,
+) -> Option<WlMitmVerdict> {
+    state.replace_event(|config| {
+        WlSurfacePreferredBufferScaleEvent::new(msg.obj_id(), config.scale).build()
+    })
+}
+
+fn on_preferred_scale(
+    state: &mut WlMitmState,
+    msg: &WpFractionalScaleV1PreferredScaleEvent,
+) -> Option<WlMitmVerdict> {
+    // In 120ths
+    state.replace_event(|config| {
+        WpFractionalScaleV1PreferredScaleEvent::new(msg.obj_id(), config.scale.max(1) as u32 * 120)
+            .build()
+    })
+}
+
+fn on_configure_bounds(
+    state: &mut WlMitmState,
+    msg: &XdgToplevelConfigureBoundsEvent,
+) -> Option<WlMitmVerdict> {
+    // 0x0 means the bounds are unknown, which is no giveaway
+    if msg.width == 0 && msg.height == 0 {
+        return None;
+    }
+    state.replace_event(|config| {
+        let (width, height) = config.logical_size();
+        XdgToplevelConfigureBoundsEvent::new(msg.obj_id(), width, height).build()
+    })
+}
+
+impl WlMitmState {
+    /// Whether the client is presented the virtual output, see `[virtual_output]`
+    /// in the config
+    pub fn has_virtual_output(&self) -> bool {
+        self.virtual_output
+    }
+
+    /// Pick the client for the virtual output, going by its identity
+    pub(super) fn detect_virtual_output(&mut self) {
+        self.virtual_output = self.config.virtual_output.matches(&self.peer);
+    }
+
+    /// Whether the wl_output global `name` is to be hidden, because another
+    /// one is presented as the virtual output
+    pub(super) fn hides_output_global(&mut self, name: u32) -> bool {
+        if !self.virtual_output {
+            return false;
+        }
+
+        match self.virtual_output_global {
+            Some(virtual_name) => virtual_name != name,
+            None => {
+                debug!(name, "Presenting output as the virtual output");
+                self.virtual_output_global = Some(name);
+                false
+            }
+        }
+    }
+
+    /// The client's live wl_output objects, which are all the virtual output
+    fn virtual_outputs(&self) -> Vec<u32> {
+        self.objects
+            .objects_of_type(WL_OUTPUT)
+            .filter(|id| !self.objects.is_half_destroyed(*id))
+            .collect()
+    }
+
+    /// Replace the event being handled by the one `build` makes, if the
+    /// client is presented the virtual output
+    fn replace_event(
+        &mut self,
+        build: impl FnOnce(&crate::config::WlVirtualOutputConfig) -> WlRawMsg,
+    ) -> Option<WlMitmVerdict> {
+        if !self.virtual_output {
+            return None;
+        }
+
+        let msg = build(&self.config.virtual_output);
+        self.replies.push(msg);
+        Some(WlMitmVerdict::Replaced)
+    }
+}
@@ -0,0 +1,191 @@
+//! wl_registry handling: which globals the client gets to see and bind
+
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    objects::WlObjectProvenance,
+    proto::{
+        AnyWlParsedMessage, WL_OUTPUT, WlRegistryBindRequest, WlRegistryGlobalEvent,
+        WlRegistryGlobalRemoveEvent,
+    },
+};
+
+use super::{WlHandlers, WlMitmState, WlMitmVerdict};
+
+pub(super) fn register(handlers: &mut WlHandlers) {
+    handle!(handlers, requests, WlRegistryBindRequest => on_bind);
+    handle!(handlers, events, WlRegistryGlobalEvent => on_global);
+    handle!(handlers, events, WlRegistryGlobalRemoveEvent => on_global_remove);
+}
+
+/// The bind request doesn't create interface with a fixed type; it is handled here
+/// rather than with other created objects.
+fn on_bind(state: &mut WlMitmState, msg: &WlRegistryBindRequest) -> Option<WlMitmVerdict> {
+    // If we have blocked this global, this lookup should return None, thus blocking client attempts
+    // to bind to a blocked global.
+    // Note that because we've removed said global from the registry, a client _SHOULD NOT_ be attempting
+    // to bind to it; if it does, it's likely a malicious client!
+    // So, we simply remove these messages from the stream, which will cause the Wayland server to error out.
+    let obj_type = state
+        .objects
+        .lookup_global(msg.obj_id(), msg.name)
+        .or_else(|| {
+            // Legal, but only seen from clients mixing up their registries, or
+            // using one we have forgotten about
+            let obj_type = state.objects.lookup_global_any(msg.name)?;
+            debug!(
+                registry = msg.obj_id(),
+                name = msg.name,
+                forgotten = state.objects.is_registry_forgotten(msg.obj_id()),
+                "Client binding global announced on another registry"
+            );
+            Some(obj_type)
+        });
+    let Some(obj_type) = obj_type else {
+        warn!(
+            interface = msg.name,
+            version = msg.id_interface_version,
+            obj_id = msg.id,
+            "Client binding non-existent or filtered interface"
+        );
+        return Some(WlMitmVerdict::Terminate);
+    };
+
+    if obj_type.interface() != msg.id_interface_name {
+        error!(
+            "Client binding to interface {}, but the interface name {} should correspond to {}",
+            msg.id_interface_name,
+            msg.name,
+            obj_type.interface()
+        );
+        return Some(WlMitmVerdict::Terminate);
+    }
+
+    info!(
+        interface = obj_type.interface(),
+        version = msg.id_interface_version,
+        obj_id = msg.id,
+        "Client binding interface"
+    );
+
+    if !state.objects.check_object_limit() {
+        return Some(WlMitmVerdict::Terminate);
+    }
+
+    if let Some(ref policy) = state.policy {
+        policy.record_bind(obj_type.interface());
+    }
+
+    if let Some(ref mut learning) = state.learning
+        && !learning.on_bind(obj_type.interface())
+    {
+        warn!(
+            interface = obj_type.interface(),
+            obj_id = msg.id,
+            "Blocked binding interface as it is not in the app profile"
+        );
+        return Some(WlMitmVerdict::Filtered);
+    }
+
+    state.objects.registry_used(msg.obj_id());
+    state.objects.record_child_object(
+        obj_type,
+        msg.id,
+        msg.obj_id(),
+        Some(msg.id_interface_version),
+    );
+    state.objects.set_provenance(
+        msg.id,
+        WlObjectProvenance::new(msg.object_type().interface(), msg.msg_name()),
+    );
+    None
+}
+
+fn on_global(state: &mut WlMitmState, msg: &WlRegistryGlobalEvent) -> Option<WlMitmVerdict> {
+    // This event is how Wayland servers announce globals -- and they are the entrypoint to
+    // most extensions! You need at least one global registered for clients to be able to
+    // access methods from that extension; but those methods _could_ create more objects.
+    debug!(
+        interface = msg.interface,
+        name = msg.name,
+        version = msg.version,
+        "got global"
+    );
+
+    // A name announced again without being removed first is a server bug. Either way,
+    // only the latest announcement counts: forget what was announced before.
+    if let Some(stale) = state.objects.remove_global(msg.obj_id(), msg.name) {
+        warn!(
+            name = msg.name,
+            interface = msg.interface,
+            stale_interface = stale.interface(),
+            "Server re-announced global name without removing it first"
+        );
+    }
+
+    let Some(obj_type) = crate::proto::lookup_known_object_type(msg.interface) else {
+        error!(
+            interface = msg.interface,
+            "Unknown interface removed! If required, please include its XML when building wl-mitm!"
+        );
+
+        state.objects.record_filtered_global(msg.name);
+        state.hidden_globals.insert(msg.interface.to_string());
+        return Some(WlMitmVerdict::Filtered);
+    };
+
+    // To block entire extensions, we just need to filter out their announced global objects.
+    // While generating a policy, clients get to see everything we know, to find out what they need.
+    let allowed_globals = match state.config.xwayland.allowed_globals {
+        Some(ref allowed) if state.xwayland => allowed,
+        _ => &state.config.filter.allowed_globals,
+    };
+    if !allowed_globals.contains(msg.interface) && state.policy.is_none() {
+        info!(
+            interface = msg.interface,
+            "Removing interface from published globals"
+        );
+        state.objects.record_filtered_global(msg.name);
+        state.hidden_globals.insert(msg.interface.to_string());
+        return Some(WlMitmVerdict::Filtered);
+    }
+
+    // Clients presented the virtual output only ever see one wl_output
+    if obj_type == WL_OUTPUT && state.hides_output_global(msg.name) {
+        debug!(name = msg.name, "Hiding output behind the virtual output");
+        state.objects.record_filtered_global(msg.name);
+        return Some(WlMitmVerdict::Filtered);
+    }
+
+    // Else, record the global object. These are the only ones we're ever going to allow through.
+    // We block bind requests on any interface that's not recorded here.
+    if !state
+        .objects
+        .record_global(msg.obj_id(), msg.name, obj_type)
+    {
+        state.objects.record_filtered_global(msg.name);
+        return Some(WlMitmVerdict::Filtered);
+    }
+    None
+}
+
+fn on_global_remove(
+    state: &mut WlMitmState,
+    msg: &WlRegistryGlobalRemoveEvent,
+) -> Option<WlMitmVerdict> {
+    // Remove globals that the server has removed. The client has never seen globals
+    // we filtered, so it shouldn't learn about their removal either.
+    let known = if state.objects.is_registry_forgotten(msg.obj_id()) {
+        !state.objects.is_filtered_global(msg.name)
+    } else {
+        state
+            .objects
+            .remove_global(msg.obj_id(), msg.name)
+            .is_some()
+    };
+    if !known {
+        debug!(name = msg.name, "Hiding removal of filtered global");
+        return Some(WlMitmVerdict::Filtered);
+    }
+    None
+}
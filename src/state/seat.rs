@@ -0,0 +1,277 @@
+//! Tracking of wl_seats, their input devices, and which surfaces those
+//! devices are focused on
+
+use serde_derive::Serialize;
+use tracing::debug;
+
+use crate::{
+    objects::{WlObjectExtension, WlObjectHandle},
+    proto::{
+        AnyWlParsedMessage, WL_KEYBOARD, WL_SEAT, WlKeyboardEnterEvent, WlKeyboardLeaveEvent,
+        WlPointerEnterEvent, WlPointerLeaveEvent, WlRegistryBindRequest, WlSeatCapabilitiesEvent,
+        WlSeatNameEvent, WlTouchDownEvent,
+    },
+};
+
+use super::{WlHandlers, WlMitmState, WlMitmVerdict};
+
+/// Which input focus tells best what the user was doing when a request was
+/// sent, see [WlMitmState::focus_kind_for]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum FocusKind {
+    Keyboard,
+    /// Pointer or touch
+    Pointer,
+}
+
+/// The surface one kind of input device of a seat has entered
+#[derive(Clone, Copy, Debug)]
+pub(super) struct SeatFocus {
+    pub surface: WlObjectHandle,
+    /// Whether the device has left the surface since
+    pub left: bool,
+    /// Increases with every enter on the connection, to tell the most recent one
+    pub seq: u64,
+}
+
+/// What the server told us about a wl_seat object
+#[derive(Debug)]
+pub(super) struct SeatInfo {
+    /// Name of the global the seat was bound from. Several seat objects
+    /// bound from the same global refer to the same seat.
+    pub global: u32,
+    pub name: Option<String>,
+    pub capabilities: u32,
+    pub keyboard: Option<SeatFocus>,
+    pub pointer: Option<SeatFocus>,
+}
+
+impl SeatInfo {
+    pub fn focus(&self, kind: FocusKind) -> Option<&SeatFocus> {
+        match kind {
+            FocusKind::Keyboard => self.keyboard.as_ref(),
+            FocusKind::Pointer => self.pointer.as_ref(),
+        }
+    }
+
+    fn focus_mut(&mut self, kind: FocusKind) -> &mut Option<SeatFocus> {
+        match kind {
+            FocusKind::Keyboard => &mut self.keyboard,
+            FocusKind::Pointer => &mut self.pointer,
+        }
+    }
+}
+
+impl WlObjectExtension for SeatInfo {
+    fn describe(&self) -> String {
+        format!(
+            "seat global={} name={:?} capabilities={:#x}",
+            self.global, self.name, self.capabilities
+        )
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct WlSeatDeviceInfo {
+    pub id: u32,
+    pub interface: String,
+}
+
+/// What's known about one wl_seat object, for [crate::dump]
+#[derive(Serialize, Debug, Clone)]
+pub struct WlSeatInfo {
+    pub id: u32,
+    /// Name of the global the seat was bound from
+    pub global: Option<u32>,
+    pub name: Option<String>,
+    /// Bitmask of wl_seat.capability
+    pub capabilities: u32,
+    /// Input devices created through this seat
+    pub devices: Vec<WlSeatDeviceInfo>,
+    /// wl_surface the seat's keyboard is in, if any
+    pub keyboard_focus: Option<u32>,
+    /// wl_surface the seat's pointer (or touch) is in, if any
+    pub pointer_focus: Option<u32>,
+}
+
+pub(super) fn register(handlers: &mut WlHandlers) {
+    handle!(handlers, requests, WlRegistryBindRequest => on_bind);
+    handle!(handlers, events, WlSeatNameEvent => on_name);
+    handle!(handlers, events, WlSeatCapabilitiesEvent => on_capabilities);
+    handle!(handlers, events, WlPointerEnterEvent => on_pointer_enter);
+    handle!(handlers, events, WlPointerLeaveEvent => on_pointer_leave);
+    handle!(handlers, events, WlKeyboardEnterEvent => on_keyboard_enter);
+    handle!(handlers, events, WlKeyboardLeaveEvent => on_keyboard_leave);
+    handle!(handlers, events, WlTouchDownEvent => on_touch_down);
+}
+
+/// Runs after [super::registry] has recorded the bound object
+fn on_bind(state: &mut WlMitmState, msg: &WlRegistryBindRequest) -> Option<WlMitmVerdict> {
+    if state.objects.lookup_object(msg.id) == Some(WL_SEAT) {
+        state.objects.put_extension(
+            msg.id,
+            SeatInfo {
+                global: msg.name,
+                name: None,
+                capabilities: 0,
+                keyboard: None,
+                pointer: None,
+            },
+        );
+    }
+    None
+}
+
+fn on_name(state: &mut WlMitmState, msg: &WlSeatNameEvent) -> Option<WlMitmVerdict> {
+    if let Some(info) = state.objects.extension_mut::<SeatInfo>(msg.obj_id()) {
+        info.name = Some(msg.name.to_string());
+    }
+    None
+}
+
+fn on_capabilities(
+    state: &mut WlMitmState,
+    msg: &WlSeatCapabilitiesEvent,
+) -> Option<WlMitmVerdict> {
+    if let Some(info) = state.objects.extension_mut::<SeatInfo>(msg.obj_id()) {
+        info.capabilities = msg.capabilities;
+    }
+    None
+}
+
+fn on_pointer_enter(state: &mut WlMitmState, msg: &WlPointerEnterEvent) -> Option<WlMitmVerdict> {
+    state.update_focus(msg.obj_id(), msg.surface, true);
+    None
+}
+
+fn on_pointer_leave(state: &mut WlMitmState, msg: &WlPointerLeaveEvent) -> Option<WlMitmVerdict> {
+    state.update_focus(msg.obj_id(), msg.surface, false);
+    None
+}
+
+fn on_keyboard_enter(state: &mut WlMitmState, msg: &WlKeyboardEnterEvent) -> Option<WlMitmVerdict> {
+    state.update_focus(msg.obj_id(), msg.surface, true);
+    None
+}
+
+fn on_keyboard_leave(state: &mut WlMitmState, msg: &WlKeyboardLeaveEvent) -> Option<WlMitmVerdict> {
+    state.update_focus(msg.obj_id(), msg.surface, false);
+    None
+}
+
+fn on_touch_down(state: &mut WlMitmState, msg: &WlTouchDownEvent) -> Option<WlMitmVerdict> {
+    state.update_focus(msg.obj_id(), msg.surface, true);
+    None
+}
+
+impl WlMitmState {
+    /// All wl_seat objects with their input devices, sorted by object ID
+    pub fn seats(&self) -> Vec<WlSeatInfo> {
+        self.objects
+            .seats()
+            .into_iter()
+            .map(|id| {
+                let info = self.objects.extension::<SeatInfo>(id);
+                let focus = |kind| {
+                    info.and_then(|i| i.focus(kind))
+                        .filter(|f| !f.left && self.objects.is_current(f.surface))
+                        .map(|f| f.surface.id)
+                };
+                WlSeatInfo {
+                    id,
+                    global: info.map(|i| i.global),
+                    name: info.and_then(|i| i.name.clone()),
+                    capabilities: info.map_or(0, |i| i.capabilities),
+                    devices: self
+                        .objects
+                        .seat_devices(id)
+                        .map(|(id, t)| WlSeatDeviceInfo {
+                            id,
+                            interface: t.interface().to_string(),
+                        })
+                        .collect(),
+                    keyboard_focus: focus(FocusKind::Keyboard),
+                    pointer_focus: focus(FocusKind::Pointer),
+                }
+            })
+            .collect()
+    }
+
+    /// Which focus to attribute `msg` to. We can never tell for sure what
+    /// caused a request, but drag-and-drop and pointer related requests
+    /// follow the pointer, while everything else (e.g. the clipboard, which
+    /// is tied to keyboard focus) follows the keyboard.
+    pub(super) fn focus_kind_for(msg: &dyn AnyWlParsedMessage) -> FocusKind {
+        let interface = msg.object_type().interface();
+        if msg.msg_name().contains("drag")
+            || interface.contains("pointer")
+            || interface.contains("cursor")
+        {
+            FocusKind::Pointer
+        } else {
+            FocusKind::Keyboard
+        }
+    }
+
+    /// The seat and toplevel to tell ask and notify scripts about, preferring
+    /// a current focus over one that has been left, and focus of `kind` over
+    /// the other kind. Among equals, the most recently entered one wins.
+    pub(super) fn attributed_focus(
+        &self,
+        kind: FocusKind,
+    ) -> Option<(u32, FocusKind, WlObjectHandle)> {
+        self.objects
+            .iter_extensions::<SeatInfo>()
+            .flat_map(|(seat, info)| {
+                [FocusKind::Keyboard, FocusKind::Pointer]
+                    .into_iter()
+                    .filter_map(move |k| Some((seat, k, *info.focus(k)?)))
+            })
+            .filter_map(|(seat, k, focus)| {
+                let toplevel = self.toplevel_of_surface(focus.surface)?;
+                Some(((!focus.left, k == kind, focus.seq), (seat, k, toplevel)))
+            })
+            .max_by_key(|(rank, _)| *rank)
+            .map(|(_, attributed)| attributed)
+    }
+
+    /// Input device `device` entered (or left) `surface`
+    fn update_focus(&mut self, device: u32, surface: u32, entered: bool) {
+        let kind = if self.objects.lookup_object(device) == Some(WL_KEYBOARD) {
+            FocusKind::Keyboard
+        } else {
+            FocusKind::Pointer
+        };
+        let seat = self.objects.seat_of(device);
+        debug!(
+            device,
+            surface,
+            seat,
+            seat_name = ?seat
+                .and_then(|s| self.objects.extension::<SeatInfo>(s))
+                .and_then(|i| i.name.as_deref()),
+            ?kind,
+            entered,
+            "Focus changed"
+        );
+
+        let handle = self.objects.handle(surface);
+        self.focus_seq += 1;
+        let seq = self.focus_seq;
+        let Some(info) = seat.and_then(|s| self.objects.extension_mut::<SeatInfo>(s)) else {
+            return;
+        };
+        let focus = info.focus_mut(kind);
+        if entered {
+            *focus = handle.map(|surface| SeatFocus {
+                surface,
+                left: false,
+                seq,
+            });
+        } else if let Some(f) = focus
+            && Some(f.surface) == handle
+        {
+            f.left = true;
+        }
+    }
+}
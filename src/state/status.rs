@@ -0,0 +1,324 @@
+//! wl_mitm_v1, the global wl-mitm offers clients itself (see
+//! `proto/wl-mitm-v1.xml`), enabled with `[status]` in the config
+//!
+//! The compositor never learns about the global or its objects. Requests on
+//! them are answered here with [WlMitmVerdict::Answered]. Still, libwayland
+//! on the compositor's side only accepts new IDs from the client in order, so
+//! a bind is replaced with a `wl_display.sync` creating a placeholder of the
+//! same ID. The compositor releases it right away; its events are dropped.
+//! Once the placeholder is gone, the client's object IDs are released with our
+//! own `wl_display.delete_id`, and with the compositor's before.
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    codec::WlRawMsg,
+    config::{Config, WlFilterRequest, WlFilterRequestAction},
+    objects::{WlObjectExtension, WlObjectProvenance},
+    proto::{
+        self, AnyWlParsedMessage, WL_DISPLAY_OBJECT_ID, WL_MITM_V1, WaylandProtocolParsingOutcome,
+        WlConstructableMessage, WlDisplayDeleteIdEvent, WlDisplayGetRegistryRequest,
+        WlDisplaySyncRequest, WlMitmV1DestroyRequest, WlMitmV1DoneEvent, WlMitmV1GetStatusRequest,
+        WlMitmV1HiddenGlobalEvent, WlMitmV1PermissionEvent, WlMitmV1PermissionRevokedEvent,
+        WlMitmV1RequestPermissionRequest, WlMitmV1RestrictedEvent, WlParsedMessage,
+        WlRegistryBindRequest, WlRegistryGlobalEvent,
+    },
+    spawner,
+};
+
+use super::{FocusKind, WlHandlers, WlMitmState, WlMitmVerdict};
+
+/// Name of our global on every registry. Compositors count names up from 1.
+pub const STATUS_GLOBAL_NAME: u32 = u32::MAX;
+
+const STATUS_VERSION: u32 = 1;
+
+/// `wl_mitm_v1.permission`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum WlPermission {
+    Unrestricted = 0,
+    Granted = 1,
+    Denied = 2,
+    Blocked = 3,
+}
+
+/// Marks the client's wl_mitm_v1 objects
+struct StatusObject;
+
+impl WlObjectExtension for StatusObject {}
+
+pub(super) fn register(handlers: &mut WlHandlers) {
+    handle!(handlers, requests, WlDisplayGetRegistryRequest => on_get_registry);
+    handle!(handlers, requests, WlRegistryBindRequest => on_bind);
+    handle!(handlers, requests, WlMitmV1DestroyRequest => on_destroy);
+    handle!(handlers, requests, WlMitmV1GetStatusRequest => on_get_status);
+}
+
+fn on_get_registry(
+    state: &mut WlMitmState,
+    msg: &WlDisplayGetRegistryRequest,
+) -> Option<WlMitmVerdict> {
+    if state.config.status.enabled {
+        state.replies.push(
+            WlRegistryGlobalEvent::new(
+                msg.registry,
+                STATUS_GLOBAL_NAME,
+                WL_MITM_V1.interface(),
+                STATUS_VERSION,
+            )
+            .build(),
+        );
+    }
+    None
+}
+
+fn on_bind(state: &mut WlMitmState, msg: &WlRegistryBindRequest) -> Option<WlMitmVerdict> {
+    if msg.name != STATUS_GLOBAL_NAME || !state.config.status.enabled {
+        return None;
+    }
+
+    if msg.id_interface_name != WL_MITM_V1.interface()
+        || msg.id_interface_version == 0
+        || msg.id_interface_version > STATUS_VERSION
+    {
+        warn!(
+            interface = msg.id_interface_name,
+            version = msg.id_interface_version,
+            "Client binding wl_mitm_v1 with the wrong interface or version"
+        );
+        return Some(WlMitmVerdict::Terminate);
+    }
+
+    if state.objects.lookup_object(msg.id).is_some() || !state.objects.check_object_limit() {
+        return Some(WlMitmVerdict::Terminate);
+    }
+
+    info!(obj_id = msg.id, "Client binding wl_mitm_v1");
+    state.objects.record_child_object(
+        WL_MITM_V1,
+        msg.id,
+        msg.obj_id(),
+        Some(msg.id_interface_version),
+    );
+    state.objects.set_provenance(
+        msg.id,
+        WlObjectProvenance::new(msg.object_type().interface(), msg.msg_name()),
+    );
+    state.objects.put_extension(msg.id, StatusObject);
+    state.status_placeholders.insert(msg.id);
+    state
+        .replacements
+        .push(WlDisplaySyncRequest::new(WL_DISPLAY_OBJECT_ID, msg.id).build());
+    Some(WlMitmVerdict::Replaced)
+}
+
+fn on_destroy(state: &mut WlMitmState, msg: &WlMitmV1DestroyRequest) -> Option<WlMitmVerdict> {
+    // The compositor's delete_id for the placeholder is the client's, if it
+    // is still to come. Otherwise nothing else will ever acknowledge the
+    // destruction.
+    if !state.status_placeholders.contains(&msg.obj_id()) {
+        state.objects.remove_object(msg.obj_id(), false);
+        state
+            .replies
+            .push(WlDisplayDeleteIdEvent::new(WL_DISPLAY_OBJECT_ID, msg.obj_id()).build());
+    }
+    Some(WlMitmVerdict::Answered)
+}
+
+fn on_get_status(state: &mut WlMitmState, msg: &WlMitmV1GetStatusRequest) -> Option<WlMitmVerdict> {
+    let id = msg.obj_id();
+    for interface in &state.hidden_globals {
+        state
+            .replies
+            .push(WlMitmV1HiddenGlobalEvent::new(id, interface).build());
+    }
+
+    let mut restricted: Vec<_> = state
+        .config
+        .filter
+        .requests
+        .iter()
+        .flat_map(|(interface, rules)| {
+            rules
+                .iter()
+                .enumerate()
+                .map(move |(index, rule)| (interface, index, rule))
+        })
+        .filter(|(interface, index, rule)| state.applies_to_client(interface, *index, rule))
+        .flat_map(|(interface, _, rule)| {
+            rule.requests
+                .iter()
+                .map(move |request| (interface.as_str(), request.as_str(), action_value(rule)))
+        })
+        .collect();
+    restricted.sort();
+    restricted.dedup();
+    for (interface, request, action) in restricted {
+        state
+            .replies
+            .push(WlMitmV1RestrictedEvent::new(id, interface, request, action).build());
+    }
+
+    let dry_run = state.config.filter.dry_run as u32;
+    state
+        .replies
+        .push(WlMitmV1DoneEvent::new(id, dry_run).build());
+    Some(WlMitmVerdict::Answered)
+}
+
+/// `wl_mitm_v1.action`
+fn action_value(rule: &WlFilterRequest) -> u32 {
+    match rule.action {
+        WlFilterRequestAction::Block => 0,
+        WlFilterRequestAction::Ask => 1,
+        WlFilterRequestAction::Notify => 2,
+    }
+}
+
+impl WlMitmState {
+    /// Drop the events of placeholders for wl_mitm_v1 objects: the callback's
+    /// `done`, and its `delete_id` unless the client has destroyed the object
+    /// since and waits for one
+    pub(super) fn on_status_placeholder_event(
+        &mut self,
+        raw_msg: &WlRawMsg,
+    ) -> Option<WlMitmVerdict> {
+        if self.status_placeholders.is_empty() {
+            return None;
+        }
+        if self.status_placeholders.contains(&raw_msg.obj_id) {
+            return Some(WlMitmVerdict::Filtered);
+        }
+
+        let WaylandProtocolParsingOutcome::Ok(msg) =
+            WlDisplayDeleteIdEvent::try_from_msg(&self.objects, raw_msg)
+        else {
+            return None;
+        };
+        if !self.status_placeholders.remove(&msg.id) {
+            return None;
+        }
+        debug!(obj_id = msg.id, "wl_mitm_v1 placeholder deleted");
+        (!self.objects.is_half_destroyed(msg.id)).then_some(WlMitmVerdict::Filtered)
+    }
+
+    /// The first rule which may apply to `interface::request` for this
+    /// client, as (interface key, index, rule). Returns [Err] if no such
+    /// request is known.
+    fn find_permission_rule<'c>(
+        &self,
+        config: &'c Config,
+        interface: &str,
+        request: &str,
+    ) -> Result<Option<(&'c str, usize, &'c WlFilterRequest)>, ()> {
+        let obj_type = proto::lookup_known_object_type(interface).ok_or(())?;
+        let opcode = proto::known_requests()
+            .find(|(t, _, parser)| *t == obj_type && parser.msg_name() == request)
+            .map(|(_, opcode, _)| opcode)
+            .ok_or(())?;
+
+        Ok(config
+            .filter
+            .candidates(obj_type, opcode)
+            .iter()
+            .map(|(interface, i)| {
+                (
+                    interface.as_str(),
+                    *i,
+                    &config.filter.requests[interface][*i],
+                )
+            })
+            .find(|(interface, i, rule)| self.applies_to_client(interface, *i, rule)))
+    }
+
+    /// `wl_mitm_v1.request_permission`: ask the user ahead of time, and
+    /// remember if they allow it
+    pub(super) async fn on_request_permission(
+        &mut self,
+        msg: &WlMitmV1RequestPermissionRequest<'_>,
+    ) -> WlMitmVerdict {
+        let permission = self.request_permission(msg.interface, msg.request).await;
+        info!(
+            interface = msg.interface,
+            request = msg.request,
+            ?permission,
+            "Client requested permission"
+        );
+        self.replies.push(
+            WlMitmV1PermissionEvent::new(
+                msg.obj_id(),
+                msg.interface,
+                msg.request,
+                permission as u32,
+            )
+            .build(),
+        );
+        WlMitmVerdict::Answered
+    }
+
+    async fn request_permission(&mut self, interface: &str, request: &str) -> WlPermission {
+        let config = self.config.clone();
+        let Ok(rule) = self.find_permission_rule(&config, interface, request) else {
+            // Nothing the client could ever send
+            return WlPermission::Blocked;
+        };
+        let Some((key, index, rule)) = rule else {
+            return WlPermission::Unrestricted;
+        };
+        let key = (key.to_string(), index);
+
+        match rule.action {
+            WlFilterRequestAction::Block => WlPermission::Blocked,
+            WlFilterRequestAction::Notify => WlPermission::Unrestricted,
+            WlFilterRequestAction::Ask if self.granted.contains(&key) => WlPermission::Granted,
+            WlFilterRequestAction::Ask => {
+                let Some(ref ask_cmd) = config.exec.ask_cmd else {
+                    return WlPermission::Blocked;
+                };
+
+                let mut cmd = self.prepare_client_command(
+                    interface,
+                    request,
+                    ask_cmd,
+                    rule.desc.as_deref().unwrap_or(""),
+                    FocusKind::Keyboard,
+                );
+                cmd.env("WL_MITM_PERMISSION_REQUEST", "1");
+
+                match spawner::status(cmd).await {
+                    Ok(status) if status.success() => {
+                        self.granted.insert(key);
+                        WlPermission::Granted
+                    }
+                    _ => WlPermission::Denied,
+                }
+            }
+        }
+    }
+
+    /// Revoke the permission for `interface::request` granted through
+    /// wl_mitm_v1, telling the client. Returns false if it wasn't granted.
+    pub fn revoke_permission(&mut self, interface: &str, request: &str) -> bool {
+        let config = self.config.clone();
+        let Ok(Some((key, index, _))) = self.find_permission_rule(&config, interface, request)
+        else {
+            return false;
+        };
+        if !self.granted.remove(&(key.to_string(), index)) {
+            return false;
+        }
+
+        debug!(interface, request, "Permission revoked");
+        let objects: Vec<_> = self
+            .objects
+            .iter_extensions::<StatusObject>()
+            .map(|(id, _)| id)
+            .collect();
+        for id in objects {
+            self.replies
+                .push(WlMitmV1PermissionRevokedEvent::new(id, interface, request).build());
+        }
+        true
+    }
+}
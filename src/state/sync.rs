@@ -0,0 +1,98 @@
+//! Round trips to the server, riding along with the client's own
+//! `wl_display.sync` requests
+//!
+//! wl-mitm can't send a `wl_display.sync` of its own: its callback would need
+//! an ID from the client's range, and libwayland only accepts the next unused
+//! one there, which the client is about to allocate itself. Instead, barriers
+//! wait for the next `wl_display.sync` the client sends, and resolve once its
+//! callback is done. The callback's events reach the client as usual.
+//!
+//! A client that is idle, or never syncs at all, would leave barriers waiting
+//! forever. They are given up on after a timeout instead, see
+//! [WlMitmState::expire_syncs].
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use crate::{
+    codec::WlRawMsg,
+    proto::{
+        AnyWlParsedMessage, WL_DISPLAY_OBJECT_ID, WaylandProtocolParsingOutcome,
+        WlCallbackDoneEvent, WlDisplaySyncRequest, WlParsedMessage,
+    },
+};
+
+use super::{WlHandlers, WlMitmState, WlMitmVerdict};
+
+pub(super) fn register(handlers: &mut WlHandlers) {
+    handle!(handlers, events, WlCallbackDoneEvent => on_done);
+}
+
+fn on_done(state: &mut WlMitmState, msg: &WlCallbackDoneEvent) -> Option<WlMitmVerdict> {
+    for tx in state.syncs.remove(&msg.obj_id()).into_iter().flatten() {
+        debug!(callback = msg.obj_id(), "Round trip done");
+        tx.send(()).ok();
+    }
+    None
+}
+
+impl WlMitmState {
+    /// Wait for a round trip to the server. Once the server has processed
+    /// everything forwarded to it before the client's next `wl_display.sync`,
+    /// the returned receiver resolves. If the client sends none within
+    /// `timeout`, the receiver fails instead.
+    pub fn sync(&mut self, timeout: Duration) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.pending_syncs.push((tx, Instant::now() + timeout));
+        rx
+    }
+
+    /// When the first round trip still waiting for a `wl_display.sync` is to
+    /// be given up on, see [Self::expire_syncs]
+    pub fn sync_deadline(&self) -> Option<Instant> {
+        self.pending_syncs
+            .iter()
+            .map(|(_, deadline)| *deadline)
+            .min()
+    }
+
+    /// Fail round trips that have waited too long for the client to send a
+    /// `wl_display.sync`
+    pub fn expire_syncs(&mut self) {
+        let now = Instant::now();
+        let before = self.pending_syncs.len();
+        self.pending_syncs.retain(|(_, deadline)| *deadline > now);
+        if self.pending_syncs.len() < before {
+            warn!(
+                expired = before - self.pending_syncs.len(),
+                "Client sent no wl_display::sync in time, giving up on round trips"
+            );
+        }
+    }
+
+    /// Note a request that has been forwarded to the server. If it is a
+    /// `wl_display.sync`, pending round trips resolve along with it.
+    pub fn on_forwarded_request(&mut self, msg: &WlRawMsg) {
+        if self.pending_syncs.is_empty() || msg.obj_id != WL_DISPLAY_OBJECT_ID {
+            return;
+        }
+        let WaylandProtocolParsingOutcome::Ok(sync) =
+            WlDisplaySyncRequest::try_from_msg(&self.objects, msg)
+        else {
+            return;
+        };
+
+        debug!(
+            callback = sync.callback,
+            waiting = self.pending_syncs.len(),
+            "Round trip rides along with the client's wl_display::sync"
+        );
+        let waiting = std::mem::take(&mut self.pending_syncs);
+        self.syncs
+            .entry(sync.callback)
+            .or_default()
+            .extend(waiting.into_iter().map(|(tx, _)| tx));
+    }
+}
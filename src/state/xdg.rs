@@ -0,0 +1,164 @@
+//! Tracking of xdg_toplevels and the wl_surfaces behind them, so that ask and
+//! notify scripts can be told which window a request came from
+
+use serde_derive::Serialize;
+use tracing::debug;
+
+use crate::{
+    objects::{WlObjectExtension, WlObjectHandle},
+    proto::{
+        AnyWlParsedMessage, XdgSurfaceGetToplevelRequest, XdgToplevelSetAppIdRequest,
+        XdgToplevelSetTitleRequest, XdgWmBaseGetXdgSurfaceRequest,
+    },
+};
+
+use super::{FocusKind, SeatInfo, WlHandlers, WlMitmState, WlMitmVerdict};
+
+/// Association between a wl_surface and an xdg_surface, to facilitate
+/// lookup for [ToplevelSurfaceInfo] from a wl_surface
+pub(crate) struct SurfaceXdgAssociation(pub(crate) WlObjectHandle);
+/// Association between an xdg_surface and an xdg_toplevel
+struct XdgToplevelAssociation(WlObjectHandle);
+
+/// A struct to track information about an app's top-level surfaces (windows)
+/// This gets passed down to ask and notify scripts to produce user-friendly
+/// messages.
+#[derive(Default, Debug)]
+pub(super) struct ToplevelSurfaceInfo {
+    pub title: Option<String>,
+    pub app_id: Option<String>,
+}
+
+impl WlObjectExtension for SurfaceXdgAssociation {}
+impl WlObjectExtension for XdgToplevelAssociation {}
+
+impl WlObjectExtension for ToplevelSurfaceInfo {
+    fn describe(&self) -> String {
+        format!("toplevel title={:?} app_id={:?}", self.title, self.app_id)
+    }
+
+    fn on_destroy(&mut self, obj: WlObjectHandle) {
+        debug!(
+            obj_id = obj.id,
+            title = ?self.title,
+            app_id = ?self.app_id,
+            "Toplevel closed"
+        );
+    }
+}
+
+/// What's known about one toplevel, for [crate::dump]
+#[derive(Serialize, Debug, Clone)]
+pub struct WlToplevelInfo {
+    /// Object ID of the xdg_toplevel
+    pub id: u32,
+    pub title: Option<String>,
+    pub app_id: Option<String>,
+    /// Whether any seat's keyboard is in this toplevel
+    pub keyboard_focus: bool,
+    /// Whether any seat's pointer (or touch) is in this toplevel
+    pub pointer_focus: bool,
+}
+
+pub(super) fn register(handlers: &mut WlHandlers) {
+    handle!(handlers, requests, XdgWmBaseGetXdgSurfaceRequest => on_get_xdg_surface);
+    handle!(handlers, requests, XdgSurfaceGetToplevelRequest => on_get_toplevel);
+    handle!(handlers, requests, XdgToplevelSetAppIdRequest => on_set_app_id);
+    handle!(handlers, requests, XdgToplevelSetTitleRequest => on_set_title);
+}
+
+fn on_get_xdg_surface(
+    state: &mut WlMitmState,
+    msg: &XdgWmBaseGetXdgSurfaceRequest,
+) -> Option<WlMitmVerdict> {
+    if let Some(xdg_surface) = state.objects.handle(msg.id) {
+        state
+            .objects
+            .put_extension(msg.surface, SurfaceXdgAssociation(xdg_surface));
+    }
+    None
+}
+
+fn on_get_toplevel(
+    state: &mut WlMitmState,
+    msg: &XdgSurfaceGetToplevelRequest,
+) -> Option<WlMitmVerdict> {
+    if let Some(xdg_toplevel) = state.objects.handle(msg.id) {
+        state
+            .objects
+            .put_extension(msg.obj_id(), XdgToplevelAssociation(xdg_toplevel));
+    }
+    state
+        .objects
+        .put_extension(msg.id, ToplevelSurfaceInfo::default());
+    None
+}
+
+fn on_set_app_id(
+    state: &mut WlMitmState,
+    msg: &XdgToplevelSetAppIdRequest,
+) -> Option<WlMitmVerdict> {
+    if let Some(info) = state
+        .objects
+        .extension_mut::<ToplevelSurfaceInfo>(msg.obj_id())
+    {
+        info.app_id = Some(msg.app_id.to_string());
+        state.conn_span.record("app_id", msg.app_id);
+    }
+    None
+}
+
+fn on_set_title(
+    state: &mut WlMitmState,
+    msg: &XdgToplevelSetTitleRequest,
+) -> Option<WlMitmVerdict> {
+    if let Some(info) = state
+        .objects
+        .extension_mut::<ToplevelSurfaceInfo>(msg.obj_id())
+    {
+        info.title = Some(msg.title.to_string());
+    }
+    None
+}
+
+impl WlMitmState {
+    /// All toplevels which have a title or app_id set, sorted by object ID
+    pub fn toplevels(&self) -> Vec<WlToplevelInfo> {
+        let focused = |kind| {
+            self.objects
+                .iter_extensions::<SeatInfo>()
+                .filter_map(move |(_, seat)| seat.focus(kind))
+                .filter(|f| !f.left)
+                .filter_map(|f| self.toplevel_of_surface(f.surface))
+                .collect::<Vec<_>>()
+        };
+        let (keyboard, pointer) = (focused(FocusKind::Keyboard), focused(FocusKind::Pointer));
+
+        let mut toplevels: Vec<_> = self
+            .objects
+            .iter_extensions::<ToplevelSurfaceInfo>()
+            .map(|(id, info)| {
+                let handle = self.objects.handle(id);
+                WlToplevelInfo {
+                    id,
+                    title: info.title.clone(),
+                    app_id: info.app_id.clone(),
+                    keyboard_focus: keyboard.iter().any(|t| Some(*t) == handle),
+                    pointer_focus: pointer.iter().any(|t| Some(*t) == handle),
+                }
+            })
+            .collect();
+        toplevels.sort_by_key(|t| t.id);
+        toplevels
+    }
+
+    /// The toplevel (NOT the underlying wl_surface) `surface` belongs to
+    pub(super) fn toplevel_of_surface(&self, surface: WlObjectHandle) -> Option<WlObjectHandle> {
+        if !self.objects.is_current(surface) {
+            return None;
+        }
+        let SurfaceXdgAssociation(xdg_surface) = self.objects.extension(surface.id)?;
+        let XdgToplevelAssociation(xdg_toplevel) = self.objects.handle_extension(*xdg_surface)?;
+        Some(*xdg_toplevel)
+    }
+}
@@ -0,0 +1,138 @@
+//! Recognizing XWayland, and tracking which of its wl_surfaces are X11 windows
+//!
+//! XWayland is one client to the compositor, but acts for every X11 app. Its
+//! connection gets its own globals and filter rules (see `[xwayland]` in the
+//! config), and its surfaces are tied to X11 windows through xwayland_shell_v1:
+//! the serial set on an xwayland_surface_v1 is also set on the X11 window as
+//! `WL_SURFACE_SERIAL`.
+//!
+//! Only the executable counts for recognizing XWayland. Any client can bind
+//! xwayland_shell_v1, so that is merely recorded.
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    objects::{WlObjectExtension, WlObjectHandle},
+    proto::{
+        AnyWlParsedMessage, WlRegistryBindRequest, XwaylandShellV1GetXwaylandSurfaceRequest,
+        XwaylandSurfaceV1SetSerialRequest,
+    },
+};
+
+use super::{FocusKind, SeatInfo, WlHandlers, WlMitmState, WlMitmVerdict};
+
+/// Association between an xwayland_surface_v1 and its wl_surface
+struct XwaylandSurfaceAssociation(WlObjectHandle);
+
+/// A wl_surface which is the contents of an X11 window
+#[derive(Default, Debug)]
+struct X11SurfaceInfo {
+    /// The `WL_SURFACE_SERIAL` of the X11 window, once set
+    serial: Option<u64>,
+}
+
+impl WlObjectExtension for XwaylandSurfaceAssociation {}
+
+impl WlObjectExtension for X11SurfaceInfo {
+    fn describe(&self) -> String {
+        format!("X11 window serial={:?}", self.serial)
+    }
+}
+
+pub(super) fn register(handlers: &mut WlHandlers) {
+    handle!(handlers, requests, WlRegistryBindRequest => on_bind);
+    handle!(handlers, requests, XwaylandShellV1GetXwaylandSurfaceRequest => on_get_xwayland_surface);
+    handle!(handlers, requests, XwaylandSurfaceV1SetSerialRequest => on_set_serial);
+}
+
+/// Runs after [super::registry]'s handler has let the bind through
+fn on_bind(state: &mut WlMitmState, msg: &WlRegistryBindRequest) -> Option<WlMitmVerdict> {
+    if msg.id_interface_name == "xwayland_shell_v1" && !state.xwayland_shell {
+        if !state.xwayland {
+            warn!("Client bound xwayland_shell_v1, but its executable isn't XWayland's");
+        }
+        state.xwayland_shell = true;
+    }
+    None
+}
+
+fn on_get_xwayland_surface(
+    state: &mut WlMitmState,
+    msg: &XwaylandShellV1GetXwaylandSurfaceRequest,
+) -> Option<WlMitmVerdict> {
+    if let Some(surface) = state.objects.handle(msg.surface) {
+        state
+            .objects
+            .put_extension(msg.id, XwaylandSurfaceAssociation(surface));
+    }
+    state
+        .objects
+        .put_extension(msg.surface, X11SurfaceInfo::default());
+    None
+}
+
+fn on_set_serial(
+    state: &mut WlMitmState,
+    msg: &XwaylandSurfaceV1SetSerialRequest,
+) -> Option<WlMitmVerdict> {
+    let &XwaylandSurfaceAssociation(surface) = state.objects.extension(msg.obj_id())?;
+    if !state.objects.is_current(surface) {
+        return None;
+    }
+
+    let serial = ((msg.serial_hi as u64) << 32) | msg.serial_lo as u64;
+    if let Some(info) = state.objects.extension_mut::<X11SurfaceInfo>(surface.id) {
+        debug!(surface = surface.id, serial, "Surface is an X11 window");
+        info.serial = Some(serial);
+    }
+    None
+}
+
+impl WlMitmState {
+    /// Whether the client is XWayland, going by its executable (see
+    /// [crate::config::WlXwaylandConfig])
+    pub fn is_xwayland(&self) -> bool {
+        self.xwayland
+    }
+
+    /// Whether the client has bound xwayland_shell_v1, which doesn't make it
+    /// XWayland by itself
+    pub fn uses_xwayland_shell(&self) -> bool {
+        self.xwayland_shell
+    }
+
+    /// Recognize XWayland by the executable of `peer`
+    pub(super) fn detect_xwayland(&mut self) {
+        if !self.xwayland && self.config.xwayland.matches(&self.peer) {
+            info!("Client is XWayland");
+            self.xwayland = true;
+            self.conn_span.record("xwayland", true);
+        }
+    }
+
+    /// The `WL_SURFACE_SERIAL` of the X11 window `surface` belongs to, if it
+    /// is one and the serial has been set. Returns [None] for anything that
+    /// isn't a wl_surface.
+    pub fn x11_surface_serial(&self, surface: u32) -> Option<u64> {
+        self.objects.extension::<X11SurfaceInfo>(surface)?.serial
+    }
+
+    /// Like [Self::attributed_focus], but for X11 windows, which have no
+    /// toplevel on the Wayland side. Returns the serial of the window.
+    pub(super) fn attributed_x11_focus(&self, kind: FocusKind) -> Option<u64> {
+        self.objects
+            .iter_extensions::<SeatInfo>()
+            .flat_map(|(_, info)| {
+                [FocusKind::Keyboard, FocusKind::Pointer]
+                    .into_iter()
+                    .filter_map(move |k| Some((k, *info.focus(k)?)))
+            })
+            .filter(|(_, focus)| self.objects.is_current(focus.surface))
+            .filter_map(|(k, focus)| {
+                let serial = self.x11_surface_serial(focus.surface.id)?;
+                Some(((!focus.left, k == kind, focus.seq), serial))
+            })
+            .max_by_key(|(rank, _)| *rank)
+            .map(|(_, serial)| serial)
+    }
+}
@@ -0,0 +1,217 @@
+//! Per-connection usage statistics, for comparing app behavior across runs
+//!
+//! With `stats.dir` set, every connection counts its messages by interface
+//! and message, along with the verdicts they got. The counts are written to a
+//! file of the connection's own, as JSON or CSV, whenever a dump is triggered
+//! (see [crate::dump]), every `stats.interval_secs` if set, and once the
+//! connection is closed. Each export overwrites the previous one.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_derive::Serialize;
+
+use crate::{
+    config::{WlStatsConfig, WlStatsFormat},
+    latency::WlConnLatency,
+    recorder::WlDirection,
+    state::WlMitmVerdict,
+};
+
+/// How often one kind of message was seen, and what became of it
+#[derive(Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct WlMsgTally {
+    pub count: u64,
+    pub allowed: u64,
+    pub filtered: u64,
+    pub rejected: u64,
+    /// Answered by wl-mitm itself
+    pub answered: u64,
+    /// Replaced by wl-mitm with events of its own
+    pub replaced: u64,
+    pub terminated: u64,
+}
+
+impl WlMsgTally {
+    fn add(&mut self, verdict: &WlMitmVerdict) {
+        self.count += 1;
+        match verdict {
+            WlMitmVerdict::Allowed => self.allowed += 1,
+            WlMitmVerdict::Filtered => self.filtered += 1,
+            WlMitmVerdict::Rejected(_) => self.rejected += 1,
+            WlMitmVerdict::Answered => self.answered += 1,
+            WlMitmVerdict::Replaced => self.replaced += 1,
+            WlMitmVerdict::Terminate => self.terminated += 1,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct WlStatsRow {
+    pub direction: WlDirection,
+    pub interface: &'static str,
+    pub message: &'static str,
+    #[serde(flatten)]
+    pub tally: WlMsgTally,
+}
+
+/// Everything exported for one connection
+#[derive(Serialize, Debug)]
+pub struct WlStatsExport {
+    pub conn_id: u64,
+    pub pid: Option<i32>,
+    pub exe: Option<String>,
+    pub app_id: Option<String>,
+    /// Seconds since the Unix epoch at which the connection was accepted
+    pub started: u64,
+    /// Seconds since the Unix epoch at which the counts were taken
+    pub timestamp: u64,
+    /// Time forwarded messages spent inside wl-mitm. Not part of CSV exports.
+    pub latency: WlConnLatency,
+    pub messages: Vec<WlStatsRow>,
+}
+
+impl WlStatsExport {
+    fn write_csv(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "conn_id,app_id,direction,interface,message,count,allowed,filtered,rejected,answered,replaced,terminated"
+        )?;
+        for row in &self.messages {
+            let t = &row.tally;
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{},{},{},{},{}",
+                self.conn_id,
+                self.app_id.as_deref().unwrap_or(""),
+                match row.direction {
+                    WlDirection::ClientToServer => "c2s",
+                    WlDirection::ServerToClient => "s2c",
+                },
+                row.interface,
+                row.message,
+                t.count,
+                t.allowed,
+                t.filtered,
+                t.rejected,
+                t.answered,
+                t.replaced,
+                t.terminated
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Message counts of one connection
+pub struct WlConnStats {
+    conn_id: u64,
+    path: PathBuf,
+    format: WlStatsFormat,
+    started: u64,
+    tallies: BTreeMap<(WlDirection, &'static str, &'static str), WlMsgTally>,
+}
+
+impl WlConnStats {
+    /// Returns [None] if statistics are disabled
+    pub fn new(config: &WlStatsConfig, conn_id: u64) -> Option<WlConnStats> {
+        let dir = config.dir.as_ref()?;
+        let started = unix_secs();
+        let ext = match config.format {
+            WlStatsFormat::Json => "json",
+            WlStatsFormat::Csv => "csv",
+        };
+        let path = Path::new(dir).join(format!(
+            "wl-mitm-stats-{}-{}-{}.{}",
+            std::process::id(),
+            conn_id,
+            started,
+            ext
+        ));
+
+        Some(WlConnStats {
+            conn_id,
+            path,
+            format: config.format,
+            started,
+            tallies: BTreeMap::new(),
+        })
+    }
+
+    /// The file this connection's statistics are written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Count `interface::message`, which got `verdict`
+    pub fn record(
+        &mut self,
+        direction: WlDirection,
+        interface: &'static str,
+        message: &'static str,
+        verdict: &WlMitmVerdict,
+    ) {
+        self.tallies
+            .entry((direction, interface, message))
+            .or_default()
+            .add(verdict);
+    }
+
+    /// The counts so far; who the client is and latencies are filled in by the caller
+    pub fn snapshot(&self) -> WlStatsExport {
+        WlStatsExport {
+            conn_id: self.conn_id,
+            pid: None,
+            exe: None,
+            app_id: None,
+            started: self.started,
+            timestamp: unix_secs(),
+            latency: Default::default(),
+            messages: self
+                .tallies
+                .iter()
+                .map(|(&(direction, interface, message), tally)| WlStatsRow {
+                    direction,
+                    interface,
+                    message,
+                    tally: tally.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Write `export` over the previous one. The file is replaced at once,
+    /// so readers never see a partial export.
+    pub fn write(&self, export: &WlStatsExport) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        match self.format {
+            WlStatsFormat::Json => {
+                serde_json::to_writer_pretty(&mut w, export)?;
+                w.write_all(b"\n")?;
+            }
+            WlStatsFormat::Csv => export.write_csv(&mut w)?,
+        }
+        w.flush()?;
+        drop(w);
+
+        std::fs::rename(tmp, &self.path)
+    }
+}
@@ -0,0 +1,135 @@
+//! Decoded, human-readable traces of connections
+//!
+//! This is `WAYLAND_DEBUG=1` for clients that can't be restarted with it set:
+//! every message is written to a per-connection file, formatted with the
+//! [std::fmt::Display] impls generated for all known messages, along with the
+//! verdict wl-mitm reached on it. Traces are independent of the log level.
+//!
+//! ```text
+//! [     12.345] -> wl_surface#3.damage(0, 0, 10, 10)
+//! [     12.401] <- wl_callback#7.done(1234)
+//! [     13.020] -> zwlr_screencopy_manager_v1#9.capture_output(new id zwlr_screencopy_frame_v1#10, 0, #5) Filtered
+//! ```
+
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    codec::WlRawMsg,
+    config::WlTraceConfig,
+    objects::WlObjects,
+    peer::WlPeerInfo,
+    proto::{self, WaylandProtocolParsingOutcome},
+    recorder::WlDirection,
+    state::WlMitmVerdict,
+};
+
+pub struct WlTracer {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl WlTracer {
+    /// Create a trace file for connection `conn_id`, if tracing is enabled
+    /// for this peer
+    pub fn create(
+        config: &WlTraceConfig,
+        conn_id: u64,
+        peer: &WlPeerInfo,
+    ) -> io::Result<Option<WlTracer>> {
+        let Some(ref dir) = config.dir else {
+            return Ok(None);
+        };
+
+        if !config.matches(peer) {
+            return Ok(None);
+        }
+
+        // Traces contain every key pressed, like recordings
+        let dir = Path::new(dir);
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+        let start_time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let path: PathBuf = dir.join(format!(
+            "wl-mitm-{}-{}-{}.trace",
+            std::process::id(),
+            conn_id,
+            start_time_ms
+        ));
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "# conn_id {}, peer {:?}", conn_id, peer)?;
+        writer.flush()?;
+
+        Ok(Some(WlTracer {
+            writer,
+            start: Instant::now(),
+        }))
+    }
+
+    /// Format `msg` for the trace. This has to happen before the message is
+    /// processed, as processing may destroy its object.
+    pub fn describe(objects: &WlObjects, direction: WlDirection, msg: &WlRawMsg) -> String {
+        let decoded = match direction {
+            WlDirection::ClientToServer => proto::decode_request(objects, msg),
+            WlDirection::ServerToClient => proto::decode_event(objects, msg),
+        };
+
+        match decoded {
+            WaylandProtocolParsingOutcome::Ok(decoded) => decoded.to_string(),
+            _ => format!(
+                "{}#{}.?{}({} bytes, {} fds)",
+                objects
+                    .lookup_object(msg.obj_id)
+                    .map(|t| t.interface())
+                    .unwrap_or("(unknown)"),
+                msg.obj_id,
+                msg.opcode,
+                msg.payload().len(),
+                msg.fds.len()
+            ),
+        }
+    }
+
+    /// Write a message described with [Self::describe], along with its verdict
+    pub fn trace(
+        &mut self,
+        direction: WlDirection,
+        desc: &str,
+        verdict: &WlMitmVerdict,
+    ) -> io::Result<()> {
+        let elapsed = self.start.elapsed();
+        write!(
+            self.writer,
+            "[{:7}.{:03}] {} {}",
+            elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64,
+            elapsed.subsec_micros() % 1000,
+            match direction {
+                WlDirection::ClientToServer => "->",
+                WlDirection::ServerToClient => "<-",
+            },
+            desc
+        )?;
+
+        if !verdict.is_allowed() {
+            write!(self.writer, " {:?}", verdict)?;
+        }
+
+        self.writer.write_all(b"\n")?;
+        // Flush every message so that traces are complete even if we crash
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,498 @@
+//! Translation of fds for transports that can't carry them (i.e. TCP).
+//!
+//! Two wl-mitm instances sit on either side of the fd-less link. The one
+//! holding the real fd (the "sender") replaces it with in-band messages
+//! describing how to reconstruct it; the other end (the "receiver") creates
+//! its own memfd from those and attaches it to the message in place of the
+//! original fd. This is similar in spirit to what waypipe does.
+//!
+//! In-band messages are framed like regular Wayland messages, but are
+//! addressed to object ID 0, which is never valid in the Wayland protocol.
+//!
+//! Two kinds of fds can be translated:
+//!
+//! 1. `wl_shm` pools. The sender keeps the client's pool fd, and every time
+//!    a surface with an shm buffer is committed, the damaged rows of that
+//!    buffer are copied over to the receiver's memfd before the commit.
+//! 2. Any other regular file (e.g. `wl_keyboard::keymap`). These are
+//!    snapshotted in full when the message is sent.
+
+use std::{
+    collections::HashMap,
+    io,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+};
+
+use byteorder::{ByteOrder, NativeEndian};
+use bytes::BufMut;
+use nix::sys::{
+    memfd::{MemFdCreateFlag, memfd_create},
+    stat::{SFlag, fstat},
+    uio::{pread, pwrite},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    codec::WlRawMsg,
+    io_util::WlMsgWriter,
+    objects::WlObjects,
+    proto::{
+        WL_BUFFER, WL_SHM_POOL, WL_SURFACE, WaylandProtocolParsingOutcome, WlBufferDestroyRequest,
+        WlParsedMessage, WlShmCreatePoolRequest, WlShmPoolCreateBufferRequest,
+        WlShmPoolDestroyRequest, WlShmPoolResizeRequest, WlSurfaceAttachRequest,
+        WlSurfaceCommitRequest, WlSurfaceDamageBufferRequest, WlSurfaceDamageRequest,
+        WlSurfaceDestroyRequest,
+    },
+};
+
+/// Object ID used by in-band translation messages
+pub const WL_MITM_INBAND_OBJECT_ID: u32 = 0;
+
+/// Create a memfd of the given size in slot: `[slot, size]`
+const INBAND_MEMFD_CREATE: u16 = 0;
+/// Write data into a slot: `[slot, offset, data (as a wl array)]`
+const INBAND_MEMFD_WRITE: u16 = 1;
+/// Resize a slot: `[slot, size]`
+const INBAND_MEMFD_RESIZE: u16 = 2;
+/// Attach the memfd in a slot to the next non-in-band message: `[slot]`
+const INBAND_MEMFD_ATTACH: u16 = 3;
+/// Drop a slot: `[slot]`
+const INBAND_MEMFD_RELEASE: u16 = 4;
+
+/// Max amount of data carried by one write message. Wayland messages can't exceed 64 KiB.
+const INBAND_WRITE_CHUNK: usize = 0xF000;
+/// Max size of a memfd the peer can ask us to create
+const INBAND_MEMFD_MAX_SIZE: usize = 256 * 1024 * 1024;
+/// Max number of memfds the peer can have us hold at once, i.e. shm pools
+/// plus files in flight
+const INBAND_MEMFD_MAX_SLOTS: usize = 1024;
+/// Max total size of the memfds the peer can have us hold at once
+const INBAND_MEMFD_MAX_TOTAL_SIZE: usize = 1024 * 1024 * 1024;
+
+struct ShmPool {
+    slot: u32,
+    fd: OwnedFd,
+    /// Whether the client has destroyed the pool. Its memory lives on until all
+    /// buffers created from it are destroyed too.
+    destroyed: bool,
+    num_buffers: usize,
+}
+
+struct ShmBuffer {
+    pool: u32,
+    offset: usize,
+    stride: usize,
+    height: usize,
+}
+
+#[derive(Default)]
+enum SurfaceDamage {
+    #[default]
+    None,
+    /// Buffer rows in `[start, end)`
+    Rows(usize, usize),
+    Full,
+}
+
+#[derive(Default)]
+struct SurfaceState {
+    buffer: Option<u32>,
+    newly_attached: bool,
+    damage: SurfaceDamage,
+}
+
+/// Per-connection state for translating fds, see module docs
+#[derive(Default)]
+pub struct WlFdTranslator {
+    next_slot: u32,
+    /// wl_shm_pool ID -> pool (sender side)
+    pools: HashMap<u32, ShmPool>,
+    /// wl_buffer ID -> shm buffer (sender side)
+    buffers: HashMap<u32, ShmBuffer>,
+    /// wl_surface ID -> surface state (sender side)
+    surfaces: HashMap<u32, SurfaceState>,
+    /// slot -> (memfd, size) (receiver side)
+    slots: HashMap<u32, (OwnedFd, usize)>,
+    /// fds to attach to the next non-in-band message (receiver side)
+    pending_fds: Vec<OwnedFd>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn is_regular_file(fd: &OwnedFd) -> bool {
+    fstat(fd.as_raw_fd())
+        .map(|st| SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFREG)
+        .unwrap_or(false)
+}
+
+/// Convert the size of an fd to what an in-band create or resize carries,
+/// or None if the receiver would refuse it.
+fn inband_size(size: i64) -> Option<u32> {
+    usize::try_from(size)
+        .ok()
+        .filter(|size| *size <= INBAND_MEMFD_MAX_SIZE)
+        .and_then(|size| u32::try_from(size).ok())
+}
+
+impl WlFdTranslator {
+    pub fn new() -> WlFdTranslator {
+        Default::default()
+    }
+
+    fn alloc_slot(&mut self) -> u32 {
+        self.next_slot = self.next_slot.wrapping_add(1);
+        self.next_slot
+    }
+
+    fn emit(dest: &mut WlMsgWriter<'_>, opcode: u16, args: &[u32], data: Option<&[u8]>) {
+        dest.queue_write(WlRawMsg::build(
+            WL_MITM_INBAND_OBJECT_ID,
+            opcode,
+            |buf, _| {
+                for arg in args {
+                    buf.put_u32_ne(*arg);
+                }
+
+                if let Some(data) = data {
+                    buf.put_u32_ne(data.len() as u32);
+                    buf.extend_from_slice(data);
+                    if data.len() % 4 != 0 {
+                        buf.put_bytes(0, 4 - data.len() % 4);
+                    }
+                }
+            },
+        ));
+    }
+
+    /// Copy `len` bytes starting at `offset` of `fd` into `slot` on the receiver side
+    fn emit_contents(
+        dest: &mut WlMsgWriter<'_>,
+        slot: u32,
+        fd: &OwnedFd,
+        offset: usize,
+        len: usize,
+    ) {
+        let mut chunk = vec![0u8; INBAND_WRITE_CHUNK.min(len)];
+        let mut pos = 0;
+        while pos < len {
+            let want = INBAND_WRITE_CHUNK.min(len - pos);
+            // Use pread rather than mmap: the client may shrink its fd at any time,
+            // which would get us a SIGBUS if we had it mapped.
+            let Ok(write_offset) = u32::try_from(offset + pos) else {
+                break;
+            };
+            let read = match pread(fd.as_fd(), &mut chunk[..want], (offset + pos) as i64) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            Self::emit(
+                dest,
+                INBAND_MEMFD_WRITE,
+                &[slot, write_offset],
+                Some(&chunk[..read]),
+            );
+            pos += read;
+        }
+    }
+
+    fn maybe_release_pool(&mut self, dest: &mut WlMsgWriter<'_>, pool_id: u32) {
+        if self
+            .pools
+            .get(&pool_id)
+            .is_some_and(|pool| pool.destroyed && pool.num_buffers == 0)
+        {
+            let pool = self.pools.remove(&pool_id).unwrap();
+            Self::emit(dest, INBAND_MEMFD_RELEASE, &[pool.slot], None);
+        }
+    }
+
+    /// Keep track of shm pools, buffers and surfaces, and send over damaged buffer
+    /// contents whenever a surface is committed.
+    ///
+    /// Returns false if a pool is resized to a size the receiver won't accept.
+    fn track_shm(
+        &mut self,
+        objects: &WlObjects,
+        msg: &WlRawMsg,
+        dest: &mut WlMsgWriter<'_>,
+    ) -> bool {
+        let Some(obj_type) = objects.lookup_object(msg.obj_id) else {
+            return true;
+        };
+
+        if obj_type == WL_SHM_POOL {
+            if let WaylandProtocolParsingOutcome::Ok(req) =
+                WlShmPoolResizeRequest::try_from_msg(objects, msg)
+            {
+                if let Some(pool) = self.pools.get(&msg.obj_id) {
+                    let Some(size) = inband_size(req.size.into()) else {
+                        return false;
+                    };
+                    Self::emit(dest, INBAND_MEMFD_RESIZE, &[pool.slot, size], None);
+                }
+            } else if let WaylandProtocolParsingOutcome::Ok(req) =
+                WlShmPoolCreateBufferRequest::try_from_msg(objects, msg)
+            {
+                if let Some(pool) = self.pools.get_mut(&msg.obj_id) {
+                    pool.num_buffers += 1;
+                    self.buffers.insert(
+                        req.id,
+                        ShmBuffer {
+                            pool: msg.obj_id,
+                            offset: req.offset.max(0) as usize,
+                            stride: req.stride.max(0) as usize,
+                            height: req.height.max(0) as usize,
+                        },
+                    );
+                }
+            } else if let WaylandProtocolParsingOutcome::Ok(_) =
+                WlShmPoolDestroyRequest::try_from_msg(objects, msg)
+            {
+                if let Some(pool) = self.pools.get_mut(&msg.obj_id) {
+                    pool.destroyed = true;
+                }
+                self.maybe_release_pool(dest, msg.obj_id);
+            }
+        } else if obj_type == WL_BUFFER {
+            let WaylandProtocolParsingOutcome::Ok(_) =
+                WlBufferDestroyRequest::try_from_msg(objects, msg)
+            else {
+                return true;
+            };
+
+            if let Some(buffer) = self.buffers.remove(&msg.obj_id) {
+                if let Some(pool) = self.pools.get_mut(&buffer.pool) {
+                    pool.num_buffers -= 1;
+                }
+                self.maybe_release_pool(dest, buffer.pool);
+            }
+        } else if obj_type == WL_SURFACE {
+            if let WaylandProtocolParsingOutcome::Ok(req) =
+                WlSurfaceAttachRequest::try_from_msg(objects, msg)
+            {
+                let surface = self.surfaces.entry(msg.obj_id).or_default();
+                surface.buffer = Some(req.buffer).filter(|b| *b != 0);
+                surface.newly_attached = true;
+            } else if let WaylandProtocolParsingOutcome::Ok(_) =
+                WlSurfaceDamageRequest::try_from_msg(objects, msg)
+            {
+                // Surface-local damage would need to account for scale and transform;
+                // just resend everything instead.
+                self.surfaces.entry(msg.obj_id).or_default().damage = SurfaceDamage::Full;
+            } else if let WaylandProtocolParsingOutcome::Ok(req) =
+                WlSurfaceDamageBufferRequest::try_from_msg(objects, msg)
+            {
+                let surface = self.surfaces.entry(msg.obj_id).or_default();
+                let start = req.y.max(0) as usize;
+                let end = req.y.saturating_add(req.height).max(0) as usize;
+                surface.damage = match surface.damage {
+                    SurfaceDamage::None => SurfaceDamage::Rows(start, end),
+                    SurfaceDamage::Rows(s, e) => SurfaceDamage::Rows(s.min(start), e.max(end)),
+                    SurfaceDamage::Full => SurfaceDamage::Full,
+                };
+            } else if let WaylandProtocolParsingOutcome::Ok(_) =
+                WlSurfaceCommitRequest::try_from_msg(objects, msg)
+            {
+                self.sync_surface(msg.obj_id, dest);
+            } else if let WaylandProtocolParsingOutcome::Ok(_) =
+                WlSurfaceDestroyRequest::try_from_msg(objects, msg)
+            {
+                self.surfaces.remove(&msg.obj_id);
+            }
+        }
+
+        true
+    }
+
+    fn sync_surface(&mut self, surface_id: u32, dest: &mut WlMsgWriter<'_>) {
+        let Some(surface) = self.surfaces.get_mut(&surface_id) else {
+            return;
+        };
+
+        let damage = std::mem::take(&mut surface.damage);
+        let newly_attached = std::mem::replace(&mut surface.newly_attached, false);
+
+        let Some(buffer) = surface.buffer.and_then(|b| self.buffers.get(&b)) else {
+            return;
+        };
+        let Some(pool) = self.pools.get(&buffer.pool) else {
+            return;
+        };
+
+        let (start, end) = match damage {
+            SurfaceDamage::Full => (0, buffer.height),
+            SurfaceDamage::Rows(start, end) => (start, end.min(buffer.height)),
+            SurfaceDamage::None if newly_attached => (0, buffer.height),
+            SurfaceDamage::None => return,
+        };
+
+        if start >= end {
+            return;
+        }
+
+        debug!(
+            surface = surface_id,
+            rows_start = start,
+            rows_end = end,
+            "Syncing shm buffer contents"
+        );
+
+        Self::emit_contents(
+            dest,
+            pool.slot,
+            &pool.fd,
+            buffer.offset + start * buffer.stride,
+            (end - start) * buffer.stride,
+        );
+    }
+
+    /// Translate a message about to be sent over a transport that can't carry fds.
+    /// Any in-band message needed is queued to `dest` before returning, so the
+    /// caller should queue `msg` right afterwards.
+    ///
+    /// Returns false if `msg` carries fds that can't be translated, or sizes an
+    /// fd beyond what the receiver accepts. In that case, nothing is queued and
+    /// the fds are left untouched.
+    pub fn translate_outgoing(
+        &mut self,
+        objects: &WlObjects,
+        msg: &mut WlRawMsg,
+        dest: &mut WlMsgWriter<'_>,
+    ) -> bool {
+        if !self.track_shm(objects, msg, dest) {
+            return false;
+        }
+
+        if msg.fds.is_empty() {
+            return true;
+        }
+
+        let pool = match WlShmCreatePoolRequest::try_from_msg(objects, msg) {
+            WaylandProtocolParsingOutcome::Ok(req) => Some((req.id, req.size)),
+            _ => None,
+        };
+
+        if let Some((pool_id, size)) = pool {
+            let Some(size) = inband_size(size.into()) else {
+                return false;
+            };
+            let slot = self.alloc_slot();
+            Self::emit(dest, INBAND_MEMFD_CREATE, &[slot, size], None);
+            Self::emit(dest, INBAND_MEMFD_ATTACH, &[slot], None);
+            self.pools.insert(
+                pool_id,
+                ShmPool {
+                    slot,
+                    fd: msg.fds.remove(0),
+                    destroyed: false,
+                    num_buffers: 0,
+                },
+            );
+            return true;
+        }
+
+        if !msg.fds.iter().all(is_regular_file) {
+            return false;
+        }
+
+        // Size everything up first, so that nothing is queued if any one fd is
+        // too large for the receiver.
+        let mut sizes = Vec::with_capacity(msg.fds.len());
+        for fd in &msg.fds {
+            let Some(size) = fstat(fd.as_raw_fd())
+                .ok()
+                .and_then(|st| inband_size(st.st_size))
+            else {
+                return false;
+            };
+            sizes.push(size);
+        }
+
+        for (fd, size) in msg.fds.drain(..).zip(sizes) {
+            let slot = self.alloc_slot();
+            Self::emit(dest, INBAND_MEMFD_CREATE, &[slot, size], None);
+            Self::emit_contents(dest, slot, &fd, 0, size as usize);
+            Self::emit(dest, INBAND_MEMFD_ATTACH, &[slot], None);
+            Self::emit(dest, INBAND_MEMFD_RELEASE, &[slot], None);
+        }
+
+        true
+    }
+
+    /// Handle a message read from a transport that can't carry fds.
+    ///
+    /// In-band messages are consumed and true is returned. Otherwise, fds
+    /// reconstructed from prior in-band messages are attached to `msg`.
+    pub fn handle_incoming(&mut self, msg: &mut WlRawMsg) -> io::Result<bool> {
+        if msg.obj_id != WL_MITM_INBAND_OBJECT_ID {
+            msg.fds.append(&mut self.pending_fds);
+            return Ok(false);
+        }
+
+        let payload = msg.payload();
+        let arg = |idx: usize| -> io::Result<u32> {
+            payload
+                .get(idx * 4..idx * 4 + 4)
+                .map(NativeEndian::read_u32)
+                .ok_or_else(|| invalid_data("truncated in-band message"))
+        };
+
+        match msg.opcode {
+            INBAND_MEMFD_CREATE | INBAND_MEMFD_RESIZE => {
+                let (slot, size) = (arg(0)?, arg(1)? as usize);
+                if size > INBAND_MEMFD_MAX_SIZE {
+                    return Err(invalid_data("in-band memfd too large"));
+                }
+
+                if msg.opcode == INBAND_MEMFD_CREATE {
+                    if self.slots.contains_key(&slot) {
+                        return Err(invalid_data("in-band create of a slot in use"));
+                    }
+                    if self.slots.len() >= INBAND_MEMFD_MAX_SLOTS {
+                        return Err(invalid_data("too many in-band memfds"));
+                    }
+                    let fd = memfd_create(c"wl-mitm", MemFdCreateFlag::MFD_CLOEXEC)?;
+                    self.slots.insert(slot, (fd, 0));
+                }
+
+                let total: usize = self.slots.values().map(|(_, size)| size).sum();
+                let Some((fd, cur_size)) = self.slots.get_mut(&slot) else {
+                    return Err(invalid_data("in-band resize of unknown slot"));
+                };
+                if total - *cur_size + size > INBAND_MEMFD_MAX_TOTAL_SIZE {
+                    return Err(invalid_data("in-band memfds too large in total"));
+                }
+                nix::unistd::ftruncate(fd.as_fd(), size as i64)?;
+                *cur_size = size;
+            }
+            INBAND_MEMFD_WRITE => {
+                let (slot, offset, len) = (arg(0)?, arg(1)? as usize, arg(2)? as usize);
+                let Some(data) = payload.get(12..12 + len) else {
+                    return Err(invalid_data("truncated in-band write"));
+                };
+                let Some((fd, size)) = self.slots.get(&slot) else {
+                    return Err(invalid_data("in-band write to unknown slot"));
+                };
+                if offset + len > *size {
+                    warn!(slot, offset, len, size, "Out-of-bounds in-band write");
+                    return Err(invalid_data("out-of-bounds in-band write"));
+                }
+                pwrite(fd.as_fd(), data, offset as i64)?;
+            }
+            INBAND_MEMFD_ATTACH => {
+                let Some((fd, _)) = self.slots.get(&arg(0)?) else {
+                    return Err(invalid_data("in-band attach of unknown slot"));
+                };
+                self.pending_fds.push(fd.try_clone()?);
+            }
+            INBAND_MEMFD_RELEASE => {
+                self.slots.remove(&arg(0)?);
+            }
+            _ => return Err(invalid_data("unknown in-band message")),
+        }
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,449 @@
+//! `wl-mitm tui`: an interactive inspector attaching to the control socket
+//!
+//! Streams every message passing through wl-mitm, optionally narrowed down to
+//! one connection and to interfaces / objects matching a filter, shows the
+//! object table of a connection, and allows filter rules to be toggled live.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixStream},
+    },
+    sync::mpsc,
+    time::Duration,
+};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::{
+    DefaultTerminal, Frame,
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+};
+
+use crate::{
+    control::{
+        WlControlConnInfo, WlControlMessage, WlControlReply, WlControlRequest, WlControlRuleInfo,
+    },
+    objects::WlObjectInfo,
+    recorder::WlDirection,
+    socket::WlSocketAddr,
+};
+
+/// How many messages to keep around for display
+const TUI_MAX_MESSAGES: usize = 10000;
+
+#[derive(PartialEq, Eq)]
+enum View {
+    Messages,
+    Objects,
+    Rules,
+}
+
+struct App {
+    conn: UnixStream,
+    messages: VecDeque<WlControlMessage>,
+    connections: Vec<WlControlConnInfo>,
+    /// Only show messages of this connection
+    conn_filter: Option<u64>,
+    /// Only show messages on objects whose interface or message name contains
+    /// this string; or, if this is a number, on that object ID
+    filter: String,
+    editing_filter: bool,
+    paused: bool,
+    missed: u64,
+    view: View,
+    objects: Vec<WlObjectInfo>,
+    rules: Vec<WlControlRuleInfo>,
+    rules_state: ListState,
+    status: String,
+}
+
+impl App {
+    fn send(&mut self, req: &WlControlRequest) -> io::Result<()> {
+        let mut line = serde_json::to_vec(req)?;
+        line.push(b'\n');
+        self.conn.write_all(&line)
+    }
+
+    fn matches(&self, msg: &WlControlMessage) -> bool {
+        if self.conn_filter.is_some_and(|c| c != msg.conn_id) {
+            return false;
+        }
+
+        if self.filter.is_empty() {
+            return true;
+        }
+
+        if let Ok(obj_id) = self.filter.parse::<u32>() {
+            return msg.obj_id == obj_id;
+        }
+
+        msg.interface
+            .as_deref()
+            .is_some_and(|i| i.contains(&self.filter))
+            || msg
+                .msg_name
+                .as_deref()
+                .is_some_and(|m| m.contains(&self.filter))
+    }
+
+    fn handle_reply(&mut self, reply: WlControlReply) {
+        match reply {
+            WlControlReply::Message(msg) => {
+                if self.paused {
+                    return;
+                }
+                if self.messages.len() >= TUI_MAX_MESSAGES {
+                    self.messages.pop_front();
+                }
+                self.messages.push_back(msg);
+            }
+            WlControlReply::ConnOpened(info) => {
+                self.status = format!("connection {} opened", info.conn_id);
+                self.connections.push(info);
+            }
+            WlControlReply::ConnClosed { conn_id } => {
+                self.status = format!("connection {} closed", conn_id);
+                self.connections.retain(|c| c.conn_id != conn_id);
+            }
+            WlControlReply::Connections { connections } => self.connections = connections,
+            WlControlReply::Objects { objects, .. } => self.objects = objects,
+            WlControlReply::Rules { rules } => self.rules = rules,
+            WlControlReply::Lagged { missed } => self.missed += missed,
+            WlControlReply::Error { message } => self.status = message,
+            WlControlReply::LogLevels { default, overrides } => {
+                self.status = format!("log level: {}, {} override(s)", default, overrides.len());
+            }
+            WlControlReply::Dumped { connections } => {
+                self.status = format!("dump requested from {} connection(s)", connections);
+            }
+            WlControlReply::SessionLock { locked } => {
+                self.status = format!("session {}", if locked { "locked" } else { "unlocked" });
+            }
+            WlControlReply::SessionEnded => self.status = "session ended".to_string(),
+            WlControlReply::PermissionRevoked { conn_id, revoked } => {
+                self.status = if revoked {
+                    format!("permission revoked from connection {}", conn_id)
+                } else {
+                    format!("connection {} hadn't been granted the permission", conn_id)
+                };
+            }
+            WlControlReply::Pong(status) => {
+                self.status = format!("ready: {}", status.ready);
+            }
+            WlControlReply::Explanation(explanation) => {
+                self.status = format!(
+                    "{}.{}: {}",
+                    explanation.interface, explanation.request, explanation.verdict
+                );
+            }
+        }
+    }
+
+    /// Cycle through showing all connections and each connection in turn
+    fn next_conn_filter(&mut self) {
+        let mut ids: Vec<_> = self.connections.iter().map(|c| c.conn_id).collect();
+        ids.sort();
+        self.conn_filter = match self.conn_filter {
+            None => ids.first().copied(),
+            Some(cur) => ids.into_iter().find(|id| *id > cur),
+        };
+    }
+
+    fn toggle_selected_rule(&mut self) -> io::Result<()> {
+        let Some(rule) = self.rules_state.selected().and_then(|i| self.rules.get(i)) else {
+            return Ok(());
+        };
+
+        let req = WlControlRequest::SetRule {
+            interface: rule.interface.clone(),
+            index: rule.index,
+            enabled: !rule.enabled,
+        };
+        self.send(&req)
+    }
+
+    /// Returns false when the user asks to quit
+    fn handle_key(&mut self, code: KeyCode) -> io::Result<bool> {
+        if self.editing_filter {
+            match code {
+                KeyCode::Enter => self.editing_filter = false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.editing_filter = false;
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => {}
+            }
+            return Ok(true);
+        }
+
+        match code {
+            KeyCode::Char('q') => return Ok(false),
+            KeyCode::Esc => self.view = View::Messages,
+            KeyCode::Char('/') => {
+                self.view = View::Messages;
+                self.editing_filter = true;
+            }
+            KeyCode::Char('c') => self.next_conn_filter(),
+            KeyCode::Char('p') => self.paused = !self.paused,
+            KeyCode::Char('x') => self.messages.clear(),
+            KeyCode::Char('o') => match self.conn_filter {
+                Some(conn_id) => {
+                    self.view = View::Objects;
+                    self.send(&WlControlRequest::Objects { conn_id })?;
+                }
+                None => self.status = "select a connection with 'c' first".to_string(),
+            },
+            KeyCode::Char('r') => {
+                self.view = View::Rules;
+                self.send(&WlControlRequest::Rules)?;
+            }
+            KeyCode::Up if self.view == View::Rules => self.rules_state.select_previous(),
+            KeyCode::Down if self.view == View::Rules => self.rules_state.select_next(),
+            KeyCode::Enter | KeyCode::Char(' ') if self.view == View::Rules => {
+                self.toggle_selected_rule()?
+            }
+            _ => {}
+        }
+
+        Ok(true)
+    }
+
+    fn header(&self) -> Line<'_> {
+        let conn = match self.conn_filter {
+            Some(c) => format!("conn {}", c),
+            None => "all conns".to_string(),
+        };
+        let filter = if self.editing_filter {
+            format!("/{}_", self.filter)
+        } else if self.filter.is_empty() {
+            "no filter".to_string()
+        } else {
+            format!("/{}", self.filter)
+        };
+
+        let mut spans = vec![
+            Span::raw(" wl-mitm ").bold().reversed(),
+            Span::raw(format!(
+                " {} | {} | {} connection(s)",
+                conn,
+                filter,
+                self.connections.len()
+            )),
+        ];
+        if self.paused {
+            spans.push(Span::raw(" | PAUSED").yellow());
+        }
+        if self.missed > 0 {
+            spans.push(Span::raw(format!(" | missed {}", self.missed)).red());
+        }
+        if !self.status.is_empty() {
+            spans.push(Span::raw(format!(" | {}", self.status)));
+        }
+        Line::from(spans)
+    }
+
+    fn message_line(msg: &WlControlMessage) -> ListItem<'_> {
+        let arrow = match msg.direction {
+            WlDirection::ClientToServer => "->",
+            WlDirection::ServerToClient => "<-",
+        };
+        let verdict_style = if msg.verdict == "Allowed" {
+            Style::default().fg(Color::DarkGray)
+        } else {
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+        };
+
+        ListItem::new(Line::from(vec![
+            Span::raw(format!("#{:<3} {} ", msg.conn_id, arrow)),
+            Span::raw(format!(
+                "{}@{}",
+                msg.interface.as_deref().unwrap_or("?"),
+                msg.obj_id
+            ))
+            .cyan(),
+            Span::raw(format!(
+                ".{}",
+                msg.msg_name
+                    .clone()
+                    .unwrap_or_else(|| format!("opcode {}", msg.opcode))
+            ))
+            .bold(),
+            Span::raw(format!(" {}", msg.args.as_deref().unwrap_or(""))),
+            Span::styled(format!(" [{}]", msg.verdict), verdict_style),
+        ]))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        frame.render_widget(Paragraph::new(self.header()), header);
+
+        match self.view {
+            View::Messages => {
+                let visible: Vec<_> = self.messages.iter().filter(|m| self.matches(m)).collect();
+                // Follow the tail of the stream
+                let height = body.height.saturating_sub(2) as usize;
+                let items: Vec<_> = visible[visible.len().saturating_sub(height)..]
+                    .iter()
+                    .map(|m| Self::message_line(m))
+                    .collect();
+                frame.render_widget(
+                    List::new(items).block(Block::bordered().title("Messages")),
+                    body,
+                );
+            }
+            View::Objects => {
+                let items: Vec<_> = self
+                    .objects
+                    .iter()
+                    .map(|o| {
+                        let item = ListItem::new(format!(
+                            "{:>10}  {} v{}  {}",
+                            o.id,
+                            o.interface,
+                            o.version,
+                            o.extensions.join(", ")
+                        ));
+                        if o.half_destroyed {
+                            item.dark_gray()
+                        } else {
+                            item
+                        }
+                    })
+                    .collect();
+                frame.render_widget(
+                    List::new(items).block(Block::bordered().title("Objects")),
+                    body,
+                );
+            }
+            View::Rules => {
+                let items: Vec<_> = self
+                    .rules
+                    .iter()
+                    .map(|r| {
+                        ListItem::new(format!(
+                            "[{}] {}[{}] {} {} {}",
+                            if r.enabled { "x" } else { " " },
+                            r.interface,
+                            r.index,
+                            r.action,
+                            r.requests.join(","),
+                            r.desc.as_deref().unwrap_or("")
+                        ))
+                    })
+                    .collect();
+                frame.render_stateful_widget(
+                    List::new(items)
+                        .block(Block::bordered().title("Rules"))
+                        .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+                    body,
+                    &mut self.rules_state,
+                );
+            }
+        }
+
+        let help = match self.view {
+            View::Rules => " up/down select | space toggle | esc back | q quit",
+            _ => " / filter | c connection | o objects | r rules | p pause | x clear | q quit",
+        };
+        frame.render_widget(Paragraph::new(help).dark_gray(), footer);
+    }
+}
+
+fn connect(addr: &WlSocketAddr) -> io::Result<UnixStream> {
+    match addr {
+        WlSocketAddr::Path(p) => UnixStream::connect(p),
+        WlSocketAddr::Abstract(name) => {
+            UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)
+        }
+        WlSocketAddr::Tcp(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "control socket must be a unix socket",
+        )),
+    }
+}
+
+fn run_app(terminal: &mut DefaultTerminal, mut app: App) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let reader = BufReader::new(app.conn.try_clone()?);
+    std::thread::spawn(move || {
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+            let Ok(reply) = serde_json::from_str::<WlControlReply>(&line) else {
+                continue;
+            };
+            if tx.send(reply).is_err() {
+                break;
+            }
+        }
+    });
+
+    app.send(&WlControlRequest::Subscribe)?;
+    app.send(&WlControlRequest::Connections)?;
+
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(reply) => app.handle_reply(reply),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "wl-mitm closed the control socket",
+                    ));
+                }
+            }
+        }
+
+        terminal.draw(|f| app.draw(f))?;
+
+        if !event::poll(Duration::from_millis(100))? {
+            continue;
+        }
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+
+        if key.kind == KeyEventKind::Press && !app.handle_key(key.code)? {
+            return Ok(());
+        }
+    }
+}
+
+pub fn run(addr: &WlSocketAddr) -> io::Result<()> {
+    let app = App {
+        conn: connect(addr)?,
+        messages: VecDeque::new(),
+        connections: Vec::new(),
+        conn_filter: None,
+        filter: String::new(),
+        editing_filter: false,
+        paused: false,
+        missed: 0,
+        view: View::Messages,
+        objects: Vec::new(),
+        rules: Vec::new(),
+        rules_state: ListState::default().with_selected(Some(0)),
+        status: String::new(),
+    };
+
+    let mut terminal = ratatui::init();
+    let res = run_app(&mut terminal, app);
+    ratatui::restore();
+    res
+}
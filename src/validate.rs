@@ -0,0 +1,140 @@
+//! Validation of protocol invariants wl-mitm can observe on its own
+//!
+//! Some client bugs are only caught by the compositor, which usually kills the
+//! client with a protocol error that is hard to trace back to its cause. With
+//! `validation.mode` set, wl-mitm checks a few state machines itself and logs
+//! (or terminates on) violations before the compositor gets to see them:
+//!
+//! - `xdg_surface.ack_configure` must reference a configure serial the client
+//!   has received, and not acked (or skipped by acking a later one) yet.
+//! - No buffer may be attached to a wl_surface with an xdg_surface role
+//!   before the first `xdg_surface.configure`.
+
+use std::{any::TypeId, collections::VecDeque};
+
+use tracing::warn;
+
+use crate::{
+    config::{WlValidationConfig, WlValidationMode},
+    objects::{WlObjectExtension, WlObjects},
+    proto::{
+        AnyWlParsedMessage, WlSurfaceAttachRequest, XdgSurfaceAckConfigureRequest,
+        XdgSurfaceConfigureEvent, XdgWmBaseGetXdgSurfaceRequest,
+    },
+    state::SurfaceXdgAssociation,
+};
+
+/// Configure serials received by a client may pile up if it never acks any.
+/// Only this many of the most recent ones are remembered.
+const MAX_PENDING_CONFIGURES: usize = 64;
+
+/// Configure sequence of an xdg_surface
+#[derive(Default)]
+struct XdgSurfaceConfigures {
+    configured: bool,
+    /// Serials received but not yet acked, oldest first
+    pending: VecDeque<u32>,
+}
+
+impl WlObjectExtension for XdgSurfaceConfigures {
+    fn describe(&self) -> String {
+        format!(
+            "configures configured={} pending={:?}",
+            self.configured, self.pending
+        )
+    }
+}
+
+pub struct WlValidator {
+    mode: WlValidationMode,
+}
+
+impl WlValidator {
+    pub fn new(config: &WlValidationConfig) -> WlValidator {
+        WlValidator { mode: config.mode }
+    }
+
+    /// Whether messages of type `msg_type` (see [AnyWlParsedMessage::static_type_id])
+    /// need to be passed to this validator
+    pub fn is_interested(&self, msg_type: TypeId) -> bool {
+        self.mode != WlValidationMode::Off
+            && [
+                TypeId::of::<XdgWmBaseGetXdgSurfaceRequest<'static>>(),
+                TypeId::of::<XdgSurfaceAckConfigureRequest<'static>>(),
+                TypeId::of::<WlSurfaceAttachRequest<'static>>(),
+                TypeId::of::<XdgSurfaceConfigureEvent<'static>>(),
+            ]
+            .contains(&msg_type)
+    }
+
+    /// Check a request from the client after it has been recorded in
+    /// `objects`, and roles have been associated by [crate::state]. Returns false if the connection shall be terminated.
+    pub fn on_request(&self, objects: &mut WlObjects, msg: &dyn AnyWlParsedMessage) -> bool {
+        if self.mode == WlValidationMode::Off {
+            return true;
+        }
+
+        let violation = if let Some(msg) = msg.downcast_ref::<XdgWmBaseGetXdgSurfaceRequest>() {
+            objects.put_extension(msg.id, XdgSurfaceConfigures::default());
+            None
+        } else if let Some(msg) = msg.downcast_ref::<XdgSurfaceAckConfigureRequest>() {
+            objects
+                .extension_mut::<XdgSurfaceConfigures>(msg.obj_id())
+                .and_then(|c| match c.pending.iter().position(|s| *s == msg.serial) {
+                    Some(i) => {
+                        c.pending.drain(..=i);
+                        None
+                    }
+                    None => Some(format!(
+                        "ack_configure with serial {} which is not pending",
+                        msg.serial
+                    )),
+                })
+        } else if let Some(msg) = msg.downcast_ref::<WlSurfaceAttachRequest>() {
+            let configures = objects
+                .extension::<SurfaceXdgAssociation>(msg.obj_id())
+                .and_then(|SurfaceXdgAssociation(xdg_surface)| {
+                    objects.handle_extension::<XdgSurfaceConfigures>(*xdg_surface)
+                });
+            match configures {
+                Some(c) if msg.buffer != 0 && !c.configured => {
+                    Some("buffer attached before the first xdg_surface.configure".to_string())
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        let Some(violation) = violation else {
+            return true;
+        };
+
+        warn!(
+            obj_id = msg.obj_id(),
+            mode = ?self.mode,
+            "Protocol violation in {}::{}: {}",
+            msg.object_type().interface(),
+            msg.msg_name(),
+            violation
+        );
+        self.mode != WlValidationMode::Terminate
+    }
+
+    /// Track an event from the server after it has been recorded in `objects`
+    pub fn on_event(&self, objects: &mut WlObjects, msg: &dyn AnyWlParsedMessage) {
+        if self.mode == WlValidationMode::Off {
+            return;
+        }
+
+        if let Some(msg) = msg.downcast_ref::<XdgSurfaceConfigureEvent>()
+            && let Some(c) = objects.extension_mut::<XdgSurfaceConfigures>(msg.obj_id())
+        {
+            c.configured = true;
+            if c.pending.len() >= MAX_PENDING_CONFIGURES {
+                c.pending.pop_front();
+            }
+            c.pending.push_back(msg.serial);
+        }
+    }
+}
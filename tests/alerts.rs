@@ -0,0 +1,154 @@
+//! Alert rules counting requests within a sliding window
+
+mod harness;
+
+use std::time::{Duration, Instant};
+
+use harness::{Harness, REGISTRY_ID, TEST_CONFIG, test_config};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use wl_mitm::{
+    alerts::WlAlerts,
+    config::Config,
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceSetBufferScaleRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const SURFACE_ID: u32 = 4;
+
+fn config(webhook: &str) -> String {
+    test_config(&format!(
+        r#"
+[alerts]
+webhook = {:?}
+rules = [
+    {{ name = "scale burst", interface = "wl_surface", requests = ["set_buffer_scale"], count = 2, window_secs = 60 }},
+    {{ name = "blocked burst", verdict = "blocked", count = 3, window_secs = 10 }},
+]
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    {{ interface = "wl_surface", requests = ["set_buffer_transform"], action = "block" }},
+]
+"#,
+        webhook
+    ))
+}
+
+fn names(fired: Vec<&wl_mitm::config::WlAlertRule>) -> Vec<String> {
+    fired.into_iter().map(|r| r.name.clone()).collect()
+}
+
+#[test]
+fn rules_fire_within_window() {
+    let config = Config::parse(&config("http://127.0.0.1:1/")).unwrap();
+    let mut alerts = WlAlerts::new(&config.alerts).unwrap();
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+
+    assert!(names(alerts.record("wl_surface", "set_buffer_scale", false, at(0))).is_empty());
+    // The first one has left the window by now
+    assert!(names(alerts.record("wl_surface", "set_buffer_scale", false, at(60))).is_empty());
+    assert_eq!(
+        names(alerts.record("wl_surface", "set_buffer_scale", false, at(61))),
+        ["scale burst"]
+    );
+    // Counting starts over
+    assert!(names(alerts.record("wl_surface", "set_buffer_scale", false, at(62))).is_empty());
+
+    // Only blocked requests count towards the second rule
+    for secs in 0..2 {
+        assert!(names(alerts.record("wl_surface", "damage", true, at(secs))).is_empty());
+    }
+    assert!(names(alerts.record("wl_surface", "damage", false, at(2))).is_empty());
+    assert_eq!(
+        names(alerts.record("wl_surface", "damage", true, at(3))),
+        ["blocked burst"]
+    );
+}
+
+#[test]
+fn no_rules_no_alerts() {
+    let config = Config::parse(TEST_CONFIG).unwrap();
+    assert!(WlAlerts::new(&config.alerts).is_none());
+}
+
+/// Accept one POST on `listener`, answer it, and return its JSON body
+async fn receive_webhook(listener: &TcpListener) -> (String, Value) {
+    let (mut stream, _) = tokio::time::timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("expected the webhook to be called")
+        .unwrap();
+
+    let mut request = Vec::new();
+    let (head, body_len) = loop {
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "webhook request cut short");
+        request.extend_from_slice(&buf[..n]);
+
+        let text = String::from_utf8_lossy(&request).into_owned();
+        if let Some((head, _)) = text.split_once("\r\n\r\n") {
+            let len = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            break (head.to_string(), len);
+        }
+    };
+    while request.len() < head.len() + 4 + body_len {
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    stream
+        .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let body = serde_json::from_slice(&request[head.len() + 4..]).unwrap();
+    (head, body)
+}
+
+#[tokio::test]
+async fn alerts_are_posted_to_webhook() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+    let mut h = Harness::new(&config(&url));
+
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    for _ in 0..2 {
+        h.assert_c2s(
+            WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+    }
+
+    let (head, alert) = receive_webhook(&listener).await;
+    assert!(head.starts_with("POST /alerts HTTP/1.1\r\n"));
+    assert_eq!(alert["rule"], "scale burst");
+    assert_eq!(alert["interface"], "wl_surface");
+    assert_eq!(alert["request"], "set_buffer_scale");
+    assert_eq!(alert["count"], 2);
+    h.finish().await.unwrap();
+}
@@ -0,0 +1,145 @@
+//! The tamper-evident audit log
+
+mod harness;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use harness::{Harness, REGISTRY_ID, TEST_CONFIG, temp_path};
+use wl_mitm::{
+    audit::{self, WlAuditLog},
+    config::Config,
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceSetBufferScaleRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const KEY: &[u8] = b"not much of a secret";
+
+/// An empty log file, and a key file holding [KEY]
+fn files(name: &str) -> (PathBuf, PathBuf) {
+    let log = temp_path(&format!("{}-log", name));
+    let key = temp_path(&format!("{}-key", name));
+    std::fs::write(&key, KEY).unwrap();
+    (log, key)
+}
+
+fn open(log: &Path, key: &Path) -> Arc<WlAuditLog> {
+    let config = Config::parse(&format!(
+        "{}\n[audit]\nfile = {:?}\nkey_file = {:?}\n",
+        TEST_CONFIG, log, key
+    ))
+    .unwrap();
+    WlAuditLog::open(&config.audit).unwrap().unwrap()
+}
+
+/// Have a client make `n` requests blocked by a filter rule
+async fn blocked_requests(audit: Arc<WlAuditLog>, n: usize) {
+    let mut h = Harness::with_setup(TEST_CONFIG, move |duplex| duplex.set_audit(audit));
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    for _ in 0..n {
+        h.assert_c2s(
+            WlSurfaceSetBufferScaleRequest::new(4, 2).build(),
+            WlMitmVerdict::Filtered,
+        )
+        .await;
+    }
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn records_decisions_in_a_chain() {
+    let (log, key) = files("audit-chain");
+    blocked_requests(open(&log, &key), 2).await;
+    // Reopening continues the chain
+    blocked_requests(open(&log, &key), 1).await;
+
+    let contents = std::fs::read_to_string(&log).unwrap();
+    let records: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["interface"], "wl_surface");
+    assert_eq!(records[0]["request"], "set_buffer_scale");
+    assert_eq!(records[0]["verdict"], "blocked");
+    assert_eq!(records[0]["provenance"][0]["interface"], "wl_surface");
+    assert_eq!(records[2]["seq"], 2);
+    assert_eq!(records[2]["prev"], records[1]["mac"]);
+
+    let summary = audit::verify(&contents, Some(KEY), None).unwrap();
+    assert_eq!(summary.records, 3);
+    assert_eq!(
+        summary.head,
+        Some((2, records[2]["mac"].as_str().unwrap().to_string()))
+    );
+    assert!(audit::verify(&contents, Some(b"wrong key"), None).is_err());
+    std::fs::remove_file(&log).ok();
+    std::fs::remove_file(&key).ok();
+}
+
+#[tokio::test]
+async fn detects_tampering() {
+    let (log, key) = files("audit-tamper");
+    blocked_requests(open(&log, &key), 3).await;
+    let contents = std::fs::read_to_string(&log).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    let verify = |lines: &[&str], head| audit::verify(&lines.join("\n"), Some(KEY), head);
+
+    assert!(verify(&lines, None).is_ok());
+    let altered = lines[1].replace("\"blocked\"", "\"approved\"");
+    assert!(verify(&[lines[0], &altered, lines[2]], None).is_err());
+    assert!(verify(&[lines[0], lines[2]], None).is_err());
+    assert!(verify(&[lines[1], lines[2]], None).is_err());
+    assert!(verify(&[lines[0], lines[2], lines[1]], None).is_err());
+
+    // Cutting off the last records can only be told from where the log ended
+    let head = serde_json::from_str::<serde_json::Value>(lines[2]).unwrap()["mac"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(verify(&lines[..2], None).is_ok());
+    assert!(verify(&lines[..2], Some(&head)).is_err());
+    assert!(verify(&lines, Some(&head)).is_ok());
+    std::fs::remove_file(&log).ok();
+    std::fs::remove_file(&key).ok();
+}
+
+#[tokio::test]
+async fn refuses_to_continue_a_damaged_log() {
+    let (log, key) = files("audit-damaged");
+    blocked_requests(open(&log, &key), 1).await;
+    let contents = std::fs::read_to_string(&log).unwrap();
+    std::fs::write(&log, &contents[..contents.len() - 10]).unwrap();
+
+    let config = Config::parse(&format!(
+        "{}\n[audit]\nfile = {:?}\nkey_file = {:?}\n",
+        TEST_CONFIG, log, key
+    ))
+    .unwrap();
+    let Err(err) = WlAuditLog::open(&config.audit) else {
+        panic!("damaged audit log continued");
+    };
+    assert!(err.to_string().contains("malformed"));
+    // Nothing was appended
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap().len(),
+        contents.len() - 10
+    );
+    std::fs::remove_file(&log).ok();
+    std::fs::remove_file(&key).ok();
+}
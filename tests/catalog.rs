@@ -0,0 +1,184 @@
+//! Rule descriptions looked up in per-locale message catalogs
+
+mod harness;
+
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use harness::{Harness, REGISTRY_ID, TEST_CONFIG, temp_path};
+use wl_mitm::{
+    catalog::WlResolvedDesc,
+    config::Config,
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceSetBufferScaleRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+/// A catalog directory with English, German and Swiss German messages
+fn catalogs(name: &str) -> PathBuf {
+    let dir = temp_path(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (locale, contents) in [
+        (
+            "en",
+            "[surface]\nscale = \"Change the scale of a window\"\nonly_en = \"English only\"\n",
+        ),
+        (
+            "de",
+            "[surface]\nscale = \"Die Skalierung eines Fensters ändern\"\n",
+        ),
+        (
+            "de_CH",
+            "\"surface.scale\" = \"Skalierig vomene Fänschter\"\n",
+        ),
+    ] {
+        std::fs::write(dir.join(format!("{}.toml", locale)), contents).unwrap();
+    }
+    dir
+}
+
+fn config(dir: &Path, locale: &str) -> String {
+    format!(
+        "{}\n[i18n]\ncatalog_dir = {:?}\nlocale = {:?}\n",
+        TEST_CONFIG, dir, locale
+    )
+}
+
+#[test]
+fn resolves_by_locale() {
+    let dir = catalogs("catalog-locale");
+    let resolve = |locale: &str| {
+        let config = Config::parse(&config(&dir, locale)).unwrap();
+        assert_eq!(config.catalog.locale(), Some(locale));
+        config.catalog.resolve("@surface.scale").text.to_string()
+    };
+
+    assert_eq!(resolve("de_CH.UTF-8"), "Skalierig vomene Fänschter");
+    assert_eq!(
+        resolve("de_DE.UTF-8"),
+        "Die Skalierung eines Fensters ändern"
+    );
+    assert_eq!(
+        resolve("de_AT@euro"),
+        "Die Skalierung eines Fensters ändern"
+    );
+    assert_eq!(resolve("fr_FR"), "Change the scale of a window");
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn falls_back_to_english_and_keys() {
+    let dir = catalogs("catalog-fallback");
+    let config = Config::parse(&config(&dir, "de_DE")).unwrap();
+    let catalog = &config.catalog;
+
+    assert_eq!(
+        catalog.resolve("@surface.only_en"),
+        WlResolvedDesc {
+            text: "English only",
+            key: Some("surface.only_en"),
+        }
+    );
+    // Missing keys stand for themselves
+    assert_eq!(
+        catalog.resolve("@surface.missing"),
+        WlResolvedDesc {
+            text: "surface.missing",
+            key: Some("surface.missing"),
+        }
+    );
+    // Descriptions which aren't keys are shown as they are
+    assert_eq!(
+        catalog.resolve("new surface"),
+        WlResolvedDesc {
+            text: "new surface",
+            key: None,
+        }
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn rejects_malformed_catalogs() {
+    let dir = catalogs("catalog-malformed");
+    std::fs::write(dir.join("de.toml"), "scale = \n").unwrap();
+    let Err(err) = Config::parse(&config(&dir, "de_DE")) else {
+        panic!("malformed catalog accepted");
+    };
+    assert!(err.to_string().contains("can't load message catalogs"));
+
+    // Without a catalog directory, nothing is looked up
+    let config = Config::parse(TEST_CONFIG).unwrap();
+    assert_eq!(config.catalog.locale(), None);
+    assert_eq!(
+        config.catalog.resolve("@surface.scale").text,
+        "surface.scale"
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn ask_cmd_gets_localized_text() {
+    let dir = catalogs("catalog-ask");
+    let (script, out) = (dir.join("ask.sh"), dir.join("out"));
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$3|$WL_MITM_DESC_KEY|$WL_MITM_LOCALE\" >> {}\n",
+            out.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut h = Harness::new(&format!(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[exec]
+ask_cmd = "{}"
+
+[i18n]
+catalog_dir = {:?}
+locale = "de_DE.UTF-8"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    {{ interface = "wl_compositor", requests = ["create_surface"], action = "ask", desc = "new surface" }},
+    {{ interface = "wl_surface", requests = ["set_buffer_scale"], action = "ask", desc = "@surface.scale" }},
+]
+"#,
+        script.display(),
+        dir
+    ));
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    for msg in [
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlSurfaceSetBufferScaleRequest::new(4, 2).build(),
+    ] {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+    h.finish().await.unwrap();
+
+    let asked = std::fs::read_to_string(&out).unwrap();
+    assert_eq!(
+        asked.lines().collect::<Vec<_>>(),
+        [
+            "new surface||",
+            "Die Skalierung eines Fensters ändern|surface.scale|de_DE.UTF-8",
+        ]
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,160 @@
+//! Chaos mode, with the strategies and probability pinned so that results are deterministic
+
+mod harness;
+
+use harness::{Harness, REGISTRY_ID};
+use wl_mitm::{
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceCommitRequest, WlSurfaceDamageRequest, WlSurfacePreferredBufferScaleEvent,
+    },
+    state::WlMitmVerdict,
+};
+
+fn chaos_config(strategies: &str, direction: &str, interfaces: &str) -> String {
+    format!(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[chaos]
+enabled = true
+seed = 1
+probability = 1.0
+strategies = {strategies}
+direction = "{direction}"
+interfaces = {interfaces}
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = []
+"#
+    )
+}
+
+/// Create a wl_surface (id 4); chaos is limited to wl_surface, so this is untouched
+async fn setup_surface(h: &mut Harness) {
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn duplicate() {
+    let mut h = Harness::new(&chaos_config(
+        r#"["duplicate"]"#,
+        "requests",
+        r#"["wl_surface"]"#,
+    ));
+    setup_surface(&mut h).await;
+
+    let commit = WlSurfaceCommitRequest::new(4).build();
+    let bytes = commit.as_bytes().to_vec();
+    h.client.send(commit).await;
+    assert_eq!(h.server.recv().await.as_bytes(), bytes);
+    assert_eq!(h.server.recv().await.as_bytes(), bytes);
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn drop() {
+    let mut h = Harness::new(&chaos_config(
+        r#"["drop"]"#,
+        "requests",
+        r#"["wl_surface"]"#,
+    ));
+    setup_surface(&mut h).await;
+    h.client.send(WlSurfaceCommitRequest::new(4).build()).await;
+    h.server.expect_nothing().await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn bitflip_keeps_header() {
+    let mut h = Harness::new(&chaos_config(
+        r#"["bitflip"]"#,
+        "events",
+        r#"["wl_surface"]"#,
+    ));
+    setup_surface(&mut h).await;
+
+    let scale = WlSurfacePreferredBufferScaleEvent::new(4, 2).build();
+    let bytes = scale.as_bytes().to_vec();
+    h.server.send(scale).await;
+
+    let received = h.client.recv().await;
+    assert_eq!(received.as_bytes()[..8], bytes[..8]);
+    let flipped: u32 = received
+        .as_bytes()
+        .iter()
+        .zip(bytes.iter())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    assert_eq!(flipped, 1);
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn requests_keep_their_arguments() {
+    let mut h = Harness::new(&chaos_config(
+        r#"["bitflip", "scramble"]"#,
+        "requests",
+        r#"["wl_surface"]"#,
+    ));
+    setup_surface(&mut h).await;
+
+    // They were let through as they are
+    for _ in 0..4 {
+        h.assert_c2s(
+            WlSurfaceDamageRequest::new(4, 0, 0, 64, 64).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+    }
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn reorder() {
+    let mut h = Harness::new(&chaos_config(
+        r#"["reorder"]"#,
+        "requests",
+        r#"["wl_surface"]"#,
+    ));
+    setup_surface(&mut h).await;
+
+    // The damage is held back; the commit takes its place but is held back
+    // in turn, releasing the damage
+    h.client
+        .send(WlSurfaceDamageRequest::new(4, 0, 0, 64, 64).build())
+        .await;
+    h.server.expect_nothing().await;
+    h.client.send(WlSurfaceCommitRequest::new(4).build()).await;
+    assert_eq!(h.server.recv().await.opcode, 2);
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn reorder_lets_go_eventually() {
+    let mut h = Harness::new(&chaos_config(
+        r#"["reorder"]"#,
+        "requests",
+        r#"["wl_surface"]"#,
+    ));
+    setup_surface(&mut h).await;
+
+    // Nothing overtakes the commit, which may well be waiting for a frame
+    // callback before sending anything else
+    h.client.send(WlSurfaceCommitRequest::new(4).build()).await;
+    assert_eq!(h.server.recv().await.opcode, 6);
+    h.finish().await.unwrap();
+}
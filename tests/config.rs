@@ -0,0 +1,160 @@
+//! Loading of the config file
+
+use wl_mitm::{
+    config::{Config, WlFilter},
+    proto::{
+        WL_SHM_POOL, WL_SURFACE, WlParsedMessage, WlShmPoolDestroyRequest, WlSurfaceDestroyRequest,
+        WlSurfaceSetBufferScaleRequest, WlSurfaceSetBufferTransformRequest,
+    },
+};
+
+const CONFIG: &str = r#"
+[socket]
+listen = "wayland-proxied"
+upstream = "wayland-0"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    { interface = "*", requests = ["destroy"], action = "notify" },
+    { interface = "wl_surface", requests = ["set_buffer_scale"], action = "block" },
+    { interface = "wl_surface", requests = ["*"], action = "notify", min_version = 6 },
+]
+"#;
+
+#[test]
+fn filter_rules_are_compiled_by_message() {
+    let config = Config::parse(CONFIG).unwrap();
+    let candidates = |obj_type, opcode| {
+        config
+            .filter
+            .candidates(obj_type, opcode)
+            .iter()
+            .map(|(interface, i)| (interface.as_str(), *i))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        candidates(WL_SURFACE, WlSurfaceSetBufferScaleRequest::opcode()),
+        [("wl_surface", 0), ("wl_surface", 1)]
+    );
+    assert_eq!(
+        candidates(WL_SURFACE, WlSurfaceSetBufferTransformRequest::opcode()),
+        [("wl_surface", 1)]
+    );
+    // Rules for the interface take precedence over rules for "*"
+    assert_eq!(
+        candidates(WL_SURFACE, WlSurfaceDestroyRequest::opcode()),
+        [("wl_surface", 1), ("*", 0)]
+    );
+    assert_eq!(
+        candidates(WL_SHM_POOL, WlShmPoolDestroyRequest::opcode()),
+        [("*", 0)]
+    );
+    assert!(candidates(WL_SHM_POOL, WlShmPoolDestroyRequest::opcode() + 1).is_empty());
+}
+
+#[test]
+fn filter_rules_are_compiled_by_any_deserializer() {
+    let filter: WlFilter = serde_json::from_str(
+        r#"{
+            "allowed_globals": [],
+            "requests": [
+                { "interface": "wl_surface", "requests": ["set_buffer_scale"], "action": "block" }
+            ]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        filter.candidates(WL_SURFACE, WlSurfaceSetBufferScaleRequest::opcode()),
+        [("wl_surface".to_string(), 0)]
+    );
+}
+
+const PROXIES: &str = r#"
+[socket]
+listen = "wayland-proxied"
+upstream = "wayland-0"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = []
+
+[[proxy]]
+socket = { listen = "wayland-nested-proxied", upstream = "wayland-nested" }
+
+[[proxy]]
+socket = { listen = "wayland-dev-proxied", upstream = "wayland-0" }
+filter = { allowed_globals = ["wl_compositor", "wl_shm"], requests = [] }
+"#;
+
+#[test]
+fn proxies_inherit_what_they_dont_set() {
+    let config = Config::parse(PROXIES).unwrap();
+    let listen: Vec<_> = config
+        .proxies
+        .iter()
+        .map(|p| p.socket.listen_socket_addr().to_string())
+        .collect();
+    assert!(
+        listen[0].ends_with("wayland-nested-proxied"),
+        "{:?}",
+        listen
+    );
+    assert!(listen[1].ends_with("wayland-dev-proxied"), "{:?}", listen);
+
+    assert!(
+        config.proxies[0].all_upstreams()[0]
+            .to_string()
+            .ends_with("wayland-nested")
+    );
+    assert_eq!(config.proxies[0].filter.allowed_globals.len(), 1);
+    assert!(config.proxies[1].filter.allowed_globals.contains("wl_shm"));
+    assert!(!config.filter.allowed_globals.contains("wl_shm"));
+}
+
+#[test]
+fn proxies_cant_set_shared_sections() {
+    let config = format!("{}runtime = {{ worker_threads = 2 }}\n", PROXIES);
+    let err = Config::parse(&config).err().unwrap().to_string();
+    assert!(err.contains("`runtime` is shared"), "{}", err);
+
+    // App profiles are learned by the whole process
+    let config = format!("{}learning = {{ dir = \"/profiles\" }}\n", PROXIES);
+    let err = Config::parse(&config).err().unwrap().to_string();
+    assert!(err.contains("`learning` is shared"), "{}", err);
+}
+
+#[test]
+fn sandbox_refuses_peer_identity() {
+    let sandboxed = |config: &str| format!("{}\n[sandbox]\nenabled = true\n", config);
+    Config::parse(&sandboxed(CONFIG)).unwrap();
+
+    // Landlock keeps clients' /proc entries out of reach
+    let config = CONFIG.replace(
+        "action = \"block\" }",
+        "action = \"block\", flatpak_app_id = \"org.example.*\" }",
+    );
+    Config::parse(&config).unwrap();
+    let err = Config::parse(&sandboxed(&config))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.contains("`filter.requests.flatpak_app_id` needs"),
+        "{}",
+        err
+    );
+
+    // In any proxy
+    let config = format!(
+        "{}trace = {{ dir = \"/traces\", exe = [\"*/foot\"] }}\n",
+        PROXIES
+    );
+    Config::parse(&config).unwrap();
+    let err = Config::parse(&sandboxed(&config))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("`trace.exe` needs"), "{}", err);
+}
@@ -0,0 +1,19 @@
+//! The control socket
+
+mod harness;
+
+use harness::TEST_CONFIG;
+use wl_mitm::{config::Config, control};
+
+#[tokio::test]
+async fn abstract_control_sockets_are_refused() {
+    let config = Config::parse(&format!(
+        "{}\n[control]\nsocket = \"@wl-mitm-test-control\"\n",
+        TEST_CONFIG
+    ))
+    .unwrap();
+    let Err(err) = control::bind_control_socket(&config, false).await else {
+        panic!("abstract control socket accepted");
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
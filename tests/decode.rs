@@ -0,0 +1,131 @@
+//! Decoding captures offline
+
+mod harness;
+
+use std::{
+    io::Write,
+    os::{fd::AsFd, unix::fs::PermissionsExt},
+    path::Path,
+};
+
+use harness::temp_path;
+use wl_mitm::{
+    config::WlRecordingFormat,
+    decode::{self, WlDecodeInput},
+    proto::{
+        WL_DISPLAY_OBJECT_ID, WlCompositorCreateSurfaceRequest, WlConstructableMessage,
+        WlDisplayGetRegistryRequest, WlRegistryBindRequest, WlRegistryGlobalEvent,
+        WlShmCreatePoolRequest, WlSurfaceDamageRequest, WlSurfaceDestroyRequest,
+    },
+    recorder::{WlDirection, WlRecordEntry, WlRecorder},
+};
+
+fn decoded(file: &Path, input: WlDecodeInput) -> Vec<String> {
+    let mut out = Vec::new();
+    decode::decode(file, input, &mut out).unwrap();
+    String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn raw_requests_are_decoded() {
+    let requests = [
+        WlDisplayGetRegistryRequest::new(WL_DISPLAY_OBJECT_ID, 2).build(),
+        WlRegistryBindRequest::new(2, 1, "wl_compositor", 6, 3).build(),
+        WlRegistryBindRequest::new(2, 2, "wl_shm", 2, 4).build(),
+        WlCompositorCreateSurfaceRequest::new(3, 5).build(),
+        WlSurfaceDamageRequest::new(5, 0, 0, 10, 10).build(),
+        WlSurfaceDestroyRequest::new(5).build(),
+        // Nothing was created with this ID
+        WlSurfaceDamageRequest::new(6, 0, 0, 10, 10).build(),
+    ];
+
+    let file = temp_path("decode-raw");
+    let mut capture = std::fs::File::create(&file).unwrap();
+    for msg in requests {
+        capture.write_all(msg.as_bytes()).unwrap();
+    }
+    // The fd of a request isn't part of the capture
+    let null = std::fs::File::open("/dev/null").unwrap();
+    let pool = WlShmCreatePoolRequest::new(4, 7, null.as_fd(), 4096).build();
+    capture.write_all(pool.as_bytes()).unwrap();
+    capture.write_all(&[0, 0]).unwrap();
+    drop(capture);
+
+    let lines = decoded(&file, WlDecodeInput::Raw(WlDirection::ClientToServer));
+    std::fs::remove_file(&file).ok();
+
+    assert_eq!(lines.len(), 9);
+    assert_eq!(
+        lines[1],
+        "-> wl_registry#2.bind(1, \"wl_compositor\", 6, new id #3)"
+    );
+    assert_eq!(lines[4], "-> wl_surface#5.damage(0, 0, 10, 10)");
+    assert_eq!(lines[6], "-> (unknown)#6.?2(16 bytes)");
+    assert!(lines[7].starts_with("-> wl_shm#4.create_pool(new id wl_shm_pool#7, fd "));
+    assert_eq!(lines[8], "# 2 trailing bytes");
+}
+
+#[test]
+fn recordings_are_decoded() {
+    let entries = [
+        (
+            WlDirection::ClientToServer,
+            WlDisplayGetRegistryRequest::new(WL_DISPLAY_OBJECT_ID, 2).build(),
+            "Allowed",
+        ),
+        (
+            WlDirection::ServerToClient,
+            WlRegistryGlobalEvent::new(2, 1, "zwlr_screencopy_manager_v1", 3).build(),
+            "Filtered",
+        ),
+    ];
+
+    let file = temp_path("decode-recording");
+    let mut recording = std::fs::File::create(&file).unwrap();
+    for (i, (direction, msg, verdict)) in entries.into_iter().enumerate() {
+        let entry = WlRecordEntry {
+            time_ns: 0,
+            offset_ns: i as u64 * 1_500_000,
+            conn_id: 1,
+            direction,
+            obj_id: msg.obj_id,
+            opcode: msg.opcode,
+            verdict: verdict.to_string(),
+            fds: vec![],
+            data: msg.as_bytes().to_vec(),
+        };
+        serde_json::to_writer(&mut recording, &entry).unwrap();
+        recording.write_all(b"\n").unwrap();
+    }
+    drop(recording);
+
+    let lines = decoded(&file, WlDecodeInput::Recording);
+    std::fs::remove_file(&file).ok();
+
+    assert_eq!(
+        lines,
+        [
+            "[      0.000] -> wl_display#1.get_registry(new id wl_registry#2)",
+            "[      1.500] <- wl_registry#2.global(1, \"zwlr_screencopy_manager_v1\", 3) Filtered",
+        ]
+    );
+}
+
+#[test]
+fn recordings_are_private() {
+    let dir = temp_path("decode-private");
+    WlRecorder::create(&dir, WlRecordingFormat::Jsonl, 1).unwrap();
+
+    let mode =
+        |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&dir), 0o700);
+    let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(mode(&entries[0].as_ref().unwrap().path()), 0o600);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
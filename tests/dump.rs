@@ -0,0 +1,235 @@
+//! State dumps triggered at runtime
+
+mod harness;
+
+use std::path::Path;
+
+use harness::{Harness, REGISTRY_ID, poll_until, temp_path};
+use serde_json::Value;
+use wl_mitm::{
+    proto::{
+        WL_DISPLAY_OBJECT_ID, WlCompositorCreateSurfaceRequest, WlConstructableMessage,
+        WlDisplayDeleteIdEvent, WlKeyboardEnterEvent, WlRegistryBindRequest,
+        WlSeatCapabilitiesEvent, WlSeatGetKeyboardRequest, WlSeatGetPointerRequest,
+        WlSeatNameEvent, XdgSurfaceDestroyRequest, XdgSurfaceGetToplevelRequest,
+        XdgToplevelDestroyRequest, XdgToplevelSetTitleRequest, XdgWmBaseGetXdgSurfaceRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+async fn read_dumps(file: &Path, count: usize) -> Vec<Value> {
+    poll_until(&format!("{} dump(s) in {}", count, file.display()), || {
+        let dumps: Vec<Value> = std::fs::read_to_string(file)
+            .ok()?
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        (dumps.len() >= count).then_some(dumps)
+    })
+    .await
+}
+
+fn dumped_config(file: &Path) -> String {
+    format!(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[dump]
+file = "{}"
+
+[filter]
+allowed_globals = ["wl_compositor", "wl_seat", "xdg_wm_base"]
+requests = []
+"#,
+        file.display()
+    )
+}
+
+#[tokio::test]
+async fn dump_has_objects_and_globals() {
+    let file = temp_path("dump-objects");
+    let mut h = Harness::new(&dumped_config(&file));
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    assert_eq!(h.dumper.trigger(), 1);
+    let dumps = read_dumps(&file, 1).await;
+    let dump = &dumps[0];
+
+    let objects: Vec<_> = dump["objects"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|o| (o["id"].as_u64().unwrap(), o["interface"].as_str().unwrap()))
+        .collect();
+    assert!(objects.contains(&(3, "wl_compositor")));
+    assert!(objects.contains(&(4, "wl_surface")));
+    let parents: Vec<_> = dump["objects"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|o| (o["id"].as_u64().unwrap(), o["parent"].as_u64()))
+        .collect();
+    assert!(parents.contains(&(REGISTRY_ID as u64, Some(1))));
+    assert!(parents.contains(&(3, Some(REGISTRY_ID as u64))));
+    assert!(parents.contains(&(4, Some(3))));
+    assert_eq!(dump["half_destroyed"], serde_json::json!([]));
+    assert!(dump["objects"][0]["created_at"].as_u64().unwrap() > 0);
+    assert_eq!(dump["globals"][0]["interface"], "wl_compositor");
+    assert_eq!(dump["pending_writes_upstream"], 0);
+
+    std::fs::remove_file(&file).ok();
+}
+
+#[tokio::test]
+async fn reused_ids_dont_inherit_associations() {
+    let file = temp_path("dump-reuse");
+    let mut h = Harness::new(&dumped_config(&file));
+    h.setup_registry(&[("wl_compositor", 6), ("wl_seat", 9), ("xdg_wm_base", 6)])
+        .await;
+
+    let c2s = [
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_seat", 9, 4).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 3, "xdg_wm_base", 6, 5).build(),
+        WlSeatGetKeyboardRequest::new(4, 6).build(),
+        // An old window: wl_surface 7 -> xdg_surface 8 -> xdg_toplevel 9
+        WlCompositorCreateSurfaceRequest::new(3, 7).build(),
+        XdgWmBaseGetXdgSurfaceRequest::new(5, 8, 7).build(),
+        XdgSurfaceGetToplevelRequest::new(8, 9).build(),
+        XdgToplevelSetTitleRequest::new(9, "old").build(),
+        XdgToplevelDestroyRequest::new(9).build(),
+        XdgSurfaceDestroyRequest::new(8).build(),
+    ];
+    for msg in c2s {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+    for id in [9, 8] {
+        h.assert_s2c(
+            WlDisplayDeleteIdEvent::new(WL_DISPLAY_OBJECT_ID, id).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+    }
+
+    // A new window on another wl_surface, reusing the IDs of the old one
+    let c2s = [
+        WlCompositorCreateSurfaceRequest::new(3, 10).build(),
+        XdgWmBaseGetXdgSurfaceRequest::new(5, 8, 10).build(),
+        XdgSurfaceGetToplevelRequest::new(8, 9).build(),
+        XdgToplevelSetTitleRequest::new(9, "new").build(),
+    ];
+    for msg in c2s {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+
+    // Focusing the old wl_surface must not make the new window active
+    h.assert_s2c(
+        WlKeyboardEnterEvent::new(6, 1, 7, &[]).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.dumper.trigger();
+    let toplevels = read_dumps(&file, 1).await[0]["toplevels"].clone();
+    assert_eq!(toplevels[0]["title"], "new");
+    let objects = read_dumps(&file, 1).await[0]["objects"].clone();
+    let toplevel = objects
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|o| o["id"] == 9)
+        .unwrap();
+    assert_eq!(
+        toplevel["extensions"],
+        serde_json::json!(["toplevel title=Some(\"new\") app_id=None"])
+    );
+    assert_eq!(toplevels[0]["keyboard_focus"], false);
+
+    h.assert_s2c(
+        WlKeyboardEnterEvent::new(6, 2, 10, &[]).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.dumper.trigger();
+    let toplevels = read_dumps(&file, 2).await[1]["toplevels"].clone();
+    assert_eq!(toplevels[0]["keyboard_focus"], true);
+
+    std::fs::remove_file(&file).ok();
+}
+
+#[tokio::test]
+async fn dump_has_seats_and_their_devices() {
+    let file = temp_path("dump-seats");
+    let mut h = Harness::new(&dumped_config(&file));
+    h.setup_registry(&[("wl_seat", 9), ("wl_seat", 9)]).await;
+
+    let c2s = [
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_seat", 9, 3).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_seat", 9, 4).build(),
+        // The first seat, bound again
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_seat", 9, 5).build(),
+        WlSeatGetPointerRequest::new(3, 6).build(),
+        WlSeatGetKeyboardRequest::new(4, 7).build(),
+    ];
+    for msg in c2s {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+    let s2c = [
+        WlSeatNameEvent::new(3, "seat0").build(),
+        WlSeatCapabilitiesEvent::new(3, 1).build(),
+        WlSeatNameEvent::new(4, "seat1").build(),
+        WlSeatCapabilitiesEvent::new(4, 2).build(),
+    ];
+    for msg in s2c {
+        h.assert_s2c(msg, WlMitmVerdict::Allowed).await;
+    }
+
+    h.dumper.trigger();
+    let seats = read_dumps(&file, 1).await[0]["seats"].clone();
+    assert_eq!(
+        seats,
+        serde_json::json!([
+            {
+                "id": 3,
+                "global": 1,
+                "name": "seat0",
+                "capabilities": 1,
+                "devices": [{ "id": 6, "interface": "wl_pointer" }],
+                "keyboard_focus": null,
+                "pointer_focus": null,
+            },
+            {
+                "id": 4,
+                "global": 2,
+                "name": "seat1",
+                "capabilities": 2,
+                "devices": [{ "id": 7, "interface": "wl_keyboard" }],
+                "keyboard_focus": null,
+                "pointer_focus": null,
+            },
+            {
+                "id": 5,
+                "global": 1,
+                "name": null,
+                "capabilities": 0,
+                "devices": [],
+                "keyboard_focus": null,
+                "pointer_focus": null,
+            },
+        ])
+    );
+
+    std::fs::remove_file(&file).ok();
+}
@@ -0,0 +1,84 @@
+//! `wl-mitm explain`
+
+use wl_mitm::{
+    config::Config,
+    explain::{self, WlExplainQuery, WlRuleMatch},
+};
+
+const CONFIG: &str = r#"
+[socket]
+listen = "wayland-proxied"
+upstream = "wayland-0"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    { interface = "*", requests = ["*"], action = "notify", descendant_of = "zwlr_screencopy_manager_v1" },
+    { interface = "wl_surface", requests = ["set_buffer_scale"], action = "block", block_type = "reject", error_code = 2, min_version = 3 },
+    { interface = "wl_surface", requests = ["*"], action = "ask" },
+]
+"#;
+
+fn query(request: &str, version: Option<u32>, ancestors: Option<&[&str]>) -> WlExplainQuery {
+    WlExplainQuery {
+        interface: "wl_surface".to_string(),
+        request: request.to_string(),
+        version,
+        ancestors: ancestors.map(|a| a.iter().map(|a| a.to_string()).collect()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn explain_reports_matching_rule() {
+    let config = Config::parse(CONFIG).unwrap();
+    let results = |query: &WlExplainQuery| {
+        let explanation = explain::explain(&config, query, |_, _| true).unwrap();
+        let results: Vec<_> = explanation
+            .rules
+            .iter()
+            .map(|r| (r.interface.clone(), r.index, r.result))
+            .collect();
+        (results, explanation.verdict)
+    };
+
+    let (rules, verdict) = results(&query("set_buffer_scale", Some(4), Some(&[])));
+    assert_eq!(
+        rules,
+        [
+            ("wl_surface".to_string(), 0, WlRuleMatch::Matches),
+            ("wl_surface".to_string(), 1, WlRuleMatch::Shadowed),
+            ("*".to_string(), 0, WlRuleMatch::Shadowed),
+        ]
+    );
+    assert_eq!(verdict, "blocked (rejected with error 2)");
+
+    let (rules, verdict) = results(&query("set_buffer_scale", Some(2), Some(&[])));
+    assert_eq!(rules[0].2, WlRuleMatch::Skipped);
+    assert_eq!(rules[1].2, WlRuleMatch::Matches);
+    assert_eq!(verdict, "blocked (ignored), as ask_cmd is not set");
+
+    // Conditions the query leaves open make rules only possibly match
+    let (rules, _) = results(&query("set_buffer_scale", None, None));
+    assert_eq!(rules[0].2, WlRuleMatch::MayMatch);
+    assert_eq!(rules[1].2, WlRuleMatch::Matches);
+}
+
+#[test]
+fn explain_honors_disabled_rules() {
+    let config = Config::parse(CONFIG).unwrap();
+    let explanation = explain::explain(&config, &query("damage", Some(1), Some(&[])), |i, n| {
+        (i, n) != ("wl_surface", 1)
+    })
+    .unwrap();
+
+    assert_eq!(explanation.rules[0].result, WlRuleMatch::Disabled);
+    assert_eq!(explanation.rules[1].result, WlRuleMatch::Skipped);
+    assert_eq!(explanation.verdict, "allowed, no rule matches");
+}
+
+#[test]
+fn explain_rejects_unknown_requests() {
+    let config = Config::parse(CONFIG).unwrap();
+    assert!(explain::explain(&config, &query("nonexistent", None, None), |_, _| true).is_err());
+}
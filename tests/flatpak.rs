@@ -0,0 +1,157 @@
+//! Flatpak app identification and policies depending on it
+
+mod harness;
+
+use harness::{Harness, REGISTRY_ID};
+use wl_mitm::{
+    config::Config,
+    explain::{self, WlExplainQuery, WlRuleMatch},
+    flatpak::WlFlatpakInfo,
+    peer::WlPeerInfo,
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceSetBufferScaleRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const FLATPAK_INFO: &str = "\
+[Application]
+name=org.example.App
+runtime=runtime/org.freedesktop.Platform/x86_64/24.08
+
+[Instance]
+instance-id=1234567890
+branch=stable
+
+[Context]
+shared=network;ipc;
+sockets=wayland;pulseaudio;
+devices=dri;
+";
+
+const CONFIG: &str = r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[upstreams]
+flatpak = "@wl-mitm-test-flatpak"
+
+[[routes]]
+upstream = "flatpak"
+flatpak_app_id = "org.example.*"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    { interface = "wl_surface", requests = ["set_buffer_scale"], action = "block", flatpak_app_id = "org.example.*" },
+]
+"#;
+
+fn flatpak_peer(app_id: &str) -> WlPeerInfo {
+    WlPeerInfo {
+        flatpak: Some(WlFlatpakInfo {
+            app_id: app_id.to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn parses_flatpak_info() {
+    let info = WlFlatpakInfo::parse(FLATPAK_INFO).unwrap();
+    assert_eq!(info.app_id, "org.example.App");
+    assert_eq!(
+        info.runtime.as_deref(),
+        Some("runtime/org.freedesktop.Platform/x86_64/24.08")
+    );
+    assert_eq!(info.instance_id.as_deref(), Some("1234567890"));
+    assert_eq!(info.shared, ["network", "ipc"]);
+    assert_eq!(info.sockets, ["wayland", "pulseaudio"]);
+    assert_eq!(info.devices, ["dri"]);
+    assert!(info.filesystems.is_empty());
+
+    assert_eq!(WlFlatpakInfo::parse("[Context]\nshared=network;\n"), None);
+}
+
+#[test]
+fn routes_by_flatpak_app_id() {
+    let config = Config::parse(CONFIG).unwrap();
+    assert_eq!(
+        config
+            .upstream_for(&flatpak_peer("org.example.App"))
+            .to_string(),
+        "@wl-mitm-test-flatpak"
+    );
+    assert_eq!(
+        config.upstream_for(&Default::default()).to_string(),
+        "@wl-mitm-test-upstream"
+    );
+}
+
+async fn set_buffer_scale(peer: WlPeerInfo) -> Harness {
+    let mut h = Harness::with_setup(CONFIG, move |duplex| duplex.set_peer(peer));
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h
+}
+
+#[tokio::test]
+async fn filter_rules_match_flatpak_app_id() {
+    let mut h = set_buffer_scale(flatpak_peer("org.example.App")).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(4, 2).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    let mut h = set_buffer_scale(flatpak_peer("org.other.App")).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(4, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    let mut h = set_buffer_scale(Default::default()).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(4, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[test]
+fn explain_checks_flatpak_app_id() {
+    let config = Config::parse(CONFIG).unwrap();
+    let result = |flatpak_app_id: Option<&str>| {
+        let query = WlExplainQuery {
+            interface: "wl_surface".to_string(),
+            request: "set_buffer_scale".to_string(),
+            flatpak_app_id: flatpak_app_id.map(str::to_string),
+            ..Default::default()
+        };
+        explain::explain(&config, &query, |_, _| true)
+            .unwrap()
+            .rules[0]
+            .result
+    };
+
+    assert_eq!(result(Some("org.example.App")), WlRuleMatch::Matches);
+    assert_eq!(result(Some("org.other.App")), WlRuleMatch::Skipped);
+    assert_eq!(result(Some("")), WlRuleMatch::Skipped);
+    assert_eq!(result(None), WlRuleMatch::MayMatch);
+}
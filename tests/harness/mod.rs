@@ -0,0 +1,443 @@
+//! In-process harness for end-to-end tests: a scripted fake client and fake
+//! compositor connected to a [ConnDuplex] over socket pairs.
+//!
+//! Verdicts are asserted by what comes out on the other side: an allowed
+//! message arrives unchanged, a filtered one doesn't arrive at all, a rejected
+//! request turns into a `wl_display.error` for the client, an answered one
+//! only reaches the client's answer, a replaced event reaches the client as
+//! its replacement, and a terminated connection is closed on both ends.
+
+#![allow(dead_code)]
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use nix::sys::stat::fstat;
+use sendfd::{RecvWithFd, SendWithFd};
+use tokio::{net::UnixStream, task::JoinHandle};
+use wl_mitm::{
+    ConnDuplex,
+    codec::{DecoderOutcome, WlDecoder, WlRawMsg},
+    config::Config,
+    dump::WlDumper,
+    objects::WlObjects,
+    proto::{
+        self, WL_DISPLAY_OBJECT_ID, WaylandProtocolParsingOutcome, WlConstructableMessage,
+        WlDisplayErrorEvent, WlDisplayGetRegistryRequest, WlParsedMessage, WlRegistryBindRequest,
+        WlRegistryGlobalEvent,
+    },
+    socket::WlStream,
+    state::{WlMitmState, WlMitmVerdict},
+    trace::WlTracer,
+};
+
+/// How long to wait for a message that is expected to arrive
+const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait before concluding that a message has been filtered
+const FILTERED_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub const REGISTRY_ID: u32 = 2;
+/// IDs above this are allocated by the compositor
+const MAX_CLIENT_ID: u32 = 0xFEFFFFFF;
+
+macro_rules! test_sockets {
+    () => {
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+"#
+    };
+}
+
+/// The sockets every test config uses, see [test_config]
+pub const TEST_SOCKETS: &str = test_sockets!();
+
+/// A config allowing a few core globals, with one rule of each block type
+pub const TEST_CONFIG: &str = concat!(
+    test_sockets!(),
+    r#"
+[filter]
+allowed_globals = ["wl_compositor", "wl_shm", "wl_seat"]
+requests = [
+    { interface = "wl_surface", requests = ["set_buffer_scale"], action = "block" },
+    { interface = "wl_surface", requests = ["set_buffer_transform"], action = "block", block_type = "reject", error_code = 7 },
+]
+"#
+);
+
+/// A config of [TEST_SOCKETS] and `sections`, for tests with a filter of their own
+pub fn test_config(sections: &str) -> String {
+    format!("{}{}", TEST_SOCKETS, sections)
+}
+
+/// One end of a connection to the proxy, speaking raw Wayland messages
+pub struct MockPeer {
+    stream: UnixStream,
+    decoder: WlDecoder,
+    /// Only present on the compositor's end
+    client_ids: Option<ClientIds>,
+}
+
+impl MockPeer {
+    fn new(stream: UnixStream) -> MockPeer {
+        MockPeer {
+            stream,
+            decoder: WlDecoder::new(),
+            client_ids: None,
+        }
+    }
+
+    /// The compositor's end, which checks new IDs in requests like libwayland
+    fn compositor(stream: UnixStream) -> MockPeer {
+        MockPeer {
+            client_ids: Some(ClientIds {
+                objects: WlObjects::new(),
+                next: WL_DISPLAY_OBJECT_ID + 1,
+            }),
+            ..Self::new(stream)
+        }
+    }
+
+    pub async fn send(&mut self, msg: WlRawMsg) {
+        let (buf, fds) = msg.into_parts();
+        let raw_fds: Vec<_> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        let mut written = 0;
+
+        while written < buf.len() {
+            self.stream.writable().await.unwrap();
+            let fds = if written == 0 { &raw_fds[..] } else { &[] };
+            match self.stream.send_with_fd(&buf[written..], fds) {
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => panic!("failed to send message: {}", e),
+            }
+        }
+    }
+
+    async fn read(&mut self) -> io::Result<DecoderOutcome> {
+        if let Some(DecoderOutcome::Decoded(msg)) = self.decoder.decode_buf() {
+            return Ok(DecoderOutcome::Decoded(msg));
+        }
+
+        loop {
+            self.stream.readable().await?;
+
+            let mut buf = [0u8; 4096];
+            let mut fds = [0i32; 28];
+            let (n, num_fds) = match self.stream.recv_with_fd(&mut buf, &mut fds) {
+                Ok(res) => res,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            };
+
+            let fds = fds[..num_fds]
+                .iter()
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) })
+                .collect();
+            return Ok(self.decoder.decode_after_read(&buf[..n], fds));
+        }
+    }
+
+    /// Read the next message, or `None` if the proxy closed the connection
+    async fn try_recv(&mut self, timeout: Duration) -> Option<Option<WlRawMsg>> {
+        let msg = tokio::time::timeout(timeout, async {
+            loop {
+                match self.read().await {
+                    Ok(DecoderOutcome::Decoded(msg)) => return Some(msg),
+                    Ok(DecoderOutcome::Incomplete) => continue,
+                    Ok(DecoderOutcome::Eof) | Err(_) => return None,
+                }
+            }
+        })
+        .await
+        .ok()?;
+
+        if let (Some(client_ids), Some(msg)) = (&mut self.client_ids, &msg) {
+            client_ids.check(msg);
+        }
+        Some(msg)
+    }
+
+    pub async fn recv(&mut self) -> WlRawMsg {
+        match self.try_recv(RECV_TIMEOUT).await {
+            Some(Some(msg)) => msg,
+            Some(None) => panic!("connection closed while expecting a message"),
+            None => panic!("timed out waiting for a message"),
+        }
+    }
+
+    pub async fn expect_nothing(&mut self) {
+        if let Some(res) = self.try_recv(FILTERED_TIMEOUT).await {
+            match res {
+                Some(msg) => panic!(
+                    "expected no message, got obj_id {} opcode {}",
+                    msg.obj_id, msg.opcode
+                ),
+                None => panic!("expected no message, but the connection was closed"),
+            }
+        }
+    }
+
+    pub async fn expect_closed(&mut self) {
+        match self.try_recv(RECV_TIMEOUT).await {
+            Some(None) => {}
+            Some(Some(msg)) => panic!(
+                "expected connection to be closed, got obj_id {} opcode {}",
+                msg.obj_id, msg.opcode
+            ),
+            None => panic!("timed out waiting for the connection to be closed"),
+        }
+    }
+}
+
+/// New IDs in the client's range as seen by the compositor. libwayland only
+/// accepts one if it has been used before or is the next one up (see
+/// `wl_map_insert_at`), so the proxy must never make up IDs of its own there.
+struct ClientIds {
+    objects: WlObjects,
+    next: u32,
+}
+
+impl ClientIds {
+    fn check(&mut self, msg: &WlRawMsg) {
+        let created = match proto::decode_request(&self.objects, msg) {
+            WaylandProtocolParsingOutcome::Ok(parsed) => {
+                match parsed.downcast_ref::<WlRegistryBindRequest>() {
+                    Some(bind) => proto::lookup_known_object_type(bind.id_interface_name)
+                        .map(|t| vec![(bind.id, t)]),
+                    None => parsed.known_objects_created(),
+                }
+            }
+            _ => None,
+        };
+
+        for (id, obj_type) in created.into_iter().flatten() {
+            if id > MAX_CLIENT_ID {
+                continue;
+            }
+            assert!(
+                id <= self.next,
+                "new ID {} from the client isn't contiguous, expected at most {}",
+                id,
+                self.next
+            );
+            self.next = self.next.max(id + 1);
+            self.objects
+                .record_child_object(obj_type, id, msg.obj_id, None);
+        }
+    }
+}
+
+/// Assert that `received` is byte-for-byte what was `sent`, and that every fd
+/// refers to the same file
+pub fn assert_forwarded(sent: &[u8], sent_fds: &[(u64, u64)], received: &WlRawMsg) {
+    assert_eq!(sent, received.as_bytes(), "forwarded message differs");
+    assert_eq!(
+        sent_fds,
+        &fd_identities(&received.fds)[..],
+        "forwarded fds differ"
+    );
+}
+
+/// (device, inode) of every fd
+pub fn fd_identities(fds: &[OwnedFd]) -> Vec<(u64, u64)> {
+    fds.iter()
+        .map(|fd| {
+            let st = fstat(fd.as_raw_fd()).unwrap();
+            (st.st_dev, st.st_ino)
+        })
+        .collect()
+}
+
+/// A path under the temp directory, unique to `name` and this test process,
+/// with anything a previous run left there removed
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("wl-mitm-test-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+/// Call `f` every few milliseconds until it returns something, e.g. once a
+/// file the proxy writes has turned up. Panics after two seconds.
+pub async fn poll_until<T>(what: &str, mut f: impl FnMut() -> Option<T>) -> T {
+    for _ in 0..200 {
+        if let Some(value) = f() {
+            return value;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for {}", what);
+}
+
+pub struct Harness {
+    pub client: MockPeer,
+    pub server: MockPeer,
+    /// Triggers state dumps of the proxied connection
+    pub dumper: Arc<WlDumper>,
+    proxy: JoinHandle<io::Result<()>>,
+}
+
+impl Harness {
+    pub fn new(config: &str) -> Harness {
+        Self::with_setup(config, |_| {})
+    }
+
+    /// Like [Harness::new], calling `setup` on the proxied connection before
+    /// any message is processed
+    pub fn with_setup(
+        config: &str,
+        setup: impl FnOnce(&mut ConnDuplex<'_>) + Send + 'static,
+    ) -> Harness {
+        let config = Arc::new(Config::parse(config).expect("invalid test config"));
+
+        let (client, downstream) = UnixStream::pair().unwrap();
+        let (upstream, server) = UnixStream::pair().unwrap();
+        let dumper = WlDumper::new(config.clone());
+
+        let conn_dumper = dumper.clone();
+        let proxy = tokio::spawn(async move {
+            let mut upstream = WlStream::Unix(upstream);
+            let mut downstream = WlStream::Unix(downstream);
+            let state = WlMitmState::new(config.clone(), None);
+            let tracer = WlTracer::create(&config.trace, 0, &Default::default())?;
+            let mut duplex =
+                ConnDuplex::new(config, 0, state, None, &mut upstream, &mut downstream);
+            if let Some(tracer) = tracer {
+                duplex.set_tracer(tracer);
+            }
+            duplex.set_dumper(conn_dumper);
+            setup(&mut duplex);
+            duplex.run_to_completion().await
+        });
+
+        Harness {
+            client: MockPeer::new(client),
+            server: MockPeer::compositor(server),
+            dumper,
+            proxy,
+        }
+    }
+
+    /// Send a request from the client and assert what the proxy did with it.
+    /// Returns the message as received by the server if it was allowed, or
+    /// the first one it was replaced with.
+    pub async fn assert_c2s(&mut self, msg: WlRawMsg, verdict: WlMitmVerdict) -> Option<WlRawMsg> {
+        let (obj_id, bytes, fds) = (msg.obj_id, msg.as_bytes().to_vec(), fd_identities(&msg.fds));
+        self.client.send(msg).await;
+
+        match verdict {
+            WlMitmVerdict::Allowed => {
+                let received = self.server.recv().await;
+                assert_forwarded(&bytes, &fds, &received);
+                return Some(received);
+            }
+            WlMitmVerdict::Filtered => self.server.expect_nothing().await,
+            WlMitmVerdict::Rejected(code) => {
+                self.server.expect_nothing().await;
+                let error = self.client.recv().await;
+                let objects = WlObjects::new();
+                let WaylandProtocolParsingOutcome::Ok(error) =
+                    WlDisplayErrorEvent::try_from_msg(&objects, &error)
+                else {
+                    panic!("expected wl_display.error, got opcode {}", error.opcode);
+                };
+                assert_eq!(error.object_id, obj_id);
+                assert_eq!(error.code, code);
+            }
+            WlMitmVerdict::Terminate => {
+                self.server.expect_closed().await;
+                self.client.expect_closed().await;
+            }
+            // What the client gets in answer is up to the caller
+            WlMitmVerdict::Answered => self.server.expect_nothing().await,
+            // The first replacement; any others are up to the caller
+            WlMitmVerdict::Replaced => return Some(self.server.recv().await),
+        }
+
+        None
+    }
+
+    /// Send an event from the server and assert what the proxy did with it.
+    /// Returns the message as received by the client if it was allowed, or
+    /// the first one it was replaced with.
+    pub async fn assert_s2c(&mut self, msg: WlRawMsg, verdict: WlMitmVerdict) -> Option<WlRawMsg> {
+        let (bytes, fds) = (msg.as_bytes().to_vec(), fd_identities(&msg.fds));
+        self.server.send(msg).await;
+
+        match verdict {
+            WlMitmVerdict::Allowed => {
+                let received = self.client.recv().await;
+                assert_forwarded(&bytes, &fds, &received);
+                return Some(received);
+            }
+            WlMitmVerdict::Filtered => self.client.expect_nothing().await,
+            WlMitmVerdict::Rejected(_) => panic!("events can't be rejected"),
+            WlMitmVerdict::Answered => panic!("events are never answered"),
+            // The first replacement; any others are up to the caller
+            WlMitmVerdict::Replaced => return Some(self.client.recv().await),
+            WlMitmVerdict::Terminate => {
+                self.client.expect_closed().await;
+                self.server.expect_closed().await;
+            }
+        }
+
+        None
+    }
+
+    /// Script the start of every session: the client gets the registry, and the
+    /// compositor announces `globals` as (interface, version), named from 1 up.
+    /// Returns the names of the globals which made it to the client.
+    pub async fn setup_registry(&mut self, globals: &[(&str, u32)]) -> Vec<u32> {
+        self.assert_c2s(
+            WlDisplayGetRegistryRequest::new(WL_DISPLAY_OBJECT_ID, REGISTRY_ID).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+
+        for (i, (interface, version)) in globals.iter().enumerate() {
+            self.server
+                .send(
+                    WlRegistryGlobalEvent::new(REGISTRY_ID, i as u32 + 1, interface, *version)
+                        .build(),
+                )
+                .await;
+        }
+
+        let mut seen = Vec::new();
+        loop {
+            match self.client.try_recv(FILTERED_TIMEOUT).await {
+                Some(Some(msg)) => {
+                    let objects = registry_objects();
+                    let WaylandProtocolParsingOutcome::Ok(global) =
+                        WlRegistryGlobalEvent::try_from_msg(&objects, &msg)
+                    else {
+                        panic!("expected wl_registry.global");
+                    };
+                    seen.push(global.name);
+                }
+                Some(None) => panic!("connection closed during registry setup"),
+                None => return seen,
+            }
+        }
+    }
+
+    /// Close both peers and return how the proxy finished
+    pub async fn finish(self) -> io::Result<()> {
+        drop(self.client);
+        drop(self.server);
+        self.proxy.await.unwrap()
+    }
+}
+
+fn registry_objects() -> WlObjects {
+    let mut objects = WlObjects::new();
+    objects.record_object(wl_mitm::proto::WL_REGISTRY, REGISTRY_ID, 1);
+    objects
+}
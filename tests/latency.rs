@@ -0,0 +1,54 @@
+//! Percentiles of the time messages spend inside wl-mitm
+
+use std::time::Duration;
+
+use wl_mitm::latency::{WlLatencyHistogram, WlLatencySummary};
+
+#[test]
+fn empty_histogram_reports_zero() {
+    assert_eq!(
+        WlLatencyHistogram::default().summary(),
+        WlLatencySummary::default()
+    );
+}
+
+#[test]
+fn percentiles_within_an_eighth() {
+    let mut histogram = WlLatencyHistogram::default();
+    for us in 1..=1000 {
+        histogram.record(Duration::from_micros(us));
+    }
+
+    let summary = histogram.summary();
+    assert_eq!(summary.count, 1000);
+    assert_eq!(summary.max_us, 1000);
+    for (reported, exact) in [
+        (summary.p50_us, 500),
+        (summary.p90_us, 900),
+        (summary.p99_us, 990),
+    ] {
+        assert!(
+            reported >= exact && reported <= exact + exact / 8,
+            "{} reported as {}",
+            exact,
+            reported
+        );
+    }
+}
+
+#[test]
+fn short_latencies_are_exact() {
+    let mut histogram = WlLatencyHistogram::default();
+    for us in [3, 3, 3, 7] {
+        histogram.record(Duration::from_micros(us));
+    }
+    assert_eq!(histogram.percentile_us(0.5), 3);
+    assert_eq!(histogram.percentile_us(1.0), 7);
+}
+
+#[test]
+fn outliers_are_capped_at_the_maximum() {
+    let mut histogram = WlLatencyHistogram::default();
+    histogram.record(Duration::from_secs(60 * 60 * 24 * 365));
+    assert_eq!(histogram.percentile_us(0.5), histogram.summary().max_us);
+}
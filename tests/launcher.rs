@@ -0,0 +1,106 @@
+//! Sandbox command lines built by `wl-mitm exec`
+
+use std::path::Path;
+
+use wl_mitm::{
+    launcher::{self, WlSandboxLauncher},
+    socket::WlSocketAddr,
+};
+
+const SOCKET: &str = "/run/user/1000/wl-mitm-untrusted-1";
+
+fn command(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+fn upstream() -> WlSocketAddr {
+    WlSocketAddr::Path("/run/user/1000/wayland-1".into())
+}
+
+#[test]
+fn detects_sandboxes() {
+    assert_eq!(WlSandboxLauncher::detect("bwrap"), WlSandboxLauncher::Bwrap);
+    assert_eq!(
+        WlSandboxLauncher::detect("/usr/bin/firejail"),
+        WlSandboxLauncher::Firejail
+    );
+    assert_eq!(WlSandboxLauncher::detect("foot"), WlSandboxLauncher::Other);
+}
+
+#[test]
+fn binds_socket_into_bwrap() {
+    let (args, env) = launcher::sandbox_command(
+        &command(&[
+            "bwrap",
+            "--ro-bind",
+            "/",
+            "/",
+            "--unshare-all",
+            "--tmpfs",
+            "/run/user/1000",
+            "foot",
+            "--title",
+            "-x",
+        ]),
+        Path::new(SOCKET),
+        &upstream(),
+    );
+    assert!(env.is_empty());
+    // Ours follow the original options, so none of those can mount over them
+    assert_eq!(
+        args[..7],
+        [
+            "bwrap",
+            "--ro-bind",
+            "/",
+            "/",
+            "--unshare-all",
+            "--tmpfs",
+            "/run/user/1000"
+        ]
+    );
+    assert_eq!(args[7], "--die-with-parent");
+    assert_eq!(args[8..10], ["--bind", SOCKET]);
+    assert_eq!(args[11..13], ["--setenv", "WAYLAND_DISPLAY"]);
+    assert_eq!(args[10], args[13]);
+    assert_eq!(args[14..], ["foot", "--title", "-x"]);
+
+    // Up to `--`, if given
+    let (args, _) = launcher::sandbox_command(
+        &command(&["bwrap", "--dev", "/dev", "--", "foot"]),
+        Path::new(SOCKET),
+        &upstream(),
+    );
+    assert_eq!(args[..4], ["bwrap", "--dev", "/dev", "--die-with-parent"]);
+    assert_eq!(args[args.len() - 2..], ["--", "foot"]);
+}
+
+#[test]
+fn whitelists_socket_in_firejail() {
+    let (args, env) = launcher::sandbox_command(
+        &command(&["firejail", "--private", "foot", "-e"]),
+        Path::new(SOCKET),
+        &upstream(),
+    );
+    assert!(env.is_empty());
+    assert_eq!(
+        args,
+        [
+            "firejail".to_string(),
+            "--private".to_string(),
+            format!("--whitelist={}", SOCKET),
+            format!("--env=WAYLAND_DISPLAY={}", SOCKET),
+            "--blacklist=/run/user/1000/wayland-1".to_string(),
+            "foot".to_string(),
+            "-e".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn sets_display_for_other_commands() {
+    let (args, env) =
+        launcher::sandbox_command(&command(&["foot"]), Path::new(SOCKET), &upstream());
+    assert_eq!(args, ["foot"]);
+    assert_eq!(env, [("WAYLAND_DISPLAY".to_string(), SOCKET.to_string())]);
+}
@@ -0,0 +1,184 @@
+//! Learning and enforcing per-app profiles
+
+mod harness;
+
+use std::sync::Arc;
+
+use harness::{Harness, REGISTRY_ID, temp_path, test_config};
+use wl_mitm::{
+    config::Config,
+    flatpak::WlFlatpakInfo,
+    learning::{self, WlLearning},
+    peer::WlPeerInfo,
+    proto::{
+        WL_DISPLAY_OBJECT_ID, WlCompositorCreateSurfaceRequest, WlConstructableMessage,
+        WlDisplayGetRegistryRequest, WlRegistryBindRequest, WlSurfaceDamageRequest,
+        WlSurfaceSetBufferScaleRequest, XdgSurfaceGetToplevelRequest, XdgToplevelSetAppIdRequest,
+        XdgWmBaseGetXdgSurfaceRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const SURFACE_ID: u32 = 4;
+
+fn config(dir: &str, enforce: &[&str]) -> String {
+    test_config(&format!(
+        r#"
+[learning]
+dir = {:?}
+enforce = {:?}
+
+[filter]
+allowed_globals = ["wl_compositor", "xdg_wm_base"]
+requests = []
+"#,
+        dir, enforce
+    ))
+}
+
+/// A Flatpak app
+fn flatpak(app_id: &str) -> WlPeerInfo {
+    WlPeerInfo {
+        flatpak: Some(WlFlatpakInfo {
+            app_id: app_id.to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// A client connection identified as `peer`, learned into `learning`
+fn learned(config: &str, learning: &Arc<WlLearning>, peer: WlPeerInfo) -> Harness {
+    let learning = learning.clone();
+    Harness::with_setup(config, move |duplex| {
+        duplex.set_peer(peer);
+        duplex.set_learning(learning);
+    })
+}
+
+/// Create a toplevel with `app_id`, binding everything needed on the way
+async fn setup_toplevel(h: &mut Harness, app_id: &str) {
+    h.setup_registry(&[("wl_compositor", 6), ("xdg_wm_base", 6)])
+        .await;
+    for msg in [
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlCompositorCreateSurfaceRequest::new(3, SURFACE_ID).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "xdg_wm_base", 6, 5).build(),
+        XdgWmBaseGetXdgSurfaceRequest::new(5, 6, SURFACE_ID).build(),
+        XdgSurfaceGetToplevelRequest::new(6, 7).build(),
+        XdgToplevelSetAppIdRequest::new(7, app_id).build(),
+    ] {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+}
+
+#[tokio::test]
+async fn learns_then_enforces_app_profile() {
+    let dir = temp_path("learning");
+    let dir = dir.to_str().unwrap();
+
+    // Learn
+    let learn_config = Config::parse(&config(dir, &[])).unwrap();
+    let learning = WlLearning::new(&learn_config).unwrap();
+    let mut h = learned(&config(dir, &[]), &learning, flatpak("org.example.App"));
+    setup_toplevel(&mut h, "org.example.App").await;
+    h.assert_c2s(
+        WlSurfaceDamageRequest::new(SURFACE_ID, 0, 0, 10, 10).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+    learning.flush().await.unwrap();
+
+    let profile = learning.profile("org.example.App");
+    assert!(profile.has_global("wl_compositor"));
+    assert!(profile.has_global("xdg_wm_base"));
+    assert!(profile.has_request("wl_surface", "damage"));
+    assert!(profile.has_request("xdg_toplevel", "set_app_id"));
+    assert!(!profile.has_request("wl_surface", "set_buffer_scale"));
+    assert!(learning.profile_path("org.example.App").starts_with(dir));
+
+    // Enforce, with the profile loaded back from disk
+    let enforce_config = Config::parse(&config(dir, &["org.example.*"])).unwrap();
+    let learning = WlLearning::new(&enforce_config).unwrap();
+    let mut h = learned(
+        &config(dir, &["org.example.*"]),
+        &learning,
+        flatpak("org.example.App"),
+    );
+    setup_toplevel(&mut h, "org.example.App").await;
+    h.assert_c2s(
+        WlSurfaceDamageRequest::new(SURFACE_ID, 0, 0, 10, 10).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    // Enforced profiles don't grow
+    learning.flush().await.unwrap();
+    assert_eq!(learning.profile("org.example.App"), profile);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn apps_are_identified_by_the_peer() {
+    let dir = temp_path("learning-identity");
+    let dir = dir.to_str().unwrap();
+    let config = config(dir, &["org.example.*"]);
+    let learning = WlLearning::new(&Config::parse(&config).unwrap()).unwrap();
+
+    // An app_id to match `enforce` with doesn't get another app's profile
+    let foot = WlPeerInfo {
+        exe: Some("/usr/bin/foot".into()),
+        ..Default::default()
+    };
+    assert_eq!(learning::app_identity(&foot).unwrap(), "/usr/bin/foot");
+    let mut h = learned(&config, &learning, foot);
+    setup_toplevel(&mut h, "org.example.App").await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+    assert!(
+        learning
+            .profile("/usr/bin/foot")
+            .has_request("wl_surface", "set_buffer_scale")
+    );
+    assert_eq!(learning.profile("org.example.App"), Default::default());
+
+    // Flatpak apps are known by their app ID, wherever their executable is
+    let app = WlPeerInfo {
+        exe: Some("/app/bin/app".into()),
+        ..flatpak("org.example.App")
+    };
+    assert_eq!(learning::app_identity(&app).unwrap(), "org.example.App");
+
+    // Nobody can be taken for an enforced app, so nobody unknown is let through
+    let mut h = learned(&config, &learning, Default::default());
+    h.assert_c2s(
+        WlDisplayGetRegistryRequest::new(WL_DISPLAY_OBJECT_ID, REGISTRY_ID).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn profile_paths_stay_in_dir() {
+    let config = Config::parse(&config("/profiles", &[])).unwrap();
+    let learning = WlLearning::new(&config).unwrap();
+    assert_eq!(
+        learning.profile_path("../../etc/passwd"),
+        std::path::Path::new("/profiles/_._.._etc_passwd.json")
+    );
+}
@@ -0,0 +1,438 @@
+//! Following the logind session: stricter filtering while it is locked, and
+//! closing everything once it ends
+
+mod harness;
+
+use std::{os::fd::AsFd, path::PathBuf, sync::Arc, time::Duration};
+
+use harness::{Harness, REGISTRY_ID, temp_path, test_config};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+use wl_mitm::{
+    audit::WlAuditLog,
+    config::Config,
+    dbus::{self, WlDbusMessage, WlDbusValue},
+    logind::{self, WlSession},
+    proto::{
+        WlConstructableMessage, WlDataDeviceDataOfferEvent,
+        WlDataDeviceManagerGetDataDeviceRequest, WlDataOfferReceiveRequest, WlRegistryBindRequest,
+        ZwlrVirtualPointerManagerV1CreateVirtualPointerRequest, ZwlrVirtualPointerV1ButtonRequest,
+        ZwlrVirtualPointerV1FrameRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const MANAGER_ID: u32 = 3;
+const POINTER_ID: u32 = 4;
+const OFFER_ID: u32 = 0xff000000;
+
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+
+/// Following session "c1", with `session` added to its section and
+/// `section` after it
+fn config(session: &str, section: &str) -> String {
+    test_config(&format!(
+        r#"
+[session]
+id = "c1"
+poll_secs = 3600
+{}
+
+{}
+
+[filter]
+allowed_globals = ["zwlr_virtual_pointer_manager_v1", "wl_seat", "wl_data_device_manager"]
+requests = [
+    {{ interface = "zwlr_virtual_pointer_v1", requests = ["button"], action = "notify" }},
+]
+"#,
+        session, section
+    ))
+}
+
+fn lock_config() -> String {
+    config("", "[lock]\nenabled = true")
+}
+
+fn end_config() -> String {
+    config("terminate_on_end = true", "")
+}
+
+/// The system bus, as far as one client of it is concerned
+struct FakeBus {
+    path: PathBuf,
+    stream: UnixStream,
+    buf: Vec<u8>,
+    serial: u32,
+}
+
+impl FakeBus {
+    /// Have `session` watch a bus of our own, and accept its connection
+    async fn start(
+        name: &str,
+        session: Arc<WlSession>,
+    ) -> (FakeBus, JoinHandle<std::io::Result<()>>) {
+        let path = temp_path(name);
+        let listener = UnixListener::bind(&path).unwrap();
+        let watcher = tokio::spawn({
+            let path = path.to_str().unwrap().to_string();
+            async move { session.watch(&path).await }
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut bus = FakeBus {
+            path,
+            stream,
+            buf: Vec::new(),
+            serial: 0,
+        };
+        bus.read_until(b"\r\n").await;
+        assert!(bus.buf.starts_with(b"\0AUTH EXTERNAL "));
+        bus.stream
+            .write_all(b"OK 0123456789abcdef\r\n")
+            .await
+            .unwrap();
+        let begin = bus.read_until(b"BEGIN\r\n").await;
+        bus.buf.drain(..begin);
+        (bus, watcher)
+    }
+
+    /// Read until `buf` contains `needle`, returning the offset just past it
+    async fn read_until(&mut self, needle: &[u8]) -> usize {
+        loop {
+            if let Some(i) = self.buf.windows(needle.len()).position(|w| w == needle) {
+                return i + needle.len();
+            }
+            self.read().await;
+        }
+    }
+
+    async fn read(&mut self) {
+        let mut chunk = [0u8; 1024];
+        let n = self.stream.read(&mut chunk).await.unwrap();
+        assert_ne!(n, 0, "bus client hung up");
+        self.buf.extend_from_slice(&chunk[..n]);
+    }
+
+    async fn recv(&mut self) -> WlDbusMessage {
+        loop {
+            if let Some(msg) = dbus::split_message(&mut self.buf).unwrap() {
+                return dbus::parse_message(&msg).unwrap();
+            }
+            self.read().await;
+        }
+    }
+
+    /// The next call of `member`, skipping anything else
+    async fn expect_call(&mut self, member: &str) -> WlDbusMessage {
+        loop {
+            let msg = self.recv().await;
+            if msg.member.as_deref() == Some(member) {
+                return msg;
+            }
+        }
+    }
+
+    async fn send(&mut self, build: impl FnOnce(u32) -> Vec<u8>) {
+        self.serial += 1;
+        self.stream.write_all(&build(self.serial)).await.unwrap();
+    }
+}
+
+impl Drop for FakeBus {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+async fn wait_until(what: &str, cond: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !cond() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting until {}", what));
+}
+
+#[tokio::test]
+async fn denies_while_locked() {
+    let session = WlSession::new(&Config::parse(&lock_config()).unwrap(), None).unwrap();
+    let mut h = Harness::with_setup(&lock_config(), {
+        let session = session.clone();
+        move |duplex| duplex.set_session(session)
+    });
+    h.setup_registry(&[("zwlr_virtual_pointer_manager_v1", 2)])
+        .await;
+    for msg in [
+        WlRegistryBindRequest::new(
+            REGISTRY_ID,
+            1,
+            "zwlr_virtual_pointer_manager_v1",
+            2,
+            MANAGER_ID,
+        )
+        .build(),
+        ZwlrVirtualPointerManagerV1CreateVirtualPointerRequest::new(MANAGER_ID, 0, POINTER_ID)
+            .build(),
+        ZwlrVirtualPointerV1FrameRequest::new(POINTER_ID).build(),
+    ] {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+
+    // Both requests passed along unparsed and those a filter rule looks at
+    session.set_locked(true);
+    h.assert_c2s(
+        ZwlrVirtualPointerV1FrameRequest::new(POINTER_ID).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_c2s(
+        ZwlrVirtualPointerV1ButtonRequest::new(POINTER_ID, 0, 272, 1).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+
+    session.set_locked(false);
+    h.assert_c2s(
+        ZwlrVirtualPointerV1ButtonRequest::new(POINTER_ID, 0, 272, 1).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn audits_denials_while_locked() {
+    let (log, key) = (temp_path("lock-audit-log"), temp_path("lock-audit-key"));
+    std::fs::write(&key, b"key").unwrap();
+    let config = config(
+        "",
+        &format!(
+            "[lock]\nenabled = true\n\n[audit]\nfile = {:?}\nkey_file = {:?}",
+            log, key
+        ),
+    );
+    let session = WlSession::new(&Config::parse(&config).unwrap(), None).unwrap();
+    let audit = WlAuditLog::open(&Config::parse(&config).unwrap().audit)
+        .unwrap()
+        .unwrap();
+    let mut h = Harness::with_setup(&config, {
+        let session = session.clone();
+        move |duplex| {
+            duplex.set_session(session);
+            duplex.set_audit(audit);
+        }
+    });
+    h.setup_registry(&[("wl_seat", 9), ("wl_data_device_manager", 3)])
+        .await;
+    for msg in [
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_seat", 9, 3).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_data_device_manager", 3, 4).build(),
+        WlDataDeviceManagerGetDataDeviceRequest::new(4, 5, 3).build(),
+    ] {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+    h.assert_s2c(
+        WlDataDeviceDataOfferEvent::new(5, OFFER_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // Denied by default, without any filter rule having to parse it
+    session.set_locked(true);
+    let (read, _write) = nix::unistd::pipe().unwrap();
+    h.assert_c2s(
+        WlDataOfferReceiveRequest::new(OFFER_ID, "text/plain", read.as_fd()).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    let records = std::fs::read_to_string(&log).unwrap();
+    let records: Vec<serde_json::Value> = records
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["interface"], "wl_data_offer");
+    assert_eq!(records[0]["request"], "receive");
+    assert_eq!(records[0]["rule"], "lock.deny");
+    assert_eq!(records[0]["verdict"], "blocked");
+    assert_eq!(records[0]["provenance"][0]["interface"], "wl_data_offer");
+    std::fs::remove_file(&log).ok();
+    std::fs::remove_file(&key).ok();
+}
+
+#[tokio::test]
+async fn follows_lock_signals() {
+    let session = WlSession::new(&Config::parse(&lock_config()).unwrap(), None).unwrap();
+    let (mut bus, watcher) = FakeBus::start("bus-lock", session.clone()).await;
+    let path = logind::session_path("c1");
+    let add_match = bus.expect_call("AddMatch").await;
+    assert!(
+        add_match
+            .arg0
+            .unwrap()
+            .contains(&format!("path='{}'", path)),
+        "match rule doesn't select the session"
+    );
+
+    let signal = |path: &str, member: &'static str| {
+        let path = path.to_string();
+        move |serial| dbus::signal(serial, &path, SESSION_INTERFACE, member, &[])
+    };
+    bus.send(signal(&path, "Lock")).await;
+    wait_until("locked", || session.is_locked()).await;
+
+    // Other sessions are none of our business
+    bus.send(signal(&logind::session_path("c2"), "Unlock"))
+        .await;
+    bus.send(signal(&path, "PauseDevice")).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(session.is_locked());
+    bus.send(signal(&path, "Unlock")).await;
+    wait_until("unlocked", || !session.is_locked()).await;
+
+    drop(bus);
+    assert!(watcher.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn follows_locked_hint() {
+    let session = WlSession::new(&Config::parse(&lock_config()).unwrap(), None).unwrap();
+    let (mut bus, watcher) = FakeBus::start("bus-locked-hint", session.clone()).await;
+    let path = logind::session_path("c1");
+    bus.expect_call("AddMatch").await;
+    let add_match = bus.expect_call("AddMatch").await.arg0.unwrap();
+    assert!(add_match.contains("member='PropertiesChanged'"));
+    assert!(add_match.contains(&format!("arg0='{}'", SESSION_INTERFACE)));
+
+    // Locked before we started watching
+    let get = bus.expect_call("Get").await;
+    assert_eq!(get.path.as_deref(), Some(path.as_str()));
+    bus.send(|serial| dbus::method_return(serial, get.serial, &[WlDbusValue::VariantBool(true)]))
+        .await;
+    wait_until("locked", || session.is_locked()).await;
+
+    bus.send(|serial| {
+        dbus::signal(
+            serial,
+            &path,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            &[
+                WlDbusValue::Str(SESSION_INTERFACE),
+                WlDbusValue::BoolDict(&[("LockedHint", false)]),
+                WlDbusValue::StrArray(&[]),
+            ],
+        )
+    })
+    .await;
+    let get = bus.expect_call("Get").await;
+    bus.send(|serial| dbus::method_return(serial, get.serial, &[WlDbusValue::VariantBool(false)]))
+        .await;
+    wait_until("unlocked", || !session.is_locked()).await;
+
+    drop(bus);
+    assert!(watcher.await.unwrap().is_err());
+}
+
+#[test]
+fn booleans_are_decoded() {
+    let msg = dbus::method_return(2, 1, &[WlDbusValue::Bool(true)]);
+    assert_eq!(dbus::parse_message(&msg).unwrap().arg0_bool, Some(true));
+    let msg = dbus::method_return(2, 1, &[WlDbusValue::VariantBool(false)]);
+    let msg = dbus::parse_message(&msg).unwrap();
+    assert_eq!((msg.arg0, msg.arg0_bool), (None, Some(false)));
+}
+
+#[tokio::test]
+async fn ends_with_session_removed() {
+    let session = WlSession::new(&Config::parse(&end_config()).unwrap(), None).unwrap();
+    let (mut bus, watcher) = FakeBus::start("bus-removed", session.clone()).await;
+
+    let get = bus.expect_call("Get").await;
+    assert_eq!(get.path, Some(logind::session_path("c1")));
+    bus.send(|serial| dbus::method_return(serial, get.serial, &[WlDbusValue::Variant("active")]))
+        .await;
+
+    let removed = |id: &'static str| {
+        move |serial| {
+            dbus::signal(
+                serial,
+                "/org/freedesktop/login1",
+                MANAGER_INTERFACE,
+                "SessionRemoved",
+                &[
+                    WlDbusValue::Str(id),
+                    WlDbusValue::Path(&logind::session_path(id)),
+                ],
+            )
+        }
+    };
+    bus.send(removed("c2")).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!session.is_ended());
+
+    bus.send(removed("c1")).await;
+    watcher.await.unwrap().unwrap();
+    assert!(session.is_ended());
+}
+
+#[tokio::test]
+async fn ends_once_closing() {
+    let session = WlSession::new(&Config::parse(&end_config()).unwrap(), None).unwrap();
+    let (mut bus, watcher) = FakeBus::start("bus-closing", session.clone()).await;
+
+    let get = bus.expect_call("Get").await;
+    assert_eq!(get.arg0.as_deref(), Some(SESSION_INTERFACE));
+    bus.send(|serial| dbus::method_return(serial, get.serial, &[WlDbusValue::Variant("closing")]))
+        .await;
+    watcher.await.unwrap().unwrap();
+    assert!(session.is_ended());
+}
+
+#[tokio::test]
+async fn unknown_sessions_have_ended() {
+    let session = WlSession::new(&Config::parse(&end_config()).unwrap(), None).unwrap();
+    let (mut bus, watcher) = FakeBus::start("bus-unknown", session.clone()).await;
+
+    let get = bus.expect_call("Get").await;
+    bus.send(|serial| dbus::error(serial, get.serial, "org.freedesktop.login1.NoSuchSession"))
+        .await;
+    watcher.await.unwrap().unwrap();
+    assert!(session.is_ended());
+}
+
+#[tokio::test]
+async fn connections_close_when_session_ends() {
+    let session = WlSession::new(&Config::parse(&end_config()).unwrap(), None).unwrap();
+    let mut h = Harness::with_setup(&end_config(), {
+        let session = session.clone();
+        move |duplex| duplex.set_session(session)
+    });
+    h.setup_registry(&[("zwlr_virtual_pointer_manager_v1", 2)])
+        .await;
+
+    session.set_ended();
+    h.client.expect_closed().await;
+    h.server.expect_closed().await;
+    h.finish().await.unwrap();
+}
+
+#[test]
+fn session_ids_are_escaped() {
+    assert_eq!(
+        logind::session_path("c1"),
+        "/org/freedesktop/login1/session/c1"
+    );
+    assert_eq!(
+        logind::session_path("2"),
+        "/org/freedesktop/login1/session/_32"
+    );
+}
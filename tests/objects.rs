@@ -0,0 +1,210 @@
+//! Object tracking in [WlObjects], independently of any connection
+
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use wl_mitm::{
+    config::WlObjectsConfig,
+    objects::{WlObjectExtension, WlObjectHandle, WlObjects},
+    proto::{WL_COMPOSITOR, WL_KEYBOARD, WL_POINTER, WL_SEAT, WL_SURFACE},
+};
+
+const COMPOSITOR_ID: u32 = 2;
+
+/// Records the objects it was destroyed with
+struct Destroyed(Arc<Mutex<Vec<WlObjectHandle>>>);
+
+impl WlObjectExtension for Destroyed {
+    fn on_destroy(&mut self, obj: WlObjectHandle) {
+        self.0.lock().unwrap().push(obj);
+    }
+}
+
+struct Counter(Arc<AtomicUsize>);
+
+impl WlObjectExtension for Counter {
+    fn on_destroy(&mut self, _obj: WlObjectHandle) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn objects_with_surface(id: u32) -> WlObjects {
+    let mut objects = WlObjects::new();
+    objects.record_object(WL_COMPOSITOR, COMPOSITOR_ID, 6);
+    objects.record_child_object(WL_SURFACE, id, COMPOSITOR_ID, None);
+    objects
+}
+
+#[test]
+fn extensions_are_typed() {
+    let mut objects = objects_with_surface(3);
+    let count = Arc::new(AtomicUsize::new(0));
+
+    assert!(objects.put_extension(3, Counter(count.clone())).is_none());
+    assert!(objects.extension::<Counter>(3).is_some());
+    assert!(objects.extension::<Destroyed>(3).is_none());
+    assert_eq!(
+        objects
+            .iter_extensions::<Counter>()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>(),
+        vec![3]
+    );
+
+    // Replacing or taking an extension hands it back without running its hook
+    assert!(objects.put_extension(3, Counter(count.clone())).is_some());
+    assert!(objects.take_extension::<Counter>(3).is_some());
+    assert!(objects.extension::<Counter>(3).is_none());
+    assert_eq!(count.load(Ordering::Relaxed), 0);
+
+    // Extensions can't be attached to unknown objects
+    assert!(objects.put_extension(4, Counter(count.clone())).is_none());
+    assert!(objects.extension::<Counter>(4).is_none());
+}
+
+#[test]
+fn destroy_hooks_run_once() {
+    let mut objects = objects_with_surface(3);
+    let destroyed = Arc::new(Mutex::new(Vec::new()));
+    let handle = objects.handle(3).unwrap();
+    objects.put_extension(3, Destroyed(destroyed.clone()));
+
+    // Destroyed by the client: the hook runs right away, even though the
+    // object is only half-destroyed until the server ACKs it
+    objects.remove_object(3, true);
+    assert_eq!(*destroyed.lock().unwrap(), vec![handle]);
+    assert!(objects.extension::<Destroyed>(3).is_none());
+
+    objects.remove_object(3, false);
+    assert_eq!(destroyed.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn reused_ids_start_without_extensions() {
+    let mut objects = objects_with_surface(3);
+    let count = Arc::new(AtomicUsize::new(0));
+    let old = objects.handle(3).unwrap();
+    objects.put_extension(3, Counter(count.clone()));
+
+    // The server destroyed the object behind our back; its ID is reused
+    objects.record_child_object(WL_SURFACE, 3, COMPOSITOR_ID, None);
+    assert_eq!(count.load(Ordering::Relaxed), 1);
+    assert!(objects.extension::<Counter>(3).is_none());
+    assert!(!objects.is_current(old));
+    assert!(objects.handle_extension::<Counter>(old).is_none());
+}
+
+#[test]
+fn objects_remember_parents_and_versions() {
+    let mut objects = objects_with_surface(3);
+    assert_eq!(objects.lookup_parent(3), Some(COMPOSITOR_ID));
+    assert_eq!(objects.lookup_object_version(3), Some(6));
+    assert_eq!(objects.descendants(COMPOSITOR_ID), vec![3]);
+    assert!(objects.has_ancestor(3, "wl_compositor"));
+
+    let table: serde_json::Value = serde_json::from_str(&objects.to_json()).unwrap();
+    assert_eq!(table[2]["id"], 3);
+    assert_eq!(table[2]["interface"], "wl_surface");
+    assert_eq!(table[2]["version"], 6);
+    assert_eq!(table[2]["parent"], COMPOSITOR_ID);
+
+    // Children outlive their parent, as orphans which still know what
+    // they have been created through
+    objects.remove_object(COMPOSITOR_ID, false);
+    assert_eq!(objects.lookup_parent(3), None);
+    assert!(objects.lookup_object(3).is_some());
+    assert!(objects.has_ancestor(3, "wl_compositor"));
+}
+
+#[test]
+fn objects_are_indexed_by_type() {
+    let mut objects = objects_with_surface(3);
+    objects.record_child_object(WL_SURFACE, 4, COMPOSITOR_ID, None);
+    let surfaces = |objects: &WlObjects| {
+        let mut ids: Vec<_> = objects.objects_of_type(WL_SURFACE).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(surfaces(&objects), vec![3, 4]);
+    assert_eq!(
+        objects.objects_of_type(WL_COMPOSITOR).collect::<Vec<_>>(),
+        vec![COMPOSITOR_ID]
+    );
+
+    // Half-destroyed objects are gone already
+    objects.remove_object(3, true);
+    assert_eq!(surfaces(&objects), vec![4]);
+    objects.remove_object(3, false);
+    assert_eq!(surfaces(&objects), vec![4]);
+
+    // An ID reused for another type moves to that type
+    objects.remove_object(4, false);
+    objects.record_child_object(WL_SEAT, 4, COMPOSITOR_ID, None);
+    assert!(surfaces(&objects).is_empty());
+    assert_eq!(
+        objects.objects_of_type(WL_SEAT).collect::<Vec<_>>(),
+        vec![4]
+    );
+}
+
+#[test]
+fn extensions_are_bounded() {
+    let mut objects = WlObjects::with_config(&WlObjectsConfig {
+        max_extensions: 1,
+        ..Default::default()
+    });
+    objects.record_object(WL_COMPOSITOR, COMPOSITOR_ID, 6);
+    objects.record_child_object(WL_SURFACE, 3, COMPOSITOR_ID, None);
+    objects.record_child_object(WL_SURFACE, 4, COMPOSITOR_ID, None);
+    let count = Arc::new(AtomicUsize::new(0));
+
+    objects.put_extension(3, Counter(count.clone()));
+    objects.put_extension(4, Counter(count.clone()));
+    assert!(objects.extension::<Counter>(4).is_none());
+
+    // Replacing an extension doesn't take up more room
+    assert!(objects.put_extension(3, Counter(count.clone())).is_some());
+
+    // Destroying an object frees its room
+    objects.remove_object(3, false);
+    objects.put_extension(4, Counter(count.clone()));
+    assert!(objects.extension::<Counter>(4).is_some());
+
+    let stats = objects.stats();
+    assert_eq!(stats.extensions, 1);
+    assert_eq!(stats.extensions_refused, 1);
+}
+
+#[test]
+fn input_devices_belong_to_their_seat() {
+    let mut objects = WlObjects::new();
+    objects.record_object(WL_SEAT, 3, 9);
+    objects.record_object(WL_SEAT, 4, 9);
+    objects.record_child_object(WL_POINTER, 5, 3, None);
+    objects.record_child_object(WL_KEYBOARD, 6, 4, None);
+    objects.record_child_object(WL_POINTER, 7, 4, None);
+
+    assert_eq!(objects.seats(), vec![3, 4]);
+    assert_eq!(objects.seat_of(3), Some(3));
+    assert_eq!(objects.seat_of(5), Some(3));
+    assert_eq!(objects.seat_of(7), Some(4));
+    assert_eq!(objects.seat_of(COMPOSITOR_ID), None);
+
+    let mut devices: Vec<_> = objects.seat_devices(4).map(|(id, _)| id).collect();
+    devices.sort();
+    assert_eq!(devices, vec![6, 7]);
+
+    // Released devices are gone right away
+    objects.remove_object(7, true);
+    let devices: Vec<_> = objects
+        .seat_devices(4)
+        .map(|(id, t)| (id, t.interface()))
+        .collect();
+    assert_eq!(devices, vec![(6, WL_KEYBOARD.interface())]);
+    // Devices of a destroyed seat are orphaned
+    objects.remove_object(3, false);
+    assert_eq!(objects.seats(), vec![4]);
+    assert_eq!(objects.seat_of(5), None);
+}
@@ -0,0 +1,119 @@
+//! Frame-rate caps on screen capture
+
+mod harness;
+
+use std::time::{Duration, Instant};
+
+use harness::{Harness, REGISTRY_ID, assert_forwarded};
+use wl_mitm::{
+    config::Config,
+    pacing::WlCapturePacer,
+    proto::{
+        WlConstructableMessage, WlRegistryBindRequest, ZwlrScreencopyFrameV1CopyRequest,
+        ZwlrScreencopyFrameV1DestroyRequest, ZwlrScreencopyManagerV1CaptureOutputRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const CONFIG: &str = r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[capture]
+max_fps = 10
+
+[filter]
+allowed_globals = ["zwlr_screencopy_manager_v1"]
+requests = []
+"#;
+
+const MANAGER_ID: u32 = 3;
+const FRAME_ID: u32 = 4;
+
+fn pacer() -> WlCapturePacer {
+    WlCapturePacer::new(&Config::parse(CONFIG).unwrap().capture).unwrap()
+}
+
+#[test]
+fn schedules_one_frame_per_interval() {
+    let mut pacer = pacer();
+    let start = Instant::now();
+    let ms = |ms| start + Duration::from_millis(ms);
+
+    assert_eq!(
+        pacer.schedule("zwlr_screencopy_frame_v1", "copy", start),
+        None
+    );
+    assert_eq!(
+        pacer.schedule("ext_image_copy_capture_frame_v1", "capture", ms(30)),
+        Some(ms(100))
+    );
+    // Other requests are never held back by the pacer itself
+    assert_eq!(
+        pacer.schedule("zwlr_screencopy_frame_v1", "destroy", ms(30)),
+        None
+    );
+    assert_eq!(
+        pacer.schedule("zwlr_screencopy_frame_v1", "copy_with_damage", ms(150)),
+        Some(ms(200))
+    );
+    assert_eq!(
+        pacer.schedule("zwlr_screencopy_frame_v1", "copy", ms(500)),
+        None
+    );
+}
+
+#[test]
+fn uncapped_without_max_fps() {
+    let config = Config::parse(&CONFIG.replace("max_fps = 10", "")).unwrap();
+    assert!(WlCapturePacer::new(&config.capture).is_none());
+}
+
+#[test]
+fn rejects_tiny_caps() {
+    for max_fps in ["0", "-1", "1e-300", "nan", "inf"] {
+        let config = CONFIG.replace("max_fps = 10", &format!("max_fps = {}", max_fps));
+        let Err(err) = Config::parse(&config) else {
+            panic!("max_fps = {} accepted", max_fps);
+        };
+        assert!(err.to_string().contains("`max_fps` must be"));
+    }
+    let config = Config::parse(&CONFIG.replace("max_fps = 10", "max_fps = 0.01")).unwrap();
+    assert!(WlCapturePacer::new(&config.capture).is_some());
+}
+
+#[tokio::test]
+async fn holds_back_frames_over_the_cap() {
+    let mut h = Harness::new(CONFIG);
+    h.setup_registry(&[("zwlr_screencopy_manager_v1", 3)]).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "zwlr_screencopy_manager_v1", 3, MANAGER_ID)
+            .build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        ZwlrScreencopyManagerV1CaptureOutputRequest::new(MANAGER_ID, FRAME_ID, 0, 7).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        ZwlrScreencopyFrameV1CopyRequest::new(FRAME_ID, 8).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // The second frame waits for its slot, and what comes after it with it
+    let start = Instant::now();
+    let copy = ZwlrScreencopyFrameV1CopyRequest::new(FRAME_ID, 8).build();
+    let destroy = ZwlrScreencopyFrameV1DestroyRequest::new(FRAME_ID).build();
+    let sent = [copy.as_bytes().to_vec(), destroy.as_bytes().to_vec()];
+    h.client.send(copy).await;
+    h.client.send(destroy).await;
+    for bytes in &sent {
+        assert_forwarded(bytes, &[], &h.server.recv().await);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    h.finish().await.unwrap();
+}
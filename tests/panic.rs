@@ -0,0 +1,57 @@
+//! Panics are caught and reported along with the message being processed
+
+use std::io;
+
+use wl_mitm::{
+    panic::{WlConnPanic, WlPanickedMsg, catch_unwind},
+    proto::{WlConstructableMessage, WlSurfaceDamageRequest},
+    recorder::WlDirection,
+};
+
+#[tokio::test]
+async fn catch_unwind_passes_output_through() {
+    let res = catch_unwind(async {
+        tokio::task::yield_now().await;
+        42
+    })
+    .await;
+    assert_eq!(res.ok(), Some(42));
+}
+
+#[tokio::test]
+async fn catch_unwind_catches_panic_after_await() {
+    let res = catch_unwind(async {
+        tokio::task::yield_now().await;
+        panic!("bad message {}", 3);
+    })
+    .await;
+
+    let report = WlConnPanic::new(res.unwrap_err(), None);
+    assert_eq!(report.message, "bad message 3");
+}
+
+#[test]
+fn report_survives_io_error() {
+    let msg = WlSurfaceDamageRequest::new(3, 0, 0, 10, 10).build();
+    let report = WlConnPanic::new(
+        Box::new("oops"),
+        Some(WlPanickedMsg::new(
+            WlDirection::ClientToServer,
+            Some("wl_surface"),
+            &msg,
+        )),
+    );
+
+    let e: io::Error = report.into();
+    let report = WlConnPanic::from_io_error(&e).expect("not a panic report");
+    assert_eq!(report.message, "oops");
+
+    let panicked = report.msg.as_ref().unwrap();
+    assert_eq!(panicked.obj_id, 3);
+    assert_eq!(panicked.opcode, 2);
+    assert_eq!(panicked.interface.as_deref(), Some("wl_surface"));
+    assert_eq!(panicked.raw.len(), msg.as_bytes().len() * 2);
+    assert!(panicked.raw.starts_with("03000000"));
+
+    assert!(WlConnPanic::from_io_error(&io::Error::other("unrelated")).is_none());
+}
@@ -0,0 +1,96 @@
+//! `wl-mitm --generate-policy`
+
+mod harness;
+
+use harness::{Harness, REGISTRY_ID};
+use wl_mitm::{
+    config::Config,
+    policygen::{self, WlPolicyGenerator},
+    proto::{
+        self, WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        ZwlrScreencopyManagerV1CaptureOutputRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const CONFIG: &str = r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = []
+"#;
+
+#[tokio::test]
+async fn generates_policy_from_usage() {
+    let policy = WlPolicyGenerator::new("/nonexistent/policy.toml");
+    let mut h = Harness::with_setup(CONFIG, {
+        let policy = policy.clone();
+        move |duplex| duplex.set_policy_generator(policy)
+    });
+
+    // Globals outside of allowed_globals are announced too
+    let seen = h
+        .setup_registry(&[
+            ("wl_compositor", 6),
+            ("zwlr_screencopy_manager_v1", 3),
+            ("wl_shm", 2),
+        ])
+        .await;
+    assert_eq!(seen, [1, 2, 3]);
+
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "zwlr_screencopy_manager_v1", 3, 5).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        ZwlrScreencopyManagerV1CaptureOutputRequest::new(5, 6, 0, 7).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    let suggested = format!(
+        "[socket]\nlisten = \"wayland-proxied\"\n{}",
+        policy.generate()
+    );
+    let config = Config::parse(&suggested).expect("suggested policy doesn't parse");
+
+    let mut globals: Vec<_> = config.filter.allowed_globals.iter().cloned().collect();
+    globals.sort();
+    assert_eq!(globals, ["wl_compositor", "zwlr_screencopy_manager_v1"]);
+
+    let rules = &config.filter.requests["zwlr_screencopy_manager_v1"];
+    assert_eq!(rules.len(), 1);
+    assert!(rules[0].matches_request("capture_output"));
+    assert!(!config.filter.requests.contains_key("wl_compositor"));
+}
+
+#[test]
+fn sensitive_requests_are_known() {
+    for (interface, requests) in policygen::sensitive_requests() {
+        for request in requests {
+            assert!(
+                proto::known_requests().any(|(obj_type, _, parser)| {
+                    obj_type.interface() == interface && parser.msg_name() == *request
+                }),
+                "unknown request {}::{}",
+                interface,
+                request
+            );
+        }
+    }
+}
@@ -0,0 +1,816 @@
+//! End-to-end tests running a scripted client and compositor through the proxy
+
+mod harness;
+
+use std::{
+    fs::File,
+    os::{fd::AsFd, unix::fs::PermissionsExt},
+    path::PathBuf,
+    time::Duration,
+};
+
+use fixed::types::I24F8;
+use harness::{Harness, REGISTRY_ID, TEST_CONFIG, temp_path};
+use nix::sys::memfd::{MemFdCreateFlag, memfd_create};
+use wl_mitm::{
+    codec::WlRawMsg,
+    proto::{
+        WL_DISPLAY_OBJECT_ID, WlBufferDestroyRequest, WlCallbackDoneEvent,
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlDisplayDeleteIdEvent,
+        WlDisplayGetRegistryRequest, WlDisplaySyncRequest, WlKeyboardEnterEvent,
+        WlKeyboardKeymapEvent, WlKeyboardLeaveEvent, WlParsedMessage, WlPointerEnterEvent,
+        WlPointerSetCursorRequest, WlRegistryBindRequest, WlRegistryGlobalEvent,
+        WlRegistryGlobalRemoveEvent, WlSeatGetKeyboardRequest, WlSeatGetPointerRequest,
+        WlSeatNameEvent, WlShmCreatePoolRequest, WlShmPoolCreateBufferRequest,
+        WlShmPoolDestroyRequest, WlSurfaceCommitRequest, WlSurfaceDamageRequest,
+        WlSurfaceDestroyRequest, WlSurfaceSetBufferScaleRequest,
+        WlSurfaceSetBufferTransformRequest, WlWireArg, XdgSurfaceGetToplevelRequest,
+        XdgToplevelSetMinimizedRequest, XdgToplevelSetTitleRequest, XdgWmBaseGetXdgSurfaceRequest,
+        is_well_formed,
+    },
+    state::WlMitmVerdict,
+};
+
+const COMPOSITOR_ID: u32 = 3;
+const SURFACE_ID: u32 = 4;
+
+const GLOBALS: &[(&str, u32)] = &[
+    ("wl_compositor", 6),
+    ("wl_shm", 2),
+    ("wl_seat", 9),
+    ("zwlr_screencopy_manager_v1", 3),
+];
+
+fn memfd(size: u64) -> File {
+    let file = File::from(memfd_create(c"wl-mitm-test", MemFdCreateFlag::MFD_CLOEXEC).unwrap());
+    file.set_len(size).unwrap();
+    file
+}
+
+/// Write an `ask_cmd` approving everything, which appends `line` (expanded by
+/// the shell) to a file. Returns the paths of the script and that file.
+fn recording_ask_cmd(name: &str, line: &str) -> (PathBuf, PathBuf) {
+    let dir = temp_path(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (script, out) = (dir.join("ask.sh"), dir.join("out"));
+    std::fs::write(
+        &script,
+        format!("#!/bin/sh\necho \"{}\" >> {}\n", line, out.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    (script, out)
+}
+
+/// Bind wl_compositor and create a surface on it
+async fn setup_surface(h: &mut Harness) {
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, COMPOSITOR_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn registry_filters_disallowed_globals() {
+    let mut h = Harness::new(TEST_CONFIG);
+    let seen = h.setup_registry(GLOBALS).await;
+    assert_eq!(seen, vec![1, 2, 3]);
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn bind_allowed_global() {
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 3, "wl_seat", 9, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn bind_filtered_global_terminates() {
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 4, "zwlr_screencopy_manager_v1", 3, 3).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn bind_unknown_global_terminates() {
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 42, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn bind_mismatched_interface_terminates() {
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+    // Name 1 is wl_compositor
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_seat", 9, 3).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn globals_are_tracked_per_registry() {
+    const SECOND_REGISTRY_ID: u32 = 3;
+
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlDisplayGetRegistryRequest::new(WL_DISPLAY_OBJECT_ID, SECOND_REGISTRY_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_s2c(
+        WlRegistryGlobalEvent::new(SECOND_REGISTRY_ID, 1, "wl_compositor", 6).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // Removing the global from one registry leaves the other one alone
+    h.assert_s2c(
+        WlRegistryGlobalRemoveEvent::new(SECOND_REGISTRY_ID, 1).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // Filtered globals stay filtered on every registry
+    h.assert_s2c(
+        WlRegistryGlobalEvent::new(SECOND_REGISTRY_ID, 4, "zwlr_screencopy_manager_v1", 3).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(SECOND_REGISTRY_ID, 4, "zwlr_screencopy_manager_v1", 3, 5)
+            .build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn least_recently_used_registries_are_forgotten() {
+    const SECOND_REGISTRY_ID: u32 = 3;
+
+    let mut h = Harness::new(&format!("{}\n[objects]\nmax_registries = 1\n", TEST_CONFIG));
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlDisplayGetRegistryRequest::new(WL_DISPLAY_OBJECT_ID, SECOND_REGISTRY_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    for (name, (interface, version)) in GLOBALS.iter().enumerate() {
+        h.server
+            .send(
+                WlRegistryGlobalEvent::new(
+                    SECOND_REGISTRY_ID,
+                    name as u32 + 1,
+                    interface,
+                    *version,
+                )
+                .build(),
+            )
+            .await;
+    }
+    for _ in 0..3 {
+        h.client.recv().await;
+    }
+    h.client.expect_nothing().await;
+
+    // The first registry is forgotten, but still works through the second one
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_s2c(
+        WlRegistryGlobalEvent::new(REGISTRY_ID, 5, "zwlr_screencopy_manager_v1", 3).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_s2c(
+        WlRegistryGlobalRemoveEvent::new(REGISTRY_ID, 5).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_s2c(
+        WlRegistryGlobalRemoveEvent::new(REGISTRY_ID, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 4, "zwlr_screencopy_manager_v1", 3, 5).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn global_names_follow_latest_announcement() {
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+
+    // The client never saw zwlr_screencopy_manager_v1 come, nor will it see it go
+    h.assert_s2c(
+        WlRegistryGlobalRemoveEvent::new(REGISTRY_ID, 4).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_s2c(
+        WlRegistryGlobalRemoveEvent::new(REGISTRY_ID, 1).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // A buggy server reusing the name of wl_shm for a filtered global
+    h.assert_s2c(
+        WlRegistryGlobalEvent::new(REGISTRY_ID, 2, "zwlr_screencopy_manager_v1", 3).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_shm", 2, 3).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn request_on_destroyed_object_terminates() {
+    let mut h = Harness::new(TEST_CONFIG);
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlSurfaceDestroyRequest::new(SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceCommitRequest::new(SURFACE_ID).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn delete_id_allows_reuse() {
+    let mut h = Harness::new(TEST_CONFIG);
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlSurfaceDestroyRequest::new(SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_s2c(
+        WlDisplayDeleteIdEvent::new(WL_DISPLAY_OBJECT_ID, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceCommitRequest::new(SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn unacked_destruction_is_forgotten_beyond_limit() {
+    let mut h = Harness::new(&format!(
+        "{}\n[objects]\nmax_half_destroyed = 1\n",
+        TEST_CONFIG
+    ));
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, 5).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // The server never ACKs the first destruction; the second one pushes it out
+    for id in [SURFACE_ID, 5] {
+        h.assert_c2s(
+            WlSurfaceDestroyRequest::new(id).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+    }
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, 5).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn too_many_objects_terminate() {
+    // wl_display, wl_registry, wl_compositor and the first wl_surface
+    let mut h = Harness::new(&format!("{}\n[objects]\nmax_objects = 5\n", TEST_CONFIG));
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, 5).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, 6).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn too_many_globals_are_filtered() {
+    let mut h = Harness::new(&format!("{}\n[objects]\nmax_globals = 2\n", TEST_CONFIG));
+    let seen = h.setup_registry(GLOBALS).await;
+    assert_eq!(seen, vec![1, 2]);
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn request_rules_block_and_reject() {
+    let mut h = Harness::new(TEST_CONFIG);
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceSetBufferTransformRequest::new(SURFACE_ID, 1).build(),
+        WlMitmVerdict::Rejected(7),
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn only_interesting_requests_are_parsed() {
+    let mut h = Harness::new(TEST_CONFIG);
+    setup_surface(&mut h).await;
+    // Nothing looks into wl_surface::damage, so it is passed along as it is
+    h.assert_c2s(
+        WlSurfaceDamageRequest::new(SURFACE_ID, 0, 0, 64, 64).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    // ... as long as its arguments fit its signature
+    h.assert_c2s(
+        WlRawMsg::build(SURFACE_ID, WlSurfaceDamageRequest::opcode(), |buf, _| {
+            buf.extend_from_slice(&[0; 5 * 4])
+        }),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[test]
+fn unparsed_messages_are_checked_against_their_signature() {
+    use WlWireArg::*;
+    let words = |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|w| w.to_ne_bytes()).collect() };
+
+    assert!(is_well_formed(&[Word, Fd, Word], &words(&[1, 2])));
+    assert!(!is_well_formed(&[Word, Word], &words(&[1])));
+    assert!(!is_well_formed(&[Word], &words(&[1, 2])));
+
+    // "ab" takes a word with its NUL, an empty array none
+    let string = words(&[3, u32::from_ne_bytes(*b"ab\0\0")]);
+    assert!(is_well_formed(&[String], &string));
+    assert!(is_well_formed(&[Array, Word], &words(&[0, 7])));
+    assert!(!is_well_formed(
+        &[String],
+        &words(&[3, u32::from_ne_bytes(*b"abc\0")])
+    ));
+    assert!(!is_well_formed(&[Array], &words(&[u32::MAX, 0])));
+}
+
+#[tokio::test]
+async fn request_rules_limited_to_versions() {
+    // wl_compositor is bound with version 6 in setup_surface, and the surface
+    // inherits it
+    let mut h = Harness::new(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    { interface = "wl_surface", requests = ["set_buffer_scale"], action = "block", max_version = 5 },
+    { interface = "wl_surface", requests = ["set_buffer_transform"], action = "block", min_version = 6 },
+]
+"#,
+    );
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceSetBufferTransformRequest::new(SURFACE_ID, 1).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn request_rules_cascade_to_descendants() {
+    let mut h = Harness::new(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[filter]
+allowed_globals = ["wl_compositor", "wl_shm"]
+requests = [
+    { interface = "*", requests = ["*"], action = "block", descendant_of = "wl_shm" },
+]
+"#,
+    );
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_shm", 2, 5).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // Requests on wl_shm itself and unrelated objects pass, everything
+    // created through it doesn't
+    let pool = memfd(4096);
+    h.assert_c2s(
+        WlShmCreatePoolRequest::new(5, 6, pool.as_fd(), 4096).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlShmPoolCreateBufferRequest::new(6, 7, 0, 16, 16, 64, 0).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceCommitRequest::new(SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn request_rules_cascade_past_destroyed_ancestors() {
+    let mut h = Harness::new(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[filter]
+allowed_globals = ["wl_compositor", "wl_shm"]
+requests = [
+    { interface = "wl_buffer", requests = ["*"], action = "block", descendant_of = "wl_shm_pool" },
+]
+"#,
+    );
+    setup_surface(&mut h).await;
+    let pool = memfd(4096);
+    for msg in [
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_shm", 2, 5).build(),
+        WlShmCreatePoolRequest::new(5, 6, pool.as_fd(), 4096).build(),
+        WlShmPoolCreateBufferRequest::new(6, 7, 0, 16, 16, 64, 0).build(),
+        WlShmPoolDestroyRequest::new(6).build(),
+    ] {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+    h.assert_s2c(
+        WlDisplayDeleteIdEvent::new(WL_DISPLAY_OBJECT_ID, 6).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // The pool is gone, but the buffer was still created through it
+    h.assert_c2s(
+        WlBufferDestroyRequest::new(7).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn ask_cmd_gets_provenance() {
+    let (script, out) = recording_ask_cmd("provenance", "$WL_MITM_PROVENANCE_JSON");
+    let mut h = Harness::new(&format!(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[exec]
+ask_cmd = "{}"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    {{ interface = "wl_compositor", requests = ["create_surface"], action = "ask", desc = "new surface" }},
+    {{ interface = "wl_surface", requests = ["set_buffer_scale"], action = "ask" }},
+]
+"#,
+        script.display()
+    ));
+    setup_surface(&mut h).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    let content = std::fs::read_to_string(&out).unwrap();
+    let asked: Vec<serde_json::Value> = content
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(asked.len(), 2);
+
+    // The second prompt shows the surface was approved in the first one
+    let chain: Vec<_> = asked[1]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| {
+            (
+                p["id"].as_u64().unwrap(),
+                p["created_by"].as_str(),
+                p["verdict"].as_str(),
+                p["rule"].as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        chain,
+        vec![
+            (
+                SURFACE_ID as u64,
+                Some("wl_compositor::create_surface"),
+                Some("approved"),
+                Some("new surface")
+            ),
+            (
+                COMPOSITOR_ID as u64,
+                Some("wl_registry::bind"),
+                Some("allowed"),
+                None
+            ),
+            (
+                REGISTRY_ID as u64,
+                Some("wl_display::get_registry"),
+                Some("allowed"),
+                None
+            ),
+            (WL_DISPLAY_OBJECT_ID as u64, None, None, None),
+        ]
+    );
+    assert!(asked[1][0]["created_at"].as_u64().unwrap() > 0);
+
+    std::fs::remove_dir_all(script.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn prompts_are_attributed_by_focus() {
+    let (script, out) = recording_ask_cmd(
+        "focus",
+        "$WL_MITM_FOCUS $WL_MITM_FOCUS_SEAT $WL_MITM_LAST_TOPLEVEL_TITLE",
+    );
+    let mut h = Harness::new(&format!(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[exec]
+ask_cmd = "{}"
+
+[filter]
+allowed_globals = ["wl_compositor", "wl_seat", "xdg_wm_base"]
+requests = [
+    {{ interface = "xdg_toplevel", requests = ["set_minimized"], action = "ask" }},
+    {{ interface = "wl_pointer", requests = ["set_cursor"], action = "ask" }},
+]
+"#,
+        script.display()
+    ));
+    h.setup_registry(&[("wl_compositor", 6), ("wl_seat", 9), ("xdg_wm_base", 6)])
+        .await;
+
+    let mut c2s = vec![
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_seat", 9, 4).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 3, "xdg_wm_base", 6, 5).build(),
+        WlSeatGetKeyboardRequest::new(4, 6).build(),
+        WlSeatGetPointerRequest::new(4, 7).build(),
+    ];
+    // Two windows: wl_surface 8 and 11, xdg_surface 9 and 12, xdg_toplevel 10 and 13
+    for (base, title) in [(8, "one"), (11, "two")] {
+        c2s.extend([
+            WlCompositorCreateSurfaceRequest::new(3, base).build(),
+            XdgWmBaseGetXdgSurfaceRequest::new(5, base + 1, base).build(),
+            XdgSurfaceGetToplevelRequest::new(base + 1, base + 2).build(),
+            XdgToplevelSetTitleRequest::new(base + 2, title).build(),
+        ]);
+    }
+    for msg in c2s {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+
+    let s2c = [
+        WlSeatNameEvent::new(4, "seat0").build(),
+        WlKeyboardEnterEvent::new(6, 1, 8, &[]).build(),
+        WlPointerEnterEvent::new(7, 2, 11, I24F8::ZERO, I24F8::ZERO).build(),
+    ];
+    for msg in s2c {
+        h.assert_s2c(msg, WlMitmVerdict::Allowed).await;
+    }
+
+    // The keyboard is in window one, the pointer in window two
+    h.assert_c2s(
+        XdgToplevelSetMinimizedRequest::new(13).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlPointerSetCursorRequest::new(7, 2, 0, 0, 0).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // Once the keyboard has left, the pointer is a better hint
+    h.assert_s2c(
+        WlKeyboardLeaveEvent::new(6, 3, 8).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        XdgToplevelSetMinimizedRequest::new(10).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    let content = std::fs::read_to_string(&out).unwrap();
+    assert_eq!(
+        content.lines().collect::<Vec<_>>(),
+        vec![
+            "keyboard seat0 one",
+            "pointer seat0 two",
+            "pointer seat0 two"
+        ]
+    );
+
+    std::fs::remove_dir_all(script.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn round_trips_ride_along_with_client_syncs() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut h = Harness::with_setup(TEST_CONFIG, move |duplex| {
+        tx.send(duplex.sync_upstream(Duration::from_secs(10)))
+            .unwrap();
+    });
+
+    // Nothing is made up for the compositor; the client's next sync is
+    // waited for instead
+    h.server.expect_nothing().await;
+    let mut done = rx.try_recv().unwrap();
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlDisplaySyncRequest::new(WL_DISPLAY_OBJECT_ID, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    assert!(done.try_recv().is_err());
+
+    // Its callback reaches the client as usual
+    h.assert_s2c(
+        WlCallbackDoneEvent::new(3, 0).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    done.await.unwrap();
+    h.assert_s2c(
+        WlDisplayDeleteIdEvent::new(WL_DISPLAY_OBJECT_ID, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn round_trips_give_up_on_idle_clients() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut h = Harness::with_setup(TEST_CONFIG, move |duplex| {
+        tx.send(duplex.sync_upstream(Duration::from_millis(100)))
+            .unwrap();
+    });
+    h.setup_registry(GLOBALS).await;
+
+    let done = rx.try_recv().unwrap();
+    assert!(done.await.is_err());
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn passes_fds_to_compositor() {
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_shm", 2, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    let pool = memfd(4096);
+    let received = h
+        .assert_c2s(
+            WlShmCreatePoolRequest::new(3, 4, pool.as_fd(), 4096).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await
+        .unwrap();
+    assert_eq!(received.fds.len(), 1);
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn passes_fds_to_client() {
+    let mut h = Harness::new(TEST_CONFIG);
+    h.setup_registry(GLOBALS).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 3, "wl_seat", 9, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSeatGetKeyboardRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    let keymap = memfd(1024);
+    let received = h
+        .assert_s2c(
+            WlKeyboardKeymapEvent::new(4, 1, keymap.as_fd(), 1024).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await
+        .unwrap();
+    assert_eq!(received.fds.len(), 1);
+    h.finish().await.unwrap();
+}
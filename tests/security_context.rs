@@ -0,0 +1,92 @@
+//! Filter rules limited to clients with some LSM security context
+
+mod harness;
+
+use harness::{Harness, REGISTRY_ID};
+use wl_mitm::{
+    config::Config,
+    explain::{self, WlExplainQuery, WlRuleMatch},
+    peer::WlPeerInfo,
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceSetBufferScaleRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const CONFIG: &str = r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    { interface = "wl_surface", requests = ["set_buffer_scale"], action = "block", security_context = "*:untrusted_t:*" },
+]
+"#;
+
+fn peer(security_context: Option<&str>) -> WlPeerInfo {
+    WlPeerInfo {
+        security_context: security_context.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+async fn set_buffer_scale(peer: WlPeerInfo, verdict: WlMitmVerdict) {
+    let mut h = Harness::with_setup(CONFIG, move |duplex| duplex.set_peer(peer));
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(WlSurfaceSetBufferScaleRequest::new(4, 2).build(), verdict)
+        .await;
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn filter_rules_match_security_context() {
+    set_buffer_scale(
+        peer(Some("user_u:user_r:untrusted_t:s0")),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    set_buffer_scale(
+        peer(Some("user_u:user_r:user_t:s0")),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    set_buffer_scale(peer(None), WlMitmVerdict::Allowed).await;
+}
+
+#[test]
+fn explain_checks_security_context() {
+    let config = Config::parse(CONFIG).unwrap();
+    let result = |security_context: Option<&str>| {
+        let query = WlExplainQuery {
+            interface: "wl_surface".to_string(),
+            request: "set_buffer_scale".to_string(),
+            security_context: security_context.map(str::to_string),
+            ..Default::default()
+        };
+        explain::explain(&config, &query, |_, _| true)
+            .unwrap()
+            .rules[0]
+            .result
+    };
+
+    assert_eq!(
+        result(Some("user_u:user_r:untrusted_t:s0")),
+        WlRuleMatch::Matches
+    );
+    assert_eq!(result(Some("unconfined")), WlRuleMatch::Skipped);
+    assert_eq!(result(Some("")), WlRuleMatch::Skipped);
+    assert_eq!(result(None), WlRuleMatch::MayMatch);
+}
@@ -0,0 +1,124 @@
+//! Usage statistics exported per connection
+
+mod harness;
+
+use std::path::Path;
+
+use harness::{Harness, REGISTRY_ID, TEST_CONFIG, poll_until, temp_path};
+use serde_json::Value;
+use wl_mitm::{
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceSetBufferScaleRequest, WlSurfaceSetBufferTransformRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const SURFACE_ID: u32 = 4;
+
+fn config(dir: &Path, format: &str) -> String {
+    format!(
+        "{}\n[stats]\ndir = {:?}\nformat = {:?}\n",
+        TEST_CONFIG,
+        dir.to_str().unwrap(),
+        format
+    )
+}
+
+/// The content of the only statistics file in `dir`, once there is one
+async fn read_stats(dir: &Path) -> String {
+    poll_until(&format!("statistics in {}", dir.display()), || {
+        let files: Vec<_> = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e != "tmp"))
+            .collect();
+        match &files[..] {
+            [file] => Some(std::fs::read_to_string(file).unwrap()),
+            _ => None,
+        }
+    })
+    .await
+}
+
+async fn exercise(h: &mut Harness) {
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    for _ in 0..2 {
+        h.assert_c2s(
+            WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+            WlMitmVerdict::Filtered,
+        )
+        .await;
+    }
+    h.assert_c2s(
+        WlSurfaceSetBufferTransformRequest::new(SURFACE_ID, 1).build(),
+        WlMitmVerdict::Rejected(7),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn json_written_on_close() {
+    let dir = temp_path("stats-json");
+    let mut h = Harness::new(&config(&dir, "json"));
+    exercise(&mut h).await;
+    h.finish().await.unwrap();
+
+    let stats: Value = serde_json::from_str(&read_stats(&dir).await).unwrap();
+    let row = |interface: &str, message: &str| {
+        stats["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|r| r["interface"] == interface && r["message"] == message)
+            .unwrap_or_else(|| panic!("no row for {}::{}", interface, message))
+            .clone()
+    };
+
+    let scale = row("wl_surface", "set_buffer_scale");
+    assert_eq!(scale["direction"], "c2s");
+    assert_eq!(scale["count"], 2);
+    assert_eq!(scale["filtered"], 2);
+    assert_eq!(row("wl_surface", "set_buffer_transform")["rejected"], 1);
+    assert_eq!(row("wl_registry", "global")["direction"], "s2c");
+    assert_eq!(row("wl_registry", "global")["allowed"], 1);
+
+    // Only what was forwarded: get_registry, bind and create_surface
+    assert_eq!(stats["latency"]["c2s"]["count"], 3);
+    assert_eq!(stats["latency"]["s2c"]["count"], 1);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn csv_written_on_dump() {
+    let dir = temp_path("stats-csv");
+    let mut h = Harness::new(&config(&dir, "csv"));
+    exercise(&mut h).await;
+    h.dumper.trigger();
+
+    let csv = read_stats(&dir).await;
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "conn_id,app_id,direction,interface,message,count,allowed,filtered,rejected,answered,replaced,terminated"
+    );
+    assert!(
+        lines.any(|l| l.ends_with(",c2s,wl_surface,set_buffer_scale,2,0,2,0,0,0,0")),
+        "{}",
+        csv
+    );
+    h.finish().await.unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+}
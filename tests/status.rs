@@ -0,0 +1,243 @@
+//! The wl_mitm_v1 global clients use to query their sandbox status
+
+mod harness;
+
+use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+
+use harness::{Harness, REGISTRY_ID, temp_path, test_config};
+use wl_mitm::{
+    codec::WlRawMsg,
+    objects::WlObjects,
+    proto::{
+        WL_DISPLAY, WL_DISPLAY_OBJECT_ID, WL_MITM_V1, WaylandProtocolParsingOutcome,
+        WlCallbackDoneEvent, WlCompositorCreateSurfaceRequest, WlConstructableMessage,
+        WlDisplayDeleteIdEvent, WlDisplaySyncRequest, WlMitmV1DestroyRequest, WlMitmV1DoneEvent,
+        WlMitmV1GetStatusRequest, WlMitmV1HiddenGlobalEvent, WlMitmV1PermissionEvent,
+        WlMitmV1RequestPermissionRequest, WlMitmV1RestrictedEvent, WlParsedMessage,
+        WlRegistryBindRequest, WlSurfaceSetBufferScaleRequest,
+    },
+    state::{STATUS_GLOBAL_NAME, WlMitmVerdict, WlPermission},
+};
+
+const COMPOSITOR_ID: u32 = 3;
+const SURFACE_ID: u32 = 4;
+const STATUS_ID: u32 = 5;
+
+const GLOBALS: &[(&str, u32)] = &[("wl_compositor", 6), ("wl_shm", 1)];
+
+/// An `ask_cmd` which only approves permission requests made through
+/// wl_mitm_v1, so that asking at the time of the request is told apart
+fn permission_ask_cmd(name: &str) -> PathBuf {
+    let dir = temp_path(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("ask.sh");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\n[ -n \"$WL_MITM_PERMISSION_REQUEST\" ]\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    script
+}
+
+fn config(ask_cmd: &str, enabled: bool) -> String {
+    test_config(&format!(
+        r#"
+[exec]
+ask_cmd = {:?}
+
+[status]
+enabled = {}
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    {{ interface = "wl_surface", requests = ["set_buffer_scale"], action = "ask", desc = "set a buffer scale" }},
+    {{ interface = "wl_surface", requests = ["set_buffer_transform"], action = "block" }},
+]
+"#,
+        ask_cmd, enabled
+    ))
+}
+
+fn status_objects() -> WlObjects {
+    let mut objects = WlObjects::new();
+    objects.record_object(WL_DISPLAY, WL_DISPLAY_OBJECT_ID, 1);
+    objects.record_object(WL_MITM_V1, STATUS_ID, 1);
+    objects
+}
+
+fn parse<'a, T: WlParsedMessage<'a> + 'a>(objects: &WlObjects, msg: &'a WlRawMsg) -> T {
+    let WaylandProtocolParsingOutcome::Ok(msg) = T::try_from_msg(objects, msg) else {
+        panic!("unexpected message from wl-mitm");
+    };
+    msg
+}
+
+/// Bind wl_compositor and wl_mitm_v1, and create a surface
+async fn setup(h: &mut Harness) {
+    assert_eq!(h.setup_registry(GLOBALS).await, [STATUS_GLOBAL_NAME, 1]);
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, COMPOSITOR_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    bind_status(h, STATUS_ID).await;
+
+    // The compositor's side of the placeholder never reaches the client
+    h.assert_s2c(
+        WlCallbackDoneEvent::new(STATUS_ID, 0).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_s2c(
+        WlDisplayDeleteIdEvent::new(WL_DISPLAY_OBJECT_ID, STATUS_ID).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+}
+
+/// Bind wl_mitm_v1, which the compositor only sees as a placeholder taking up
+/// the same ID
+async fn bind_status(h: &mut Harness, id: u32) {
+    let placeholder = h
+        .assert_c2s(
+            WlRegistryBindRequest::new(REGISTRY_ID, STATUS_GLOBAL_NAME, "wl_mitm_v1", 1, id)
+                .build(),
+            WlMitmVerdict::Replaced,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        placeholder.as_bytes(),
+        WlDisplaySyncRequest::new(WL_DISPLAY_OBJECT_ID, id)
+            .build()
+            .as_bytes()
+    );
+}
+
+#[tokio::test]
+async fn status_reports_hidden_globals_and_rules() {
+    let mut h = Harness::new(&config("/bin/false", true));
+    setup(&mut h).await;
+    h.assert_c2s(
+        WlMitmV1GetStatusRequest::new(STATUS_ID).build(),
+        WlMitmVerdict::Answered,
+    )
+    .await;
+
+    let objects = status_objects();
+    let msg = h.client.recv().await;
+    assert_eq!(
+        parse::<WlMitmV1HiddenGlobalEvent>(&objects, &msg).interface,
+        "wl_shm"
+    );
+    let mut restricted = Vec::new();
+    for _ in 0..2 {
+        let msg = h.client.recv().await;
+        let event = parse::<WlMitmV1RestrictedEvent>(&objects, &msg);
+        restricted.push((event.request.to_string(), event.action));
+    }
+    assert_eq!(
+        restricted,
+        [
+            ("set_buffer_scale".to_string(), 1),
+            ("set_buffer_transform".to_string(), 0)
+        ]
+    );
+    let msg = h.client.recv().await;
+    assert_eq!(parse::<WlMitmV1DoneEvent>(&objects, &msg).dry_run, 0);
+
+    h.assert_c2s(
+        WlMitmV1DestroyRequest::new(STATUS_ID).build(),
+        WlMitmVerdict::Answered,
+    )
+    .await;
+    let msg = h.client.recv().await;
+    assert_eq!(
+        parse::<WlDisplayDeleteIdEvent>(&objects, &msg).id,
+        STATUS_ID
+    );
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn granted_permissions_skip_asking() {
+    let script = permission_ask_cmd("status-grant");
+    let mut h = Harness::new(&config(script.to_str().unwrap(), true));
+    setup(&mut h).await;
+
+    // Asking at the time of the request is denied by the script
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+
+    let objects = status_objects();
+    for (request, permission) in [
+        ("set_buffer_scale", WlPermission::Granted),
+        ("set_buffer_transform", WlPermission::Blocked),
+        ("damage", WlPermission::Unrestricted),
+    ] {
+        h.assert_c2s(
+            WlMitmV1RequestPermissionRequest::new(STATUS_ID, "wl_surface", request).build(),
+            WlMitmVerdict::Answered,
+        )
+        .await;
+        let msg = h.client.recv().await;
+        let event = parse::<WlMitmV1PermissionEvent>(&objects, &msg);
+        assert_eq!(event.request, request);
+        assert_eq!(event.permission, permission as u32);
+    }
+
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+    std::fs::remove_dir_all(script.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn destroyed_before_placeholder_is_deleted() {
+    let mut h = Harness::new(&config("/bin/false", true));
+    assert_eq!(h.setup_registry(GLOBALS).await, [STATUS_GLOBAL_NAME, 1]);
+    // Bound as the first object after the registry
+    let id = REGISTRY_ID + 1;
+    for _ in 0..2 {
+        bind_status(&mut h, id).await;
+        h.assert_c2s(
+            WlMitmV1DestroyRequest::new(id).build(),
+            WlMitmVerdict::Answered,
+        )
+        .await;
+        h.client.expect_nothing().await;
+
+        // The ID is only free once the compositor has deleted the placeholder
+        h.assert_s2c(
+            WlCallbackDoneEvent::new(id, 0).build(),
+            WlMitmVerdict::Filtered,
+        )
+        .await;
+        h.assert_s2c(
+            WlDisplayDeleteIdEvent::new(WL_DISPLAY_OBJECT_ID, id).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+    }
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn no_global_unless_enabled() {
+    let mut h = Harness::new(&config("/bin/false", false));
+    assert_eq!(h.setup_registry(GLOBALS).await, [1]);
+    h.finish().await.unwrap();
+}
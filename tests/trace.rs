@@ -0,0 +1,110 @@
+//! Decoded traces, written like WAYLAND_DEBUG would
+
+mod harness;
+
+use std::{os::unix::fs::PermissionsExt, path::Path};
+
+use harness::{Harness, REGISTRY_ID, temp_path};
+use wl_mitm::{
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceDamageRequest, WlSurfaceSetBufferScaleRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+fn traced_config(dir: &Path) -> String {
+    format!(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[trace]
+dir = "{}"
+
+[filter]
+allowed_globals = ["wl_compositor"]
+requests = [
+    {{ interface = "wl_surface", requests = ["set_buffer_scale"], action = "block" }},
+]
+"#,
+        dir.display()
+    )
+}
+
+fn read_trace(dir: &Path) -> Vec<String> {
+    let mut files: Vec<_> = std::fs::read_dir(dir).unwrap().collect();
+    assert_eq!(files.len(), 1, "expected exactly one trace file");
+    let path = files.pop().unwrap().unwrap().path();
+    assert!(path.to_str().unwrap().ends_with(".trace"));
+
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.split_once("] ").unwrap().1.to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn trace_is_decoded_with_verdicts() {
+    let dir = temp_path("trace-decoded");
+    let mut h = Harness::new(&traced_config(&dir));
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, 4).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceDamageRequest::new(4, 0, 0, 10, 20).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(4, 2).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.finish().await.unwrap();
+
+    assert_eq!(
+        read_trace(&dir),
+        [
+            "-> wl_display#1.get_registry(new id wl_registry#2)",
+            "<- wl_registry#2.global(1, \"wl_compositor\", 6)",
+            "-> wl_registry#2.bind(1, \"wl_compositor\", 6, new id #3)",
+            "-> wl_compositor#3.create_surface(new id wl_surface#4)",
+            "-> wl_surface#4.damage(0, 0, 10, 20)",
+            "-> wl_surface#4.set_buffer_scale(2) Filtered",
+        ]
+    );
+
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&dir), 0o700);
+    let trace = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+    assert_eq!(mode(&trace.path()), 0o600);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn trace_limited_to_matching_exe() {
+    let dir = temp_path("trace-exe");
+    let config = traced_config(&dir).replace(
+        "[filter]",
+        "exe = [\"/usr/bin/some-other-app\"]\n\n[filter]",
+    );
+    let mut h = Harness::new(&config);
+    h.setup_registry(&[("wl_compositor", 6)]).await;
+    h.finish().await.unwrap();
+
+    assert!(!dir.exists());
+}
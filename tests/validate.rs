@@ -0,0 +1,118 @@
+//! Validation of protocol state machines
+
+mod harness;
+
+use harness::{Harness, REGISTRY_ID};
+use wl_mitm::{
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceAttachRequest, XdgSurfaceAckConfigureRequest, XdgSurfaceConfigureEvent,
+        XdgWmBaseGetXdgSurfaceRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const SURFACE_ID: u32 = 5;
+const XDG_SURFACE_ID: u32 = 6;
+
+fn validated_config(mode: &str) -> String {
+    format!(
+        r#"
+[socket]
+listen = "@wl-mitm-test"
+upstream = "@wl-mitm-test-upstream"
+
+[validation]
+mode = "{}"
+
+[filter]
+allowed_globals = ["wl_compositor", "xdg_wm_base"]
+requests = []
+"#,
+        mode
+    )
+}
+
+/// Create a wl_surface with an xdg_surface role
+async fn setup_xdg_surface(h: &mut Harness) {
+    h.setup_registry(&[("wl_compositor", 6), ("xdg_wm_base", 6)])
+        .await;
+    let c2s = [
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "xdg_wm_base", 6, 4).build(),
+        WlCompositorCreateSurfaceRequest::new(3, SURFACE_ID).build(),
+        XdgWmBaseGetXdgSurfaceRequest::new(4, XDG_SURFACE_ID, SURFACE_ID).build(),
+    ];
+    for msg in c2s {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+}
+
+#[tokio::test]
+async fn acks_must_reference_pending_configures() {
+    let mut h = Harness::new(&validated_config("terminate"));
+    setup_xdg_surface(&mut h).await;
+
+    for serial in [10, 11, 12] {
+        h.assert_s2c(
+            XdgSurfaceConfigureEvent::new(XDG_SURFACE_ID, serial).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+    }
+    // Acking the latest serial only is fine, but skips the earlier ones
+    h.assert_c2s(
+        XdgSurfaceAckConfigureRequest::new(XDG_SURFACE_ID, 11).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceAttachRequest::new(SURFACE_ID, 7, 0, 0).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        XdgSurfaceAckConfigureRequest::new(XDG_SURFACE_ID, 10).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn buffers_wait_for_first_configure() {
+    let mut h = Harness::new(&validated_config("terminate"));
+    setup_xdg_surface(&mut h).await;
+
+    // Detaching is always fine
+    h.assert_c2s(
+        WlSurfaceAttachRequest::new(SURFACE_ID, 0, 0, 0).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlSurfaceAttachRequest::new(SURFACE_ID, 7, 0, 0).build(),
+        WlMitmVerdict::Terminate,
+    )
+    .await;
+    assert!(h.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn violations_pass_unless_terminating() {
+    for config in [validated_config("log"), validated_config("off")] {
+        let mut h = Harness::new(&config);
+        setup_xdg_surface(&mut h).await;
+        h.assert_c2s(
+            WlSurfaceAttachRequest::new(SURFACE_ID, 7, 0, 0).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+        h.assert_c2s(
+            XdgSurfaceAckConfigureRequest::new(XDG_SURFACE_ID, 1).build(),
+            WlMitmVerdict::Allowed,
+        )
+        .await;
+        h.finish().await.unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+//! Presenting selected clients a single virtual output
+
+mod harness;
+
+use harness::{Harness, REGISTRY_ID, test_config};
+use wl_mitm::{
+    codec::WlRawMsg,
+    objects::WlObjects,
+    proto::{
+        AnyWlParsedMessage, WL_OUTPUT, WL_SURFACE, WaylandProtocolParsingOutcome,
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlOutputModeEvent,
+        WlOutputNameEvent, WlOutputScaleEvent, WlParsedMessage, WlRegistryBindRequest,
+        WlSurfaceAttachRequest, WlSurfaceEnterEvent, WlSurfaceLeaveEvent,
+    },
+    state::WlMitmVerdict,
+};
+
+const OUTPUT_ID: u32 = 3;
+const COMPOSITOR_ID: u32 = 4;
+const SURFACE_ID: u32 = 5;
+const BUFFER_ID: u32 = 7;
+
+const GLOBALS: &[(&str, u32)] = &[("wl_compositor", 6), ("wl_output", 4), ("wl_output", 4)];
+
+/// `selection` picks the clients, all of them if empty
+fn config(selection: &str) -> String {
+    test_config(&format!(
+        r#"
+[virtual_output]
+enabled = true
+{}
+width = 2560
+height = 1440
+scale = 2
+
+[filter]
+allowed_globals = ["wl_compositor", "wl_output"]
+requests = []
+"#,
+        selection
+    ))
+}
+
+fn parse<'a, T: WlParsedMessage<'a> + 'a>(objects: &WlObjects, msg: &'a WlRawMsg) -> T {
+    let WaylandProtocolParsingOutcome::Ok(msg) = T::try_from_msg(objects, msg) else {
+        panic!("unexpected message from wl-mitm");
+    };
+    msg
+}
+
+fn client_objects() -> WlObjects {
+    let mut objects = WlObjects::new();
+    objects.record_object(WL_OUTPUT, OUTPUT_ID, 4);
+    objects.record_object(WL_SURFACE, SURFACE_ID, 6);
+    objects
+}
+
+async fn bind_output(h: &mut Harness, id: u32) {
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 2, "wl_output", 4, id).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn one_output_with_configured_mode() {
+    let mut h = Harness::new(&config(""));
+    assert_eq!(h.setup_registry(GLOBALS).await, [1, 2]);
+    bind_output(&mut h, OUTPUT_ID).await;
+    let objects = client_objects();
+
+    let mode = h
+        .assert_s2c(
+            WlOutputModeEvent::new(OUTPUT_ID, 0x3, 3840, 2160, 144000).build(),
+            WlMitmVerdict::Replaced,
+        )
+        .await
+        .unwrap();
+    let mode: WlOutputModeEvent = parse(&objects, &mode);
+    assert_eq!(
+        (mode.flags, mode.width, mode.height, mode.refresh),
+        (0x3, 2560, 1440, 60000)
+    );
+    h.assert_s2c(
+        WlOutputModeEvent::new(OUTPUT_ID, 0, 1920, 1080, 60000).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+
+    let scale = h
+        .assert_s2c(
+            WlOutputScaleEvent::new(OUTPUT_ID, 1).build(),
+            WlMitmVerdict::Replaced,
+        )
+        .await
+        .unwrap();
+    assert_eq!(parse::<WlOutputScaleEvent>(&objects, &scale).factor, 2);
+
+    let name = h
+        .assert_s2c(
+            WlOutputNameEvent::new(OUTPUT_ID, "DP-1").build(),
+            WlMitmVerdict::Replaced,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        parse::<WlOutputNameEvent>(&objects, &name).name,
+        "WL-MITM-1"
+    );
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn surfaces_enter_on_first_buffer() {
+    let mut h = Harness::new(&config(""));
+    h.setup_registry(GLOBALS).await;
+    bind_output(&mut h, OUTPUT_ID).await;
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, COMPOSITOR_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(COMPOSITOR_ID, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    // The compositor's idea of where the surface is never reaches the client
+    h.assert_s2c(
+        WlSurfaceEnterEvent::new(SURFACE_ID, OUTPUT_ID).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+    h.assert_s2c(
+        WlSurfaceLeaveEvent::new(SURFACE_ID, OUTPUT_ID).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+
+    let mut objects = client_objects();
+    h.assert_c2s(
+        WlSurfaceAttachRequest::new(SURFACE_ID, BUFFER_ID, 0, 0).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    let enter = h.client.recv().await;
+    let enter: WlSurfaceEnterEvent = parse(&objects, &enter);
+    assert_eq!((enter.obj_id(), enter.output), (SURFACE_ID, OUTPUT_ID));
+
+    // Only once
+    h.assert_c2s(
+        WlSurfaceAttachRequest::new(SURFACE_ID, BUFFER_ID, 0, 0).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.client.expect_nothing().await;
+
+    // Outputs bound later are entered right away
+    bind_output(&mut h, 6).await;
+    objects.record_object(WL_OUTPUT, 6, 4);
+    let enter = h.client.recv().await;
+    let enter: WlSurfaceEnterEvent = parse(&objects, &enter);
+    assert_eq!((enter.obj_id(), enter.output), (SURFACE_ID, 6));
+    h.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn unselected_clients_see_real_outputs() {
+    let mut h = Harness::new(&config(r#"exe = ["*/nonexistent"]"#));
+    assert_eq!(h.setup_registry(GLOBALS).await, [1, 2, 3]);
+    bind_output(&mut h, OUTPUT_ID).await;
+    h.assert_s2c(
+        WlOutputModeEvent::new(OUTPUT_ID, 0x3, 3840, 2160, 144000).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.finish().await.unwrap();
+}
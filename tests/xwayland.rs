@@ -0,0 +1,210 @@
+//! Recognizing XWayland and policing it apart from native clients
+
+mod harness;
+
+use std::path::Path;
+
+use harness::{Harness, REGISTRY_ID, poll_until, temp_path, test_config};
+use serde_json::Value;
+use wl_mitm::{
+    config::{Config, WlXwaylandConfig},
+    explain::{self, WlExplainQuery, WlRuleMatch},
+    peer::WlPeerInfo,
+    proto::{
+        WlCompositorCreateSurfaceRequest, WlConstructableMessage, WlRegistryBindRequest,
+        WlSurfaceSetBufferScaleRequest, XwaylandShellV1GetXwaylandSurfaceRequest,
+        XwaylandSurfaceV1SetSerialRequest,
+    },
+    state::WlMitmVerdict,
+};
+
+const SURFACE_ID: u32 = 4;
+
+fn config(dump: &str) -> String {
+    test_config(&format!(
+        r#"
+[dump]
+file = {:?}
+
+[xwayland]
+allowed_globals = ["wl_compositor", "xwayland_shell_v1"]
+
+[filter]
+allowed_globals = ["wl_compositor", "wp_viewporter", "xwayland_shell_v1"]
+requests = [
+    {{ interface = "wl_surface", requests = ["set_buffer_scale"], action = "block", xwayland = true }},
+]
+"#,
+        dump
+    ))
+}
+
+async fn read_dump(file: &Path) -> Value {
+    poll_until(&format!("a dump in {}", file.display()), || {
+        let content = std::fs::read_to_string(file).ok()?;
+        Some(serde_json::from_str(content.lines().next()?).unwrap())
+    })
+    .await
+}
+
+fn xwayland_peer() -> WlPeerInfo {
+    WlPeerInfo {
+        exe: Some("/usr/bin/Xwayland".into()),
+        ..Default::default()
+    }
+}
+
+/// Bind wl_compositor and create a surface
+async fn create_surface(h: &mut Harness) {
+    h.assert_c2s(
+        WlRegistryBindRequest::new(REGISTRY_ID, 1, "wl_compositor", 6, 3).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+    h.assert_c2s(
+        WlCompositorCreateSurfaceRequest::new(3, SURFACE_ID).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+}
+
+const GLOBALS: &[(&str, u32)] = &[
+    ("wl_compositor", 6),
+    ("wp_viewporter", 1),
+    ("xwayland_shell_v1", 1),
+];
+
+#[tokio::test]
+async fn xwayland_by_exe_gets_own_globals_and_rules() {
+    let file = temp_path("xwayland-exe");
+    let mut h = Harness::with_setup(&config(file.to_str().unwrap()), |duplex| {
+        duplex.set_peer(xwayland_peer())
+    });
+    assert_eq!(h.setup_registry(GLOBALS).await, [1, 3]);
+    create_surface(&mut h).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Filtered,
+    )
+    .await;
+
+    h.dumper.trigger();
+    assert_eq!(read_dump(&file).await["xwayland"], true);
+    h.finish().await.unwrap();
+    std::fs::remove_file(&file).ok();
+}
+
+#[tokio::test]
+async fn native_clients_are_policed_separately() {
+    let file = temp_path("xwayland-native");
+    let mut h = Harness::new(&config(file.to_str().unwrap()));
+    assert_eq!(h.setup_registry(GLOBALS).await, [1, 2, 3]);
+    create_surface(&mut h).await;
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    h.dumper.trigger();
+    assert_eq!(read_dump(&file).await["xwayland"], false);
+    h.finish().await.unwrap();
+    std::fs::remove_file(&file).ok();
+}
+
+#[tokio::test]
+async fn xwayland_shell_alone_isnt_trusted() {
+    let file = temp_path("xwayland-shell");
+    let mut h = Harness::new(&config(file.to_str().unwrap()));
+    h.setup_registry(GLOBALS).await;
+    create_surface(&mut h).await;
+
+    // Any client can bind xwayland_shell_v1, so XWayland's rules don't apply
+    for msg in [
+        WlRegistryBindRequest::new(REGISTRY_ID, 3, "xwayland_shell_v1", 1, 5).build(),
+        XwaylandShellV1GetXwaylandSurfaceRequest::new(5, 6, SURFACE_ID).build(),
+        XwaylandSurfaceV1SetSerialRequest::new(6, 42, 1).build(),
+    ] {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+    h.assert_c2s(
+        WlSurfaceSetBufferScaleRequest::new(SURFACE_ID, 2).build(),
+        WlMitmVerdict::Allowed,
+    )
+    .await;
+
+    h.dumper.trigger();
+    let dump = read_dump(&file).await;
+    assert_eq!(dump["xwayland"], false);
+    assert_eq!(dump["xwayland_shell"], true);
+    h.finish().await.unwrap();
+    std::fs::remove_file(&file).ok();
+}
+
+#[tokio::test]
+async fn x11_surfaces() {
+    let file = temp_path("xwayland-surfaces");
+    let mut h = Harness::with_setup(&config(file.to_str().unwrap()), |duplex| {
+        duplex.set_peer(xwayland_peer())
+    });
+    h.setup_registry(GLOBALS).await;
+    create_surface(&mut h).await;
+    for msg in [
+        WlRegistryBindRequest::new(REGISTRY_ID, 3, "xwayland_shell_v1", 1, 5).build(),
+        XwaylandShellV1GetXwaylandSurfaceRequest::new(5, 6, SURFACE_ID).build(),
+        XwaylandSurfaceV1SetSerialRequest::new(6, 42, 1).build(),
+    ] {
+        h.assert_c2s(msg, WlMitmVerdict::Allowed).await;
+    }
+
+    h.dumper.trigger();
+    let dump = read_dump(&file).await;
+    assert_eq!(dump["xwayland"], true);
+    assert_eq!(dump["xwayland_shell"], true);
+    let surface = dump["objects"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|o| o["id"] == SURFACE_ID)
+        .unwrap();
+    assert_eq!(
+        surface["extensions"],
+        serde_json::json!([format!("X11 window serial=Some({})", (1u64 << 32) | 42)])
+    );
+    h.finish().await.unwrap();
+    std::fs::remove_file(&file).ok();
+}
+
+#[test]
+fn default_exe_is_system_xwayland() {
+    let config = WlXwaylandConfig::default();
+    let peer = |exe: &str| WlPeerInfo {
+        exe: Some(exe.into()),
+        ..Default::default()
+    };
+    assert!(config.matches(&peer("/usr/bin/Xwayland")));
+    assert!(config.matches(&peer("/usr/libexec/Xwayland")));
+    assert!(!config.matches(&peer("/home/user/.local/bin/Xwayland")));
+    assert!(!config.matches(&peer("/tmp/Xwayland")));
+}
+
+#[test]
+fn explain_checks_xwayland() {
+    let config = Config::parse(&config("")).unwrap();
+    let result = |xwayland| {
+        let query = WlExplainQuery {
+            interface: "wl_surface".to_string(),
+            request: "set_buffer_scale".to_string(),
+            xwayland,
+            ..Default::default()
+        };
+        explain::explain(&config, &query, |_, _| true)
+            .unwrap()
+            .rules[0]
+            .result
+    };
+
+    assert_eq!(result(Some(true)), WlRuleMatch::Matches);
+    assert_eq!(result(Some(false)), WlRuleMatch::Skipped);
+    assert_eq!(result(None), WlRuleMatch::MayMatch);
+}
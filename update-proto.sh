@@ -12,7 +12,8 @@ cleanup() {
 
 trap cleanup EXIT
 
-rm -rf "${PROJECT_DIR}"/proto/*
+# Keep our own protocols, which are not part of wayland-explorer
+find "${PROJECT_DIR}"/proto -name "*.xml" ! -name "wl-mitm-*.xml" -delete
 
 git clone --recursive --depth=1 https://github.com/vially/wayland-explorer
 for xml in $(find "wayland-explorer/protocols/" -name '*.xml'); do